@@ -1,20 +1,53 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::Write;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use ab_glyph::Font;
 use std::sync::Arc;
-use crate::terminal_emulator::{ cursor_to_buffer_position, BlinkMode, CursorPos, CursorState, FormatTag, TerminalColor, TerminalEmulator, TerminalInput};
-use eframe::egui::{ self, text::LayoutJob, CentralPanel, Color32, DragValue, Event, FontData, FontDefinitions,
-                    FontFamily, FontId, InputState, Key, Modifiers, Rect, TextFormat, TextStyle, Ui};
-use std::borrow::Cow;
-use log::info;
+use crate::terminal_emulator::{
+    line_at, trim_line_ends, word_at, BlinkMode, CursorPos, CursorShape, CursorStyle, DynamicColor, FormatTag, HtmlColors, PromptMark, PromptMarkKind, ImagePlacement, KeyModifiers, TerminalColor, TerminalData, TerminalEmulator,
+    TerminalError, TerminalInput, DEFAULT_WORD_SEPARATORS,
+};
+use crate::terminal_emulator::tty::{Options, HANGUP_GRACE_PERIOD};
+mod keybindings;
+mod search;
+mod theme;
+mod urls;
 
-const REGULAR_FONT_NAME: &str = "JetBrainsMono-Regular";
-const BOLD_FONT_NAME: &str = "JetBrainsMono-Bold";
+pub use keybindings::{KeyAction, KeyBindings, KeyCombo};
+pub use theme::Theme;
+use search::Search;
+use urls::find_urls;
 
-const ITALIC_FONT_NAME: &str = "JetBrainsMono-Italic";
+use eframe::egui::{ self, text::{LayoutJob, LayoutSection}, CentralPanel, Color32, DragValue, Event, FontData, FontDefinitions,
+                    FontFamily, FontId, ImeEvent, InputState, Key, Modifiers, Pos2, Rect, TextFormat, TextStyle, Ui};
 
-fn char_to_ctrl_code(c: u8) -> u8 {
-    // https://catern.com/posts/terminal_quirks.html
-    // man ascii
-    c & 0b0001_1111
-}
+const REGULAR_FONT_NAME: &str = "terminal-regular";
+const BOLD_FONT_NAME: &str = "terminal-bold";
+const ITALIC_FONT_NAME: &str = "terminal-italic";
+/// Emoji fonts that come with egui's default fonts, tried in order for glyphs the faces above
+/// do not have
+const FALLBACK_FONT_NAMES: [&str; 2] = ["NotoEmoji-Regular", "emoji-icon-font"];
+/// Sized to fit in two cells, see [`fit_fallback_font`]
+const FALLBACK_SAMPLE_CHAR: char = '😀';
+
+/// Half of a cursor blink, the cursor is shown for this long and then hidden for as long
+const CURSOR_BLINK_INTERVAL: f64 = 0.5;
+/// Half of a text blink for SGR 5 and SGR 6, about once and twice a second
+const TEXT_BLINK_SLOW_INTERVAL: f64 = 0.5;
+const TEXT_BLINK_RAPID_INTERVAL: f64 = 0.25;
+/// Font sizes that can be picked in the context menu or with the font [`KeyAction`]s
+const FONT_SIZE_RANGE: std::ops::RangeInclusive<f32> = 1.0..=100.0;
+const DEFAULT_FONT_SIZE: f32 = 12.0;
+/// How often a shell is read when it could not get a reader thread to wake the GUI up
+const PTY_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(16);
+/// Window title until the shell sets one
+const DEFAULT_WINDOW_TITLE: &str = "Terminaux";
+/// Line spacing that can be configured, see [`GuiConfig::line_spacing`]
+const LINE_SPACING_RANGE: std::ops::RangeInclusive<f32> = 0.5..=3.0;
+/// Margin egui gives the central panel
+const DEFAULT_PADDING: i8 = 8;
 
 struct TerminalFonts {
     regular: FontFamily,
@@ -41,105 +74,595 @@ impl TerminalFonts {
         }
     }
 }
-fn terminal_color_to_egui(default_color: &Color32, color: &TerminalColor) -> Color32 {
-    match color {
-        TerminalColor::Default => default_color.clone(),
-        TerminalColor::ForegroundBlack => Color32::BLACK,
-        TerminalColor::ForegroundRed => Color32::RED,
-        TerminalColor::ForegroundGreen => Color32::GREEN,
-        TerminalColor::ForegroundYellow => Color32::YELLOW,
-        TerminalColor::ForegroundBlue => Color32::BLUE,
-        TerminalColor::ForegroundMagenta => Color32::from_rgb(255, 0, 255),
-        TerminalColor::ForegroundCyan => Color32::from_rgb(0, 255, 255),
-        TerminalColor::ForegroundWhite => Color32::WHITE,
-        TerminalColor::ForegroundBrightRed => Color32::from_rgb(255, 0, 0),
-        TerminalColor::ForegroundBrightGreen => Color32::from_rgb(0, 255, 0),
-        TerminalColor::ForegroundBrightYellow => Color32::from_rgb(255, 255, 0),
-        TerminalColor::ForegroundBrightBlue => Color32::from_rgb(0, 0, 255),
-        TerminalColor::ForegroundBrightMagenta => Color32::from_rgb(255, 0, 255),
-        TerminalColor::ForegroundBrightCyan => Color32::from_rgb(0, 255, 255),
-        TerminalColor::ForegroundBrightWhite => Color32::from_rgb(255, 255, 255),
-        TerminalColor::ForegroundRgb(r, g, b) => Color32::from_rgb(*r, *g, *b),
-        TerminalColor::Foreground8Bit(n) => {
-            let (r, g, b) = index_to_rgb(*n);
-            Color32::from_rgb(r, g, b)
-        }
-        TerminalColor::BackgroundTrueColor(r, g, b) => Color32::from_rgb(*r, *g, *b),
-        TerminalColor::BackgroundBlack => Color32::BLACK,
-        TerminalColor::BackgroundRed => Color32::RED,
-        TerminalColor::BackgroundGreen => Color32::GREEN,
-        TerminalColor::BackgroundYellow => Color32::YELLOW,
-        TerminalColor::BackgroundBlue => Color32::BLUE,
-        TerminalColor::BackgroundMagenta => Color32::from_rgb(255, 0, 255),
-        TerminalColor::BackgroundCyan => Color32::from_rgb(0, 255, 255),
-        TerminalColor::BackgroundWhite => Color32::WHITE,
-        TerminalColor::BackgroundBrightRed => Color32::from_rgb(255, 0, 0),
-        TerminalColor::BackgroundBrightGreen => Color32::from_rgb(0, 255, 0),
-        TerminalColor::BackgroundBrightYellow => Color32::from_rgb(255, 255, 0),
-        TerminalColor::BackgroundBrightBlue => Color32::from_rgb(0, 0, 255),
-        TerminalColor::BackgroundBrightMagenta => Color32::from_rgb(255, 0, 255),
-        TerminalColor::BackgroundBrightCyan => Color32::from_rgb(0, 255, 255),
-        TerminalColor::BackgroundBrightWhite => Color32::from_rgb(255, 255, 255),
-        _ =>  default_color.clone()
+/// Colors terminal output is drawn with, the theme's unless a program changed them. With the
+/// screen in reverse video the foreground and background are swapped
+struct OutputColors<'a> {
+    palette: &'a [(u8, u8, u8); 256],
+    foreground: Color32,
+    background: Color32,
+    cursor: Color32,
+    selection: Color32,
+    /// See [`Theme::bold_is_bright`]
+    bold_is_bright: bool,
+    text_blink: TextBlink,
+}
+
+impl OutputColors<'_> {
+    fn new<'a>(terminal_emulator: &'a TerminalEmulator, theme: &Theme) -> OutputColors<'a> {
+        let color = |color, default| match terminal_emulator.dynamic_color(color) {
+            Some((r, g, b)) => Color32::from_rgb(r, g, b),
+            None => default,
+        };
+        let mut foreground = color(DynamicColor::Foreground, theme.foreground);
+        let mut background = color(DynamicColor::Background, theme.background);
+        if terminal_emulator.reverse_screen() {
+            std::mem::swap(&mut foreground, &mut background);
+        }
+        OutputColors {
+            palette: terminal_emulator.palette(),
+            foreground,
+            background,
+            cursor: color(DynamicColor::Cursor, theme.cursor),
+            selection: theme.selection,
+            bold_is_bright: theme.bold_is_bright,
+            text_blink: TextBlink::default(),
+        }
+    }
+}
+
+/// Which blinking text is shown. All blinking text of a speed is on the same wall clock phase,
+/// independent of the cursor's blinking and of when the text was written
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct TextBlink {
+    slow: bool,
+    rapid: bool,
+}
+
+impl Default for TextBlink {
+    /// Everything shown, for output that is not drawn at a point in time
+    fn default() -> TextBlink {
+        TextBlink {
+            slow: true,
+            rapid: true,
+        }
+    }
+}
+
+impl TextBlink {
+    /// The phase at `time` seconds, each speed starts out shown
+    fn at(time: f64) -> TextBlink {
+        let shown = |interval: f64| ((time / interval) as u64).is_multiple_of(2);
+        TextBlink {
+            slow: shown(TEXT_BLINK_SLOW_INTERVAL),
+            rapid: shown(TEXT_BLINK_RAPID_INTERVAL),
+        }
+    }
+
+    /// Seconds from `time` until either speed toggles next
+    fn until_change(time: f64) -> f64 {
+        TEXT_BLINK_RAPID_INTERVAL - time.rem_euclid(TEXT_BLINK_RAPID_INTERVAL)
+    }
+
+    fn shows(&self, blink: BlinkMode) -> bool {
+        match blink {
+            BlinkMode::NoBlink => true,
+            BlinkMode::SlowBlink => self.slow,
+            BlinkMode::RapidBlink => self.rapid,
+        }
+    }
+}
+
+/// The bright version of the 8 basic foreground colors
+fn bright_foreground(color: &TerminalColor) -> Option<TerminalColor> {
+    let bright = match color {
+        TerminalColor::ForegroundBlack => TerminalColor::ForegroundBrightBlack,
+        TerminalColor::ForegroundRed => TerminalColor::ForegroundBrightRed,
+        TerminalColor::ForegroundGreen => TerminalColor::ForegroundBrightGreen,
+        TerminalColor::ForegroundYellow => TerminalColor::ForegroundBrightYellow,
+        TerminalColor::ForegroundBlue => TerminalColor::ForegroundBrightBlue,
+        TerminalColor::ForegroundMagenta => TerminalColor::ForegroundBrightMagenta,
+        TerminalColor::ForegroundCyan => TerminalColor::ForegroundBrightCyan,
+        TerminalColor::ForegroundWhite => TerminalColor::ForegroundBrightWhite,
+        _ => return None,
+    };
+    Some(bright)
+}
+
+/// How far faint text is moved from its color towards the background
+const FAINT_BLEND: f32 = 0.4;
+
+/// Color `tag` sets text to, in its bright version when bold is drawn that way
+fn tag_foreground(tag: &FormatTag, colors: &OutputColors) -> Color32 {
+    let bright = (colors.bold_is_bright && tag.attributes.bold)
+        .then(|| bright_foreground(&tag.fg_color))
+        .flatten();
+    let fg_color = bright.as_ref().unwrap_or(&tag.fg_color);
+    terminal_color_to_egui(&colors.foreground, fg_color, colors.palette)
+}
+
+/// Text color of `tag`, taking the attributes that change it into account
+fn foreground_to_egui(tag: &FormatTag, colors: &OutputColors) -> Color32 {
+    let color = match tag.attributes.reverse {
+        true => terminal_color_to_egui(&colors.background, &tag.bg_color, colors.palette),
+        false => tag_foreground(tag, colors),
+    };
+    if !tag.attributes.faint {
+        return color;
+    }
+
+    let background = match background_to_egui(tag, colors) {
+        Color32::TRANSPARENT => colors.background,
+        background => background,
+    };
+    let blend = |fg: u8, bg: u8| (fg as f32 + (bg as f32 - fg as f32) * FAINT_BLEND).round() as u8;
+    Color32::from_rgb(
+        blend(color.r(), background.r()),
+        blend(color.g(), background.g()),
+        blend(color.b(), background.b()),
+    )
+}
+
+/// Background of `tag`, transparent where the output's own background shows through. Reverse
+/// video swaps it with the text color, so with the screen reversed too it is back to the default
+fn background_to_egui(tag: &FormatTag, colors: &OutputColors) -> Color32 {
+    match tag.attributes.reverse {
+        true => tag_foreground(tag, colors),
+        false => terminal_color_to_egui(&Color32::TRANSPARENT, &tag.bg_color, colors.palette),
     }
 }
 
+fn terminal_color_to_egui(
+    default_color: &Color32,
+    color: &TerminalColor,
+    palette: &[(u8, u8, u8); 256],
+) -> Color32 {
+    let (r, g, b) = match color {
+        TerminalColor::Default => return *default_color,
+        TerminalColor::ForegroundRgb(r, g, b) | TerminalColor::BackgroundTrueColor(r, g, b) => {
+            (*r, *g, *b)
+        }
+        color => palette[color.palette_index().expect("only default and rgb have no index") as usize],
+    };
+    Color32::from_rgb(r, g, b)
+}
+
 struct TerminalOutputRenderResponse {
 scrollback_area: Rect,
 canvas_area: Rect,
+/// Parts of the scrollback and visible data that were drawn in the areas above
+scrollback_range: Range<usize>,
+canvas_range: Range<usize>,
 }
 
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum SelectionMode {
+    /// Everything from one character to the other, like selecting text anywhere else
+    Linear,
+    /// The rectangle between the two cells, with Alt held. The byte offsets lose the column when
+    /// the pointer is past the end of a line, so the columns are kept here
+    Block {
+        anchor_column: usize,
+        end_column: usize,
+    },
+}
 
-fn render_terminal_output(
-    ui: &mut egui::Ui,
-    terminal_emulator: &TerminalEmulator,
-    font_size: f32,
+/// Selected terminal output, as byte offsets into the scrollback and visible data joined by a
+/// newline. `anchor` is where the drag started and `end` is where the pointer is now, both
+/// inclusive
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Selection {
+    anchor: usize,
+    end: usize,
+    mode: SelectionMode,
+}
 
+impl Selection {
+    /// Selection of the characters in `range`, None if it is empty
+    fn from_range(range: Range<usize>) -> Option<Selection> {
+        (!range.is_empty()).then(|| Selection {
+            anchor: range.start,
+            end: range.end - 1,
+            mode: SelectionMode::Linear,
+        })
+    }
 
-) -> TerminalOutputRenderResponse {
-    let terminal_data = terminal_emulator.data();
-    let mut scrollback_data = terminal_data.scrollback;
-    let mut canvas_data = terminal_data.visible;
-    let mut format_data = terminal_emulator.format_data();
+    fn range(&self) -> Range<usize> {
+        self.anchor.min(self.end)..self.anchor.max(self.end) + 1
+    }
+
+    /// Byte ranges of `joined` that are selected, with lines wrapping at `width` columns. Block
+    /// selections have one per row, empty for rows that end left of the rectangle
+    fn ranges(&self, joined: &[u8], width: usize) -> Vec<Range<usize>> {
+        let SelectionMode::Block {
+            anchor_column,
+            end_column,
+        } = self.mode
+        else {
+            let range = self.range();
+            let range = range.start.min(joined.len())..range.end.min(joined.len());
+            return Vec::from([range]);
+        };
+
+        let rows = wrapped_rows(joined, width);
+        let (anchor_row, end_row) = (row_of(&rows, self.anchor), row_of(&rows, self.end));
+        let columns = anchor_column.min(end_column)..anchor_column.max(end_column) + 1;
+        rows[anchor_row.min(end_row)..anchor_row.max(end_row) + 1]
+            .iter()
+            .map(|row| {
+                let start = (row.start + columns.start).min(row.end);
+                start..(row.start + columns.end).min(row.end)
+            })
+            .collect()
+    }
+}
+
+/// Row of `rows` that `index` is on. An index at the end of a line, where a pointer past the end
+/// of it is mapped to, is on the line's last row
+fn row_of(rows: &[Range<usize>], index: usize) -> usize {
+    rows.iter()
+        .position(|row| row.contains(&index))
+        .or_else(|| rows.iter().position(|row| row.end == index))
+        .unwrap_or(rows.len().saturating_sub(1))
+}
+
+/// Offset of the visible data when joined after scrollback
+fn visible_offset(scrollback: &[u8]) -> usize {
+    if scrollback.is_empty() {
+        0
+    } else {
+        scrollback.len() + 1
+    }
+}
+
+/// Intersect `range` with `data_range`, and make it relative to the start of `data_range`
+fn range_within(range: &Range<usize>, data_range: Range<usize>) -> Option<Range<usize>> {
+    let start = range.start.max(data_range.start);
+    let end = range.end.min(data_range.end);
+    if start >= end {
+        return None;
+    }
+    Some(start - data_range.start..end - data_range.start)
+}
 
-    // Arguably incorrect. Scrollback does end with a newline, and that newline causes a blank
-    // space between widgets. Should we strip it here, or in the terminal emulator output?
-    if scrollback_data.ends_with(b"\n") {
-        scrollback_data = &scrollback_data[0..scrollback_data.len() - 1];
-        if let Some(last_tag) = format_data.scrollback.last_mut() {
-            last_tag.end = last_tag.end.min(scrollback_data.len());
+/// Scrollback and visible data as one, which is what [`Selection`] indexes into
+fn joined_data(data: &TerminalData<&[u8]>) -> Vec<u8> {
+    let mut joined = data.scrollback.to_vec();
+    if !joined.is_empty() {
+        joined.push(b'\n');
+    }
+    joined.extend_from_slice(data.visible);
+    joined
+}
+
+/// Text of the `ranges` of `joined` that [`Selection::ranges`] picked. A linear selection has
+/// trailing spaces dropped from every line, the rows of a block one are kept as they are so
+/// that columns still line up
+fn selected_text(joined: &[u8], ranges: &[Range<usize>], mode: SelectionMode) -> String {
+    let text = ranges
+        .iter()
+        .map(|range| &joined[range.clone()])
+        .collect::<Vec<_>>()
+        .join(&b'\n');
+    match mode {
+        SelectionMode::Linear => String::from_utf8_lossy(&trim_line_ends(&text)).into_owned(),
+        SelectionMode::Block { .. } => String::from_utf8_lossy(&text).into_owned(),
+    }
+}
+
+/// Byte ranges of the rows `data` takes up on screen, with lines wrapping at `width` columns
+fn wrapped_rows(data: &[u8], width: usize) -> Vec<Range<usize>> {
+    let width = width.max(1);
+    let mut rows = Vec::new();
+    if data.is_empty() {
+        return rows;
+    }
+
+    let mut line_start = 0;
+    for line in data.split(|b| *b == b'\n') {
+        if line.is_empty() {
+            rows.push(line_start..line_start);
         }
+        for row_start in (0..line.len()).step_by(width) {
+            let row_end = (row_start + width).min(line.len());
+            rows.push(line_start + row_start..line_start + row_end);
+        }
+        line_start += line.len() + 1;
+    }
+    rows
+}
+
+/// Rows to show on a screen `height` rows tall, scrolled `scroll_offset` rows up from the bottom
+/// of `total_rows`. The offset is clamped so the window never goes past the top
+fn visible_line_window(total_rows: usize, height: usize, scroll_offset: usize) -> Range<usize> {
+    let max_offset = total_rows.saturating_sub(height);
+    let end = total_rows - scroll_offset.min(max_offset);
+    end.saturating_sub(height)..end
+}
+
+/// Scroll offset once `new_rows` rows of output came in below the view. At the bottom the view
+/// follows the output, scrolled up it stays on the rows it was showing
+fn scroll_offset_after_output(scroll_offset: usize, new_rows: usize) -> usize {
+    match scroll_offset {
+        0 => 0,
+        offset => offset + new_rows,
     }
+}
 
-    if canvas_data.ends_with(b"\n") {
-        canvas_data = &canvas_data[0..canvas_data.len() - 1];
+/// Text of the button that scrolls back down, with how much output came in while scrolled up
+fn jump_to_bottom_label(rows_below: usize) -> String {
+    match rows_below {
+        0 => "Jump to bottom".to_string(),
+        1 => "1 new line, jump to bottom".to_string(),
+        rows => format!("{rows} new lines, jump to bottom"),
     }
+}
 
-    let response = egui::ScrollArea::new([false, true])
-        .auto_shrink([false, false])
-        .stick_to_bottom(true)
-        .show(ui, |ui| {
-            let scrollback_area =
-                add_terminal_data_to_ui(ui, scrollback_data, &format_data.scrollback, font_size)
-                    .rect;
-            let canvas_area =
-                add_terminal_data_to_ui(ui, canvas_data, &format_data.visible, font_size).rect;
-            TerminalOutputRenderResponse {
-                scrollback_area,
-                canvas_area,
+/// Row index, counting scrollback rows first, of every prompt start in `marks`
+fn prompt_rows(data: &TerminalData<&[u8]>, marks: &[PromptMark], width: usize) -> Vec<usize> {
+    let scrollback_rows = wrapped_rows(data.scrollback, width);
+    let canvas_rows = wrapped_rows(data.visible, width);
+    let canvas_offset = visible_offset(data.scrollback);
+    let row_of = |rows: &[Range<usize>], offset| {
+        rows.partition_point(|row| row.start <= offset).saturating_sub(1)
+    };
+
+    let mut prompt_rows = marks
+        .iter()
+        .filter(|mark| mark.kind == PromptMarkKind::PromptStart)
+        .map(|mark| match mark.start.checked_sub(canvas_offset) {
+            Some(offset) if !canvas_rows.is_empty() => {
+                scrollback_rows.len() + row_of(&canvas_rows, offset)
             }
+            _ => row_of(&scrollback_rows, mark.start),
+        })
+        .collect::<Vec<_>>();
+    prompt_rows.dedup();
+    prompt_rows
+}
+
+/// Row index, counting scrollback rows first, of `index` into the scrollback and visible data
+/// joined like [`joined_data`] does
+fn joined_row(data: &TerminalData<&[u8]>, index: usize, width: usize) -> usize {
+    let scrollback_rows = wrapped_rows(data.scrollback, width);
+    match index.checked_sub(visible_offset(data.scrollback)) {
+        Some(offset) if !data.visible.is_empty() => {
+            scrollback_rows.len() + row_of(&wrapped_rows(data.visible, width), offset)
+        }
+        _ => row_of(&scrollback_rows, index),
+    }
+}
+
+/// Byte range covering `row_range` of `rows`
+fn rows_to_byte_range(rows: &[Range<usize>], row_range: Range<usize>) -> Range<usize> {
+    if row_range.is_empty() {
+        return 0..0;
+    }
+    rows[row_range.start].start..rows[row_range.end - 1].end
+}
+
+/// Format tags for `range` of the data they were generated for, relative to the range start
+fn slice_format_tags(tags: &[FormatTag], range: &Range<usize>) -> Vec<FormatTag> {
+    tags.iter()
+        .filter_map(|tag| {
+            let tag_range = range_within(&(tag.start..tag.end), range.clone())?;
+            Some(FormatTag {
+                start: tag_range.start,
+                end: tag_range.end,
+                ..tag.clone()
+            })
+        })
+        .collect()
+}
+
+/// Size of the output text and how tall its rows are
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct OutputFont {
+    size: f32,
+    /// See [`line_height`]
+    line_height: Option<f32>,
+}
+
+/// What the laid out output depends on besides the emulator contents, which are tracked by
+/// `generation`
+#[derive(Clone, Debug, PartialEq)]
+struct OutputCacheKey {
+    /// Bumped whenever [`TerminalEmulator::take_dirty`] reports a change
+    generation: u64,
+    font: OutputFont,
+    available_width: f32,
+    /// See [`Selection::ranges`]
+    selection: Vec<Range<usize>>,
+    /// [`Search::generation`] of the search being highlighted, which is passed alongside
+    search: Option<u64>,
+    screen_size_chars: (usize, usize),
+    scroll_offset: usize,
+    /// Phase blinking text is drawn in, [`TextBlink::default`] unless the output has some
+    text_blink: TextBlink,
+}
+
+/// Output laid out for the last frame, reused until its [`OutputCacheKey`] changes so an idle
+/// terminal does not go through all of its data every frame
+struct OutputCache {
+    key: OutputCacheKey,
+    /// None when no scrollback is in view
+    scrollback_job: Option<LayoutJob>,
+    canvas_job: LayoutJob,
+    scrollback_range: Range<usize>,
+    canvas_range: Range<usize>,
+    /// Some of the laid out text blinks, so it has to be laid out again as the phase changes
+    blinking: bool,
+}
+
+impl OutputCache {
+    fn new(
+        style: &egui::Style,
+        terminal_emulator: &TerminalEmulator,
+        theme: &Theme,
+        search: Option<&Search>,
+        key: OutputCacheKey,
+    ) -> OutputCache {
+        let (width_chars, height_chars) = key.screen_size_chars;
+        let terminal_data = terminal_emulator.data();
+        let format_data = terminal_emulator.format_data();
+        let colors = OutputColors {
+            text_blink: key.text_blink,
+            ..OutputColors::new(terminal_emulator, theme)
+        };
+
+        let scrollback_rows = wrapped_rows(terminal_data.scrollback, width_chars);
+        let canvas_rows = wrapped_rows(terminal_data.visible, width_chars);
+        let num_scrollback_rows = scrollback_rows.len();
+        let window = visible_line_window(
+            num_scrollback_rows + canvas_rows.len(),
+            height_chars,
+            key.scroll_offset,
+        );
+
+        let scrollback_range = rows_to_byte_range(
+            &scrollback_rows,
+            window.start.min(num_scrollback_rows)..window.end.min(num_scrollback_rows),
+        );
+        let canvas_range = rows_to_byte_range(
+            &canvas_rows,
+            window.start.saturating_sub(num_scrollback_rows)
+                ..window.end.saturating_sub(num_scrollback_rows),
+        );
+
+        // Drawn in this order, so the selection shows over search matches
+        let mut highlights = Vec::new();
+        if let Some(search) = search {
+            let matches = search.matches().iter().cloned();
+            highlights.extend(matches.map(|range| (range, theme.search_match)));
+            highlights.extend(search.current_match().map(|range| (range, theme.search_current)));
+        }
+        highlights.extend(key.selection.iter().map(|range| (range.clone(), colors.selection)));
+        let highlights_within = |data_range: Range<usize>| {
+            highlights
+                .iter()
+                .filter_map(|(range, color)| Some((range_within(range, data_range.clone())?, *color)))
+                .collect::<Vec<_>>()
+        };
+        let scrollback_highlights = highlights_within(scrollback_range.clone());
+        let canvas_offset = visible_offset(terminal_data.scrollback);
+        let canvas_highlights = highlights_within(
+            canvas_offset + canvas_range.start..canvas_offset + canvas_range.end,
+        );
+
+        // An empty label still takes up a row, so only add scrollback when some is in view
+        let scrollback_job = (!scrollback_range.is_empty()).then(|| {
+            terminal_output_job(
+                style,
+                key.available_width,
+                &terminal_data.scrollback[scrollback_range.clone()],
+                &slice_format_tags(&format_data.scrollback, &scrollback_range),
+                key.font,
+                &scrollback_highlights,
+                &colors,
+            )
         });
 
-    response.inner
+        let canvas_job = terminal_output_job(
+            style,
+            key.available_width,
+            &terminal_data.visible[canvas_range.clone()],
+            &slice_format_tags(&format_data.visible, &canvas_range),
+            key.font,
+            &canvas_highlights,
+            &colors,
+        );
+
+        let blinking = [
+            slice_format_tags(&format_data.scrollback, &scrollback_range),
+            slice_format_tags(&format_data.visible, &canvas_range),
+        ]
+        .iter()
+        .flatten()
+        .any(|tag| tag.attributes.blink != BlinkMode::NoBlink);
+
+        OutputCache {
+            key,
+            scrollback_job,
+            canvas_job,
+            blinking,
+            scrollback_range,
+            canvas_range,
+        }
+    }
+}
+
+fn render_terminal_output(
+    ui: &mut egui::Ui,
+    terminal_emulator: &TerminalEmulator,
+    theme: &Theme,
+    search: Option<&Search>,
+    cache: &mut Option<OutputCache>,
+    key: OutputCacheKey,
+) -> TerminalOutputRenderResponse {
+    let cache = match cache {
+        Some(cache) if cache.key == key => cache,
+        _ => cache.insert(OutputCache::new(ui.style(), terminal_emulator, theme, search, key)),
+    };
+
+    ui.spacing_mut().item_spacing.y = 0.0;
+
+    let scrollback_area = match &cache.scrollback_job {
+        Some(job) => ui.label(job.clone()).rect,
+        None => Rect::from_min_size(ui.cursor().min, egui::Vec2::ZERO),
+    };
+    let canvas_area = ui.label(cache.canvas_job.clone()).rect;
+
+    TerminalOutputRenderResponse {
+        scrollback_area,
+        canvas_area,
+        scrollback_range: cache.scrollback_range.clone(),
+        canvas_range: cache.canvas_range.clone(),
+    }
 }
 
 struct DebugRenderer {
     enable: bool,
+    /// Show what the shell wrote before and after parsing, see [`TerminalEmulator::trace`]
+    raw_output: bool,
 }
 
 impl DebugRenderer {
     fn new() -> DebugRenderer {
-        DebugRenderer { enable: false }
+        DebugRenderer {
+            enable: false,
+            raw_output: false,
+        }
+    }
+
+    /// The raw output window, tracing `terminal_emulator` only while it is open
+    fn render_raw_output(&mut self, ctx: &egui::Context, terminal_emulator: &mut TerminalEmulator) {
+        terminal_emulator.set_tracing(self.raw_output);
+        let Some(trace) = terminal_emulator.trace() else {
+            return;
+        };
+
+        egui::Window::new("Raw output")
+            .open(&mut self.raw_output)
+            .default_size(egui::vec2(600.0, 400.0))
+            .show(ctx, |ui| {
+                ui.label(format!("Last {} bytes read", trace.bytes.len()));
+                egui::ScrollArea::vertical()
+                    .id_salt("raw_output_bytes")
+                    .max_height(ui.available_height() / 2.0)
+                    .stick_to_bottom(true)
+                    .show(ui, |ui| {
+                        ui.monospace(hex_dump(&trace.bytes));
+                    });
+                ui.separator();
+                ui.label(format!("Last {} parsed outputs", trace.outputs.len()));
+                egui::ScrollArea::vertical()
+                    .id_salt("raw_output_parsed")
+                    .stick_to_bottom(true)
+                    .show(ui, |ui| {
+                        for output in &trace.outputs {
+                            ui.monospace(output);
+                        }
+                    });
+            });
     }
 
     fn render(&self, ui: &mut Ui, rect: Rect, color: Color32) {
@@ -152,6 +675,152 @@ impl DebugRenderer {
     }
 }
 
+/// `bytes` as lines of an offset, 16 bytes in hex and the printable ones as ASCII, like
+/// `hexdump -C`
+fn hex_dump(bytes: &[u8]) -> String {
+    let mut dump = String::new();
+    for (i, line) in bytes.chunks(16).enumerate() {
+        let _ = write!(dump, "{:08x} ", i * 16);
+        for column in 0..16 {
+            if column == 8 {
+                dump.push(' ');
+            }
+            match line.get(column) {
+                Some(b) => {
+                    let _ = write!(dump, " {b:02x}");
+                }
+                None => dump.push_str("   "),
+            }
+        }
+        dump.push_str("  |");
+        dump.extend(line.iter().map(|&b| {
+            if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '.'
+            }
+        }));
+        dump.push_str("|\n");
+    }
+    dump
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum BellStyle {
+    None,
+    Visual,
+    Audible,
+    Both,
+}
+
+impl BellStyle {
+    const ALL: [BellStyle; 4] = [
+        BellStyle::None,
+        BellStyle::Visual,
+        BellStyle::Audible,
+        BellStyle::Both,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            BellStyle::None => "None",
+            BellStyle::Visual => "Visual",
+            BellStyle::Audible => "Audible",
+            BellStyle::Both => "Visual and audible",
+        }
+    }
+}
+
+/// How the visual bell flashes, see [`GuiConfig::bell`]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BellConfig {
+    /// Wash the window in the foreground color, which turns a dark background light and a light
+    /// one dark, instead of tinting it white
+    pub invert: bool,
+    /// How long the flash takes to fade away
+    pub fade_seconds: f64,
+}
+
+impl Default for BellConfig {
+    fn default() -> BellConfig {
+        BellConfig {
+            invert: true,
+            fade_seconds: 0.15,
+        }
+    }
+}
+
+/// Opacity of the foreground color at the start of an inverting flash, short of hiding the text
+const BELL_INVERT_OPACITY: f32 = 0.8;
+/// Opacity of white at the start of a tinting flash
+const BELL_TINT_OPACITY: f32 = 0.3;
+
+/// How much of the flash is left `elapsed` seconds after the bell, from 1 right when it rang
+/// down to 0 once `fade_seconds` have passed
+fn flash_alpha(elapsed: f64, fade_seconds: f64) -> f32 {
+    if !(0.0..fade_seconds).contains(&elapsed) {
+        return 0.0;
+    }
+    (1.0 - elapsed / fade_seconds) as f32
+}
+
+struct Bell {
+    style: BellStyle,
+    config: BellConfig,
+    /// When the bell last rang, ringing again starts the fade over
+    flash_start: Option<f64>,
+}
+
+impl Bell {
+    fn new(config: BellConfig) -> Bell {
+        Bell {
+            style: BellStyle::Visual,
+            config,
+            flash_start: None,
+        }
+    }
+
+    fn ring(&mut self, now: f64) {
+        if matches!(self.style, BellStyle::Audible | BellStyle::Both) {
+            // egui cannot play sounds, pass the bell on to whatever terminaux was started from
+            let mut stdout = std::io::stdout();
+            let _ = stdout.write_all(b"\x07");
+            let _ = stdout.flush();
+        }
+
+        if matches!(self.style, BellStyle::Visual | BellStyle::Both) {
+            self.flash_start = Some(now);
+        }
+    }
+
+    /// Flash the whole window after the bell rang, fading back to normal
+    fn render(&mut self, ctx: &egui::Context, foreground: Color32) {
+        let Some(flash_start) = self.flash_start else {
+            return;
+        };
+
+        let elapsed = ctx.input(|i| i.time) - flash_start;
+        let alpha = flash_alpha(elapsed, self.config.fade_seconds);
+        if alpha == 0.0 {
+            self.flash_start = None;
+            return;
+        }
+
+        let color = if self.config.invert {
+            foreground.gamma_multiply(BELL_INVERT_OPACITY * alpha)
+        } else {
+            Color32::WHITE.gamma_multiply(BELL_TINT_OPACITY * alpha)
+        };
+        let painter = ctx.layer_painter(egui::LayerId::new(
+            egui::Order::Foreground,
+            egui::Id::new("bell_flash"),
+        ));
+        painter.rect_filled(ctx.screen_rect(), 0.0, color);
+        // Every frame until the flash is gone, for the fade to be smooth
+        ctx.request_repaint();
+    }
+}
+
 fn create_terminal_output_layout_job(
     style: &egui::Style,
     width: f32,
@@ -170,143 +839,321 @@ fn create_terminal_output_layout_job(
     job.sections.clear();
     (job, textformat)
 }
-fn write_input_to_terminal(input: &InputState, terminal_emulator: &mut TerminalEmulator) {
+/// Cursor, editing and function keys, which are sent along with their modifiers, and Tab
+fn key_to_terminal_input(key: Key, modifiers: &Modifiers) -> Option<TerminalInput> {
+    let modifiers = KeyModifiers {
+        shift: modifiers.shift,
+        alt: modifiers.alt,
+        ctrl: modifiers.ctrl,
+    };
+    let input = match key {
+        Key::Tab if modifiers.shift => TerminalInput::BackTab,
+        Key::Tab => TerminalInput::Tab,
+        Key::ArrowUp => TerminalInput::ArrowUp(modifiers),
+        Key::ArrowDown => TerminalInput::ArrowDown(modifiers),
+        Key::ArrowLeft => TerminalInput::ArrowLeft(modifiers),
+        Key::ArrowRight => TerminalInput::ArrowRight(modifiers),
+        Key::Home => TerminalInput::Home(modifiers),
+        Key::End => TerminalInput::End(modifiers),
+        Key::Insert => TerminalInput::Insert(modifiers),
+        Key::Delete => TerminalInput::Delete(modifiers),
+        Key::PageUp => TerminalInput::PageUp(modifiers),
+        Key::PageDown => TerminalInput::PageDown(modifiers),
+        _ if (Key::F1..=Key::F12).contains(&key) => {
+            TerminalInput::Function(key as u8 - Key::F1 as u8 + 1, modifiers)
+        }
+        _ => return None,
+    };
+    Some(input)
+}
+
+/// Alt sends ESC before the key, the "meta sends escape" convention
+fn write_meta_prefix(
+    terminal_emulator: &mut TerminalEmulator,
+    modifiers: &Modifiers,
+    meta_sends_escape: bool,
+) -> Result<(), TerminalError> {
+    if meta_sends_escape && modifiers.alt {
+        terminal_emulator.write(TerminalInput::Ascii(b'\x1b'))?;
+    }
+    Ok(())
+}
+
+/// Text an input method finished composing, None while it is still composing. `preedit` follows
+/// what is being composed, to show at the cursor until it is committed. Dead keys go through this
+/// too on some platforms
+fn ime_committed<'a>(preedit: &mut String, event: &'a ImeEvent) -> Option<&'a str> {
+    match event {
+        ImeEvent::Enabled => None,
+        ImeEvent::Preedit(text) => {
+            text.clone_into(preedit);
+            None
+        }
+        ImeEvent::Commit(text) => {
+            preedit.clear();
+            // Enter is sent as a key as well, like egui's TextEdit the commit is dropped
+            (!text.is_empty() && text != "\n" && text != "\r").then_some(text.as_str())
+        }
+        ImeEvent::Disabled => {
+            preedit.clear();
+            None
+        }
+    }
+}
+
+/// Keys an input method edits the composition with, which are not for the shell while composing.
+/// The same ones egui's TextEdit drops
+fn is_composition_key(event: &Event) -> bool {
+    matches!(
+        event,
+        Event::Key { repeat: true, .. }
+            | Event::Key {
+                key: Key::Backspace
+                    | Key::ArrowUp
+                    | Key::ArrowDown
+                    | Key::ArrowLeft
+                    | Key::ArrowRight,
+                ..
+            }
+    )
+}
+
+/// Text on the clipboard, egui can only write it. Empty when it cannot be read
+fn read_clipboard() -> String {
+    arboard::Clipboard::new()
+        .and_then(|mut clipboard| clipboard.get_text())
+        .unwrap_or_else(|e| {
+            warn!("Failed to read clipboard: {e}");
+            String::new()
+        })
+}
+
+/// Output that can be copied all at once
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum CopyTarget {
+    Screen,
+    All,
+}
+
+/// What [`write_input_to_terminal`] leaves for the window to do
+#[derive(Debug, Default, PartialEq)]
+struct InputActions {
+    /// Actions of bound keys, in the order they were pressed
+    keys: Vec<KeyAction>,
+    /// Pasted text held back to be confirmed first
+    held_paste: Option<String>,
+}
+
+/// Paste `text`, unless `paste_confirm` asks to confirm it first. Then it is left in `held`
+fn paste_or_hold(
+    terminal_emulator: &mut TerminalEmulator,
+    text: &str,
+    paste_confirm: &PasteConfirmConfig,
+    held: &mut Option<String>,
+) -> Result<(), TerminalError> {
+    if paste_confirm.needed(text, terminal_emulator.bracketed_paste_mode()) {
+        *held = Some(text.to_string());
+        return Ok(());
+    }
+    terminal_emulator.paste(text)
+}
+
+/// Whether a middle click pastes the primary selection, as on X11. Not on macOS, which has no
+/// such thing
+const PASTE_PRIMARY_ON_MIDDLE_CLICK: bool = cfg!(not(target_os = "macos"));
+
+/// Paste `primary_selection` like [`paste_or_hold`] when the middle button is pressed over
+/// `output_area`
+fn paste_primary_on_middle_click(
+    ui: &Ui,
+    output_area: Rect,
+    terminal_emulator: &mut TerminalEmulator,
+    primary_selection: &str,
+    paste_confirm: &PasteConfirmConfig,
+    held: &mut Option<String>,
+) -> Result<(), TerminalError> {
+    let pressed = ui.input(|i| i.pointer.button_pressed(egui::PointerButton::Middle));
+    if !PASTE_PRIMARY_ON_MIDDLE_CLICK
+        || !pressed
+        || primary_selection.is_empty()
+        || !ui.rect_contains_pointer(output_area)
+    {
+        return Ok(());
+    }
+    paste_or_hold(terminal_emulator, primary_selection, paste_confirm, held)
+}
+
+/// Goes through `input` sending keys and text to the shell, except for keys `bindings` give an
+/// action. Those are returned in order, a paste with the text it came with is done right away
+/// unless it has to be confirmed. Input method text is only sent once committed, until then it
+/// is kept in `preedit`
+fn write_input_to_terminal(
+    input: &InputState,
+    terminal_emulator: &mut TerminalEmulator,
+    meta_sends_escape: bool,
+    bindings: &KeyBindings,
+    paste_confirm: &PasteConfirmConfig,
+    preedit: &mut String,
+) -> Result<InputActions, TerminalError> {
+    let mut actions = InputActions::default();
+    // Text typed with a bound key is part of it
+    let mut skip_text = false;
     for event in &input.raw.events {
+        if let Event::Ime(ime_event) = event {
+            if let Some(text) = ime_committed(preedit, ime_event) {
+                for b in text.as_bytes() {
+                    terminal_emulator.write(TerminalInput::Ascii(*b))?;
+                }
+            }
+            continue;
+        }
+        if !preedit.is_empty() && is_composition_key(event) {
+            continue;
+        }
+
+        // Copy and paste shortcuts arrive without their key
+        let bound_key = match event {
+            Event::Key {
+                key,
+                pressed: true,
+                modifiers,
+                ..
+            } => Some((*key, *modifiers)),
+            Event::Copy => Some((Key::C, input.modifiers)),
+            Event::Paste(_) => Some((Key::V, input.modifiers)),
+            _ => None,
+        };
+        let action = bound_key.and_then(|(key, modifiers)| bindings.action(key, &modifiers));
+        match (action, event) {
+            (None | Some(KeyAction::SendInput), _) => (),
+            (Some(KeyAction::Paste), Event::Paste(text)) => {
+                paste_or_hold(terminal_emulator, text, paste_confirm, &mut actions.held_paste)?;
+                continue;
+            }
+            (Some(action), _) => {
+                actions.keys.push(action);
+                skip_text = true;
+                continue;
+            }
+        }
+        if bound_key.is_some() {
+            skip_text = false;
+        }
+
+        if let Event::Key {
+            key,
+            pressed: true,
+            modifiers,
+            ..
+        } = event
+        {
+            if let Some(input) = key_to_terminal_input(*key, modifiers) {
+                terminal_emulator.write(input)?;
+                continue;
+            }
+        }
+
         match event {
+            Event::Text(_) if skip_text => skip_text = false,
             Event::Text(text) => {
-                for c in text.as_bytes() {
-                    terminal_emulator.write(TerminalInput::Ascii(*c));
+                for c in text.chars() {
+                    write_meta_prefix(terminal_emulator, &input.modifiers, meta_sends_escape)?;
+                    for b in c.encode_utf8(&mut [0; 4]).as_bytes() {
+                        terminal_emulator.write(TerminalInput::Ascii(*b))?;
+                    }
                 }
             }
+            Event::Paste(text) => {
+                paste_or_hold(terminal_emulator, text, paste_confirm, &mut actions.held_paste)?;
+            }
             Event::Key {
                 key: Key::Enter,
                 pressed: true,
+                modifiers,
                 ..
             } => {
-            terminal_emulator.write(TerminalInput::Enter);
-        }
+                write_meta_prefix(terminal_emulator, modifiers, meta_sends_escape)?;
+                terminal_emulator.write(TerminalInput::Enter)?;
+            }
             // https://github.com/emilk/egui/issues/3653
             Event::Copy => {
-                terminal_emulator.write(TerminalInput::Ctrl(b'c'));
+                terminal_emulator.write(TerminalInput::Ctrl(b'c'))?;
             }
+            // XOFF/XON are handled here rather than by the kernel so the pause can be shown
             Event::Key {
-                key,
+                key: key @ (Key::S | Key::Q),
                 pressed: true,
                 modifiers: Modifiers { ctrl: true, .. },
                 ..
+            } if terminal_emulator.flow_control_enabled() => {
+                terminal_emulator.set_paused(*key == Key::S);
+            }
+            Event::Key {
+                key,
+                pressed: true,
+                modifiers: modifiers @ Modifiers { ctrl: true, .. },
+                ..
             } => {
-                if *key >= Key::A && *key <= Key::Z {
+                let c = if *key >= Key::A && *key <= Key::Z {
                     let name = key.name();
                     assert!(name.len() == 1);
-                    let name_c = name.as_bytes()[0];
-                    terminal_emulator.write(TerminalInput::Ctrl(name_c));
+                    name.as_bytes()[0]
                 } else if *key == Key::OpenBracket {
-                    terminal_emulator.write(TerminalInput::Ctrl(b'['));
+                    b'['
                 } else if *key == Key::CloseBracket {
-                    let ctrl_code = char_to_ctrl_code(b']');
-                    terminal_emulator.write(TerminalInput::Ctrl(b']'));
+                    b']'
                 } else if *key == Key::Backslash {
-                    terminal_emulator.write(TerminalInput::Ctrl(b'\\'));
+                    b'\\'
                 } else {
                     warn!("Unexpected ctrl key: {}", key.name());
-                }
+                    continue;
+                };
+                write_meta_prefix(terminal_emulator, modifiers, meta_sends_escape)?;
+                terminal_emulator.write(TerminalInput::Ctrl(c))?;
             }
             Event::Key {
                 key: Key::Backspace,
                 pressed: true,
+                modifiers,
                 ..
             } => {
-                terminal_emulator.write(TerminalInput::Backspace);
-            }
-            Event::Key {
-                key: Key::ArrowUp,
-                pressed: true,
-                ..
-            } => {
-                terminal_emulator.write(TerminalInput::ArrowUp);
-            }
-            Event::Key {
-                key: Key::ArrowDown,
-                pressed: true,
-                ..
-            } => {
-                terminal_emulator.write(TerminalInput::ArrowDown);
-            }
-            Event::Key {
-                key: Key::ArrowLeft,
-                pressed: true,
-                ..
-            } => {
-                terminal_emulator.write(TerminalInput::ArrowLeft);
-            }
-            Event::Key {
-                key: Key::ArrowRight,
-                pressed: true,
-                ..
-            } => {
-                terminal_emulator.write(TerminalInput::ArrowRight);
-            }
-            Event::Key {
-                key: Key::Home,
-                pressed: true,
-                ..
-            } => {
-                terminal_emulator.write(TerminalInput::Home);
-            }
-            Event::Key {
-                key: Key::End,
-                pressed: true,
-                ..
-            } => {
-                terminal_emulator.write(TerminalInput::End);
+                write_meta_prefix(terminal_emulator, modifiers, meta_sends_escape)?;
+                terminal_emulator.write(TerminalInput::Backspace)?;
             }
             _ => (),
         };
 
     }
+
+    Ok(actions)
+}
+
+/// `font_size` after a font [`KeyAction`], which steps it by a point within [`FONT_SIZE_RANGE`]
+fn zoom_font_size(font_size: f32, action: KeyAction) -> f32 {
+    let font_size = match action {
+        KeyAction::IncreaseFont => font_size + 1.0,
+        KeyAction::DecreaseFont => font_size - 1.0,
+        KeyAction::ResetFont => DEFAULT_FONT_SIZE,
+        _ => font_size,
+    };
+    font_size.clamp(*FONT_SIZE_RANGE.start(), *FONT_SIZE_RANGE.end())
+}
+
+/// Height of a row of output, `line_spacing` times the font's own. None leaves it to the font.
+/// Rounded to whole pixels so that every row is as tall
+fn line_height(ctx: &egui::Context, font_size: f32, line_spacing: f32) -> Option<f32> {
+    if line_spacing == 1.0 {
+        return None;
+    }
+    let font_id = FontId::new(font_size, FontFamily::Name(REGULAR_FONT_NAME.into()));
+    let height = ctx.fonts(|fonts| fonts.row_height(&font_id)) * line_spacing;
+    let pixels_per_point = ctx.pixels_per_point();
+    Some((height * pixels_per_point).round() / pixels_per_point)
 }
-fn index_to_rgb(index: u8) -> (u8, u8, u8) {
-let index = index as u32;
-if index < 16 {
-// Basic 16 colors
-match index {
-0 => (0, 0, 0),       // Black
-1 => (128, 0, 0),     // Red
-2 => (0, 128, 0),     // Green
-3 => (128, 128, 0),   // Yellow
-4 => (0, 0, 128),     // Blue
-5 => (128, 0, 128),   // Magenta
-6 => (0, 128, 128),   // Cyan
-7 => (192, 192, 192), // White
-8 => (128, 128, 128), // Bright black
-9 => (255, 0, 0),     // Bright red
-10 => (0, 255, 0),    // Bright green
-11 => (255, 255, 0), // Bright yellow
-12 => (0, 0, 255),   // Bright blue
-13 => (255, 0, 255), // Bright magenta
-14 => (0, 255, 255), // Bright cyan
-15 => (255, 255, 255), // Bright white
-_ => (0, 0, 0),
-}
-} else if index >= 16 && index <= 231 {
-// 6x6x6 color cube
-let index = index - 16;
-let r = index / 36;
-let g = (index % 36) / 6;
-let b = index % 6;
-(
-(r * 51) as u8,
-(g * 51) as u8,
-(b * 51) as u8,
-)
-} else {
-// Grayscale
-let gray = 8 + (index - 232) as u8 * 10;
-(gray, gray, gray)
-}
-}
-
-fn get_char_size(ctx: &egui::Context, font_size: f32) -> (f32, f32) {
+
+/// Size of a character cell, with rows `line_height` tall like [`terminal_output_job`] lays
+/// them out
+fn get_char_size(ctx: &egui::Context, font_size: f32, line_height: Option<f32>) -> (f32, f32) {
     let font_id = FontId {
         size: font_size,
         family: FontFamily::Name(REGULAR_FONT_NAME.into()),
@@ -316,8 +1163,7 @@ fn get_char_size(ctx: &egui::Context, font_size: f32) -> (f32, f32) {
     // bounds of a single character is not reasonable. Instead we layout 16 rows and 16 cols and
     // divide by 16. This seems to work better at all font scales
     ctx.fonts(move |fonts| {
-        let rect = fonts
-            .layout(
+        let mut job = LayoutJob::simple(
                 "asdfasdfasdfasdf\n\
                  asdfasdfasdfasdf\n\
                  asdfasdfasdfasdf\n\
@@ -338,28 +1184,110 @@ fn get_char_size(ctx: &egui::Context, font_size: f32) -> (f32, f32) {
                 font_id.clone(),
                 Color32::WHITE,
                 f32::INFINITY,
-            )
-            .rect;
+            );
+        job.sections[0].format.line_height = line_height;
+        let galley = fonts.layout_job(job);
 
-        let width = rect.width() / 16.0;
-        let height = rect.height() / 16.0;
+        // Across the row from the first glyph to the last, as the text's bounds also take how far
+        // the last glyph sticks out, which adds up to whole pixels across a wide screen
+        let glyphs = &galley.rows[0].glyphs;
+        let width = (glyphs[15].pos.x - glyphs[0].pos.x) / 15.0;
+        let height = galley.rect.height() / 16.0;
 
         (width, height)
     })
 }
 
-fn character_to_cursor_offset(
-    character_pos: &CursorPos,
+/// Map a pixel offset from the top left of rendered output to the cell under it
+fn cursor_offset_to_character(offset: egui::Vec2, character_size: &(f32, f32)) -> CursorPos {
+    CursorPos {
+        x: (offset.x / character_size.0).max(0.0) as usize,
+        y: (offset.y / character_size.1).max(0.0) as usize,
+    }
+}
+
+/// Index into `data` of the character drawn at `cell`, with lines wrapping at `width` columns
+///
+/// Cells past the end of a line map to the end of that line, cells below the data map to its end
+fn character_to_data_index(data: &[u8], width: usize, cell: &CursorPos) -> usize {
+    let width = width.max(1);
+    let mut row = 0;
+    let mut line_start = 0;
+    for line in data.split(|b| *b == b'\n') {
+        let num_rows = line.len().div_ceil(width).max(1);
+        if cell.y < row + num_rows {
+            let column = (cell.y - row) * width + cell.x.min(width - 1);
+            return line_start + column.min(line.len());
+        }
+        row += num_rows;
+        line_start += line.len() + 1;
+    }
+
+    data.len()
+}
+
+/// Map a pointer position to an index into the joined scrollback/visible data
+fn pointer_to_selection_index(
+    pos: Pos2,
+    areas: &TerminalOutputRenderResponse,
+    data: &TerminalData<&[u8]>,
+    width_chars: usize,
+    character_size: &(f32, f32),
+) -> usize {
+    if pos.y < areas.canvas_area.top() {
+        let cell = cursor_offset_to_character(pos - areas.scrollback_area.min, character_size);
+        let shown = &data.scrollback[areas.scrollback_range.clone()];
+        areas.scrollback_range.start + character_to_data_index(shown, width_chars, &cell)
+    } else {
+        let cell = cursor_offset_to_character(pos - areas.canvas_area.min, character_size);
+        let shown = &data.visible[areas.canvas_range.clone()];
+        visible_offset(data.scrollback)
+            + areas.canvas_range.start
+            + character_to_data_index(shown, width_chars, &cell)
+    }
+}
+
+/// Draw sixel images over the cells reserved for them, uploading new ones as textures
+/// Size of a character cell in physical pixels, which programs size images by
+fn cell_size_in_pixels(character_size: &(f32, f32), pixels_per_point: f32) -> (usize, usize) {
+    (
+        (character_size.0 * pixels_per_point).round() as usize,
+        (character_size.1 * pixels_per_point).round() as usize,
+    )
+}
+
+fn paint_images(
+    ui: &mut Ui,
+    canvas_area: Rect,
     character_size: &(f32, f32),
-    content: &[u8],
-) -> (f32, f32) {
-    let content_by_lines: Vec<&[u8]> = content.split(|b| *b == b'\n').collect();
-    let num_lines = content_by_lines.len();
-    let x_offset = character_pos.x as f32 * character_size.0;
-    let y_offset = (character_pos.y as i64 - num_lines as i64) as f32 * character_size.1;
-    (x_offset, y_offset)
+    images: &[ImagePlacement],
+    textures: &mut HashMap<u64, egui::TextureHandle>,
+) {
+    textures.retain(|id, _| images.iter().any(|placement| placement.id == *id));
 
+    for placement in images {
+        let image = &placement.image;
+        let texture = textures.entry(placement.id).or_insert_with(|| {
+            let color_image =
+                egui::ColorImage::from_rgba_unmultiplied([image.width, image.height], &image.rgba);
+            ui.ctx().load_texture(
+                format!("terminal_image_{}", placement.id),
+                color_image,
+                egui::TextureOptions::NEAREST,
+            )
+        });
 
+        let min = canvas_area.min
+            + egui::vec2(
+                placement.pos.x as f32 * character_size.0,
+                placement.pos.y as f32 * character_size.1,
+            );
+        // One image pixel per physical pixel, like the cell size programs were told
+        let size = egui::vec2(image.width as f32, image.height as f32) / ui.ctx().pixels_per_point();
+        let rect = Rect::from_min_size(min, size);
+        let uv = Rect::from_min_max(egui::pos2(0.0, 0.0), egui::pos2(1.0, 1.0));
+        ui.painter().image(texture.id(), rect, uv, Color32::WHITE);
+    }
 }
 
 fn paint_cursor(
@@ -367,50 +1295,533 @@ fn paint_cursor(
     character_size: &(f32, f32),
     cursor_pos: &CursorPos,
    // terminal_buf: &[u8],
+    color: Color32,
+    shape: CursorShape,
     ui: &mut Ui,
 ) {
-    let painter = ui.painter();
+    let pixels_per_point = ui.ctx().pixels_per_point();
+    let rect = cursor_rect(label_rect, character_size, cursor_pos, shape, pixels_per_point);
+    ui.painter().rect_filled(rect, 0.0, color);
+}
+
+/// Draw what an input method is composing over the output from the cursor on, underlined like
+/// input methods show it themselves
+fn paint_preedit(
+    ui: &mut Ui,
+    label_rect: Rect,
+    character_size: &(f32, f32),
+    cursor_pos: &CursorPos,
+    preedit: &str,
+    font_size: f32,
+    colors: &OutputColors,
+) {
+    let pixels_per_point = ui.ctx().pixels_per_point();
+    let cell = cursor_rect(label_rect, character_size, cursor_pos, CursorShape::Block, pixels_per_point);
+    let font_id = FontId::new(font_size, FontFamily::Name(REGULAR_FONT_NAME.into()));
+    let galley = ui.painter().layout_no_wrap(preedit.to_string(), font_id, colors.foreground);
+    let rect = Rect::from_min_size(cell.min, egui::vec2(galley.size().x, cell.height()));
 
-  //  let bottom = label_rect.bottom();
-    let top = label_rect.top();
-    let left = label_rect.left();
-   // let cursor_offset = character_to_cursor_offset(cursor_pos, character_size, terminal_buf);
-   // let cursor_x = cursor_offset.0 - left;
-    //let cursor_y = bottom + cursor_offset.1;
-    let y_offset = cursor_pos.y as f32 * character_size.1;
-    let x_offset = cursor_pos.x as f32 * character_size.0 - left;
+    let painter = ui.painter();
+    painter.rect_filled(rect, 0.0, colors.background);
+    painter.galley(rect.min, galley, colors.foreground);
+    painter.hline(
+        rect.x_range(),
+        rect.bottom() - 1.0 / pixels_per_point,
+        egui::Stroke::new(1.0 / pixels_per_point, colors.foreground),
+    );
+}
 
-    painter.rect_filled(
-        Rect::from_min_size(
-            egui::pos2(left + x_offset, top + y_offset),
-            egui::vec2(character_size.0, character_size.1),
+/// Where the cursor at `cursor_pos` of the output drawn in `label_rect` goes, in points
+fn cursor_rect(
+    label_rect: Rect,
+    character_size: &(f32, f32),
+    cursor_pos: &CursorPos,
+    shape: CursorShape,
+    pixels_per_point: f32,
+) -> Rect {
+    let cell = Rect::from_min_size(
+        label_rect.min
+            + egui::vec2(
+                cursor_pos.x as f32 * character_size.0,
+                cursor_pos.y as f32 * character_size.1,
+            ),
+        egui::vec2(character_size.0, character_size.1),
+    );
 
+    // Thin shapes are an eighth of the cell, but always at least a physical pixel
+    let thickness = |size: f32| (size * pixels_per_point / 8.0).round().max(1.0) / pixels_per_point;
+    match shape {
+        CursorShape::Block => cell,
+        CursorShape::Underline => Rect::from_min_max(
+            egui::pos2(cell.left(), cell.bottom() - thickness(cell.height())),
+            cell.max,
         ),
-        0.0,
-        Color32::GRAY,
-    );
+        CursorShape::Bar => Rect::from_min_max(
+            cell.min,
+            egui::pos2(cell.left() + thickness(cell.width()), cell.bottom()),
+        ),
+    }
+}
+
+/// Cursor drawn until a program picks its own with DECSCUSR
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CursorConfig {
+    pub shape: CursorShape,
+    pub blink: bool,
+}
+
+impl CursorConfig {
+    /// Names accepted by `--cursor-shape`
+    pub const SHAPE_NAMES: [&'static str; 3] = ["block", "underline", "bar"];
+
+    fn shape_by_name(name: &str) -> Option<CursorShape> {
+        match name {
+            "block" => Some(CursorShape::Block),
+            "underline" => Some(CursorShape::Underline),
+            "bar" => Some(CursorShape::Bar),
+            _ => None,
+        }
+    }
+
+    /// What to draw, `app_style` being [`TerminalEmulator::cursor_style`]
+    fn style(&self, app_style: Option<CursorStyle>) -> CursorStyle {
+        app_style.unwrap_or(CursorStyle {
+            shape: self.shape,
+            blink: self.blink,
+        })
+    }
+}
+
+impl Default for CursorConfig {
+    fn default() -> CursorConfig {
+        CursorConfig {
+            shape: CursorShape::Block,
+            blink: false,
+        }
+    }
+}
+
+/// Which pastes are shown for confirmation before they are sent to the shell
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PasteConfirmConfig {
+    /// Pastes longer than this many bytes
+    pub max_len: Option<usize>,
+    /// Pastes with a newline in them, which would run what comes before it
+    pub newlines: bool,
+}
+
+impl PasteConfirmConfig {
+    /// Whether to ask before pasting `text`. Never when the paste is `bracketed`, the program
+    /// then gets it as a whole rather than running it line by line
+    fn needed(&self, text: &str, bracketed: bool) -> bool {
+        if bracketed {
+            return false;
+        }
+        let too_long = self.max_len.is_some_and(|max_len| text.len() > max_len);
+        too_long || self.newlines && text.contains(['\n', '\r'])
+    }
+}
+
+impl Default for PasteConfirmConfig {
+    fn default() -> PasteConfirmConfig {
+        PasteConfirmConfig {
+            max_len: Some(4096),
+            newlines: true,
+        }
+    }
+}
+
+/// Whether a blinking cursor is shown at `time` seconds
+fn cursor_blink_on(time: f64) -> bool {
+    ((time / CURSOR_BLINK_INTERVAL) as u64).is_multiple_of(2)
+}
+
+/// `#rrggbb`
+fn parse_hex_color(s: &str) -> Option<Color32> {
+    let hex = s.strip_prefix('#')?;
+    if hex.len() != 6 || !hex.is_ascii() {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some(Color32::from_rgb(channel(0)?, channel(2)?, channel(4)?))
+}
+
+/// The value of `flag`, given as `flag=value` or as the next argument
+fn take_value(
+    flag: &str,
+    value: Option<String>,
+    args: &mut impl Iterator<Item = String>,
+) -> Result<String, String> {
+    value
+        .or_else(|| args.next())
+        .ok_or_else(|| format!("{flag} needs a value"))
+}
+
+/// Settings picked at startup, see [`GuiConfig::from_args`]
+#[derive(Clone, Debug)]
+pub struct GuiConfig {
+    pub fonts: FontConfig,
+    pub theme: Theme,
+    pub cursor: CursorConfig,
+    /// Characters besides whitespace that end a word for double click selection
+    pub word_separators: String,
+    pub key_bindings: KeyBindings,
+    pub paste_confirm: PasteConfirmConfig,
+    /// Physical pixels per point, None for the display's own
+    pub scale: Option<f32>,
+    /// Rows are this many times as tall as the font makes them, the extra space goes under the
+    /// text
+    pub line_spacing: f32,
+    /// Space in points between the edge of the window and the output
+    pub padding: i8,
+    pub bell: BellConfig,
+    /// Show the title, working directory and last exit status in a line under the output
+    pub status_line: bool,
+}
+
+impl Default for GuiConfig {
+    fn default() -> GuiConfig {
+        GuiConfig {
+            fonts: FontConfig::default(),
+            theme: Theme::default(),
+            cursor: CursorConfig::default(),
+            word_separators: DEFAULT_WORD_SEPARATORS.to_string(),
+            key_bindings: KeyBindings::default(),
+            paste_confirm: PasteConfirmConfig::default(),
+            scale: None,
+            line_spacing: 1.0,
+            padding: DEFAULT_PADDING,
+            bell: BellConfig::default(),
+            status_line: false,
+        }
+    }
+}
+
+impl GuiConfig {
+    /// The arguments of [`FontConfig::from_args`], `--theme <name>` with one of [`Theme::NAMES`],
+    /// `--bold-is-bright`, `--cursor-color` and `--selection-color` with a `#rrggbb` color to use
+    /// instead of the theme's, `--cursor-shape <name>` with one of [`CursorConfig::SHAPE_NAMES`],
+    /// `--cursor-blink`, `--word-separators <chars>`, `--bind <keys>=<action>` as in
+    /// [`KeyBindings::bind_str`], which can be given more than once,
+    /// `--paste-confirm-size <bytes>` and `--no-paste-confirm-newlines` for
+    /// [`PasteConfirmConfig`], `--scale <factor>` for [`GuiConfig::scale`],
+    /// `--line-spacing <factor>`, `--padding <points>`, `--bell-tint` and
+    /// `--bell-fade <milliseconds>` for [`BellConfig`] and `--status-line`. Besides those only the
+    /// arguments of [`crate::log::LogConfig::from_env_and_args`] are allowed
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<GuiConfig, String> {
+        let args = args.into_iter().collect::<Vec<_>>();
+        let mut theme = Theme::default();
+        let mut bold_is_bright = false;
+        let mut cursor_color = None;
+        let mut selection_color = None;
+        let mut cursor = CursorConfig::default();
+        let mut word_separators = DEFAULT_WORD_SEPARATORS.to_string();
+        let mut key_bindings = KeyBindings::default();
+        let mut paste_confirm = PasteConfirmConfig::default();
+        let mut scale = None;
+        let mut line_spacing = 1.0;
+        let mut padding = DEFAULT_PADDING;
+        let mut bell = BellConfig::default();
+        let mut status_line = false;
+
+        let mut iter = args.iter().cloned();
+        while let Some(arg) = iter.next() {
+            let (flag, value) = match arg.split_once('=') {
+                Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+                None => (arg, None),
+            };
+
+            match flag.as_str() {
+                "--theme" => {
+                    let value = take_value(&flag, value, &mut iter)?;
+                    theme = Theme::by_name(&value).ok_or_else(|| {
+                        format!(
+                            "Unknown theme \"{value}\", expected one of {}",
+                            Theme::NAMES.join(", ")
+                        )
+                    })?;
+                }
+                "--bold-is-bright" => bold_is_bright = true,
+                "--cursor-color" | "--selection-color" => {
+                    let value = take_value(&flag, value, &mut iter)?;
+                    let color = parse_hex_color(&value)
+                        .ok_or_else(|| format!("Invalid color \"{value}\", expected #rrggbb"))?;
+                    if flag == "--cursor-color" {
+                        cursor_color = Some(color);
+                    } else {
+                        selection_color = Some(color);
+                    }
+                }
+                "--cursor-shape" => {
+                    let value = take_value(&flag, value, &mut iter)?;
+                    cursor.shape = CursorConfig::shape_by_name(&value).ok_or_else(|| {
+                        format!(
+                            "Unknown cursor shape \"{value}\", expected one of {}",
+                            CursorConfig::SHAPE_NAMES.join(", ")
+                        )
+                    })?;
+                }
+                "--cursor-blink" => cursor.blink = true,
+                "--word-separators" => {
+                    word_separators = take_value(&flag, value, &mut iter)?;
+                }
+                "--bind" => {
+                    let value = take_value(&flag, value, &mut iter)?;
+                    key_bindings.bind_str(&value)?;
+                }
+                "--paste-confirm-size" => {
+                    let value = take_value(&flag, value, &mut iter)?;
+                    let max_len = value
+                        .parse()
+                        .map_err(|_| format!("Invalid size \"{value}\", expected bytes"))?;
+                    paste_confirm.max_len = Some(max_len);
+                }
+                "--no-paste-confirm-newlines" => paste_confirm.newlines = false,
+                "--line-spacing" => {
+                    let value = take_value(&flag, value, &mut iter)?;
+                    line_spacing = value
+                        .parse::<f32>()
+                        .ok()
+                        .filter(|factor| LINE_SPACING_RANGE.contains(factor))
+                        .ok_or_else(|| {
+                            format!("Invalid line spacing \"{value}\", expected e.g. 1.2")
+                        })?;
+                }
+                "--padding" => {
+                    let value = take_value(&flag, value, &mut iter)?;
+                    padding = value
+                        .parse::<i8>()
+                        .ok()
+                        .filter(|points| *points >= 0)
+                        .ok_or_else(|| {
+                            format!("Invalid padding \"{value}\", expected 0 to 127 points")
+                        })?;
+                }
+                "--bell-tint" => bell.invert = false,
+                "--bell-fade" => {
+                    let value = take_value(&flag, value, &mut iter)?;
+                    let millis = value
+                        .parse::<u64>()
+                        .map_err(|_| format!("Invalid fade \"{value}\", expected milliseconds"))?;
+                    bell.fade_seconds = millis as f64 / 1000.0;
+                }
+                "--status-line" => status_line = true,
+                "--scale" => {
+                    let value = take_value(&flag, value, &mut iter)?;
+                    let factor = value
+                        .parse::<f32>()
+                        .ok()
+                        .filter(|factor| factor.is_finite() && *factor > 0.0)
+                        .ok_or_else(|| format!("Invalid scale \"{value}\", expected e.g. 1.5"))?;
+                    scale = Some(factor);
+                }
+                // Read by FontConfig::from_args below and by log::LogConfig, their values still
+                // have to be skipped
+                "--font" | "--font-regular" | "--font-bold" | "--font-italic" | "--log-level"
+                | "--log-file" => {
+                    take_value(&flag, value, &mut iter)?;
+                }
+                "--no-font-fallback" => (),
+                _ => return Err(format!("Unknown argument {flag}")),
+            }
+        }
+        // Applied to whichever theme was picked, no matter the order of the flags
+        theme.bold_is_bright |= bold_is_bright;
+        theme.cursor = cursor_color.unwrap_or(theme.cursor);
+        theme.selection = selection_color.unwrap_or(theme.selection);
+
+        Ok(GuiConfig {
+            fonts: FontConfig::from_args(args)?,
+            theme,
+            cursor,
+            word_separators,
+            key_bindings,
+            paste_confirm,
+            scale,
+            line_spacing,
+            padding,
+            bell,
+            status_line,
+        })
+    }
+}
+
+/// Fonts to draw output with, see [`FontConfig::from_args`]. Faces that are not configured or fail
+/// to load use the bundled JetBrains Mono
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FontConfig {
+    pub regular: Option<PathBuf>,
+    pub bold: Option<PathBuf>,
+    pub italic: Option<PathBuf>,
+    /// Installed font family, looked up with fontconfig for the faces without a path
+    pub family: Option<String>,
+    /// Look up glyphs the faces above do not have, like emoji, in the emoji fonts bundled with
+    /// egui instead of drawing boxes
+    pub symbol_fallback: bool,
+}
+
+impl Default for FontConfig {
+    fn default() -> FontConfig {
+        FontConfig {
+            regular: None,
+            bold: None,
+            italic: None,
+            family: None,
+            symbol_fallback: true,
+        }
+    }
+}
+
+impl FontConfig {
+    /// `--font <family>`, `--font-regular`, `--font-bold`, `--font-italic` `<path>` and
+    /// `--no-font-fallback`. Arguments that are not about fonts are ignored
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<FontConfig, String> {
+        let mut config = FontConfig::default();
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let (flag, value) = match arg.split_once('=') {
+                Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+                None => (arg, None),
+            };
+
+            match flag.as_str() {
+                "--font" | "--font-regular" | "--font-bold" | "--font-italic" => {
+                    let value = take_value(&flag, value, &mut args)?;
+                    match flag.as_str() {
+                        "--font" => config.family = Some(value),
+                        "--font-regular" => config.regular = Some(PathBuf::from(value)),
+                        "--font-bold" => config.bold = Some(PathBuf::from(value)),
+                        _ => config.italic = Some(PathBuf::from(value)),
+                    }
+                }
+                "--no-font-fallback" => config.symbol_fallback = false,
+                _ => (),
+            }
+        }
+
+        Ok(config)
+    }
+}
 
+/// File of the `style` face of an installed font `family`, according to `fc-match`
+fn find_system_font(family: &str, style: &str) -> Option<PathBuf> {
+    let output = std::process::Command::new("fc-match")
+        .arg("--format=%{family}\n%{file}")
+        .arg(format!("{family}:style={style}"))
+        .output()
+        .map_err(|e| warn!("Failed to run fc-match: {e}"))
+        .ok()?;
+    let output = String::from_utf8_lossy(&output.stdout);
+    let (families, file) = output.split_once('\n')?;
 
+    // fc-match falls back to some other font rather than failing
+    if !families
+        .split(',')
+        .any(|name| name.trim().eq_ignore_ascii_case(family))
+    {
+        warn!("Font \"{family}\" is not installed");
+        return None;
+    }
+    Some(PathBuf::from(file))
+}
+
+/// Read a TTF/OTF file, making sure egui will be able to use it
+fn load_font(path: &Path) -> Result<FontData, String> {
+    let bytes = std::fs::read(path).map_err(|e| e.to_string())?;
+    let font = ab_glyph::FontRef::try_from_slice(&bytes).map_err(|e| e.to_string())?;
+    // egui panics outside of this range too
+    if !font
+        .units_per_em()
+        .is_some_and(|units| (16.0..=16384.0).contains(&units))
+    {
+        return Err("unsupported units per em".to_string());
+    }
+    Ok(FontData::from_owned(bytes))
+}
 
+/// Horizontal advance of `c`, in ems. None when the font does not have it
+fn advance_in_em(data: &FontData, c: char) -> Option<f32> {
+    let font = ab_glyph::FontRef::try_from_slice_and_index(&data.font, data.index).ok()?;
+    let glyph = font.glyph_id(c);
+    if glyph.0 == 0 {
+        return None;
+    }
+    Some(font.h_advance_unscaled(glyph) / font.units_per_em()?)
+}
 
+/// Shrink the glyphs of `fallback` so that they fit in two cells of `regular`, the space a wide
+/// character gets in other terminals
+fn fit_fallback_font(fallback: &mut FontData, regular: &FontData) {
+    let (Some(cell_width), Some(glyph_width)) = (
+        advance_in_em(regular, 'M'),
+        advance_in_em(fallback, FALLBACK_SAMPLE_CHAR),
+    ) else {
+        return;
+    };
+    fallback.tweak.scale = fallback.tweak.scale.min(2.0 * cell_width / glyph_width);
 }
 
-fn setup_fonts(ctx: &egui::Context) {
+fn font_definitions(config: &FontConfig) -> FontDefinitions {
     let mut fonts = FontDefinitions::default();
 
-    fonts.font_data.insert(
-        REGULAR_FONT_NAME.to_owned(),
-        Arc::from(FontData::from_static(include_bytes!("../res/JetBrainsMono-Regular.ttf"))),
-    );
+    let faces: [(&str, &Option<PathBuf>, &str, &'static [u8]); 3] = [
+        (
+            REGULAR_FONT_NAME,
+            &config.regular,
+            "Regular",
+            include_bytes!("../res/JetBrainsMono-Regular.ttf"),
+        ),
+        (
+            BOLD_FONT_NAME,
+            &config.bold,
+            "Bold",
+            include_bytes!("../res/JetBrainsMono-Bold.ttf"),
+        ),
+        (
+            ITALIC_FONT_NAME,
+            &config.italic,
+            "Italic",
+            include_bytes!("../res/JetBrainsMono-Italic.ttf"),
+        ),
+    ];
 
-    fonts.font_data.insert(
-        BOLD_FONT_NAME.to_owned(),
-        Arc::from(FontData::from_static(include_bytes!("../res/JetBrainsMono-Bold.ttf"))),
-    );
-    fonts.font_data.insert(
-        ITALIC_FONT_NAME.to_owned(),
-        Arc::from(FontData::from_static(include_bytes!("../res/JetBrainsMono-Italic.ttf"))),
-    );
+    for (name, path, style, bundled) in faces {
+        let path = path.clone().or_else(|| {
+            config
+                .family
+                .as_ref()
+                .and_then(|family| find_system_font(family, style))
+        });
+        let data = path
+            .and_then(|path| {
+                load_font(&path)
+                    .map_err(|e| {
+                        warn!("Failed to load font {}, using the bundled one: {e}", path.display())
+                    })
+                    .ok()
+            })
+            .unwrap_or_else(|| FontData::from_static(bundled));
+
+        fonts.font_data.insert(name.to_owned(), Arc::new(data));
+        fonts
+            .families
+            .insert(FontFamily::Name(name.into()), vec![name.to_owned()]);
+    }
+
+    if config.symbol_fallback {
+        let regular = Arc::clone(&fonts.font_data[REGULAR_FONT_NAME]);
+        for name in FALLBACK_FONT_NAMES {
+            if let Some(data) = fonts.font_data.get_mut(name) {
+                fit_fallback_font(Arc::make_mut(data), &regular);
+            }
+        }
+        for name in [REGULAR_FONT_NAME, BOLD_FONT_NAME, ITALIC_FONT_NAME] {
+            if let Some(family) = fonts.families.get_mut(&FontFamily::Name(name.into())) {
+                family.extend(FALLBACK_FONT_NAMES.map(str::to_owned));
+            }
+        }
+    }
 
     fonts
         .families
@@ -418,31 +1829,66 @@ fn setup_fonts(ctx: &egui::Context) {
         .unwrap()
         .insert(0, REGULAR_FONT_NAME.to_owned());
 
-    fonts.families.insert(
-        FontFamily::Name(REGULAR_FONT_NAME.to_string().into()),
-        vec![REGULAR_FONT_NAME.to_string()],
-    );
-    fonts.families.insert(
-        FontFamily::Name(BOLD_FONT_NAME.to_string().into()),
-        vec![BOLD_FONT_NAME.to_string()],
-    );
-    fonts.families.insert(
-        FontFamily::Name(ITALIC_FONT_NAME.to_string().into()),
-        vec![ITALIC_FONT_NAME.to_string()],
-    );
+    fonts
+}
 
-    ctx.set_fonts(fonts);
+fn setup_fonts(ctx: &egui::Context, config: &FontConfig) {
+    ctx.set_fonts(font_definitions(config));
 }
-fn add_terminal_data_to_ui(
-    ui: &mut Ui,
+/// Split layout sections so that the parts covered by `selection` are drawn highlighted, with a
+/// `color` background
+fn highlight_selection(
+    sections: Vec<LayoutSection>,
+    selection: &Range<usize>,
+    color: Color32,
+) -> Vec<LayoutSection> {
+    let mut ret = Vec::with_capacity(sections.len() + 2);
+    for section in sections {
+        let Some(selected) = range_within(selection, section.byte_range.clone()) else {
+            ret.push(section);
+            continue;
+        };
+
+        let start = section.byte_range.start;
+        let selected = start + selected.start..start + selected.end;
+        let end = section.byte_range.end;
+
+        if start < selected.start {
+            ret.push(LayoutSection {
+                byte_range: start..selected.start,
+                ..section.clone()
+            });
+        }
+
+        let mut format = section.format.clone();
+        format.background = color;
+        ret.push(LayoutSection {
+            leading_space: section.leading_space,
+            byte_range: selected.clone(),
+            format,
+        });
+
+        if selected.end < end {
+            ret.push(LayoutSection {
+                byte_range: selected.end..end,
+                ..section
+            });
+        }
+    }
+    ret
+}
+
+fn terminal_output_job(
+    style: &egui::Style,
+    width: f32,
     data: &[u8],
     format_data: &[FormatTag],
-    font_size: f32,
-) -> egui::Response {
-    let (mut job, mut textformat) =
-        create_terminal_output_layout_job(ui.style(), ui.available_width(), data);
+    font: OutputFont,
+    highlights: &[(Range<usize>, Color32)],
+    colors: &OutputColors,
+) -> LayoutJob {
+    let (mut job, mut textformat) = create_terminal_output_layout_job(style, width, data);
 
-    let default_fg_color = textformat.color;
     let terminal_fonts = TerminalFonts::new();
     for tag in format_data {
         let mut range = tag.start..tag.end;
@@ -462,14 +1908,18 @@ fn add_terminal_data_to_ui(
             _ => (),
         }
 
-        textformat.font_id.family = terminal_fonts.get_family(tag.bold, tag.italic);
-        textformat.font_id.size = font_size;
+        textformat.font_id.family = terminal_fonts.get_family(tag.attributes.bold, tag.attributes.italic);
+        textformat.font_id.size = font.size;
+        textformat.line_height = font.line_height;
 
-        // Apply foreground color
-        textformat.color = terminal_color_to_egui(&default_fg_color, &tag.fg_color);
+        // Apply foreground color, blinking text in its off phase only leaves its background
+        textformat.color = match colors.text_blink.shows(tag.attributes.blink) {
+            true => foreground_to_egui(tag, colors),
+            false => Color32::TRANSPARENT,
+        };
 
         // Apply background color
-        textformat.background = terminal_color_to_egui(&Color32::TRANSPARENT, &tag.bg_color);
+        textformat.background = background_to_egui(tag, colors);
 
         job.sections.push(egui::text::LayoutSection {
             leading_space: 0.0f32,
@@ -478,194 +1928,2395 @@ fn add_terminal_data_to_ui(
         });
     }
 
-    ui.label(job)
+    for (range, color) in highlights {
+        job.sections = highlight_selection(std::mem::take(&mut job.sections), range, *color);
+    }
+
+    job
 }
-fn add_terminal_data_to_ui_with_debug(
-    ui: &mut Ui,
-    data: &[u8],
-    format_data: &[FormatTag],
-    font_size: f32,
-) -> egui::Response {
-    // DEBUG: Print what we're receiving
-    for tag in format_data {
-        println!("Tag: {}..{}, fg={:?}, bg={:?}, bold={}, italic={}",
-                 tag.start, tag.end, tag.fg_color, tag.bg_color, tag.bold, tag.italic);
-    }
-    let mut debug_format_data: Vec<FormatTag> = format_data.to_vec(); // Copy existing tags
-
-    debug_format_data.push(FormatTag {
-        start: 0,
-        end: data.len(), // Apply to the whole chunk
-        fg_color: TerminalColor::ForegroundGreen,
-        bg_color: TerminalColor::BackgroundBlue,
-        bold: true,
-        italic: false,
-        blink: false,
-    });
-    let (mut job, mut textformat) =
-        create_terminal_output_layout_job(ui.style(), ui.available_width(), data);
-
-    let default_fg_color = textformat.color;
-    let terminal_fonts = TerminalFonts::new();
 
-    for tag in debug_format_data {
-        let mut range = tag.start..tag.end;
+/// The shell going away closes the window, anything else is logged and we keep going
+fn handle_terminal_error(e: TerminalError) {
+    match e {
+        // Its tab is closed once reading from it finds the same, see TerminauxGui::read_sessions
+        TerminalError::ChildExited => debug!("Shell exited"),
+        e => error!("{e}"),
+    }
+}
 
-        if range.end == usize::MAX {
-            range.end = data.len();
-        }
+/// Shown instead of the terminal when the shell could not be started
+struct StartupErrorDialog {
+    message: String,
+}
 
-        match range.start.cmp(&data.len()) {
-            std::cmp::Ordering::Greater => {
-                warn!("Invalid format data for text");
-                continue;
-            }
-            std::cmp::Ordering::Equal => {
-                continue;
+impl eframe::App for StartupErrorDialog {
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        CentralPanel::default().show(ctx, |ui| {
+            ui.heading("Failed to start terminal");
+            ui.label(&self.message);
+            if ui.button("Close").clicked() {
+                ctx.send_viewport_cmd(egui::ViewportCommand::Close);
             }
-            _ => (),
-        }
-
-        textformat.font_id.family = terminal_fonts.get_family(tag.bold, tag.italic);
-        textformat.font_id.size = font_size;
-
-        // Apply foreground color
-        let fg = terminal_color_to_egui(&default_fg_color, &tag.fg_color);
-        textformat.color = fg;
-        println!("  Applied FG: {:?}", fg);
-
-        // Apply background color
-        let bg = terminal_color_to_egui(&Color32::TRANSPARENT, &tag.bg_color);
-        textformat.background = bg;
-        println!("  Applied BG: {:?}", bg);
-
-        job.sections.push(egui::text::LayoutSection {
-            leading_space: 0.0f32,
-            byte_range: range,
-            format: textformat.clone(),
         });
     }
+}
 
-    ui.label(job)
+/// A shell and how it is being looked at, one for each tab
+struct Session {
+    terminal_emulator: TerminalEmulator,
+    selection: Option<Selection>,
+    /// [`Selection::ranges`] of `selection`, worked out when it changes
+    selected_ranges: Vec<Range<usize>>,
+    selecting: bool,
+    /// How far the view is scrolled into scrollback, in rows up from the bottom
+    scroll_offset: usize,
+    /// Wheel movement that did not add up to a whole row yet
+    scroll_remainder: f32,
+    /// Total rows of output last frame, to keep the view still as output comes in
+    last_total_rows: usize,
+    /// Rows of output that came in since the view was last at the bottom
+    new_rows_below: usize,
+    /// See [`OutputCacheKey::generation`]
+    output_generation: u64,
+    output_cache: Option<OutputCache>,
+    /// [`TerminauxGui::total_rows`] by output generation and width
+    total_rows_cache: Option<((u64, usize), usize)>,
+    /// URLs in the visible data by output generation, see [`find_urls`]
+    urls_cache: Option<(u64, Vec<Range<usize>>)>,
+    /// What the search bar looks for, None while it is closed
+    search: Option<Search>,
+    /// Scroll the current search match into view next frame
+    reveal_search_match: bool,
+    /// Uploaded sixel images, by [`ImagePlacement::id`]
+    image_textures: HashMap<u64, egui::TextureHandle>,
+    /// What an input method is composing, shown at the cursor until it is committed
+    preedit: String,
 }
 
 struct TerminauxGui {
-    terminal_emulator: TerminalEmulator,
+    sessions: Vec<Session>,
+    /// Index of the session in the visible tab
+    active: usize,
+    /// How new tabs start their shell, in the working directory of the one open at the time
+    options: Options,
+    theme: Theme,
+    cursor: CursorConfig,
+    /// See [`GuiConfig::word_separators`]
+    word_separators: String,
+    key_bindings: KeyBindings,
+    paste_confirm: PasteConfirmConfig,
+    /// Paste waiting for the user to confirm it, nothing is typed meanwhile
+    pending_paste: Option<String>,
+    /// The last text selected with the mouse in any tab, see [`paste_primary_on_middle_click`].
+    /// egui only has the clipboard, so the primary selection is kept here
+    primary_selection: String,
     font_size: f32,
-    last_blink_time: Option<f64>,
-    blink_on: bool,
-    blink_state: bool,
-    last_blink_toggle: Option<f64>,
+    /// See [`GuiConfig::scale`]
+    scale: Option<f32>,
+    /// See [`GuiConfig::line_spacing`]
+    line_spacing: f32,
+    /// See [`GuiConfig::padding`]
+    padding: i8,
+    /// See [`GuiConfig::status_line`]
+    status_line: bool,
+    /// Last title given to the window, only sent again once it changes
+    window_title: String,
 
     debug_renderer: DebugRenderer,
+    bell: Bell,
+    /// Alt+key sends ESC followed by the key, instead of being left to the GUI
+    meta_sends_escape: bool,
+    /// See [`TerminalEmulator::set_allow_clipboard_write`]
+    allow_clipboard_write: bool,
+    allow_clipboard_read: bool,
+    /// Join soft wrapped lines when copying the screen or all output
+    copy_logical_lines: bool,
 }
 
-impl TerminauxGui {
-    fn update_blink_state(&mut self, ctx: &egui::Context) {
-        let current_time = ctx.input(|i| i.time);
-        let blink_interval = match self.terminal_emulator.cursor_state.blink_mode {
-            BlinkMode::NoBlink => return,
-            BlinkMode::SlowBlink => 0.5,  // 1 Hz
-            BlinkMode::RapidBlink => 0.25, // 2 Hz
-        };
-
-        if let Some(last_toggle) = self.last_blink_toggle {
-            if current_time - last_toggle >= blink_interval {
-                self.blink_state = !self.blink_state;
-                self.last_blink_toggle = Some(current_time);
-                ctx.request_repaint();
-            }
-        } else {
-            self.last_blink_toggle = Some(current_time);
-            self.blink_state = true;
-            ctx.request_repaint();
+impl Session {
+    /// Start reading `terminal_emulator`'s output, repainting as soon as the shell writes
+    /// something so that reading then only has to apply it
+    fn new(ctx: &egui::Context, mut terminal_emulator: TerminalEmulator, theme: &Theme) -> Session {
+        let ctx = ctx.clone();
+        if let Err(e) = terminal_emulator.spawn_reader_thread(move || ctx.request_repaint()) {
+            warn!("Failed to start pty reader thread, reading every frame instead: {e}");
         }
-    }
-
-    fn new(cc: &eframe::CreationContext<'_>, terminal_emulator: TerminalEmulator) -> Self {
-        cc.egui_ctx.style_mut(|style| {
-            style.override_text_style = Some(TextStyle::Monospace);
-        });
+        terminal_emulator.set_ansi_colors(theme.ansi_rgb());
 
-        cc.egui_ctx.set_pixels_per_point(1.0);
-        setup_fonts(&cc.egui_ctx);
-
-        TerminauxGui {
+        Session {
             terminal_emulator,
-            font_size: 12.0,
-            last_blink_time: None,
-            blink_on: true,
-            blink_state: false,
-            last_blink_toggle: None,
-            debug_renderer: DebugRenderer::new(),
-
+            selection: None,
+            selected_ranges: Vec::new(),
+            selecting: false,
+            scroll_offset: 0,
+            scroll_remainder: 0.0,
+            last_total_rows: 0,
+            new_rows_below: 0,
+            output_generation: 0,
+            output_cache: None,
+            total_rows_cache: None,
+            urls_cache: None,
+            search: None,
+            reveal_search_match: false,
+            image_textures: HashMap::new(),
+            preedit: String::new(),
         }
     }
-}
 
-impl eframe::App for TerminauxGui {
-    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
-        let character_size = get_char_size(ctx, self.font_size);
+    /// Last part of the shell's working directory, if it reports it
+    fn tab_label(&self) -> String {
+        match self.terminal_emulator.working_directory() {
+            Some(dir) => dir
+                .file_name()
+                .unwrap_or(dir.as_os_str())
+                .to_string_lossy()
+                .into_owned(),
+            None => "shell".to_string(),
+        }
+    }
 
+    /// Exit status of the last command, if the shell marks commands with OSC 133
+    fn last_exit_status(&self) -> Option<i32> {
+        self.terminal_emulator
+            .prompt_marks()
+            .iter()
+            .rev()
+            .find_map(|mark| match mark.kind {
+                PromptMarkKind::CommandEnd(status) => Some(status),
+                _ => None,
+            })
+            .flatten()
+    }
 
+    /// Thin line under the output, see [`GuiConfig::status_line`]
+    fn show_status_line(&self, ctx: &egui::Context) {
+        let text = status_line_text(
+            self.terminal_emulator.title(),
+            self.terminal_emulator.working_directory(),
+            self.last_exit_status(),
+        );
+        egui::TopBottomPanel::bottom("status_line").show(ctx, |ui| {
+            ui.small(text);
+        });
+    }
 
+    /// Rows all of the output takes up at `width_chars`, only counted again once it changed so
+    /// that idle frames do not depend on how much scrollback there is
+    fn total_rows(&mut self, width_chars: usize) -> usize {
+        let key = (self.output_generation, width_chars);
+        if let Some((cached_key, total_rows)) = self.total_rows_cache {
+            if cached_key == key {
+                return total_rows;
+            }
+        }
 
+        let data = self.terminal_emulator.data();
+        let total_rows = wrapped_rows(data.scrollback, width_chars).len()
+            + wrapped_rows(data.visible, width_chars).len();
+        self.total_rows_cache = Some((key, total_rows));
+        total_rows
+    }
 
-        self.terminal_emulator.read();
+    /// Move through scrollback with the mouse wheel and the scroll [`KeyAction`]s in `actions`
+    fn update_scroll_offset(
+        &mut self,
+        ctx: &egui::Context,
+        screen_size_chars: (usize, usize),
+        character_size: &(f32, f32),
+        actions: &[KeyAction],
+    ) {
+        let (width_chars, height_chars) = screen_size_chars;
+        let total_rows = self.total_rows(width_chars);
+        let data = self.terminal_emulator.data();
 
-        let panel_response = CentralPanel::default().show(ctx, |ui| {
-            let frame_response = egui::Frame::none().show(ui, |ui| {
-                let width_chars = (ui.available_width() / character_size.0).floor();
-                let height_chars = (ui.available_height() / character_size.1).floor();
+        let new_rows = total_rows.saturating_sub(self.last_total_rows);
+        self.scroll_offset = scroll_offset_after_output(self.scroll_offset, new_rows);
+        self.last_total_rows = total_rows;
 
-                self.terminal_emulator
-                    .set_win_size(width_chars as usize, height_chars as usize);
+        let wheel_delta = ctx.input(|i| i.raw_scroll_delta.y);
+        let page_up = actions.contains(&KeyAction::ScrollUp);
+        let page_down = actions.contains(&KeyAction::ScrollDown);
+        let top = actions.contains(&KeyAction::ScrollToTop);
+        let bottom = actions.contains(&KeyAction::ScrollToBottom);
+        let previous_prompt = actions.contains(&KeyAction::PreviousPrompt);
+        let next_prompt = actions.contains(&KeyAction::NextPrompt);
 
-                ui.set_width((width_chars + 0.5) * character_size.0);
-                ui.set_height((height_chars + 0.5) * character_size.1);
+        self.scroll_remainder += wheel_delta / character_size.1;
+        let wheel_rows = self.scroll_remainder.trunc();
+        self.scroll_remainder -= wheel_rows;
 
-                ui.input(|input_state| {
-                    write_input_to_terminal(input_state, &mut self.terminal_emulator);
-                });
+        let mut offset = self.scroll_offset as isize + wheel_rows as isize;
+        if page_up {
+            offset += height_chars as isize;
+        }
+        if page_down {
+            offset -= height_chars as isize;
+        }
+        if top {
+            offset = total_rows as isize;
+        }
+        if bottom {
+            offset = 0;
+        }
+        if previous_prompt || next_prompt {
+            // Put the prompt at the top of the screen
+            let top_row = visible_line_window(total_rows, height_chars, self.scroll_offset).start;
+            let prompts = prompt_rows(&data, self.terminal_emulator.prompt_marks(), width_chars);
+            let target = if previous_prompt {
+                prompts.iter().rev().find(|row| **row < top_row)
+            } else {
+                prompts.iter().find(|row| **row > top_row)
+            };
+            if let Some(row) = target {
+                offset = total_rows as isize - (*row + height_chars) as isize;
+            }
+        }
+        if std::mem::take(&mut self.reveal_search_match) {
+            if let Some(range) = self.search.as_ref().and_then(Search::current_match) {
+                let row = joined_row(&data, range.start, width_chars);
+                let window = visible_line_window(total_rows, height_chars, offset.max(0) as usize);
+                if !window.contains(&row) {
+                    // Put the match in the middle of the screen
+                    offset = total_rows as isize - (row + height_chars / 2 + 1) as isize;
+                }
+            }
+        }
 
-                let output_response = render_terminal_output(ui, &self.terminal_emulator, self.font_size);
+        let max_offset = total_rows.saturating_sub(height_chars);
+        self.scroll_offset = (offset.max(0) as usize).min(max_offset);
+        self.new_rows_below = match self.pinned_to_bottom() {
+            true => 0,
+            false => self.new_rows_below + new_rows,
+        };
+    }
 
+    /// Whether the view follows new output, see [`scroll_offset_after_output`]
+    fn pinned_to_bottom(&self) -> bool {
+        self.scroll_offset == 0
+    }
 
-                self.debug_renderer
-                    .render(ui, output_response.canvas_area, Color32::BLUE);
-                self.debug_renderer.render(ui, output_response.scrollback_area, Color32::YELLOW);
+    /// Button over the bottom of the output to go back to the live screen, shown while scrolled
+    /// up
+    fn show_jump_to_bottom(&mut self, ctx: &egui::Context) {
+        if self.pinned_to_bottom() {
+            return;
+        }
+        egui::Area::new(egui::Id::new("jump_to_bottom"))
+            .anchor(egui::Align2::RIGHT_BOTTOM, egui::vec2(-8.0, -8.0))
+            .show(ctx, |ui| {
+                if ui.button(jump_to_bottom_label(self.new_rows_below)).clicked() {
+                    self.scroll_offset = 0;
+                    self.new_rows_below = 0;
+                    ctx.request_repaint();
+                }
+            });
+    }
+
+    /// Start a selection on pointer-down over the output and extend it while dragging. Once it is
+    /// finished, by letting go of the button or by a double or triple click, it is also the
+    /// `primary_selection`
+    fn update_selection(
+        &mut self,
+        ui: &Ui,
+        areas: &TerminalOutputRenderResponse,
+        width_chars: usize,
+        character_size: &(f32, f32),
+        word_separators: &str,
+        primary_selection: &mut String,
+    ) {
+        let (pressed, down, pos, double_clicked, triple_clicked, alt) = ui.input(|i| {
+            (
+                i.pointer.primary_pressed(),
+                i.pointer.primary_down(),
+                i.pointer.interact_pos(),
+                i.pointer.button_double_clicked(egui::PointerButton::Primary),
+                i.pointer.button_triple_clicked(egui::PointerButton::Primary),
+                i.modifiers.alt,
+            )
+        });
+        let Some(pos) = pos else {
+            return;
+        };
+
+        let previous = self.selection.clone();
+        let mut finished = false;
+        let data = self.terminal_emulator.data();
+        let output_area = areas.scrollback_area.union(areas.canvas_area);
+        let column = |pos: Pos2| {
+            let area = if pos.y < areas.canvas_area.top() {
+                areas.scrollback_area
+            } else {
+                areas.canvas_area
+            };
+            cursor_offset_to_character(pos - area.min, character_size)
+                .x
+                .min(width_chars.saturating_sub(1))
+        };
+        if pressed {
+            // Checks the layer too, so clicks on the context menu do not clobber the selection
+            self.selecting = ui.rect_contains_pointer(output_area);
+            self.selection = self.selecting.then(|| {
+                let index =
+                    pointer_to_selection_index(pos, areas, &data, width_chars, character_size);
+                let mode = if alt {
+                    SelectionMode::Block {
+                        anchor_column: column(pos),
+                        end_column: column(pos),
+                    }
+                } else {
+                    SelectionMode::Linear
+                };
+                Selection {
+                    anchor: index,
+                    end: index,
+                    mode,
+                }
+            });
+        } else if self.selecting && (double_clicked || triple_clicked) {
+            // Replaces the selection the press made, and is not extended by holding the button
+            self.selecting = false;
+            let index = self.selection.as_ref().map(|selection| selection.anchor);
+            let joined = joined_data(&data);
+            let range = index.and_then(|index| {
+                if triple_clicked {
+                    line_at(&joined, index)
+                } else {
+                    word_at(&joined, index, word_separators)
+                }
+            });
+            self.selection = range.and_then(Selection::from_range);
+            finished = true;
+        } else if !down {
+            // Letting go where the button was pressed is only a click
+            let dragged = |selection: &Selection| selection.anchor != selection.end;
+            finished = self.selecting && self.selection.as_ref().is_some_and(dragged);
+            self.selecting = false;
+        } else if self.selecting {
+            let pos = output_area.clamp(pos);
+            if let Some(selection) = &mut self.selection {
+                selection.end =
+                    pointer_to_selection_index(pos, areas, &data, width_chars, character_size);
+                if let SelectionMode::Block { end_column, .. } = &mut selection.mode {
+                    *end_column = column(pos);
+                }
+            }
+            ui.ctx().request_repaint();
+        }
+
+        if self.selection != previous {
+            self.selected_ranges = match &self.selection {
+                Some(selection) => selection.ranges(&joined_data(&data), width_chars),
+                None => Vec::new(),
+            };
+        }
+
+        // Selecting only trailing spaces selects no text, which leaves the last selection
+        if finished {
+            match self.selection_text() {
+                Some(text) if !text.is_empty() => *primary_selection = text,
+                _ => (),
+            }
+        }
+    }
+
+    /// Underline the URL under the pointer while Ctrl is held, and open it on Ctrl+click
+    fn update_links(
+        &mut self,
+        ui: &Ui,
+        areas: &TerminalOutputRenderResponse,
+        width_chars: usize,
+        character_size: &(f32, f32),
+        theme: &Theme,
+    ) {
+        let (ctrl, clicked, pos) = ui.input(|i| {
+            (
+                i.modifiers.ctrl,
+                i.pointer.primary_clicked(),
+                i.pointer.hover_pos(),
+            )
+        });
+        let Some(pos) = pos.filter(|pos| ctrl && areas.canvas_area.contains(*pos)) else {
+            return;
+        };
+
+        let data = self.terminal_emulator.data();
+        let urls = match &self.urls_cache {
+            Some((generation, urls)) if *generation == self.output_generation => urls,
+            _ => {
+                let urls = find_urls(data.visible);
+                &self.urls_cache.insert((self.output_generation, urls)).1
+            }
+        };
+
+        let shown = &data.visible[areas.canvas_range.clone()];
+        let cell = cursor_offset_to_character(pos - areas.canvas_area.min, character_size);
+        let index = areas.canvas_range.start + character_to_data_index(shown, width_chars, &cell);
+        let Some(url) = urls.iter().find(|url| url.contains(&index)) else {
+            return;
+        };
+
+        ui.ctx().set_cursor_icon(egui::CursorIcon::PointingHand);
+        let color = OutputColors::new(&self.terminal_emulator, theme).foreground;
+        // A URL that wraps is underlined on every row it is on
+        let offset = areas.canvas_range.start;
+        let left = areas.canvas_area.left();
+        for (y, row) in wrapped_rows(shown, width_chars).into_iter().enumerate() {
+            let Some(columns) = range_within(url, offset + row.start..offset + row.end) else {
+                continue;
+            };
+            ui.painter().hline(
+                left + columns.start as f32 * character_size.0
+                    ..=left + columns.end as f32 * character_size.0,
+                areas.canvas_area.top() + (y + 1) as f32 * character_size.1 - 1.0,
+                egui::Stroke::new(1.0, color),
+            );
+        }
+
+        if clicked {
+            let url = String::from_utf8_lossy(&data.visible[url.clone()]).into_owned();
+            ui.ctx().open_url(egui::OpenUrl::new_tab(url));
+        }
+    }
+
+    /// Answer OSC 52 requests from the shell
+    fn handle_clipboard_requests(&mut self, ctx: &egui::Context) {
+        if let Some((_, text)) = self.terminal_emulator.take_clipboard_write() {
+            ctx.copy_text(text);
+        }
+
+        if let Some(selection) = self.terminal_emulator.take_clipboard_query() {
+            let text = read_clipboard();
+            if let Err(e) = self.terminal_emulator.reply_clipboard_query(selection, &text) {
+                handle_terminal_error(e);
+            }
+        }
+    }
+
+    fn copy_text(&self, ctx: &egui::Context, target: CopyTarget, logical_lines: bool) {
+        let text = match target {
+            CopyTarget::Screen => self.terminal_emulator.visible_text(logical_lines),
+            CopyTarget::All => self.terminal_emulator.all_text(logical_lines),
+        };
+        ctx.copy_text(text);
+    }
+
+    /// Find the search bar's query again once it or the output changed, see [`Search::update`]
+    fn update_search(&mut self) {
+        let Some(search) = &mut self.search else {
+            return;
+        };
+        let terminal_emulator = &self.terminal_emulator;
+        if search.update(self.output_generation, || joined_data(&terminal_emulator.data())) {
+            self.reveal_search_match = true;
+        }
+    }
+
+    /// Bar above the output to type what to look for into, shown while [`Session::search`] is
+    /// set. Enter goes to the next match and Shift+Enter to the previous one, Escape closes it
+    fn show_search_bar(&mut self, ctx: &egui::Context, focus: bool) {
+        let Some(search) = &mut self.search else {
+            return;
+        };
+
+        let mut step = None;
+        let mut close = false;
+        egui::TopBottomPanel::top("search").show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                let field = ui.add(
+                    egui::TextEdit::singleline(&mut search.query)
+                        .id(search_field_id())
+                        .hint_text("Find"),
+                );
+                if focus {
+                    field.request_focus();
+                }
+                // Enter takes focus away from the field, it is given back to go on from there
+                if field.lost_focus() {
+                    let (enter, shift, escape) = ui.input(|i| {
+                        (i.key_pressed(Key::Enter), i.modifiers.shift, i.key_pressed(Key::Escape))
+                    });
+                    if enter {
+                        step = Some(shift);
+                        field.request_focus();
+                    }
+                    close |= escape;
+                }
+                ui.checkbox(&mut search.case_sensitive, "Match case");
+                if ui.button("Previous").clicked() {
+                    step = Some(true);
+                }
+                if ui.button("Next").clicked() {
+                    step = Some(false);
+                }
+                ui.label(match search.matches().len() {
+                    0 => "No matches".to_string(),
+                    len => format!("{} of {len}", search.current() + 1),
+                });
+                close |= ui.small_button("×").clicked();
+            });
+        });
+
+        if let Some(backwards) = step {
+            search.step(backwards);
+            self.reveal_search_match = true;
+        }
+        if close {
+            self.search = None;
+        }
+    }
+
+    fn selection_text(&self) -> Option<String> {
+        let selection = self.selection.as_ref()?;
+        let joined = joined_data(&self.terminal_emulator.data());
+        Some(selected_text(&joined, &self.selected_ranges, selection.mode))
+    }
+
+    fn copy_selection(&self, ctx: &egui::Context) {
+        if let Some(text) = self.selection_text() {
+            ctx.copy_text(text);
+        }
+    }
+}
+
+impl TerminauxGui {
+    fn new(
+        cc: &eframe::CreationContext<'_>,
+        terminal_emulator: TerminalEmulator,
+        options: Options,
+        config: GuiConfig,
+    ) -> Self {
+        cc.egui_ctx.style_mut(|style| {
+            style.override_text_style = Some(TextStyle::Monospace);
+        });
+
+        // The font size is zoomed with key bindings instead, see KeyAction::IncreaseFont
+        cc.egui_ctx.options_mut(|options| options.zoom_with_keyboard = false);
+        setup_fonts(&cc.egui_ctx, &config.fonts);
+
+        TerminauxGui {
+            sessions: vec![Session::new(&cc.egui_ctx, terminal_emulator, &config.theme)],
+            active: 0,
+            options,
+            theme: config.theme,
+            cursor: config.cursor,
+            word_separators: config.word_separators,
+            key_bindings: config.key_bindings,
+            paste_confirm: config.paste_confirm,
+            pending_paste: None,
+            primary_selection: String::new(),
+            font_size: DEFAULT_FONT_SIZE,
+            scale: config.scale,
+            line_spacing: config.line_spacing,
+            padding: config.padding,
+            status_line: config.status_line,
+            window_title: DEFAULT_WINDOW_TITLE.to_string(),
+            debug_renderer: DebugRenderer::new(),
+            bell: Bell::new(config.bell),
+            meta_sends_escape: true,
+            allow_clipboard_write: false,
+            allow_clipboard_read: false,
+            copy_logical_lines: true,
+        }
+    }
+
+    /// Open a tab with a new shell in the working directory of the visible one, and show it
+    fn open_tab(&mut self, ctx: &egui::Context) {
+        let working_directory = self.sessions.get(self.active).and_then(|session| {
+            session.terminal_emulator.working_directory()
+        });
+        let options = new_tab_options(&self.options, working_directory);
+        match TerminalEmulator::new(options) {
+            Ok(terminal_emulator) => {
+                self.sessions.push(Session::new(ctx, terminal_emulator, &self.theme));
+                self.active = self.sessions.len() - 1;
+            }
+            Err(e) => error!("Failed to open tab: {e}"),
+        }
+    }
+
+    /// Dropping the session hangs up on its shell
+    fn close_tab(&mut self, index: usize) {
+        self.sessions.remove(index);
+        if index < self.active || self.active == self.sessions.len() {
+            self.active = self.active.saturating_sub(1);
+        }
+    }
+
+    /// Apply the output of every tab's shell, not only the visible one's, so that none of them is
+    /// left waiting to write. Tabs whose shell exited are closed, and the window with the last one
+    fn read_sessions(&mut self, ctx: &egui::Context) {
+        let mut index = 0;
+        while index < self.sessions.len() {
+            match self.sessions[index].terminal_emulator.read() {
+                Err(TerminalError::ChildExited) => {
+                    info!("Shell exited, closing its tab");
+                    self.close_tab(index);
+                    continue;
+                }
+                Err(e) => handle_terminal_error(e),
+                Ok(()) => (),
+            }
+            index += 1;
+        }
+        if self.sessions.is_empty() {
+            info!("Last shell exited, closing window");
+            ctx.send_viewport_cmd(egui::ViewportCommand::Close);
+        }
+        // Otherwise nothing repaints while the shell writes, so that egui can sleep when idle
+        if self
+            .sessions
+            .iter()
+            .any(|session| !session.terminal_emulator.has_reader_thread())
+        {
+            ctx.request_repaint_after(PTY_POLL_INTERVAL);
+        }
+    }
+
+    /// Tab strip to switch between tabs and close them, shown once there is more than one
+    fn show_tabs(&mut self, ctx: &egui::Context) {
+        if self.sessions.len() < 2 {
+            return;
+        }
+
+        let mut close = None;
+        egui::TopBottomPanel::top("tabs").show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                for (index, session) in self.sessions.iter().enumerate() {
+                    let label = session.tab_label();
+                    if ui.selectable_label(index == self.active, label).clicked() {
+                        self.active = index;
+                    }
+                    if ui.small_button("×").clicked() {
+                        close = Some(index);
+                    }
+                    ui.separator();
+                }
+            });
+        });
+        if let Some(index) = close {
+            self.close_tab(index);
+        }
+    }
+
+    /// Ask before sending [`TerminauxGui::pending_paste`] to the visible tab's shell, showing
+    /// the start of it
+    fn show_paste_confirmation(&mut self, ctx: &egui::Context) {
+        let Some(text) = &self.pending_paste else {
+            return;
+        };
+
+        let mut paste = None;
+        let modal = egui::Modal::new(egui::Id::new("paste_confirmation")).show(ctx, |ui| {
+            ui.set_max_width(600.0);
+            ui.heading("Paste this?");
+            ui.label(format!(
+                "{} bytes on {} lines. Lines are run as they are pasted",
+                text.len(),
+                text.lines().count()
+            ));
+            egui::ScrollArea::vertical().max_height(300.0).show(ui, |ui| {
+                ui.monospace(paste_preview(text));
+            });
+            ui.horizontal(|ui| {
+                if ui.button("Paste").clicked() {
+                    paste = Some(true);
+                }
+                if ui.button("Cancel").clicked() {
+                    paste = Some(false);
+                }
+            });
+        });
+        // Escape and clicking outside cancel
+        if modal.should_close() {
+            paste.get_or_insert(false);
+        }
+
+        let Some(paste) = paste else {
+            return;
+        };
+        let text = self.pending_paste.take().unwrap_or_default();
+        if let Some(session) = self.sessions.get_mut(self.active).filter(|_| paste) {
+            if let Err(e) = session.terminal_emulator.paste(&text) {
+                handle_terminal_error(e);
+            }
+        }
+    }
+}
+
+/// Text field of the search bar, which keeps typing from going to the shell while it has focus
+fn search_field_id() -> egui::Id {
+    egui::Id::new("search_field")
+}
+
+/// Start of `text` to show when confirming it is pasted, the rest could be too much to lay out
+fn paste_preview(text: &str) -> String {
+    const PREVIEW_CHARS: usize = 2000;
+    match text.char_indices().nth(PREVIEW_CHARS) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+/// What to call the window when the shell's title is `title`
+fn window_title(title: &str) -> String {
+    match title.is_empty() {
+        true => DEFAULT_WINDOW_TITLE.to_string(),
+        false => title.to_string(),
+    }
+}
+
+/// The parts of the status line that are known, see [`GuiConfig::status_line`]
+fn status_line_text(title: &str, working_directory: Option<&Path>, exit_status: Option<i32>) -> String {
+    let mut parts = Vec::new();
+    if !title.is_empty() {
+        parts.push(title.to_string());
+    }
+    if let Some(dir) = working_directory {
+        parts.push(dir.display().to_string());
+    }
+    if let Some(status) = exit_status {
+        parts.push(format!("exit {status}"));
+    }
+    parts.join("  |  ")
+}
+
+/// `options` with the shell started in `working_directory` when there is one
+fn new_tab_options(options: &Options, working_directory: Option<&Path>) -> Options {
+    let mut options = options.clone();
+    if let Some(working_directory) = working_directory {
+        options.working_directory = Some(working_directory.to_path_buf());
+    }
+    options
+}
+
+impl eframe::App for TerminauxGui {
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        // Every frame, as egui keeps the zoom relative to the display when the window moves
+        if let Some(scale) = self.scale {
+            ctx.set_pixels_per_point(scale);
+        }
+        self.read_sessions(ctx);
+        // Opened once this frame is done with the visible tab
+        let mut new_tab_requested = false;
+        self.show_tabs(ctx);
+        self.show_paste_confirmation(ctx);
+        let Some(session) = self.sessions.get_mut(self.active) else {
+            return;
+        };
+
+        // Before laying out, so that zooming resizes the grid and the pty in this frame already
+        let was_paused = session.terminal_emulator.is_paused();
+        let searching = session.search.is_some()
+            && ctx.memory(|memory| memory.has_focus(search_field_id()));
+        let write_result = match self.pending_paste {
+            Some(_) => Ok(InputActions::default()),
+            None if searching => Ok(InputActions::default()),
+            None => ctx.input(|input_state| {
+                write_input_to_terminal(
+                    input_state,
+                    &mut session.terminal_emulator,
+                    self.meta_sends_escape,
+                    &self.key_bindings,
+                    &self.paste_confirm,
+                    &mut session.preedit,
+                )
+            }),
+        };
+        let mut actions = write_result.unwrap_or_else(|e| {
+            handle_terminal_error(e);
+            InputActions::default()
+        });
+        let mut focus_search = false;
+        for action in &actions.keys {
+            match action {
+                KeyAction::Copy => session.copy_selection(ctx),
+                KeyAction::Paste => {
+                    let text = read_clipboard();
+                    if let Err(e) = paste_or_hold(
+                        &mut session.terminal_emulator,
+                        &text,
+                        &self.paste_confirm,
+                        &mut actions.held_paste,
+                    ) {
+                        handle_terminal_error(e);
+                    }
+                }
+                KeyAction::CopyScreen => {
+                    session.copy_text(ctx, CopyTarget::Screen, self.copy_logical_lines)
+                }
+                KeyAction::CopyAll => {
+                    session.copy_text(ctx, CopyTarget::All, self.copy_logical_lines)
+                }
+                KeyAction::NewTab => new_tab_requested = true,
+                KeyAction::Find => {
+                    session.search.get_or_insert_with(Search::default);
+                    focus_search = true;
+                }
+                KeyAction::IncreaseFont | KeyAction::DecreaseFont | KeyAction::ResetFont => {
+                    self.font_size = zoom_font_size(self.font_size, *action);
+                }
+                // See Session::update_scroll_offset
+                KeyAction::ScrollUp
+                | KeyAction::ScrollDown
+                | KeyAction::ScrollToTop
+                | KeyAction::ScrollToBottom
+                | KeyAction::PreviousPrompt
+                | KeyAction::NextPrompt
+                | KeyAction::SendInput => (),
+            }
+        }
+        if let Some(text) = actions.held_paste.take() {
+            self.pending_paste = Some(text);
+            ctx.request_repaint();
+        }
+
+        // Catch up on everything held back while paused without waiting for the next frame
+        if was_paused && !session.terminal_emulator.is_paused() {
+            if let Err(e) = session.terminal_emulator.read() {
+                handle_terminal_error(e);
+            }
+            ctx.request_repaint();
+        }
+
+        let line_height = line_height(ctx, self.font_size, self.line_spacing);
+        let character_size = get_char_size(ctx, self.font_size, line_height);
+        let (cell_width, cell_height) = cell_size_in_pixels(&character_size, ctx.pixels_per_point());
+        session.terminal_emulator.set_cell_size(cell_width, cell_height);
+        session
+            .terminal_emulator
+            .set_allow_clipboard_write(self.allow_clipboard_write);
+        session
+            .terminal_emulator
+            .set_allow_clipboard_read(self.allow_clipboard_read);
+        if session.terminal_emulator.take_bell() {
+            self.bell.ring(ctx.input(|i| i.time));
+        }
+        session.handle_clipboard_requests(ctx);
+        let title = window_title(session.terminal_emulator.title());
+        if title != self.window_title {
+            ctx.send_viewport_cmd(egui::ViewportCommand::Title(title.clone()));
+            self.window_title = title;
+        }
+        session.show_search_bar(ctx, focus_search);
+        if self.status_line {
+            session.show_status_line(ctx);
+        }
+
+        let mut held_primary_paste = None;
+        let panel_frame = egui::Frame::central_panel(&ctx.style()).inner_margin(self.padding);
+        let panel_response = CentralPanel::default().frame(panel_frame).show(ctx, |ui| {
+            let background = OutputColors::new(&session.terminal_emulator, &self.theme).background;
+            ui.painter().rect_filled(ui.clip_rect(), 0.0, background);
+
+            let frame_response = egui::Frame::NONE.show(ui, |ui| {
+                // Holding keyboard focus keeps egui from taking Tab, the arrows and Escape to move
+                // between widgets. Taken back whenever nothing else, like the font size, has it
+                let focus = ui.interact(
+                    ui.max_rect(),
+                    ui.id().with("terminal"),
+                    egui::Sense::focusable_noninteractive(),
+                );
+                if ui.memory(|memory| memory.focused().is_none()) {
+                    focus.request_focus();
+                }
+                ui.memory_mut(|memory| {
+                    memory.set_focus_lock_filter(
+                        focus.id,
+                        egui::EventFilter {
+                            tab: true,
+                            horizontal_arrows: true,
+                            vertical_arrows: true,
+                            escape: true,
+                        },
+                    )
+                });
+
+                // Padding, zoom or a minimized window can leave no room for a whole character,
+                // the terminal keeps a single one then
+                let width_chars = (ui.available_width() / character_size.0).floor().max(1.0);
+                let height_chars = (ui.available_height() / character_size.1).floor().max(1.0);
+
+                if let Err(e) = session
+                    .terminal_emulator
+                    .set_win_size(width_chars as usize, height_chars as usize)
+                {
+                    handle_terminal_error(e);
+                }
+
+                ui.set_width((width_chars + 0.5) * character_size.0);
+                ui.set_height((height_chars + 0.5) * character_size.1);
+
+                // More output tends to follow soon after some arrived, nothing else needs a
+                // repaint unless the user does something
+                if session.terminal_emulator.take_dirty() {
+                    session.output_generation += 1;
+                    ctx.request_repaint();
+                }
 
+                session.update_search();
 
-                paint_cursor(
-                    output_response.canvas_area,
+                let screen_size_chars = (width_chars as usize, height_chars as usize);
+                session.update_scroll_offset(ctx, screen_size_chars, &character_size, &actions.keys);
+
+                // Only output that had blinking text last frame is laid out again as it blinks
+                let time = ui.input(|i| i.time);
+                let text_blink = match &session.output_cache {
+                    Some(cache) if cache.blinking => TextBlink::at(time),
+                    _ => TextBlink::default(),
+                };
+                let cache_key = OutputCacheKey {
+                    generation: session.output_generation,
+                    font: OutputFont {
+                        size: self.font_size,
+                        line_height,
+                    },
+                    available_width: ui.available_width(),
+                    selection: session.selected_ranges.clone(),
+                    search: session.search.as_ref().map(Search::generation),
+                    screen_size_chars,
+                    scroll_offset: session.scroll_offset,
+                    text_blink,
+                };
+                let output_response = render_terminal_output(
+                    ui,
+                    &session.terminal_emulator,
+                    &self.theme,
+                    session.search.as_ref(),
+                    &mut session.output_cache,
+                    cache_key,
+                );
+                if session.output_cache.as_ref().is_some_and(|cache| cache.blinking) {
+                    ui.ctx().request_repaint_after(std::time::Duration::from_secs_f64(
+                        TextBlink::until_change(time),
+                    ));
+                }
+                session.update_selection(
+                    ui,
+                    &output_response,
+                    width_chars as usize,
                     &character_size,
-                    &self.terminal_emulator.cursor_pos(),
-                  //  self.terminal_emulator.data(),
+                    &self.word_separators,
+                    &mut self.primary_selection,
+                );
+                if self.pending_paste.is_none() {
+                    let output_area =
+                        output_response.scrollback_area.union(output_response.canvas_area);
+                    if let Err(e) = paste_primary_on_middle_click(
+                        ui,
+                        output_area,
+                        &mut session.terminal_emulator,
+                        &self.primary_selection,
+                        &self.paste_confirm,
+                        &mut held_primary_paste,
+                    ) {
+                        handle_terminal_error(e);
+                    }
+                }
+                session.update_links(
                     ui,
+                    &output_response,
+                    width_chars as usize,
+                    &character_size,
+                    &self.theme,
                 );
+
+                self.debug_renderer
+                    .render(ui, output_response.canvas_area, Color32::BLUE);
+                self.debug_renderer.render(ui, output_response.scrollback_area, Color32::YELLOW);
+
+                if session.terminal_emulator.is_paused() {
+                    ui.painter().text(
+                        output_response.canvas_area.right_top(),
+                        egui::Align2::RIGHT_TOP,
+                        "[paused]",
+                        FontId::monospace(self.font_size),
+                        Color32::YELLOW,
+                    );
+                }
+
+                // Images and the cursor are positioned on the live screen, which is somewhere below
+                // while looking at scrollback
+                if session.scroll_offset == 0 {
+                    paint_images(
+                        ui,
+                        output_response.canvas_area,
+                        &character_size,
+                        &session.terminal_emulator.visible_images(),
+                        &mut session.image_textures,
+                    );
+                    let cursor_style = self.cursor.style(session.terminal_emulator.cursor_style());
+                    if cursor_style.blink {
+                        let until_toggle =
+                            CURSOR_BLINK_INTERVAL - time.rem_euclid(CURSOR_BLINK_INTERVAL);
+                        ui.ctx().request_repaint_after(std::time::Duration::from_secs_f64(
+                            until_toggle,
+                        ));
+                    }
+                    let visible = session.terminal_emulator.cursor_visible();
+                    if visible && (!cursor_style.blink || cursor_blink_on(time)) {
+                        paint_cursor(
+                            output_response.canvas_area,
+                            &character_size,
+                            &session.terminal_emulator.cursor_pos(),
+                          //  session.terminal_emulator.data(),
+                            OutputColors::new(&session.terminal_emulator, &self.theme).cursor,
+                            cursor_style.shape,
+                            ui,
+                        );
+                    }
+                    if !session.preedit.is_empty() {
+                        paint_preedit(
+                            ui,
+                            output_response.canvas_area,
+                            &character_size,
+                            &session.terminal_emulator.cursor_pos(),
+                            &session.preedit,
+                            self.font_size,
+                            &OutputColors::new(&session.terminal_emulator, &self.theme),
+                        );
+                    }
+                }
+
+                // Input methods are only allowed while egui is told where they type, which is
+                // at the cursor. Typing goes elsewhere while searching or confirming a paste
+                if self.pending_paste.is_none() && !searching {
+                    let cursor = cursor_rect(
+                        output_response.canvas_area,
+                        &character_size,
+                        &session.terminal_emulator.cursor_pos(),
+                        CursorShape::Bar,
+                        ui.ctx().pixels_per_point(),
+                    );
+                    ui.ctx().output_mut(|output| {
+                        output.ime = Some(egui::output::IMEOutput {
+                            rect: output_response.canvas_area,
+                            cursor_rect: cursor,
+                        });
+                    });
+                }
             });
             self.debug_renderer
                 .render(ui, frame_response.response.rect, Color32::RED);
         });
+        if let Some(text) = held_primary_paste {
+            self.pending_paste = Some(text);
+            ctx.request_repaint();
+        }
+        session.show_jump_to_bottom(ctx);
+        self.debug_renderer
+            .render_raw_output(ctx, &mut session.terminal_emulator);
 
         panel_response.response.context_menu(|ui| {
+            if ui
+                .add_enabled(session.selection.is_some(), egui::Button::new("Copy"))
+                .clicked()
+            {
+                session.copy_selection(ui.ctx());
+                ui.close_menu();
+            }
+            if ui.button("Copy screen").clicked() {
+                session.copy_text(ui.ctx(), CopyTarget::Screen, self.copy_logical_lines);
+                ui.close_menu();
+            }
+            if ui.button("Copy all output").clicked() {
+                session.copy_text(ui.ctx(), CopyTarget::All, self.copy_logical_lines);
+                ui.close_menu();
+            }
+            if ui.button("Copy all output as HTML").clicked() {
+                let rgb = |color: Color32| (color.r(), color.g(), color.b());
+                let html = session.terminal_emulator.to_html(HtmlColors {
+                    foreground: rgb(self.theme.foreground),
+                    background: rgb(self.theme.background),
+                });
+                ui.ctx().copy_text(html);
+                ui.close_menu();
+            }
+            ui.checkbox(&mut self.copy_logical_lines, "Copy wrapped lines as one");
+            if ui.button("New tab").clicked() {
+                new_tab_requested = true;
+                ui.close_menu();
+            }
             ui.horizontal(|ui| {
                 ui.label("Font size:");
-                ui.add(DragValue::new(&mut self.font_size).clamp_range(1.0..=100.0));
+                ui.add(DragValue::new(&mut self.font_size).range(FONT_SIZE_RANGE));
             });
+            ui.checkbox(&mut self.meta_sends_escape, "Alt sends ESC");
+            ui.checkbox(&mut self.allow_clipboard_write, "Programs can set the clipboard");
+            ui.checkbox(&mut self.allow_clipboard_read, "Programs can read the clipboard");
+            egui::ComboBox::from_label("Bell")
+                .selected_text(self.bell.style.name())
+                .show_ui(ui, |ui| {
+                    for style in BellStyle::ALL {
+                        ui.selectable_value(&mut self.bell.style, style, style.name());
+                    }
+                });
             ui.checkbox(&mut self.debug_renderer.enable, "Debug render");
+            ui.checkbox(&mut self.debug_renderer.raw_output, "Show raw output");
         });
+
+        if new_tab_requested {
+            self.open_tab(ctx);
+        }
+        self.bell.render(ctx, self.theme.foreground);
     }
-}
 
+    /// Hang up on every tab's shell and wait for them here, since the threads dropping a session
+    /// leaves the wait to may not get to finish once the app exits. All of them are hung up on
+    /// before waiting, so the window waits for the slowest shell rather than for each in turn
+    fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+        info!("Window closed, hanging up on {} shell(s)", self.sessions.len());
+        for session in &mut self.sessions {
+            session.terminal_emulator.hang_up();
+        }
+        let deadline = std::time::Instant::now() + HANGUP_GRACE_PERIOD;
+        for session in &mut self.sessions {
+            session.terminal_emulator.reap(deadline);
+        }
+        self.sessions.clear();
+    }
+}
 
-pub fn run(terminal_emulator: TerminalEmulator) {
+/// Open the window with a shell started with `options`, which new tabs start theirs with too
+pub fn run(options: Options, config: GuiConfig) {
     let native_options = eframe::NativeOptions::default();
-    eframe::run_native(
-        "Terminaux",
-        native_options,
-        Box::new(move |cc| Ok(Box::new(TerminauxGui::new(cc, terminal_emulator)))),
-    )
+    match TerminalEmulator::new(options.clone()) {
+        Ok(terminal_emulator) => eframe::run_native(
+            DEFAULT_WINDOW_TITLE,
+            native_options,
+            Box::new(move |cc| {
+                Ok(Box::new(TerminauxGui::new(cc, terminal_emulator, options, config)))
+            }),
+        ),
+        Err(e) => {
+            error!("{e}");
+            eframe::run_native(
+                DEFAULT_WINDOW_TITLE,
+                native_options,
+                Box::new(move |_cc| {
+                    Ok(Box::new(StartupErrorDialog {
+                        message: e.to_string(),
+                    }))
+                }),
+            )
+        }
+    }
         .unwrap();
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::terminal_emulator::tty::Shell;
+    use crate::terminal_emulator::TextAttributes;
+    use crate::terminal_emulator::test::{emulator_without_shell, SharedWriter};
+
+    /// What is written to the shell and the actions returned for `events`
+    fn input_for_events(
+        events: Vec<Event>,
+        modifiers: Modifiers,
+        bindings: &KeyBindings,
+    ) -> (Vec<u8>, Vec<KeyAction>) {
+        let writer = SharedWriter::default();
+        let mut emulator = TerminalEmulator::with_io(std::io::empty(), writer.clone());
+        let mut input = InputState::default();
+        input.raw.events = events;
+        input.modifiers = modifiers;
+        let paste_confirm = PasteConfirmConfig::default();
+        let actions =
+            write_input_to_terminal(&input, &mut emulator, true, bindings, &paste_confirm, &mut String::new())
+                .unwrap();
+        (writer.take(), actions.keys)
+    }
+
+    fn written_for_events(events: Vec<Event>, modifiers: Modifiers, meta: bool) -> Vec<u8> {
+        let writer = SharedWriter::default();
+        let mut emulator = TerminalEmulator::with_io(std::io::empty(), writer.clone());
+        let mut input = InputState::default();
+        input.raw.events = events;
+        input.modifiers = modifiers;
+        let bindings = KeyBindings::default();
+        let paste_confirm = PasteConfirmConfig::default();
+        write_input_to_terminal(&input, &mut emulator, meta, &bindings, &paste_confirm, &mut String::new())
+            .unwrap();
+        writer.take()
+    }
+
+    fn key_event(key: Key, modifiers: Modifiers) -> Event {
+        Event::Key {
+            key,
+            physical_key: None,
+            pressed: true,
+            repeat: false,
+            modifiers,
+        }
+    }
+
+    #[test]
+    fn test_alt_sends_escape() {
+        let alt = Modifiers::ALT;
+        let alt_b = || vec![key_event(Key::B, alt), Event::Text("b".into())];
+        assert_eq!(written_for_events(alt_b(), alt, true), b"\x1bb");
+        assert_eq!(written_for_events(alt_b(), alt, false), b"b");
+
+        let ctrl_alt = Modifiers::ALT | Modifiers::CTRL;
+        let ctrl_alt_b = vec![key_event(Key::B, ctrl_alt)];
+        assert_eq!(written_for_events(ctrl_alt_b, ctrl_alt, true), b"\x1b\x02");
+    }
+
+    #[test]
+    fn test_ime_committed() {
+        let mut preedit = String::new();
+        let preedit_event = |text: &str| ImeEvent::Preedit(text.to_string());
+
+        assert_eq!(ime_committed(&mut preedit, &ImeEvent::Enabled), None);
+        assert_eq!(ime_committed(&mut preedit, &preedit_event("n")), None);
+        assert_eq!(preedit, "n");
+        assert_eq!(ime_committed(&mut preedit, &preedit_event("ni")), None);
+        assert_eq!(preedit, "ni");
+        let commit = ImeEvent::Commit("你".to_string());
+        assert_eq!(ime_committed(&mut preedit, &commit), Some("你"));
+        assert_eq!(preedit, "");
+
+        // Cancelled, or committed with nothing to send
+        ime_committed(&mut preedit, &preedit_event("´"));
+        assert_eq!(ime_committed(&mut preedit, &ImeEvent::Disabled), None);
+        assert_eq!(preedit, "");
+        for text in ["", "\n", "\r"] {
+            ime_committed(&mut preedit, &preedit_event("x"));
+            assert_eq!(ime_committed(&mut preedit, &ImeEvent::Commit(text.to_string())), None);
+            assert_eq!(preedit, "");
+        }
+    }
+
+    #[test]
+    fn test_ime_input_written_on_commit() {
+        let none = Modifiers::NONE;
+        let events = vec![
+            Event::Ime(ImeEvent::Enabled),
+            Event::Ime(ImeEvent::Preedit("e".into())),
+            // Editing the composition
+            key_event(Key::Backspace, none),
+            Event::Ime(ImeEvent::Preedit("é".into())),
+            Event::Ime(ImeEvent::Commit("é".into())),
+            Event::Ime(ImeEvent::Disabled),
+            key_event(Key::Backspace, none),
+        ];
+        assert_eq!(written_for_events(events, none, true), "é\x7f".as_bytes());
+
+        // Nothing is sent while composing
+        let events = vec![Event::Ime(ImeEvent::Preedit("かな".into()))];
+        assert_eq!(written_for_events(events, none, true), b"");
+    }
+
+    #[test]
+    fn test_paste_confirm_needed() {
+        let config = PasteConfirmConfig::default();
+        assert!(!config.needed("ls -la", false));
+        assert!(config.needed("ls -la\n", false));
+        assert!(config.needed("echo a\recho b", false));
+        assert!(config.needed("rm -rf build\nmake\n", false));
+        assert!(!config.needed(&"a".repeat(4096), false));
+        assert!(config.needed(&"a".repeat(4097), false));
+
+        // A program that bracketed paste gets to decide what to do with it
+        assert!(!config.needed("rm -rf build\nmake\n", true));
+        assert!(!config.needed(&"a".repeat(4097), true));
+
+        let config = PasteConfirmConfig {
+            max_len: None,
+            newlines: false,
+        };
+        assert!(!config.needed("ls -la\n", false));
+        assert!(!config.needed(&"a\n".repeat(10_000), false));
+        let config = PasteConfirmConfig {
+            max_len: Some(3),
+            newlines: false,
+        };
+        assert!(config.needed("abcd", false));
+    }
+
+    #[test]
+    fn test_paste_held_for_confirmation() {
+        let writer = SharedWriter::default();
+        let mut emulator = TerminalEmulator::with_io(std::io::empty(), writer.clone());
+        let mut input = InputState::default();
+        input.raw.events = vec![Event::Paste("one".into()), Event::Paste("two\n".into())];
+        let bindings = KeyBindings::default();
+        let paste_confirm = PasteConfirmConfig::default();
+        let actions =
+            write_input_to_terminal(&input, &mut emulator, true, &bindings, &paste_confirm, &mut String::new())
+                .unwrap();
+        assert_eq!(writer.take(), b"one");
+        assert_eq!(actions.held_paste.as_deref(), Some("two\n"));
+
+        let text = "é".repeat(3000);
+        let preview = paste_preview(&text);
+        assert_eq!(preview.chars().count(), 2001);
+        assert!(preview.ends_with('…'));
+        assert_eq!(paste_preview("short"), "short");
+    }
+
+    #[test]
+    fn test_tab_keys() {
+        let events = vec![key_event(Key::Tab, Modifiers::NONE)];
+        assert_eq!(written_for_events(events, Modifiers::NONE, true), b"\t");
+        let events = vec![key_event(Key::Tab, Modifiers::SHIFT)];
+        assert_eq!(written_for_events(events, Modifiers::SHIFT, true), b"\x1b[Z");
+    }
+
+    #[test]
+    fn test_copy_keys_not_sent() {
+        let ctrl_shift = Modifiers::CTRL | Modifiers::SHIFT;
+        let events = vec![
+            key_event(Key::A, ctrl_shift),
+            key_event(Key::S, ctrl_shift),
+            Event::Copy,
+        ];
+        let (written, actions) = input_for_events(events, ctrl_shift, &KeyBindings::default());
+        assert_eq!(written, b"");
+        assert_eq!(actions, [KeyAction::CopyAll, KeyAction::CopyScreen, KeyAction::Copy]);
+
+        // Ctrl+C stays an interrupt
+        assert_eq!(written_for_events(vec![Event::Copy], Modifiers::CTRL, true), b"\x03");
+
+        let events = vec![key_event(Key::A, Modifiers::CTRL)];
+        assert_eq!(written_for_events(events, Modifiers::CTRL, true), b"\x01");
+    }
+
+    #[test]
+    fn test_new_tab_key_not_sent() {
+        let ctrl_shift = Modifiers::CTRL | Modifiers::SHIFT;
+        let events = vec![key_event(Key::T, ctrl_shift)];
+        let (written, actions) = input_for_events(events, ctrl_shift, &KeyBindings::default());
+        assert_eq!(written, b"");
+        assert_eq!(actions, [KeyAction::NewTab]);
+
+        let events = vec![key_event(Key::T, Modifiers::CTRL)];
+        assert_eq!(written_for_events(events, Modifiers::CTRL, true), b"\x14");
+
+        let events = vec![key_event(Key::F, ctrl_shift)];
+        let (written, actions) = input_for_events(events, ctrl_shift, &KeyBindings::default());
+        assert_eq!(written, b"");
+        assert_eq!(actions, [KeyAction::Find]);
+    }
+
+    #[test]
+    fn test_zoom_font_size() {
+        assert_eq!(zoom_font_size(12.0, KeyAction::IncreaseFont), 13.0);
+        assert_eq!(zoom_font_size(12.0, KeyAction::DecreaseFont), 11.0);
+        assert_eq!(zoom_font_size(30.0, KeyAction::ResetFont), DEFAULT_FONT_SIZE);
+
+        // Stops at the ends of the range the context menu allows
+        assert_eq!(zoom_font_size(100.0, KeyAction::IncreaseFont), 100.0);
+        assert_eq!(zoom_font_size(99.5, KeyAction::IncreaseFont), 100.0);
+        assert_eq!(zoom_font_size(1.0, KeyAction::DecreaseFont), 1.0);
+        assert_eq!(zoom_font_size(1.5, KeyAction::DecreaseFont), 1.0);
+
+        let ctrl = Modifiers::CTRL;
+        let events = vec![
+            key_event(Key::Equals, ctrl),
+            key_event(Key::Minus, ctrl),
+            key_event(Key::Num0, ctrl),
+        ];
+        let (written, actions) = input_for_events(events, ctrl, &KeyBindings::default());
+        assert_eq!(written, b"");
+        assert_eq!(
+            actions,
+            [KeyAction::IncreaseFont, KeyAction::DecreaseFont, KeyAction::ResetFont]
+        );
+    }
+
+    #[test]
+    fn test_custom_key_binding() {
+        let bindings = KeyBindings::from_table(&[(Key::Plus, Modifiers::CTRL, KeyAction::IncreaseFont)]);
+        let ctrl_plus = || vec![key_event(Key::Plus, Modifiers::CTRL), Event::Text("+".into())];
+        let (written, actions) = input_for_events(ctrl_plus(), Modifiers::CTRL, &bindings);
+        assert_eq!(written, b"");
+        assert_eq!(actions, [KeyAction::IncreaseFont]);
+
+        // Only what is in the table is bound, and unbound keys go to the shell
+        let events = vec![key_event(Key::PageUp, Modifiers::SHIFT)];
+        let (written, actions) = input_for_events(events, Modifiers::SHIFT, &bindings);
+        assert_eq!(written, b"\x1b[5;2~");
+        assert!(actions.is_empty());
+
+        // Taking away a default binding sends the key
+        let mut bindings = KeyBindings::default();
+        bindings.bind_str("ctrl+shift+t=send-input").unwrap();
+        let ctrl_shift = Modifiers::CTRL | Modifiers::SHIFT;
+        let events = vec![key_event(Key::T, ctrl_shift)];
+        let (written, actions) = input_for_events(events, ctrl_shift, &bindings);
+        assert_eq!(written, b"\x14");
+        assert!(actions.is_empty());
+    }
+
+    #[test]
+    fn test_new_tab_options() {
+        let mut options = Options::default();
+        options.env.insert("FOO".to_string(), "bar".to_string());
+
+        let tab = new_tab_options(&options, Some(Path::new("/home/me")));
+        assert_eq!(tab.working_directory.as_deref(), Some(Path::new("/home/me")));
+        assert_eq!(tab.env, options.env);
+
+        // Without a directory reported the shell starts where the first one did
+        options.working_directory = Some(PathBuf::from("/srv"));
+        assert_eq!(new_tab_options(&options, None), options);
+    }
+
+    #[test]
+    fn test_new_tab_starts_in_working_directory() {
+        let dir = std::env::temp_dir().canonicalize().unwrap();
+        let options = Options {
+            shell: Some(Shell::new(
+                "sh".to_string(),
+                vec!["-c".to_string(), "pwd; sleep 1".to_string()],
+            )),
+            ..Default::default()
+        };
+        let mut emulator = TerminalEmulator::new(new_tab_options(&options, Some(&dir))).unwrap();
+
+        let expected = dir.to_string_lossy().into_owned();
+        let start = std::time::Instant::now();
+        while !emulator.visible_text(true).starts_with(&expected)
+            && start.elapsed() < std::time::Duration::from_secs(5)
+        {
+            let _ = emulator.read();
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(emulator.visible_text(true), expected);
+    }
+
+    #[test]
+    fn test_palette_changes_colors() {
+        let mut emulator = emulator_without_shell(80, 24);
+        let red = TerminalColor::ForegroundRed;
+        let default = Color32::WHITE;
+        assert_eq!(
+            terminal_color_to_egui(&default, &red, emulator.palette()),
+            Color32::from_rgb(205, 0, 0)
+        );
+
+        emulator.feed_str("\x1b]4;1;rgb:00/00/ff\x07");
+        let blue = Color32::from_rgb(0, 0, 255);
+        assert_eq!(terminal_color_to_egui(&default, &red, emulator.palette()), blue);
+        assert_eq!(
+            terminal_color_to_egui(&default, &TerminalColor::Background8Bit(1), emulator.palette()),
+            blue
+        );
+        assert_eq!(
+            terminal_color_to_egui(&default, &TerminalColor::Default, emulator.palette()),
+            default
+        );
+
+        let theme = Theme::default();
+        assert_eq!(OutputColors::new(&emulator, &theme).foreground, theme.foreground);
+        emulator.feed_str("\x1b]10;#123456\x07");
+        assert_eq!(
+            OutputColors::new(&emulator, &theme).foreground,
+            Color32::from_rgb(0x12, 0x34, 0x56)
+        );
+    }
+
+    #[test]
+    fn test_theme_colors() {
+        let mut emulator = emulator_without_shell(80, 24);
+        let green = TerminalColor::ForegroundGreen;
+        let resolve = |emulator: &TerminalEmulator, color| {
+            terminal_color_to_egui(&Color32::WHITE, color, emulator.palette())
+        };
+        assert_eq!(resolve(&emulator, &green), Theme::dark().ansi[2]);
+        // Bright colors have their own entries
+        assert_eq!(
+            resolve(&emulator, &TerminalColor::ForegroundBrightBlack),
+            Color32::from_rgb(127, 127, 127)
+        );
+        assert_ne!(
+            resolve(&emulator, &TerminalColor::ForegroundBrightGreen),
+            resolve(&emulator, &green)
+        );
+
+        let mut theme = Theme::solarized_dark();
+        theme.ansi[2] = Color32::from_rgb(1, 2, 3);
+        emulator.set_ansi_colors(theme.ansi_rgb());
+        assert_eq!(resolve(&emulator, &green), Color32::from_rgb(1, 2, 3));
+        assert_eq!(
+            OutputColors::new(&emulator, &theme).background,
+            theme.background
+        );
+    }
+
+    #[test]
+    fn test_bold_is_bright() {
+        let emulator = emulator_without_shell(80, 24);
+        let mut theme = Theme::dark();
+        theme.bold_is_bright = true;
+        let colors = OutputColors::new(&emulator, &theme);
+        let tag = |fg_color, bold| FormatTag {
+            start: 0,
+            end: 1,
+            fg_color,
+            bg_color: TerminalColor::Default,
+            attributes: TextAttributes {
+                bold,
+                ..Default::default()
+            },
+        };
+
+        let red = TerminalColor::ForegroundRed;
+        assert_eq!(foreground_to_egui(&tag(red, true), &colors), theme.ansi[9]);
+        assert_eq!(foreground_to_egui(&tag(red, false), &colors), theme.ansi[1]);
+        // Colors that are not one of the 8 basic ones stay as they are
+        let rgb = TerminalColor::ForegroundRgb(1, 2, 3);
+        assert_eq!(
+            foreground_to_egui(&tag(rgb, true), &colors),
+            Color32::from_rgb(1, 2, 3)
+        );
+        let indexed = TerminalColor::Foreground8Bit(1);
+        assert_eq!(foreground_to_egui(&tag(indexed, true), &colors), theme.ansi[1]);
+
+        theme.bold_is_bright = false;
+        let colors = OutputColors::new(&emulator, &theme);
+        assert_eq!(foreground_to_egui(&tag(red, true), &colors), theme.ansi[1]);
+    }
+
+    #[test]
+    fn test_faint() {
+        let emulator = emulator_without_shell(80, 24);
+        let mut theme = Theme::dark();
+        theme.bold_is_bright = true;
+        let colors = OutputColors::new(&emulator, &theme);
+        let tag = |bold, faint| FormatTag {
+            start: 0,
+            end: 1,
+            fg_color: TerminalColor::ForegroundWhite,
+            bg_color: TerminalColor::Default,
+            attributes: TextAttributes {
+                bold,
+                faint,
+                ..Default::default()
+            },
+        };
+
+        // White is 229 and the background 27
+        let faint = foreground_to_egui(&tag(false, true), &colors);
+        assert_eq!(faint, Color32::from_gray(148));
+        assert_eq!(foreground_to_egui(&tag(false, false), &colors), theme.ansi[7]);
+        // Bold still picks the bright color, which is then dimmed
+        let bold_faint = foreground_to_egui(&tag(true, true), &colors);
+        assert_eq!(bold_faint, Color32::from_gray(164));
+    }
+
+    #[test]
+    fn test_reverse_screen() {
+        let mut emulator = emulator_without_shell(80, 24);
+        let theme = Theme::dark();
+        emulator.feed_str("a\x1b[7mb\x1b[27;31mc");
+        let tags = emulator.format_data().visible;
+        let job = |emulator: &TerminalEmulator| {
+            let colors = OutputColors::new(emulator, &theme);
+            let style = egui::Style::default();
+            let font = OutputFont {
+                size: 12.0,
+                line_height: None,
+            };
+            let job = terminal_output_job(&style, 100.0, b"abc", &tags, font, &[], &colors);
+            let section = |byte| {
+                let section = job.sections.iter().find(|s| s.byte_range.contains(&byte));
+                let format = &section.unwrap().format;
+                (format.color, format.background)
+            };
+            (colors.foreground, colors.background, section(0), section(1), section(2))
+        };
+
+        let (foreground, background, plain, reverse, red) = job(&emulator);
+        assert_eq!((foreground, background), (theme.foreground, theme.background));
+        assert_eq!(plain, (theme.foreground, Color32::TRANSPARENT));
+        assert_eq!(reverse, (theme.background, theme.foreground));
+        assert_eq!(red, (theme.ansi[1], Color32::TRANSPARENT));
+
+        // The defaults swap for the whole screen, and reverse video on top of it cancels out
+        emulator.feed_str("\x1b[?5h");
+        assert!(emulator.reverse_screen());
+        let (foreground, background, plain, reverse, red) = job(&emulator);
+        assert_eq!((foreground, background), (theme.background, theme.foreground));
+        assert_eq!(plain, (theme.background, Color32::TRANSPARENT));
+        assert_eq!(reverse, (theme.foreground, theme.background));
+        assert_eq!(red, (theme.ansi[1], Color32::TRANSPARENT));
+
+        emulator.feed_str("\x1b[?5l");
+        assert!(!emulator.reverse_screen());
+        assert_eq!(job(&emulator).1, theme.background);
+    }
+
+    #[test]
+    fn test_cursor_config() {
+        let config = CursorConfig {
+            shape: CursorShape::Underline,
+            blink: true,
+        };
+        let mut emulator = emulator_without_shell(80, 24);
+        let configured = CursorStyle {
+            shape: CursorShape::Underline,
+            blink: true,
+        };
+        assert_eq!(config.style(emulator.cursor_style()), configured);
+
+        emulator.feed(b"\x1b[2 q");
+        let app_style = CursorStyle {
+            shape: CursorShape::Block,
+            blink: false,
+        };
+        assert_eq!(config.style(emulator.cursor_style()), app_style);
+
+        // DECSCUSR 0 hands the choice back
+        emulator.feed(b"\x1b[0 q");
+        assert_eq!(config.style(emulator.cursor_style()), configured);
+
+        let theme = Theme::dark();
+        let colors = OutputColors::new(&emulator, &theme);
+        assert_eq!(colors.cursor, theme.cursor);
+        assert_eq!(colors.selection, theme.selection);
+    }
+
+    #[test]
+    fn test_cursor_blink_on() {
+        assert!(cursor_blink_on(0.0));
+        assert!(cursor_blink_on(0.49));
+        assert!(!cursor_blink_on(0.5));
+        assert!(!cursor_blink_on(0.99));
+        assert!(cursor_blink_on(1.0));
+    }
+
+    #[test]
+    fn test_hex_dump() {
+        assert_eq!(hex_dump(b""), "");
+        assert_eq!(
+            hex_dump(b"ls\r\n\x1b[0mfile name.txt\xff"),
+            "00000000  6c 73 0d 0a 1b 5b 30 6d  66 69 6c 65 20 6e 61 6d  |ls...[0mfile nam|\n\
+             00000010  65 2e 74 78 74 ff                                 |e.txt.|\n"
+        );
+    }
+
+    #[test]
+    fn test_text_blink_phase() {
+        let phase = |time| {
+            let blink = TextBlink::at(time);
+            (blink.slow, blink.rapid)
+        };
+        assert_eq!(phase(0.0), (true, true));
+        assert_eq!(phase(0.25), (true, false));
+        assert_eq!(phase(0.5), (false, true));
+        assert_eq!(phase(0.8), (false, false));
+        assert_eq!(phase(1.0), (true, true));
+        // Only the time matters
+        assert_eq!(TextBlink::at(1234.6), TextBlink::at(0.6));
+
+        assert!(TextBlink::at(0.6).shows(BlinkMode::NoBlink));
+        assert!(!TextBlink::at(0.6).shows(BlinkMode::SlowBlink));
+        assert!(TextBlink::at(0.6).shows(BlinkMode::RapidBlink));
+        assert!((TextBlink::until_change(0.6) - 0.15).abs() < 1e-9);
+        assert!((TextBlink::until_change(1.0) - 0.25).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_blinking_text_hidden_off_phase() {
+        let mut emulator = emulator_without_shell(10, 3);
+        emulator.feed_str("a\x1b[5;44mb\x1b[25;6mc");
+        let key = |text_blink| OutputCacheKey {
+            generation: 0,
+            font: OutputFont {
+                size: 12.0,
+                line_height: None,
+            },
+            available_width: 100.0,
+            selection: Vec::new(),
+            search: None,
+            screen_size_chars: (10, 3),
+            scroll_offset: 0,
+            text_blink,
+        };
+        let theme = Theme::default();
+        let style = egui::Style::default();
+        let format_at = |cache: &OutputCache, byte: usize| {
+            let sections = &cache.canvas_job.sections;
+            let section = sections.iter().find(|s| s.byte_range.contains(&byte)).unwrap();
+            (section.format.color, section.format.background)
+        };
+
+        let cache = OutputCache::new(&style, &emulator, &theme, None, key(TextBlink::default()));
+        assert!(cache.blinking);
+        assert_ne!(format_at(&cache, 1).0, Color32::TRANSPARENT);
+
+        // Slow blink is off while rapid blink is on, the background stays
+        let cache = OutputCache::new(&style, &emulator, &theme, None, key(TextBlink::at(0.6)));
+        assert_ne!(format_at(&cache, 0).0, Color32::TRANSPARENT);
+        assert_eq!(format_at(&cache, 1).0, Color32::TRANSPARENT);
+        assert_ne!(format_at(&cache, 1).1, Color32::TRANSPARENT);
+        assert_ne!(format_at(&cache, 2).0, Color32::TRANSPARENT);
+
+        emulator.feed_str("\x1b[2J");
+        let cache = OutputCache::new(&style, &emulator, &theme, None, key(TextBlink::default()));
+        assert!(!cache.blinking);
+    }
+
+    #[test]
+    fn test_cursor_rect() {
+        let area = Rect::from_min_size(egui::pos2(8.0, 30.0), egui::vec2(200.0, 100.0));
+        let character_size = (7.25, 15.5);
+        let pos = CursorPos { x: 3, y: 1 };
+        let rect = |shape, pixels_per_point| {
+            cursor_rect(area, &character_size, &pos, shape, pixels_per_point)
+        };
+
+        // On the cell, counting from the top left of the output
+        let cell = Rect::from_min_size(egui::pos2(29.75, 45.5), egui::vec2(7.25, 15.5));
+        assert_eq!(rect(CursorShape::Block, 1.0), cell);
+        assert_eq!(rect(CursorShape::Block, 2.0), cell);
+
+        // Thin shapes are whole physical pixels thick
+        assert_eq!(rect(CursorShape::Underline, 1.0).height(), 2.0);
+        assert_eq!(rect(CursorShape::Underline, 2.0).height(), 2.0);
+        assert_eq!(rect(CursorShape::Underline, 2.0).max, cell.max);
+        assert_eq!(rect(CursorShape::Bar, 1.0).width(), 1.0);
+        assert_eq!(rect(CursorShape::Bar, 2.0).width(), 1.0);
+        assert_eq!(rect(CursorShape::Bar, 4.0).width(), 1.0);
+        assert_eq!(rect(CursorShape::Bar, 3.0).width(), 1.0);
+        assert_eq!(rect(CursorShape::Bar, 0.5).width(), 2.0);
+    }
+
+    #[test]
+    fn test_char_grid_matches_layout() {
+        for pixels_per_point in [1.0, 1.5, 2.0] {
+            let ctx = egui::Context::default();
+            ctx.set_fonts(font_definitions(&FontConfig::default()));
+            let mut input = egui::RawInput::default();
+            input.viewports.get_mut(&input.viewport_id).unwrap().native_pixels_per_point =
+                Some(pixels_per_point);
+
+            let _ = ctx.run(input, |ctx| {
+                assert_eq!(ctx.pixels_per_point(), pixels_per_point);
+                for line_spacing in [1.0, 1.2] {
+                    let line_height = line_height(ctx, 12.0, line_spacing);
+                    let character_size = get_char_size(ctx, 12.0, line_height);
+                    let font_id = FontId::new(12.0, FontFamily::Name(REGULAR_FONT_NAME.into()));
+                    let text = ["M".repeat(80), "i".repeat(80), "M".repeat(80)].join("\n");
+                    let mut job = LayoutJob::simple(text, font_id, Color32::WHITE, f32::INFINITY);
+                    job.sections[0].format.line_height = line_height;
+                    let galley = ctx.fonts(|fonts| fonts.layout_job(job));
+                    // egui snaps each row to the pixel grid, which can be a hair off a multiple of
+                    // a taller row at fractional scales. It does not add up from row to row
+                    let row_tolerance = match line_height {
+                        None => 0.01,
+                        Some(_) => 0.1 / pixels_per_point,
+                    };
+
+                    // The cells the cursor, images and the pointer are placed on are where the
+                    // text is drawn, as far as the last column and row
+                    for (y, row) in galley.rows.iter().enumerate() {
+                        assert!(
+                            (row.rect.top() - y as f32 * character_size.1).abs() < row_tolerance,
+                            "row {y} is off"
+                        );
+                        for (x, glyph) in row.glyphs.iter().enumerate() {
+                            let offset = glyph.pos.x - x as f32 * character_size.0;
+                            assert!(offset.abs() < 0.01, "column {x} is {offset} off");
+                        }
+                    }
+
+                    let (width, height) = cell_size_in_pixels(&character_size, pixels_per_point);
+                    assert_eq!(width, (character_size.0 * pixels_per_point).round() as usize);
+                    assert_eq!(height, (character_size.1 * pixels_per_point).round() as usize);
+                }
+            });
+        }
+    }
+
+    #[test]
+    fn test_line_spacing_cell_height() {
+        let ctx = egui::Context::default();
+        ctx.set_fonts(font_definitions(&FontConfig::default()));
+        let _ = ctx.run(egui::RawInput::default(), |ctx| {
+            assert_eq!(line_height(ctx, 12.0, 1.0), None);
+            let (width, height) = get_char_size(ctx, 12.0, None);
+            let (spaced_width, spaced_height) =
+                get_char_size(ctx, 12.0, line_height(ctx, 12.0, 1.2));
+            assert_eq!(spaced_width, width);
+            // Rows are rounded to whole pixels
+            assert!((spaced_height - height * 1.2).abs() <= 1.0, "{spaced_height} {height}");
+            assert!(spaced_height > height);
+        });
+    }
+
+    #[test]
+    fn test_gui_config_args() {
+        let args = |args: &[&str]| GuiConfig::from_args(args.iter().map(|s| s.to_string()));
+        let config = args(&["--theme", "solarized-dark", "--font=Hack"]).unwrap();
+        assert_eq!(config.theme, Theme::solarized_dark());
+        assert_eq!(config.fonts.family.as_deref(), Some("Hack"));
+        assert_eq!(args(&[]).unwrap().theme, Theme::dark());
+        assert!(args(&["--bold-is-bright", "--theme=dark"]).unwrap().theme.bold_is_bright);
+        assert!(args(&["--theme=neon"]).is_err());
+        let known = ["--log-level", "debug", "--font-bold", "bold.ttf", "--status-line"];
+        assert!(args(&known).is_ok());
+        assert_eq!(args(&["--theme=dark", "--colour"]).unwrap_err(), "Unknown argument --colour");
+        assert!(args(&["dark"]).is_err());
+
+        let config = args(&[
+            "--cursor-color=#ff8000",
+            "--theme",
+            "solarized-dark",
+            "--selection-color",
+            "#102030",
+            "--cursor-shape=bar",
+            "--cursor-blink",
+        ])
+        .unwrap();
+        assert_eq!(config.theme.cursor, Color32::from_rgb(0xff, 0x80, 0x00));
+        assert_eq!(config.theme.selection, Color32::from_rgb(0x10, 0x20, 0x30));
+        assert_eq!(config.theme.foreground, Theme::solarized_dark().foreground);
+        assert_eq!(
+            config.cursor,
+            CursorConfig {
+                shape: CursorShape::Bar,
+                blink: true,
+            }
+        );
+        assert_eq!(args(&[]).unwrap().cursor, CursorConfig::default());
+        assert!(args(&["--cursor-shape=beam"]).is_err());
+        assert!(args(&["--cursor-color=orange"]).is_err());
+        assert!(args(&["--selection-color=#12345"]).is_err());
+
+        assert_eq!(args(&[]).unwrap().word_separators, DEFAULT_WORD_SEPARATORS);
+        let config = args(&["--word-separators", "/"]).unwrap();
+        assert_eq!(config.word_separators, "/");
+
+        let config = args(&["--bind", "ctrl+plus=increase-font", "--bind=shift+PageUp=send-input"])
+            .unwrap();
+        assert_eq!(
+            config.key_bindings.action(Key::Plus, &Modifiers::CTRL),
+            Some(KeyAction::IncreaseFont)
+        );
+        assert_eq!(
+            config.key_bindings.action(Key::PageUp, &Modifiers::SHIFT),
+            Some(KeyAction::SendInput)
+        );
+        assert!(args(&["--bind=ctrl+plus=zoom"]).is_err());
+
+        assert_eq!(args(&[]).unwrap().paste_confirm, PasteConfirmConfig::default());
+        let config = args(&["--paste-confirm-size", "100", "--no-paste-confirm-newlines"]).unwrap();
+        assert_eq!(
+            config.paste_confirm,
+            PasteConfirmConfig {
+                max_len: Some(100),
+                newlines: false,
+            }
+        );
+        assert!(args(&["--paste-confirm-size=big"]).is_err());
+
+        assert_eq!(args(&[]).unwrap().scale, None);
+        assert_eq!(args(&["--scale", "1.5"]).unwrap().scale, Some(1.5));
+        assert_eq!(args(&["--scale=2"]).unwrap().scale, Some(2.0));
+        assert!(args(&["--scale=0"]).is_err());
+        assert!(args(&["--scale=-1"]).is_err());
+        assert!(args(&["--scale=big"]).is_err());
+
+        let config = args(&[]).unwrap();
+        assert_eq!((config.line_spacing, config.padding), (1.0, 8));
+        let config = args(&["--line-spacing", "1.2", "--padding=2"]).unwrap();
+        assert_eq!((config.line_spacing, config.padding), (1.2, 2));
+        assert!(args(&["--line-spacing=0"]).is_err());
+        assert!(args(&["--line-spacing=NaN"]).is_err());
+        assert!(args(&["--padding=-1"]).is_err());
+        assert!(args(&["--padding=200"]).is_err());
+
+        assert_eq!(args(&[]).unwrap().bell, BellConfig::default());
+        let config = args(&["--bell-tint", "--bell-fade", "300"]).unwrap();
+        assert_eq!(
+            config.bell,
+            BellConfig {
+                invert: false,
+                fade_seconds: 0.3,
+            }
+        );
+        assert!(args(&["--bell-fade=slow"]).is_err());
+
+        assert!(!args(&[]).unwrap().status_line);
+        assert!(args(&["--status-line"]).unwrap().status_line);
+    }
+
+    #[test]
+    fn test_flash_alpha() {
+        assert_eq!(flash_alpha(0.0, 0.15), 1.0);
+        assert!((flash_alpha(0.075, 0.15) - 0.5).abs() < 1e-6);
+        assert!(flash_alpha(0.1, 0.15) < flash_alpha(0.05, 0.15));
+        assert_eq!(flash_alpha(0.15, 0.15), 0.0);
+        assert_eq!(flash_alpha(1.0, 0.15), 0.0);
+        assert_eq!(flash_alpha(0.0, 0.0), 0.0);
+
+        // Ringing again during a flash starts it over instead of adding another
+        let mut bell = Bell::new(BellConfig::default());
+        bell.ring(10.0);
+        bell.ring(10.1);
+        assert_eq!(bell.flash_start, Some(10.1));
+    }
+
+    #[test]
+    fn test_window_title() {
+        let mut emulator = emulator_without_shell(80, 24);
+        assert_eq!(window_title(emulator.title()), "Terminaux");
+        emulator.feed_str("\x1b]0;bash\x07\x1b]2;vim notes.txt\x07");
+        assert_eq!(window_title(emulator.title()), "vim notes.txt");
+        emulator.feed_str("\x1b]2;\x07");
+        assert_eq!(window_title(emulator.title()), "Terminaux");
+    }
+
+    #[test]
+    fn test_status_line_text() {
+        assert_eq!(status_line_text("", None, None), "");
+        assert_eq!(
+            status_line_text("vim", Some(Path::new("/home/me")), Some(1)),
+            "vim  |  /home/me  |  exit 1"
+        );
+        assert_eq!(status_line_text("", Some(Path::new("/tmp")), Some(0)), "/tmp  |  exit 0");
+    }
+
+    #[test]
+    fn test_selected_text() {
+        let character_size = (10.0, 20.0);
+        let width_chars = 8;
+        // "hello   " fills the first row and wraps "world" onto the second
+        let data = TerminalData {
+            scrollback: b"hello   world\nfoo  " as &[u8],
+            visible: b"bar baz \nqux",
+        };
+        let areas = TerminalOutputRenderResponse {
+            scrollback_area: Rect::from_min_size(egui::pos2(5.0, 0.0), egui::vec2(80.0, 60.0)),
+            canvas_area: Rect::from_min_size(egui::pos2(5.0, 60.0), egui::vec2(80.0, 40.0)),
+            scrollback_range: 0..data.scrollback.len(),
+            canvas_range: 0..data.visible.len(),
+        };
+
+        let index = |x, y| {
+            pointer_to_selection_index(
+                egui::pos2(x, y),
+                &areas,
+                &data,
+                width_chars,
+                &character_size,
+            )
+        };
+
+        let joined = joined_data(&data);
+        let text = |selection: &Selection| {
+            selected_text(&joined, &selection.ranges(&joined, width_chars), selection.mode)
+        };
+
+        // Middle of the "e" in hello through the "a" in baz
+        let selection = Selection {
+            anchor: index(18.0, 10.0),
+            end: index(60.0, 70.0),
+            mode: SelectionMode::Linear,
+        };
+        assert_eq!(text(&selection), "ello   world\nfoo\nbar ba");
+
+        // Dragging backwards selects the same text
+        let selection = Selection {
+            anchor: selection.end,
+            end: selection.anchor,
+            mode: SelectionMode::Linear,
+        };
+        assert_eq!(text(&selection), "ello   world\nfoo\nbar ba");
+
+        // Trailing spaces are dropped, and dragging off the end of the output selects to the end
+        let selection = Selection {
+            anchor: index(75.0, 65.0),
+            end: index(200.0, 90.0),
+            mode: SelectionMode::Linear,
+        };
+        assert_eq!(text(&selection), "\nqux");
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn test_primary_selection() {
+        let ctx = egui::Context::default();
+        let writer = SharedWriter::default();
+        let mut emulator = TerminalEmulator::with_io(std::io::empty(), writer.clone());
+        emulator.feed_str("hello world");
+        let mut session = Session::new(&ctx, emulator, &Theme::dark());
+        let areas = TerminalOutputRenderResponse {
+            scrollback_area: Rect::from_min_size(egui::pos2(0.0, 0.0), egui::vec2(800.0, 0.0)),
+            canvas_area: Rect::from_min_size(egui::pos2(0.0, 0.0), egui::vec2(800.0, 480.0)),
+            scrollback_range: 0..0,
+            canvas_range: 0..11,
+        };
+        let button = |x, button, pressed| Event::PointerButton {
+            pos: egui::pos2(x, 10.0),
+            button,
+            pressed,
+            modifiers: Modifiers::NONE,
+        };
+
+        let mut primary_selection = String::new();
+        let mut held = None;
+        let mut frame = |events: Vec<Event>| {
+            let input = egui::RawInput {
+                events,
+                ..Default::default()
+            };
+            let _ = ctx.run(input, |ctx| {
+                CentralPanel::default().show(ctx, |ui| {
+                    session.update_selection(
+                        ui,
+                        &areas,
+                        80,
+                        &(10.0, 20.0),
+                        DEFAULT_WORD_SEPARATORS,
+                        &mut primary_selection,
+                    );
+                    paste_primary_on_middle_click(
+                        ui,
+                        areas.canvas_area,
+                        &mut session.terminal_emulator,
+                        &primary_selection,
+                        &PasteConfirmConfig::default(),
+                        &mut held,
+                    )
+                    .unwrap();
+                });
+            });
+            primary_selection.clone()
+        };
+
+        // Dragging over "hello" only makes it the primary selection once the button is let go
+        frame(vec![Event::PointerMoved(egui::pos2(2.0, 10.0))]);
+        frame(vec![button(2.0, egui::PointerButton::Primary, true)]);
+        assert_eq!(
+            frame(vec![Event::PointerMoved(egui::pos2(45.0, 10.0))]),
+            ""
+        );
+        assert_eq!(
+            frame(vec![button(45.0, egui::PointerButton::Primary, false)]),
+            "hello"
+        );
+        assert_eq!(writer.take(), b"");
+
+        // A click does not replace it, a middle click pastes it
+        frame(vec![button(60.0, egui::PointerButton::Primary, true)]);
+        frame(vec![button(60.0, egui::PointerButton::Primary, false)]);
+        frame(vec![button(60.0, egui::PointerButton::Middle, true)]);
+        assert_eq!(
+            frame(vec![button(60.0, egui::PointerButton::Middle, false)]),
+            "hello"
+        );
+        assert_eq!(writer.take(), b"hello");
+    }
+
+    #[test]
+    fn test_block_selection() {
+        let width = 6;
+        // The first line wraps, so the grid is
+        // "name  "
+        // "size"
+        // "a     "
+        // "bb    "
+        // ""
+        // "cccccc"
+        // "cc"
+        let joined = b"name  size\na     \nbb    \n\ncccccccc";
+        let block = |anchor, end, anchor_column, end_column| {
+            let selection = Selection {
+                anchor,
+                end,
+                mode: SelectionMode::Block {
+                    anchor_column,
+                    end_column,
+                },
+            };
+            let ranges = selection.ranges(joined, width);
+            selected_text(joined, &ranges, selection.mode)
+        };
+
+        // Columns 1 to 3 from the "a" in name down to the first c row, trailing spaces are kept
+        // and rows that end left of the rectangle are empty
+        assert_eq!(block(1, 27, 1, 3), "ame\nize\n   \nb  \n\nccc");
+        // Dragging up and left from past the end of a line gives the same rectangle
+        assert_eq!(block(joined.len(), 1, 4, 1), "ame \nize\n    \nb   \n\ncccc\nc");
+
+        // A linear selection of the same bytes trims instead
+        let linear = Selection {
+            anchor: 11,
+            end: 17,
+            mode: SelectionMode::Linear,
+        };
+        let ranges = linear.ranges(joined, width);
+        assert_eq!(selected_text(joined, &ranges, linear.mode), "a\n");
+    }
+
+    #[test]
+    fn test_wrapped_rows() {
+        assert_eq!(wrapped_rows(b"", 4), Vec::<Range<usize>>::new());
+        assert_eq!(wrapped_rows(b"ab\n\nabcdefghi", 4), [0..2, 3..3, 4..8, 8..12, 12..13]);
+        // Exactly filling a row does not add an empty one
+        assert_eq!(wrapped_rows(b"abcd\nef", 4), [0..4, 5..7]);
+    }
+
+    #[test]
+    fn test_visible_line_window() {
+        // Not enough output to fill the screen
+        assert_eq!(visible_line_window(3, 10, 0), 0..3);
+        assert_eq!(visible_line_window(3, 10, 5), 0..3);
+
+        assert_eq!(visible_line_window(100, 10, 0), 90..100);
+        assert_eq!(visible_line_window(100, 10, 25), 65..75);
+        // Scrolling past the top stops at the first row
+        assert_eq!(visible_line_window(100, 10, 95), 0..10);
+        assert_eq!(visible_line_window(100, 10, usize::MAX), 0..10);
+    }
+
+    #[test]
+    fn test_scroll_offset_after_output() {
+        // Following the output at the bottom
+        assert_eq!(scroll_offset_after_output(0, 0), 0);
+        assert_eq!(scroll_offset_after_output(0, 5), 0);
+        // Scrolled up the view moves up along with the rows it shows
+        assert_eq!(scroll_offset_after_output(3, 0), 3);
+        assert_eq!(scroll_offset_after_output(3, 5), 8);
+        assert_eq!(scroll_offset_after_output(1, 100), 101);
+
+        assert_eq!(jump_to_bottom_label(0), "Jump to bottom");
+        assert_eq!(jump_to_bottom_label(1), "1 new line, jump to bottom");
+        assert_eq!(jump_to_bottom_label(12), "12 new lines, jump to bottom");
+    }
+
+    #[test]
+    fn test_output_cache_lays_out_visible_rows() {
+        let mut emulator = emulator_without_shell(10, 3);
+        for i in 0..100 {
+            emulator.feed_str(&format!("\x1b[31mline\x1b[0m {i}\r\n"));
+        }
+
+        let key = |scroll_offset| OutputCacheKey {
+            generation: 0,
+            font: OutputFont {
+                size: 12.0,
+                line_height: None,
+            },
+            available_width: 100.0,
+            selection: Vec::new(),
+            search: None,
+            screen_size_chars: (10, 3),
+            scroll_offset,
+            text_blink: TextBlink::default(),
+        };
+        let style = egui::Style::default();
+
+        let theme = Theme::default();
+        let cache = OutputCache::new(&style, &emulator, &theme, None, key(0));
+        assert!(cache.scrollback_job.is_none());
+        assert_eq!(cache.canvas_job.text, "line 98\nline 99\n");
+
+        let cache = OutputCache::new(&style, &emulator, &theme, None, key(2));
+        let scrollback_job = cache.scrollback_job.unwrap();
+        assert_eq!(scrollback_job.text, "line 96\nline 97");
+        assert_eq!(cache.canvas_job.text, "line 98");
+        // Formatting is cut down to the rows in view too
+        assert!(scrollback_job
+            .sections
+            .iter()
+            .all(|section| section.byte_range.end <= scrollback_job.text.len()));
+        assert_eq!(scrollback_job.sections[0].byte_range, 0..4);
+    }
+
+    #[test]
+    fn test_output_cache_highlights_search() {
+        let mut emulator = emulator_without_shell(10, 3);
+        emulator.feed_str("one two\r\ntwo one");
+
+        let joined = joined_data(&emulator.data());
+        let mut search = Search::default();
+        search.query = "one".to_string();
+        search.update(0, || joined.clone());
+        search.step(false);
+        let selection = Selection::from_range(0..1).unwrap();
+        let key = OutputCacheKey {
+            generation: 0,
+            font: OutputFont {
+                size: 12.0,
+                line_height: None,
+            },
+            available_width: 100.0,
+            selection: selection.ranges(&joined, 10),
+            search: Some(search.generation()),
+            screen_size_chars: (10, 3),
+            scroll_offset: 0,
+            text_blink: TextBlink::default(),
+        };
+        let theme = Theme::default();
+        let cache =
+            OutputCache::new(&egui::Style::default(), &emulator, &theme, Some(&search), key);
+
+        let background = |byte| {
+            let sections = &cache.canvas_job.sections;
+            let section = sections.iter().find(|s| s.byte_range.contains(&byte)).unwrap();
+            section.format.background
+        };
+        // The selection is drawn over the first match, the second one is the current one
+        assert_eq!(background(0), theme.selection);
+        assert_eq!(background(1), theme.search_match);
+        assert_eq!(background(3), Color32::TRANSPARENT);
+        assert_eq!(background(12), theme.search_current);
+        assert_eq!(background(14), theme.search_current);
+    }
+
+    #[test]
+    fn test_font_config_args() {
+        let args = [
+            "--log-level=info",
+            "--font",
+            "Hack",
+            "--font-bold=/fonts/bold.ttf",
+            "--no-font-fallback",
+        ];
+        let config = FontConfig::from_args(args.iter().map(|s| s.to_string())).unwrap();
+        assert_eq!(
+            config,
+            FontConfig {
+                bold: Some(PathBuf::from("/fonts/bold.ttf")),
+                family: Some("Hack".to_string()),
+                symbol_fallback: false,
+                ..Default::default()
+            }
+        );
+        assert!(FontConfig::from_args(["--font-italic".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_fonts_load_from_path() {
+        let dir = std::env::temp_dir().join(format!("terminaux-fonts-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let regular = dir.join("Hack-Regular.ttf");
+        std::fs::copy("res/Hack-Regular.ttf", &regular).unwrap();
+        let corrupt = dir.join("corrupt.ttf");
+        std::fs::write(&corrupt, b"not a font").unwrap();
+
+        let fonts = font_definitions(&FontConfig {
+            regular: Some(regular.clone()),
+            bold: Some(corrupt),
+            italic: Some(dir.join("missing.ttf")),
+            ..Default::default()
+        });
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let hack = std::fs::read("res/Hack-Regular.ttf").unwrap();
+        assert_eq!(*fonts.font_data[REGULAR_FONT_NAME].font, hack[..]);
+        assert_eq!(
+            fonts.families[&FontFamily::Name(REGULAR_FONT_NAME.into())][0],
+            REGULAR_FONT_NAME
+        );
+        assert_eq!(fonts.families[&FontFamily::Monospace][0], REGULAR_FONT_NAME);
+
+        // Files that cannot be used fall back to the bundled faces
+        assert_eq!(
+            *fonts.font_data[BOLD_FONT_NAME].font,
+            include_bytes!("../res/JetBrainsMono-Bold.ttf")[..]
+        );
+        assert_eq!(
+            *fonts.font_data[ITALIC_FONT_NAME].font,
+            include_bytes!("../res/JetBrainsMono-Italic.ttf")[..]
+        );
+        assert!(fonts
+            .families
+            .contains_key(&FontFamily::Name(ITALIC_FONT_NAME.into())));
+    }
+
+    #[test]
+    fn test_symbol_fallback() {
+        let fonts = font_definitions(&FontConfig::default());
+        assert_eq!(
+            fonts.families[&FontFamily::Name(BOLD_FONT_NAME.into())],
+            [BOLD_FONT_NAME, "NotoEmoji-Regular", "emoji-icon-font"]
+        );
+
+        // Emoji take up no more than two cells
+        let cell_width = advance_in_em(&fonts.font_data[REGULAR_FONT_NAME], 'M').unwrap();
+        let emoji = &fonts.font_data["NotoEmoji-Regular"];
+        let emoji_width = advance_in_em(emoji, FALLBACK_SAMPLE_CHAR).unwrap() * emoji.tweak.scale;
+        assert!(emoji_width <= 2.0 * cell_width + f32::EPSILON);
+
+        let fonts = font_definitions(&FontConfig {
+            symbol_fallback: false,
+            ..Default::default()
+        });
+        assert_eq!(
+            fonts.families[&FontFamily::Name(BOLD_FONT_NAME.into())],
+            [BOLD_FONT_NAME]
+        );
+    }
+
+    #[test]
+    fn test_prompt_rows() {
+        let mark = |start, kind| PromptMark { start, kind };
+        let data = TerminalData {
+            scrollback: &b"$ ls\nabcdefgh\n$ x"[..],
+            visible: &b"out\n$ "[..],
+        };
+        let marks = [
+            mark(0, PromptMarkKind::PromptStart),
+            mark(2, PromptMarkKind::CommandStart),
+            mark(14, PromptMarkKind::PromptStart),
+            mark(22, PromptMarkKind::PromptStart),
+        ];
+        // "abcdefgh" wraps onto two rows at width 5, the visible screen starts on row 4
+        assert_eq!(prompt_rows(&data, &marks, 5), [0, 3, 5]);
+    }
+
+    #[test]
+    fn test_joined_row() {
+        let data = TerminalData {
+            scrollback: &b"$ ls\nabcdefgh"[..],
+            visible: &b"out\n$ "[..],
+        };
+        assert_eq!(joined_row(&data, 0, 5), 0);
+        assert_eq!(joined_row(&data, 6, 5), 1);
+        // Second row of the wrapped "abcdefgh"
+        assert_eq!(joined_row(&data, 11, 5), 2);
+        assert_eq!(joined_row(&data, 14, 5), 3);
+        assert_eq!(joined_row(&data, 18, 5), 4);
+
+        let data = TerminalData {
+            scrollback: &b""[..],
+            visible: &b"out\n$ "[..],
+        };
+        assert_eq!(joined_row(&data, 0, 5), 0);
+        assert_eq!(joined_row(&data, 4, 5), 1);
+    }
+
+    #[test]
+    fn test_slice_format_tags() {
+        let tag = |start, end, fg_color| FormatTag {
+            start,
+            end,
+            fg_color,
+            bg_color: TerminalColor::Default,
+            attributes: TextAttributes::default(),
+        };
+        let tags = [
+            tag(0, 5, TerminalColor::ForegroundRed),
+            tag(5, usize::MAX, TerminalColor::Default),
+        ];
+
+        assert_eq!(
+            slice_format_tags(&tags, &(3..8)),
+            [
+                tag(0, 2, TerminalColor::ForegroundRed),
+                tag(2, 5, TerminalColor::Default)
+            ]
+        );
+        assert_eq!(
+            slice_format_tags(&tags, &(6..8)),
+            [tag(0, 2, TerminalColor::Default)]
+        );
+    }
+}
@@ -0,0 +1,230 @@
+//! What keys do in the window, before they are sent to the shell
+
+use std::collections::HashMap;
+
+use eframe::egui::{Key, Modifiers};
+
+/// Something a key can be bound to instead of being sent to the shell
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum KeyAction {
+    /// Send the key to the shell like an unbound one, to take a default binding away
+    SendInput,
+    /// Copy the selection
+    Copy,
+    Paste,
+    CopyScreen,
+    /// Copy all output including scrollback
+    CopyAll,
+    NewTab,
+    IncreaseFont,
+    DecreaseFont,
+    /// Back to the font size the window started with
+    ResetFont,
+    /// Scroll a screen up into scrollback
+    ScrollUp,
+    ScrollDown,
+    ScrollToTop,
+    /// Back to the live screen
+    ScrollToBottom,
+    /// Scroll to the prompt above the top of the screen
+    PreviousPrompt,
+    NextPrompt,
+    /// Open the search bar, or go back to it
+    Find,
+}
+
+impl KeyAction {
+    pub const ALL: [KeyAction; 16] = [
+        KeyAction::SendInput,
+        KeyAction::Copy,
+        KeyAction::Paste,
+        KeyAction::CopyScreen,
+        KeyAction::CopyAll,
+        KeyAction::NewTab,
+        KeyAction::IncreaseFont,
+        KeyAction::DecreaseFont,
+        KeyAction::ResetFont,
+        KeyAction::ScrollUp,
+        KeyAction::ScrollDown,
+        KeyAction::ScrollToTop,
+        KeyAction::ScrollToBottom,
+        KeyAction::PreviousPrompt,
+        KeyAction::NextPrompt,
+        KeyAction::Find,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            KeyAction::SendInput => "send-input",
+            KeyAction::Copy => "copy",
+            KeyAction::Paste => "paste",
+            KeyAction::CopyScreen => "copy-screen",
+            KeyAction::CopyAll => "copy-all",
+            KeyAction::NewTab => "new-tab",
+            KeyAction::IncreaseFont => "increase-font",
+            KeyAction::DecreaseFont => "decrease-font",
+            KeyAction::ResetFont => "reset-font",
+            KeyAction::ScrollUp => "scroll-up",
+            KeyAction::ScrollDown => "scroll-down",
+            KeyAction::ScrollToTop => "scroll-to-top",
+            KeyAction::ScrollToBottom => "scroll-to-bottom",
+            KeyAction::PreviousPrompt => "previous-prompt",
+            KeyAction::NextPrompt => "next-prompt",
+            KeyAction::Find => "find",
+        }
+    }
+
+    pub fn by_name(name: &str) -> Option<KeyAction> {
+        KeyAction::ALL.into_iter().find(|action| action.name() == name)
+    }
+}
+
+/// A key with the modifiers held down with it. Only Ctrl, Shift and Alt count, so that a binding
+/// matches however the platform reports the command key
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct KeyCombo {
+    pub key: Key,
+    pub ctrl: bool,
+    pub shift: bool,
+    pub alt: bool,
+}
+
+impl KeyCombo {
+    pub fn new(key: Key, modifiers: &Modifiers) -> KeyCombo {
+        KeyCombo {
+            key,
+            ctrl: modifiers.ctrl,
+            shift: modifiers.shift,
+            alt: modifiers.alt,
+        }
+    }
+
+    /// Modifiers and a key joined by `+`, e.g. `ctrl+shift+c` or `shift+PageUp`. Keys are named
+    /// like [`Key::from_name`] does, in any case
+    pub fn parse(combo: &str) -> Option<KeyCombo> {
+        let mut parts = combo.split('+').collect::<Vec<_>>();
+        let key_name = parts.pop()?;
+        let key = Key::from_name(key_name).or_else(|| {
+            Key::ALL
+                .iter()
+                .copied()
+                .find(|key| key.name().eq_ignore_ascii_case(key_name))
+        })?;
+
+        let mut modifiers = Modifiers::NONE;
+        for part in parts {
+            match part.to_lowercase().as_str() {
+                "ctrl" => modifiers.ctrl = true,
+                "shift" => modifiers.shift = true,
+                "alt" => modifiers.alt = true,
+                _ => return None,
+            }
+        }
+        Some(KeyCombo::new(key, &modifiers))
+    }
+}
+
+/// Which keys do a [`KeyAction`], the rest are sent to the shell
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyBindings(HashMap<KeyCombo, KeyAction>);
+
+impl Default for KeyBindings {
+    fn default() -> KeyBindings {
+        let ctrl_shift = Modifiers::CTRL | Modifiers::SHIFT;
+        KeyBindings::from_table(&[
+            (Key::C, ctrl_shift, KeyAction::Copy),
+            (Key::V, ctrl_shift, KeyAction::Paste),
+            (Key::S, ctrl_shift, KeyAction::CopyScreen),
+            (Key::A, ctrl_shift, KeyAction::CopyAll),
+            (Key::T, ctrl_shift, KeyAction::NewTab),
+            // Plus is a shifted key on most layouts, Ctrl+= is the same key without Shift
+            (Key::Plus, Modifiers::CTRL, KeyAction::IncreaseFont),
+            (Key::Plus, ctrl_shift, KeyAction::IncreaseFont),
+            (Key::Equals, Modifiers::CTRL, KeyAction::IncreaseFont),
+            (Key::Minus, Modifiers::CTRL, KeyAction::DecreaseFont),
+            (Key::Num0, Modifiers::CTRL, KeyAction::ResetFont),
+            (Key::PageUp, Modifiers::SHIFT, KeyAction::ScrollUp),
+            (Key::PageDown, Modifiers::SHIFT, KeyAction::ScrollDown),
+            (Key::Home, Modifiers::SHIFT, KeyAction::ScrollToTop),
+            (Key::End, Modifiers::SHIFT, KeyAction::ScrollToBottom),
+            (Key::ArrowUp, ctrl_shift, KeyAction::PreviousPrompt),
+            (Key::ArrowDown, ctrl_shift, KeyAction::NextPrompt),
+            (Key::F, ctrl_shift, KeyAction::Find),
+        ])
+    }
+}
+
+impl KeyBindings {
+    /// Only the bindings in `table`, a later row for the same keys replacing an earlier one
+    pub fn from_table(table: &[(Key, Modifiers, KeyAction)]) -> KeyBindings {
+        let mut bindings = KeyBindings(HashMap::new());
+        for (key, modifiers, action) in table {
+            bindings.bind(KeyCombo::new(*key, modifiers), *action);
+        }
+        bindings
+    }
+
+    pub fn bind(&mut self, combo: KeyCombo, action: KeyAction) {
+        self.0.insert(combo, action);
+    }
+
+    /// Add a binding written as `<keys>=<action>`, see [`KeyCombo::parse`] and
+    /// [`KeyAction::name`]
+    pub fn bind_str(&mut self, binding: &str) -> Result<(), String> {
+        let (combo, action) = binding
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid key binding \"{binding}\", expected <keys>=<action>"))?;
+        let combo = KeyCombo::parse(combo).ok_or_else(|| format!("Invalid keys \"{combo}\""))?;
+        let action = KeyAction::by_name(action).ok_or_else(|| {
+            let names = KeyAction::ALL.map(KeyAction::name);
+            format!(
+                "Unknown key action \"{action}\", expected one of {}",
+                names.join(", ")
+            )
+        })?;
+        self.bind(combo, action);
+        Ok(())
+    }
+
+    /// What `key` does when pressed with `modifiers`, None when it is not bound
+    pub fn action(&self, key: Key, modifiers: &Modifiers) -> Option<KeyAction> {
+        self.0.get(&KeyCombo::new(key, modifiers)).copied()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_key_combo_parse() {
+        let ctrl_shift = Modifiers::CTRL | Modifiers::SHIFT;
+        assert_eq!(KeyCombo::parse("ctrl+shift+c"), Some(KeyCombo::new(Key::C, &ctrl_shift)));
+        assert_eq!(KeyCombo::parse("Ctrl+Plus"), Some(KeyCombo::new(Key::Plus, &Modifiers::CTRL)));
+        assert_eq!(KeyCombo::parse("PageUp"), Some(KeyCombo::new(Key::PageUp, &Modifiers::NONE)));
+        assert_eq!(KeyCombo::parse("super+c"), None);
+        assert_eq!(KeyCombo::parse("ctrl+nokey"), None);
+        assert_eq!(KeyCombo::parse(""), None);
+    }
+
+    #[test]
+    fn test_bind_str() {
+        let mut bindings = KeyBindings::default();
+        bindings.bind_str("ctrl+plus=increase-font").unwrap();
+        bindings.bind_str("ctrl+shift+t=send-input").unwrap();
+        assert_eq!(
+            bindings.action(Key::Plus, &Modifiers::CTRL),
+            Some(KeyAction::IncreaseFont)
+        );
+        let ctrl_shift = Modifiers::CTRL | Modifiers::SHIFT;
+        assert_eq!(bindings.action(Key::T, &ctrl_shift), Some(KeyAction::SendInput));
+        assert_eq!(bindings.action(Key::C, &ctrl_shift), Some(KeyAction::Copy));
+        assert_eq!(bindings.action(Key::C, &Modifiers::CTRL), None);
+
+        assert!(bindings.bind_str("ctrl+plus").is_err());
+        assert!(bindings.bind_str("ctrl+plus=zoom").is_err());
+        for action in KeyAction::ALL {
+            assert_eq!(KeyAction::by_name(action.name()), Some(action));
+        }
+    }
+}
@@ -0,0 +1,167 @@
+//! Finding text in the output, for the search bar
+
+use std::ops::Range;
+
+/// Byte ranges of every place `query` is in `text`, including ones that overlap. Lines soft
+/// wrapped on screen are one line in the data, so matches across a wrap are found too
+///
+/// Without `case_sensitive` only ASCII letters match their other case
+pub(crate) fn find_matches(text: &[u8], query: &str, case_sensitive: bool) -> Vec<Range<usize>> {
+    let query = query.as_bytes();
+    if query.is_empty() || query.len() > text.len() {
+        return Vec::new();
+    }
+
+    text.windows(query.len())
+        .enumerate()
+        .filter(|(_, window)| match case_sensitive {
+            true => *window == query,
+            false => window.eq_ignore_ascii_case(query),
+        })
+        .map(|(start, _)| start..start + query.len())
+        .collect()
+}
+
+/// What the search bar is looking for, and where it was found in the scrollback and visible data
+/// joined like a selection is
+#[derive(Debug, Default)]
+pub(crate) struct Search {
+    pub query: String,
+    pub case_sensitive: bool,
+    matches: Vec<Range<usize>>,
+    /// Index of the match being looked at
+    current: usize,
+    /// Output generation, query and case sensitivity `matches` were found for
+    found_for: Option<(u64, String, bool)>,
+    /// Bumped whenever `matches` changes, for the output cache to notice
+    generation: u64,
+}
+
+impl Search {
+    /// Find the matches again if the output or what is searched for changed since the last time.
+    /// `text` is only called then. Returns true when the query changed, so that the view can
+    /// move to the first match
+    pub fn update(&mut self, output_generation: u64, text: impl FnOnce() -> Vec<u8>) -> bool {
+        let key = (output_generation, self.query.clone(), self.case_sensitive);
+        if self.found_for.as_ref() == Some(&key) {
+            return false;
+        }
+        let query_changed = self
+            .found_for
+            .as_ref()
+            .is_none_or(|(_, query, case_sensitive)| {
+                *query != self.query || *case_sensitive != self.case_sensitive
+            });
+
+        // Stay on the same match while output comes in, unless searching for something else
+        let previous_start = self.current_match().map(|range| range.start);
+        self.matches = find_matches(&text(), &self.query, self.case_sensitive);
+        self.current = match previous_start.filter(|_| !query_changed) {
+            Some(start) => self
+                .matches
+                .iter()
+                .position(|range| range.start >= start)
+                .unwrap_or(self.matches.len().saturating_sub(1)),
+            None => 0,
+        };
+        self.found_for = Some(key);
+        self.generation += 1;
+        query_changed
+    }
+
+    pub fn matches(&self) -> &[Range<usize>] {
+        &self.matches
+    }
+
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    pub fn current_match(&self) -> Option<Range<usize>> {
+        self.matches.get(self.current).cloned()
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Move to the next match, or the previous one with `backwards`, going round at either end
+    pub fn step(&mut self, backwards: bool) {
+        let len = self.matches.len();
+        if len == 0 {
+            return;
+        }
+        self.current = match backwards {
+            true => (self.current + len - 1) % len,
+            false => (self.current + 1) % len,
+        };
+        self.generation += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_find_matches() {
+        let found = |text: &[u8], query, case_sensitive| {
+            find_matches(text, query, case_sensitive)
+                .into_iter()
+                .map(|range| (range.start, range.end))
+                .collect::<Vec<_>>()
+        };
+        let text = b"cargo build\nCargo test\ncargo";
+        assert_eq!(found(text, "cargo", true), [(0, 5), (23, 28)]);
+        assert_eq!(found(text, "cargo", false), [(0, 5), (12, 17), (23, 28)]);
+        assert_eq!(found(text, "CARGO", false), [(0, 5), (12, 17), (23, 28)]);
+        assert_eq!(found(text, "build\nCargo", true), [(6, 17)]);
+
+        // Overlapping matches are all found
+        assert_eq!(found(b"aaaa", "aa", true), [(0, 2), (1, 3), (2, 4)]);
+        assert_eq!(found(b"abababa", "aba", true), [(0, 3), (2, 5), (4, 7)]);
+
+        // A line wider than the screen has no newline where it wraps, so a match can span rows,
+        // but not a newline between lines
+        assert_eq!(found(b"hello world", "o w", true), [(4, 7)]);
+        assert_eq!(found(b"hello\nworld", "o w", true), []);
+
+        assert_eq!(found(b"abc", "", true), []);
+        assert_eq!(found(b"ab", "abc", true), []);
+        assert_eq!(found("émoji É".as_bytes(), "é", false), [(0, 2)]);
+    }
+
+    #[test]
+    fn test_search_navigation() {
+        let mut search = Search {
+            query: "ab".to_string(),
+            ..Search::default()
+        };
+        assert!(search.update(0, || b"ab ab ab".to_vec()));
+        assert!(!search.update(0, || unreachable!()));
+        assert_eq!(search.matches(), [0..2, 3..5, 6..8]);
+        assert_eq!(search.current_match(), Some(0..2));
+
+        search.step(true);
+        assert_eq!(search.current_match(), Some(6..8));
+        search.step(false);
+        search.step(false);
+        assert_eq!(search.current_match(), Some(3..5));
+
+        // New output keeps the current match, and finds matches in it
+        assert!(!search.update(1, || b"ab ab ab ab".to_vec()));
+        assert_eq!(search.current_match(), Some(3..5));
+        assert_eq!(search.matches().len(), 4);
+
+        // Searching for something else starts over from the top
+        search.query = "b a".to_string();
+        assert!(search.update(1, || b"ab ab ab ab".to_vec()));
+        assert_eq!(search.current_match(), Some(1..4));
+
+        search.query = "none".to_string();
+        search.update(1, || b"ab ab ab ab".to_vec());
+        assert_eq!(search.current_match(), None);
+        search.step(false);
+        assert_eq!(search.current_match(), None);
+    }
+}
@@ -0,0 +1,114 @@
+//! Colors output is drawn with when programs do not pick their own
+
+use eframe::egui::Color32;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Theme {
+    /// The 16 ANSI colors, normal then bright. The rest of the 256 color palette is the same for
+    /// every theme
+    pub ansi: [Color32; 16],
+    pub foreground: Color32,
+    pub background: Color32,
+    pub cursor: Color32,
+    pub selection: Color32,
+    /// Background of the text found by the search bar, and of the match being looked at
+    pub search_match: Color32,
+    pub search_current: Color32,
+    /// Draw bold text in one of the 8 basic colors in its bright version, which is what some
+    /// programs expect bold to look like
+    pub bold_is_bright: bool,
+}
+
+impl Theme {
+    /// Names accepted by [`Theme::by_name`]
+    pub const NAMES: [&'static str; 2] = ["dark", "solarized-dark"];
+
+    pub fn by_name(name: &str) -> Option<Theme> {
+        match name {
+            "dark" => Some(Theme::dark()),
+            "solarized-dark" => Some(Theme::solarized_dark()),
+            _ => None,
+        }
+    }
+
+    /// xterm's colors on a dark gray background
+    pub fn dark() -> Theme {
+        Theme {
+            ansi: [
+                Color32::from_rgb(0, 0, 0),
+                Color32::from_rgb(205, 0, 0),
+                Color32::from_rgb(0, 205, 0),
+                Color32::from_rgb(205, 205, 0),
+                Color32::from_rgb(0, 0, 238),
+                Color32::from_rgb(205, 0, 205),
+                Color32::from_rgb(0, 205, 205),
+                Color32::from_rgb(229, 229, 229),
+                Color32::from_rgb(127, 127, 127),
+                Color32::from_rgb(255, 0, 0),
+                Color32::from_rgb(0, 255, 0),
+                Color32::from_rgb(255, 255, 0),
+                Color32::from_rgb(92, 92, 255),
+                Color32::from_rgb(255, 0, 255),
+                Color32::from_rgb(0, 255, 255),
+                Color32::from_rgb(255, 255, 255),
+            ],
+            foreground: Color32::from_gray(229),
+            background: Color32::from_gray(27),
+            cursor: Color32::GRAY,
+            selection: Color32::from_rgb(70, 90, 140),
+            search_match: Color32::from_rgb(110, 90, 30),
+            search_current: Color32::from_rgb(190, 120, 20),
+            bold_is_bright: false,
+        }
+    }
+
+    /// <https://ethanschoonover.com/solarized/>
+    pub fn solarized_dark() -> Theme {
+        let base03 = Color32::from_rgb(0x00, 0x2b, 0x36);
+        let base02 = Color32::from_rgb(0x07, 0x36, 0x42);
+        let base01 = Color32::from_rgb(0x58, 0x6e, 0x75);
+        let base00 = Color32::from_rgb(0x65, 0x7b, 0x83);
+        let base0 = Color32::from_rgb(0x83, 0x94, 0x96);
+        let base1 = Color32::from_rgb(0x93, 0xa1, 0xa1);
+        let base2 = Color32::from_rgb(0xee, 0xe8, 0xd5);
+        let base3 = Color32::from_rgb(0xfd, 0xf6, 0xe3);
+        Theme {
+            ansi: [
+                base02,
+                Color32::from_rgb(0xdc, 0x32, 0x2f),
+                Color32::from_rgb(0x85, 0x99, 0x00),
+                Color32::from_rgb(0xb5, 0x89, 0x00),
+                Color32::from_rgb(0x26, 0x8b, 0xd2),
+                Color32::from_rgb(0xd3, 0x36, 0x82),
+                Color32::from_rgb(0x2a, 0xa1, 0x98),
+                base2,
+                base03,
+                Color32::from_rgb(0xcb, 0x4b, 0x16),
+                base01,
+                base00,
+                base0,
+                Color32::from_rgb(0x6c, 0x71, 0xc4),
+                base1,
+                base3,
+            ],
+            foreground: base0,
+            background: base03,
+            cursor: base1,
+            selection: base02,
+            search_match: Color32::from_rgb(0x5c, 0x4a, 0x00),
+            search_current: Color32::from_rgb(0xb5, 0x89, 0x00),
+            bold_is_bright: false,
+        }
+    }
+
+    /// [`Theme::ansi`] as the emulator's palette entries
+    pub fn ansi_rgb(&self) -> [(u8, u8, u8); 16] {
+        self.ansi.map(|color| (color.r(), color.g(), color.b()))
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Theme {
+        Theme::dark()
+    }
+}
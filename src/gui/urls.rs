@@ -0,0 +1,91 @@
+//! Bare URLs in the output, so they can be opened without the program marking them up
+
+use std::ops::Range;
+
+const SCHEMES: [&[u8]; 3] = [b"https://", b"http://", b"file://"];
+
+/// Characters that can be part of a URL, RFC 3986's unreserved and reserved ones plus `%`
+fn is_url_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"-._~:/?#[]@!$&'()*+,;=%".contains(&b)
+}
+
+/// Byte ranges of the `http://`, `https://` and `file://` URLs in `text`
+///
+/// Only newlines and characters that cannot be in a URL end one, so lines soft wrapped on screen
+/// are already joined. Punctuation at the end is taken to be the sentence's, as are closing
+/// parentheses and brackets without an opening one in the URL
+pub(crate) fn find_urls(text: &[u8]) -> Vec<Range<usize>> {
+    let mut urls = Vec::new();
+    let mut i = 0;
+    while i < text.len() {
+        // A scheme in the middle of a word, e.g. xhttp://, is not the start of a URL
+        let at_word_start = i == 0 || !text[i - 1].is_ascii_alphanumeric();
+        let scheme = SCHEMES
+            .iter()
+            .find(|scheme| at_word_start && text[i..].starts_with(scheme));
+        let Some(scheme) = scheme else {
+            i += 1;
+            continue;
+        };
+
+        let rest = &text[i + scheme.len()..];
+        let len = rest.iter().position(|b| !is_url_byte(*b)).unwrap_or(rest.len());
+        let end = i + scheme.len() + trim_url_end(&rest[..len]);
+        if end > i + scheme.len() {
+            urls.push(i..end);
+        }
+        i = end.max(i + 1);
+    }
+    urls
+}
+
+/// Length of `url` without what most likely follows it rather than being part of it
+fn trim_url_end(url: &[u8]) -> usize {
+    let mut len = url.len();
+    while len > 0 {
+        let count = |b: u8| url[..len].iter().filter(|c| **c == b).count();
+        let trim = match url[len - 1] {
+            b'.' | b',' | b';' | b':' | b'!' | b'?' | b'\'' => true,
+            b')' => count(b'(') < count(b')'),
+            b']' => count(b'[') < count(b']'),
+            _ => false,
+        };
+        if !trim {
+            break;
+        }
+        len -= 1;
+    }
+    len
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_find_urls() {
+        let text = b"see https://example.com/a?b=1#c. or (http://x.org/wiki/Foo_(bar)) and\n\
+            file:///tmp/log, not xhttp://no or http:// alone 'https://quoted.net'";
+        let urls = |text: &'static [u8]| {
+            find_urls(text)
+                .into_iter()
+                .map(|range| std::str::from_utf8(&text[range]).unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            urls(text),
+            [
+                "https://example.com/a?b=1#c",
+                "http://x.org/wiki/Foo_(bar)",
+                "file:///tmp/log",
+                "https://quoted.net",
+            ]
+        );
+
+        // Ends at the newline, and at invalid characters
+        assert_eq!(urls(b"http://a.b/c\nd"), ["http://a.b/c"]);
+        assert_eq!(urls("http://a.b/é".as_bytes()), ["http://a.b/"]);
+        assert_eq!(urls(b"<http://a.b>"), ["http://a.b"]);
+        assert!(urls(b"no links here").is_empty());
+    }
+}
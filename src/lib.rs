@@ -0,0 +1,12 @@
+//! A terminal emulator with an egui frontend
+//!
+//! [`TerminalEmulator`] can also be driven without a shell or a window, see
+//! [`TerminalEmulator::with_io`] and [`TerminalEmulator::feed`], or run on a background thread
+//! that reports changes over a channel, see [`terminal_emulator::TerminalHandle`]
+
+#[macro_use]
+pub mod log;
+pub mod gui;
+pub mod terminal_emulator;
+
+pub use terminal_emulator::TerminalEmulator;
@@ -0,0 +1,271 @@
+//! Thin logging shim around the `log` crate.
+//!
+//! Modules rely on `debug!`/`info!`/`warn!`/`error!` being in scope through `#[macro_use]` on this
+//! module, so the macros below simply forward to the `log` crate.
+//!
+//! What gets logged is controlled with `RUST_LOG` or `--log-level`, using a subset of the
+//! env_logger syntax: a comma separated list of `level`, `target` or `target=level` directives,
+//! e.g. `RUST_LOG=info,terminaux::terminal_emulator::ansi=debug`. Output goes to stderr unless
+//! `--log-file` is given.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+
+use ::log::{LevelFilter, Log, Metadata, Record};
+
+macro_rules! debug {
+    ($($arg:tt)+) => (::log::debug!($($arg)+))
+}
+
+macro_rules! info {
+    ($($arg:tt)+) => (::log::info!($($arg)+))
+}
+
+macro_rules! warn {
+    ($($arg:tt)+) => (::log::warn!($($arg)+))
+}
+
+macro_rules! error {
+    ($($arg:tt)+) => (::log::error!($($arg)+))
+}
+
+const DEFAULT_LEVEL: LevelFilter = LevelFilter::Warn;
+
+#[derive(Debug, Eq, PartialEq)]
+struct Filter {
+    /// None applies to every target not matched by a more specific filter
+    target: Option<String>,
+    level: LevelFilter,
+}
+
+fn parse_filters(spec: &str) -> Result<Vec<Filter>, String> {
+    let mut filters = Vec::new();
+    for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+        let filter = match directive.split_once('=') {
+            Some((target, level)) => Filter {
+                target: Some(target.to_string()),
+                level: level
+                    .parse()
+                    .map_err(|_| format!("Invalid log level \"{level}\" for {target}"))?,
+            },
+            None => match directive.parse() {
+                Ok(level) => Filter {
+                    target: None,
+                    level,
+                },
+                // A bare target enables everything for it, same as env_logger
+                Err(_) => Filter {
+                    target: Some(directive.to_string()),
+                    level: LevelFilter::Trace,
+                },
+            },
+        };
+        filters.push(filter);
+    }
+    Ok(filters)
+}
+
+fn target_matches(filter_target: &str, target: &str) -> bool {
+    target == filter_target
+        || target
+            .strip_prefix(filter_target)
+            .is_some_and(|rest| rest.starts_with("::"))
+}
+
+/// Logging setup from `RUST_LOG` and the command line
+pub struct LogConfig {
+    filters: Vec<Filter>,
+    file: Option<PathBuf>,
+}
+
+impl LogConfig {
+    /// `--log-level <filters>` takes precedence over `rust_log`. Arguments that are not about
+    /// logging are ignored
+    pub fn from_env_and_args(
+        rust_log: Option<String>,
+        args: impl IntoIterator<Item = String>,
+    ) -> Result<LogConfig, String> {
+        let mut spec = rust_log;
+        let mut file = None;
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let (flag, value) = match arg.split_once('=') {
+                Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+                None => (arg, None),
+            };
+
+            match flag.as_str() {
+                "--log-level" | "--log-file" => {
+                    let value = value
+                        .or_else(|| args.next())
+                        .ok_or_else(|| format!("{flag} needs a value"))?;
+                    if flag == "--log-level" {
+                        spec = Some(value);
+                    } else {
+                        file = Some(PathBuf::from(value));
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        let filters = match spec {
+            Some(spec) => parse_filters(&spec)?,
+            None => Vec::new(),
+        };
+
+        Ok(LogConfig { filters, file })
+    }
+
+    fn level_for(&self, target: &str) -> LevelFilter {
+        let specific = self
+            .filters
+            .iter()
+            .filter_map(|f| Some((f.target.as_deref()?, f.level)))
+            .filter(|(filter_target, _)| target_matches(filter_target, target))
+            .max_by_key(|(filter_target, _)| filter_target.len())
+            .map(|(_, level)| level);
+
+        let global = self
+            .filters
+            .iter()
+            .rev()
+            .find(|f| f.target.is_none())
+            .map(|f| f.level);
+
+        specific.or(global).unwrap_or(DEFAULT_LEVEL)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.filters
+            .iter()
+            .map(|f| f.level)
+            .chain(std::iter::once(self.level_for("")))
+            .max()
+            .unwrap_or(DEFAULT_LEVEL)
+    }
+}
+
+struct Logger {
+    config: LogConfig,
+    file: Option<Mutex<File>>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.config.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let line = format!("[{}] {}: {}", record.level(), record.target(), record.args());
+        match &self.file {
+            Some(file) => {
+                if let Ok(mut file) = file.lock() {
+                    let _ = writeln!(file, "{line}");
+                }
+            }
+            None => eprintln!("{line}"),
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = &self.file {
+            if let Ok(mut file) = file.lock() {
+                let _ = file.flush();
+            }
+        }
+    }
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+pub fn init(config: LogConfig) {
+    let file = config.file.as_ref().and_then(|path| {
+        match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => Some(Mutex::new(file)),
+            Err(e) => {
+                eprintln!("Failed to open log file {}, logging to stderr: {e}", path.display());
+                None
+            }
+        }
+    });
+
+    let max_level = config.max_level();
+    let logger = LOGGER.get_or_init(|| Logger { config, file });
+    if ::log::set_logger(logger).is_ok() {
+        ::log::set_max_level(max_level);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse_config(rust_log: Option<&str>, args: &[&str]) -> Result<LogConfig, String> {
+        LogConfig::from_env_and_args(
+            rust_log.map(str::to_string),
+            args.iter().map(|s| s.to_string()),
+        )
+    }
+
+    #[test]
+    fn test_parse_filters() {
+        assert_eq!(
+            parse_filters("info, terminaux::gui=debug,terminaux::terminal_emulator").unwrap(),
+            [
+                Filter {
+                    target: None,
+                    level: LevelFilter::Info
+                },
+                Filter {
+                    target: Some("terminaux::gui".into()),
+                    level: LevelFilter::Debug
+                },
+                Filter {
+                    target: Some("terminaux::terminal_emulator".into()),
+                    level: LevelFilter::Trace
+                },
+            ]
+        );
+        assert!(parse_filters("terminaux=loud").is_err());
+    }
+
+    #[test]
+    fn test_level_for() {
+        let config = parse_config(Some("error,terminaux::terminal_emulator=debug"), &[]).unwrap();
+        assert_eq!(config.level_for("terminaux::gui"), LevelFilter::Error);
+        assert_eq!(
+            config.level_for("terminaux::terminal_emulator::ansi"),
+            LevelFilter::Debug
+        );
+        // Prefixes only match whole path segments
+        assert_eq!(
+            config.level_for("terminaux::terminal_emulator_extra"),
+            LevelFilter::Error
+        );
+        assert_eq!(config.max_level(), LevelFilter::Debug);
+
+        let config = parse_config(None, &[]).unwrap();
+        assert_eq!(config.level_for("terminaux::gui"), LevelFilter::Warn);
+    }
+
+    #[test]
+    fn test_args_override_env() {
+        let config =
+            parse_config(Some("error"), &["--log-level", "info", "--log-file=trace.log"]).unwrap();
+        assert_eq!(config.level_for("terminaux"), LevelFilter::Info);
+        assert_eq!(config.file, Some(PathBuf::from("trace.log")));
+
+        let config = parse_config(Some("error"), &["--log-level=debug"]).unwrap();
+        assert_eq!(config.level_for("terminaux"), LevelFilter::Debug);
+
+        assert!(parse_config(None, &["--log-level"]).is_err());
+    }
+}
@@ -1,14 +1,26 @@
-use crate::terminal_emulator::TerminalEmulator;
-
-#[macro_use]
-mod log;
-mod gui;
-mod terminal_emulator;
+use terminaux::terminal_emulator::tty::Options;
+use terminaux::{gui, log};
 
 fn main() {
-    log::init();
-    let terminal_emulator = TerminalEmulator::new();
-    gui::run(terminal_emulator);
+    let log_config = match log::LogConfig::from_env_and_args(
+        std::env::var("RUST_LOG").ok(),
+        std::env::args().skip(1),
+    ) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(2);
+        }
+    };
+    log::init(log_config);
+    let gui_config = match gui::GuiConfig::from_args(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(2);
+        }
+    };
+    gui::run(Options::default(), gui_config);
 }
 
 
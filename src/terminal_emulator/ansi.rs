@@ -1,5 +1,11 @@
 
-use super::Mode;
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStringExt;
+use std::path::PathBuf;
+
+use super::base64;
+use super::sixel::{self, TerminalImage};
+use super::{AnsiMode, DecPrivateMode, Mode};
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum SelectGraphicRendition {
     // NOTE: Non-exhaustive list
@@ -16,6 +22,8 @@ pub enum SelectGraphicRendition {
     NotItalic,      // 23
     NotUnderline,   // 24
     NormalIntensity,// 22
+    NotBlinking,    // 25
+    NotReverse,     // 27
     ForegroundDefault,
     ForegroundBlack,
     ForegroundRed,
@@ -72,6 +80,8 @@ impl SelectGraphicRendition {
             22 => SelectGraphicRendition::NormalIntensity,
             23 => SelectGraphicRendition::NotItalic,
             24 => SelectGraphicRendition::NotUnderline,
+            25 => SelectGraphicRendition::NotBlinking,
+            27 => SelectGraphicRendition::NotReverse,
             28 => SelectGraphicRendition::Reveal,
             30 => SelectGraphicRendition::ForegroundBlack,
             31 => SelectGraphicRendition::ForegroundRed,
@@ -90,14 +100,12 @@ impl SelectGraphicRendition {
                                 return SelectGraphicRendition::Foreground8Bit(n as u8);
                             }
                         }
-                        Some(2) => {
+                        Some(2) if params.len() >= 4 => {
                             // True color: \x1b[38;2;<r>;<g>;<b>m
-                            if params.len() >= 4 {
-                                let r = params[1].unwrap_or(0) as u8;
-                                let g = params[2].unwrap_or(0) as u8;
-                                let b = params[3].unwrap_or(0) as u8;
-                                return SelectGraphicRendition::ForegroundTrueColor(r, g, b);
-                            }
+                            let r = params[1].unwrap_or(0) as u8;
+                            let g = params[2].unwrap_or(0) as u8;
+                            let b = params[3].unwrap_or(0) as u8;
+                            return SelectGraphicRendition::ForegroundTrueColor(r, g, b);
                         }
                         _ => {}
                     }
@@ -105,6 +113,7 @@ impl SelectGraphicRendition {
                 // Fallback if parameters are invalid
                 SelectGraphicRendition::Unknown(val)
             }
+            39 => SelectGraphicRendition::ForegroundDefault,
             40 => SelectGraphicRendition::BackgroundBlack,
             41 => SelectGraphicRendition::BackgroundRed,
             42 => SelectGraphicRendition::BackgroundGreen,
@@ -122,19 +131,18 @@ impl SelectGraphicRendition {
                                 return SelectGraphicRendition::Background8Bit(n as u8);
                             }
                         }
-                        Some(2) => {
-                            if params.len() >= 4 {
-                                let r = params[1].unwrap_or(0) as u8;
-                                let g = params[2].unwrap_or(0) as u8;
-                                let b = params[3].unwrap_or(0) as u8;
-                                return SelectGraphicRendition::BackgroundTrueColor(r, g, b);
-                            }
+                        Some(2) if params.len() >= 4 => {
+                            let r = params[1].unwrap_or(0) as u8;
+                            let g = params[2].unwrap_or(0) as u8;
+                            let b = params[3].unwrap_or(0) as u8;
+                            return SelectGraphicRendition::BackgroundTrueColor(r, g, b);
                         }
                         _ => {}
                     }
                 }
                 SelectGraphicRendition::Unknown(val)
             }
+            49 => SelectGraphicRendition::BackgroundDefault,
             90 => SelectGraphicRendition::ForegroundBrightBlack,
             91 => SelectGraphicRendition::ForegroundBrightRed,
             92 => SelectGraphicRendition::ForegroundBrightGreen,
@@ -159,48 +167,304 @@ impl SelectGraphicRendition {
 }
 
 
+/// Which clipboard an OSC 52 sequence is about
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ClipboardSelection {
+    Clipboard,
+    Primary,
+}
+
+/// Colors outside the palette that OSC 10, 11 and 12 change
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DynamicColor {
+    Foreground,
+    Background,
+    Cursor,
+}
+
+impl DynamicColor {
+    /// OSC 10 sets the foreground, and each spec after it the next color in order
+    fn from_osc_code(code: usize) -> Option<DynamicColor> {
+        match code {
+            10 => Some(DynamicColor::Foreground),
+            11 => Some(DynamicColor::Background),
+            12 => Some(DynamicColor::Cursor),
+            _ => None,
+        }
+    }
+}
+
+/// Window manipulation (XTWINOPS) requests that are answered, the rest are ignored
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum WindowReport {
+    // 11
+    State,
+    // 13
+    Position,
+    // 14
+    TextAreaPixels,
+    // 18
+    TextAreaChars,
+    // 19
+    ScreenChars,
+}
+
+/// Cursor shapes DECSCUSR picks from
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CursorShape {
+    Block,
+    Underline,
+    Bar,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CursorStyle {
+    pub shape: CursorShape,
+    pub blink: bool,
+}
+
+impl CursorStyle {
+    /// DECSCUSR's parameter, 0 asks for the terminal's own default which is None
+    fn from_decscusr(param: usize) -> Result<Option<CursorStyle>, ()> {
+        let (shape, blink) = match param {
+            0 => return Ok(None),
+            1 => (CursorShape::Block, true),
+            2 => (CursorShape::Block, false),
+            3 => (CursorShape::Underline, true),
+            4 => (CursorShape::Underline, false),
+            5 => (CursorShape::Bar, true),
+            6 => (CursorShape::Bar, false),
+            _ => return Err(()),
+        };
+        Ok(Some(CursorStyle { shape, blink }))
+    }
+}
+
+/// Shell integration boundaries, from OSC 133 `A` to `D`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PromptMarkKind {
+    PromptStart,
+    CommandStart,
+    OutputStart,
+    /// With the exit status, if the shell sent one
+    CommandEnd(Option<i32>),
+}
+
 #[derive(Debug, Eq, PartialEq)]
-pub enum TerminalOutput {
+pub enum TerminalOutput<'a> {
     SetCursorPos { x: Option<usize>, y: Option<usize> },
     ClearForwards,
-    SetCursorVisibility(bool),
     CarriageReturn,
     Backspace,
     Newline,
+    /// FF, a line feed in xterm rather than a new page
+    FormFeed,
+    /// VT, a line feed in xterm rather than a move to the next vertical tab stop
+    VerticalTab,
     ClearAll,
     Sgr(SelectGraphicRendition),
     Data(Vec<u8>),
+    /// Text borrowed from the parser input, see [`AnsiParser::push_with`]
+    DataBorrowed(&'a [u8]),
     Invalid,
     SetMode(Mode),
     ResetMode(Mode),
+    // DECRQM, asking whether a mode is set
+    RequestMode(Mode),
     Delete(usize),
     ClearLineForwards,
+    /// Erase from the start of the screen to the cursor, inclusive
+    ClearBackwards,
+    /// Erase from the start of the line to the cursor, inclusive
+    ClearLineBackwards,
     // ich (8.3.64 of ecma-48)
     InsertSpaces(usize),
-    //SetCursorVisibility(bool),
-    EnterAltScreen,
-    ExitAltScreen,
+    // ech (8.3.38 of ecma-48)
+    EraseChars(usize),
     CursorUp(usize),
     CursorDown(usize),
     CursorForward(usize),
     CursorBackward(usize),
+    // HT / CHT and CBT, move over this many tab stops
+    CursorForwardTab(usize),
+    CursorBackwardTab(usize),
+    // CSI t
+    WindowReport(WindowReport),
+    // HTS
+    SetTabStop,
+    // TBC, at the cursor column or everywhere
+    ClearTabStop,
+    ClearAllTabStops,
+    // DECSC / DECRC
+    SaveCursor,
+    RestoreCursor,
+    // DECSTBM, 1 based like SetCursorPos
+    SetScrollRegion { top: Option<usize>, bottom: Option<usize> },
+    // DECSLRM, 1 based. Only while left/right margin mode is on, otherwise the same CSI s saves
+    // the cursor like ESC 7
+    SetLeftRightMargins { left: Option<usize>, right: Option<usize> },
+    // DECSTR
+    SoftReset,
+    // DECSCUSR, None goes back to the GUI's default
+    SetCursorStyle(Option<CursorStyle>),
+    // RIS
+    FullReset,
+    // DECALN
+    AlignmentTest,
+    // BEL
+    Bell,
+    // DECKPAM / DECKPNM
+    SetKeypadApplicationMode(bool),
+    /// Sixel graphics
+    Image(TerminalImage),
+    // OSC 52
+    SetClipboard {
+        selection: ClipboardSelection,
+        data: Vec<u8>,
+    },
+    QueryClipboard(ClipboardSelection),
+    // OSC 4 / OSC 104, None resets the whole palette
+    SetPaletteColor { index: u8, rgb: (u8, u8, u8) },
+    ResetPaletteColor(Option<u8>),
+    // OSC 10-12 / OSC 110-112
+    SetDynamicColor { color: DynamicColor, rgb: (u8, u8, u8) },
+    ResetDynamicColor(DynamicColor),
+    // OSC 7
+    SetWorkingDirectory(PathBuf),
+    // OSC 0 and 2
+    SetTitle(String),
+    // CSI 22 t and CSI 23 t, saving and restoring the title
+    PushTitle,
+    PopTitle,
+    // OSC 133
+    PromptMark(PromptMarkKind),
 }
 
-fn mode_from_params(params: &[u8]) -> Mode {
-    match params {
-        b"?1" => Mode::Decckm,
-        _ => Mode::Unknown(params.to_vec()),
+impl TerminalOutput<'_> {
+    /// Detach from the parser input by copying any borrowed text
+    pub fn into_owned(self) -> TerminalOutput<'static> {
+        match self {
+            TerminalOutput::DataBorrowed(data) => TerminalOutput::Data(data.to_vec()),
+            TerminalOutput::Data(data) => TerminalOutput::Data(data),
+            TerminalOutput::SetCursorPos { x, y } => TerminalOutput::SetCursorPos { x, y },
+            TerminalOutput::ClearForwards => TerminalOutput::ClearForwards,
+            TerminalOutput::CarriageReturn => TerminalOutput::CarriageReturn,
+            TerminalOutput::Backspace => TerminalOutput::Backspace,
+            TerminalOutput::Newline => TerminalOutput::Newline,
+            TerminalOutput::FormFeed => TerminalOutput::FormFeed,
+            TerminalOutput::VerticalTab => TerminalOutput::VerticalTab,
+            TerminalOutput::ClearAll => TerminalOutput::ClearAll,
+            TerminalOutput::Sgr(sgr) => TerminalOutput::Sgr(sgr),
+            TerminalOutput::Invalid => TerminalOutput::Invalid,
+            TerminalOutput::SetMode(mode) => TerminalOutput::SetMode(mode),
+            TerminalOutput::ResetMode(mode) => TerminalOutput::ResetMode(mode),
+            TerminalOutput::RequestMode(mode) => TerminalOutput::RequestMode(mode),
+            TerminalOutput::Delete(n) => TerminalOutput::Delete(n),
+            TerminalOutput::ClearLineForwards => TerminalOutput::ClearLineForwards,
+            TerminalOutput::ClearBackwards => TerminalOutput::ClearBackwards,
+            TerminalOutput::ClearLineBackwards => TerminalOutput::ClearLineBackwards,
+            TerminalOutput::InsertSpaces(n) => TerminalOutput::InsertSpaces(n),
+            TerminalOutput::EraseChars(n) => TerminalOutput::EraseChars(n),
+            TerminalOutput::CursorUp(n) => TerminalOutput::CursorUp(n),
+            TerminalOutput::CursorDown(n) => TerminalOutput::CursorDown(n),
+            TerminalOutput::CursorForward(n) => TerminalOutput::CursorForward(n),
+            TerminalOutput::CursorBackward(n) => TerminalOutput::CursorBackward(n),
+            TerminalOutput::CursorForwardTab(n) => TerminalOutput::CursorForwardTab(n),
+            TerminalOutput::CursorBackwardTab(n) => TerminalOutput::CursorBackwardTab(n),
+            TerminalOutput::WindowReport(report) => TerminalOutput::WindowReport(report),
+            TerminalOutput::SetTabStop => TerminalOutput::SetTabStop,
+            TerminalOutput::ClearTabStop => TerminalOutput::ClearTabStop,
+            TerminalOutput::ClearAllTabStops => TerminalOutput::ClearAllTabStops,
+            TerminalOutput::SaveCursor => TerminalOutput::SaveCursor,
+            TerminalOutput::RestoreCursor => TerminalOutput::RestoreCursor,
+            TerminalOutput::SetScrollRegion { top, bottom } => {
+                TerminalOutput::SetScrollRegion { top, bottom }
+            }
+            TerminalOutput::SetLeftRightMargins { left, right } => {
+                TerminalOutput::SetLeftRightMargins { left, right }
+            }
+            TerminalOutput::SoftReset => TerminalOutput::SoftReset,
+            TerminalOutput::SetCursorStyle(style) => TerminalOutput::SetCursorStyle(style),
+            TerminalOutput::FullReset => TerminalOutput::FullReset,
+            TerminalOutput::AlignmentTest => TerminalOutput::AlignmentTest,
+            TerminalOutput::Bell => TerminalOutput::Bell,
+            TerminalOutput::SetKeypadApplicationMode(enabled) => {
+                TerminalOutput::SetKeypadApplicationMode(enabled)
+            }
+            TerminalOutput::Image(image) => TerminalOutput::Image(image),
+            TerminalOutput::SetClipboard { selection, data } => {
+                TerminalOutput::SetClipboard { selection, data }
+            }
+            TerminalOutput::QueryClipboard(selection) => TerminalOutput::QueryClipboard(selection),
+            TerminalOutput::SetPaletteColor { index, rgb } => {
+                TerminalOutput::SetPaletteColor { index, rgb }
+            }
+            TerminalOutput::ResetPaletteColor(index) => TerminalOutput::ResetPaletteColor(index),
+            TerminalOutput::SetDynamicColor { color, rgb } => {
+                TerminalOutput::SetDynamicColor { color, rgb }
+            }
+            TerminalOutput::ResetDynamicColor(color) => TerminalOutput::ResetDynamicColor(color),
+            TerminalOutput::SetWorkingDirectory(path) => TerminalOutput::SetWorkingDirectory(path),
+            TerminalOutput::SetTitle(title) => TerminalOutput::SetTitle(title),
+            TerminalOutput::PushTitle => TerminalOutput::PushTitle,
+            TerminalOutput::PopTitle => TerminalOutput::PopTitle,
+            TerminalOutput::PromptMark(kind) => TerminalOutput::PromptMark(kind),
+        }
     }
 }
 
+/// The modes a CSI h or l sequence sets or resets, e.g. `?1;2004` is DECCKM and bracketed paste.
+/// The `?` of DEC private modes is only written once, in front of them all. Parameters that are
+/// not numbers are errors of their own, leaving the others be
+fn modes_from_params(params: &[u8]) -> Vec<Result<Mode, ()>> {
+    let (private, params) = match params.strip_prefix(b"?") {
+        Some(params) => (true, params),
+        None => (false, params),
+    };
+    params
+        .split(|b| *b == b';')
+        .take(MAX_PARAMS)
+        .map(|param| {
+            let number = parse_param_as_usize(param)?.unwrap_or(0);
+            Ok(if private {
+                Mode::Dec(DecPrivateMode::from_number(number))
+            } else {
+                Mode::Ansi(AnsiMode::from_number(number))
+            })
+        })
+        .collect()
+}
+
 enum CsiParserState {
     Params,
     Intermediates,
-    Finished(u8),
+}
+
+/// A CSI sequence up to and including its final byte
+struct CsiSequence {
+    params: Vec<u8>,
+    intermediates: Vec<u8>,
+    final_byte: u8,
+}
+
+/// What [`CsiParser::push`] made of a byte. The parser is only handed back while the sequence
+/// goes on, so nothing can be pushed to one that finished
+enum CsiPush {
+    Incomplete(CsiParser),
+    Finished(CsiSequence),
     Invalid,
-    InvalidFinished,
 }
 
+/// Numeric parameters are clamped to this, far beyond any screen size, so that a single sequence
+/// cannot have the emulator loop or allocate for ages
+const MAX_PARAM: usize = 65535;
+
+/// Parameters after this many are ignored
+const MAX_PARAMS: usize = 32;
+
+/// Parameter and intermediate bytes kept of a sequence, the rest are dropped. Enough for
+/// [`MAX_PARAMS`] parameters up to [`MAX_PARAM`]
+const MAX_CSI_LEN: usize = 256;
 
 fn is_csi_terminator(b: u8) -> bool {
     (0x40..=0x7d).contains(&b)
@@ -221,18 +485,24 @@ fn extract_param(idx: usize, params: &[Option<usize>]) -> Option<usize> {
 fn split_params_into_semicolon_delimited_usize(params: &[u8]) -> Result<Vec<Option<usize>>, ()> {
     let params = params
         .split(|b| *b == b';')
+        .take(MAX_PARAMS)
         .map(parse_param_as_usize)
         .collect::<Result<Vec<Option<usize>>, ()>>();
 
     params
 }
 
+/// A parameter's value, clamped to [`MAX_PARAM`]
 fn parse_param_as_usize(param_bytes: &[u8]) -> Result<Option<usize>, ()> {
-    let param_str = std::str::from_utf8(param_bytes).expect("valid utf8");
-    if param_str.is_empty() {
+    if param_bytes.is_empty() {
         return Ok(None);
     }
-    let param = param_str.parse().map_err(|_| ())?; // Valid use of `?`
+    if !param_bytes.iter().all(u8::is_ascii_digit) {
+        return Err(());
+    }
+    let param = param_bytes
+        .iter()
+        .fold(0, |param, b| (param * 10 + usize::from(b - b'0')).min(MAX_PARAM));
     Ok(Some(param))
 }
 
@@ -252,47 +522,30 @@ impl CsiParser {
     }
 
 
-    fn push(&mut self, b: u8) {
-        if let CsiParserState::Finished(_) | CsiParserState::InvalidFinished = &self.state {
-            panic!("CsiParser should not be pushed to once finished");
-        }
-
-        match &mut self.state {
-            CsiParserState::Params => {
-                if is_csi_param(b) {
+    fn push(mut self, b: u8) -> CsiPush {
+        match self.state {
+            CsiParserState::Params if is_csi_param(b) => {
+                if self.params.len() < MAX_CSI_LEN {
                     self.params.push(b);
-                } else if is_csi_intermediate(b) {
-                    self.intermediates.push(b);
-                    self.state = CsiParserState::Intermediates;
-                } else if is_csi_terminator(b) {
-                    self.state = CsiParserState::Finished(b);
-
-                } else {
-                    self.state = CsiParserState::Invalid
                 }
             }
-            CsiParserState::Intermediates => {
-                if is_csi_param(b) {
-                    self.state = CsiParserState::Invalid;
-                } else if is_csi_intermediate(b) {
+            CsiParserState::Params | CsiParserState::Intermediates if is_csi_intermediate(b) => {
+                if self.intermediates.len() < MAX_CSI_LEN {
                     self.intermediates.push(b);
-                } else if is_csi_terminator(b) {
-                    self.state = CsiParserState::Finished(b);
-                } else {
-                    self.state = CsiParserState::Invalid
                 }
+                self.state = CsiParserState::Intermediates;
             }
-
-            CsiParserState::Invalid => {
-                if is_csi_terminator(b) {
-                    self.state = CsiParserState::InvalidFinished;
-                }
-
-            }
-            CsiParserState::Finished(_) | CsiParserState::InvalidFinished => {
-                unreachable!();
+            CsiParserState::Params | CsiParserState::Intermediates if is_csi_terminator(b) => {
+                return CsiPush::Finished(CsiSequence {
+                    params: self.params,
+                    intermediates: self.intermediates,
+                    final_byte: b,
+                });
             }
+            // Parameters after intermediates, and anything that is not part of a CSI sequence
+            _ => return CsiPush::Invalid,
         }
+        CsiPush::Incomplete(self)
     }
 }
 
@@ -300,64 +553,545 @@ enum AnsiParserInner {
     Empty,
     Escape,
     Csi(CsiParser),
+    // ESC ( or ESC ), waiting for the charset to designate to G0 or G1
+    Charset(u8),
+    // ESC #, waiting for the line attribute or alignment test selector
+    Hash,
+    // Device control string, collected until the string terminator, ESC \ or 8-bit 0x9c
+    Dcs(Vec<u8>),
+    // Operating system command, collected until BEL or the string terminator
+    Osc(Vec<u8>),
+}
+
+/// DCS and OSC payloads beyond this are dropped
+const MAX_STRING_LEN: usize = 16 * 1024 * 1024;
+
+/// OSC 52, `Pc;Pd` where Pc names the selections and Pd is base64 data or `?` to query
+/// https://invisible-island.net/xterm/ctlseqs/ctlseqs.html#h3-Operating-System-Commands
+fn parse_osc_52(params: &[u8]) -> TerminalOutput<'static> {
+    let (selections, payload) = match params.iter().position(|b| *b == b';') {
+        Some(pos) => (&params[..pos], &params[pos + 1..]),
+        None => (&b""[..], params),
+    };
+
+    // Cut buffers and the secondary selection are treated as the clipboard
+    let selection = if selections.contains(&b'p') && !selections.contains(&b'c') {
+        ClipboardSelection::Primary
+    } else {
+        ClipboardSelection::Clipboard
+    };
+
+    if payload == b"?" {
+        return TerminalOutput::QueryClipboard(selection);
+    }
+
+    match base64::decode(payload) {
+        Some(data) => TerminalOutput::SetClipboard { selection, data },
+        None => {
+            warn!("Invalid base64 in OSC 52");
+            TerminalOutput::Invalid
+        }
+    }
+}
+
+/// Parse an X11 color spec, `rgb:R/G/B` with 1 to 4 hex digits per component or `#RGB` with
+/// 1 to 4 digits per component. `rgb:` components are scaled, `#` ones keep their high bits
+fn parse_color_spec(spec: &[u8]) -> Option<(u8, u8, u8)> {
+    let hex = |digits: &[u8]| -> Option<u32> {
+        if digits.is_empty() || digits.len() > 4 {
+            return None;
+        }
+        u32::from_str_radix(std::str::from_utf8(digits).ok()?, 16).ok()
+    };
+
+    if let Some(components) = spec.strip_prefix(b"rgb:") {
+        let mut values = components.split(|b| *b == b'/').map(|digits| {
+            let max = (1u32 << (4 * digits.len())) - 1;
+            hex(digits).map(|v| ((v * 255 + max / 2) / max) as u8)
+        });
+        let rgb = (values.next()??, values.next()??, values.next()??);
+        return values.next().is_none().then_some(rgb);
+    }
+
+    let digits = spec.strip_prefix(b"#")?;
+    if digits.is_empty() || digits.len() % 3 != 0 {
+        return None;
+    }
+    let n = digits.len() / 3;
+    let component = |i: usize| -> Option<u8> {
+        let v = hex(&digits[i * n..(i + 1) * n])?;
+        Some(match n {
+            1 => v << 4,
+            2 => v,
+            3 => v >> 4,
+            _ => v >> 8,
+        } as u8)
+    };
+    Some((component(0)?, component(1)?, component(2)?))
+}
+
+/// OSC 4, `c;spec` pairs setting palette entries
+fn parse_osc_4(params: &[u8]) -> Vec<TerminalOutput<'static>> {
+    let mut output = Vec::new();
+    let mut params = params.split(|b| *b == b';');
+    while let (Some(index), Some(spec)) = (params.next(), params.next()) {
+        let index = std::str::from_utf8(index).ok().and_then(|i| i.parse::<u8>().ok());
+        match (index, parse_color_spec(spec)) {
+            (Some(index), Some(rgb)) => output.push(TerminalOutput::SetPaletteColor { index, rgb }),
+            // Queries are not answered
+            (Some(_), None) if spec == b"?" => (),
+            _ => warn!("Invalid OSC 4 color {:?}", String::from_utf8_lossy(spec)),
+        }
+    }
+    output
+}
+
+/// OSC 104, a list of palette entries to reset or nothing for all of them
+fn parse_osc_104(params: &[u8]) -> Vec<TerminalOutput<'static>> {
+    if params.is_empty() {
+        return vec![TerminalOutput::ResetPaletteColor(None)];
+    }
+
+    params
+        .split(|b| *b == b';')
+        .filter_map(|index| {
+            let index = std::str::from_utf8(index).ok()?.parse::<u8>().ok();
+            if index.is_none() {
+                warn!("Invalid OSC 104 index");
+            }
+            Some(TerminalOutput::ResetPaletteColor(Some(index?)))
+        })
+        .collect()
+}
+
+/// OSC 10, 11 and 12. Each spec sets the color after the previous one, so OSC 10 can set the
+/// foreground, background and cursor colors at once
+fn parse_osc_dynamic_colors(code: usize, params: &[u8]) -> Vec<TerminalOutput<'static>> {
+    let mut output = Vec::new();
+    for (code, spec) in (code..).zip(params.split(|b| *b == b';')) {
+        let Some(color) = DynamicColor::from_osc_code(code) else {
+            break;
+        };
+        match parse_color_spec(spec) {
+            Some(rgb) => output.push(TerminalOutput::SetDynamicColor { color, rgb }),
+            None if spec == b"?" => (),
+            None => warn!("Invalid OSC {code} color {:?}", String::from_utf8_lossy(spec)),
+        }
+    }
+    output
+}
+
+/// Decode `%XX` escapes, anything that is not a valid escape is kept as is
+fn percent_decode(data: &[u8]) -> Vec<u8> {
+    let hex_value = |b: u8| (b as char).to_digit(16);
+    let mut out = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        let escaped = match data[i..] {
+            [b'%', hi, lo, ..] => hex_value(hi).zip(hex_value(lo)).map(|(hi, lo)| hi * 16 + lo),
+            _ => None,
+        };
+        match escaped {
+            Some(b) => {
+                out.push(b as u8);
+                i += 3;
+            }
+            None => {
+                out.push(data[i]);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+/// OSC 7, `file://host/path` with the path percent encoded. The host is ignored, it is there
+/// for terminals that can tell a remote shell from a local one
+fn parse_osc_7(params: &[u8]) -> TerminalOutput<'static> {
+    let path = params
+        .strip_prefix(b"file://")
+        .and_then(|rest| Some(&rest[rest.iter().position(|b| *b == b'/')?..]));
+
+    match path {
+        Some(path) => {
+            let path = OsString::from_vec(percent_decode(path));
+            TerminalOutput::SetWorkingDirectory(PathBuf::from(path))
+        }
+        None => {
+            warn!("Invalid OSC 7 url {:?}", String::from_utf8_lossy(params));
+            TerminalOutput::Invalid
+        }
+    }
+}
+
+/// OSC 133, `A` to `D` followed by options. `D` has the exit status as its first option
+/// https://gitlab.freedesktop.org/Per_Bothner/specifications/blob/master/proposals/semantic-prompts.md
+fn parse_osc_133(params: &[u8]) -> TerminalOutput<'static> {
+    let mut params = params.split(|b| *b == b';');
+    let kind = match params.next().unwrap_or_default() {
+        b"A" => PromptMarkKind::PromptStart,
+        b"B" => PromptMarkKind::CommandStart,
+        b"C" => PromptMarkKind::OutputStart,
+        b"D" => {
+            let status = params
+                .next()
+                .and_then(|status| std::str::from_utf8(status).ok()?.parse().ok());
+            PromptMarkKind::CommandEnd(status)
+        }
+        kind => {
+            warn!("Unhandled OSC 133 {:?}", String::from_utf8_lossy(kind));
+            return TerminalOutput::Invalid;
+        }
+    };
+    TerminalOutput::PromptMark(kind)
+}
+
+/// Handle a finished operating system command, `data` is everything between ESC ] and the
+/// terminator
+fn parse_osc(data: &[u8]) -> Vec<TerminalOutput<'static>> {
+    let (code, params) = match data.iter().position(|b| *b == b';') {
+        Some(pos) => (&data[..pos], &data[pos + 1..]),
+        None => (data, &b""[..]),
+    };
+
+    match code {
+        b"0" | b"2" => vec![TerminalOutput::SetTitle(String::from_utf8_lossy(params).into_owned())],
+        // There is no icon to name
+        b"1" => Vec::new(),
+        b"4" => parse_osc_4(params),
+        b"7" => vec![parse_osc_7(params)],
+        b"10" | b"11" | b"12" => parse_osc_dynamic_colors(10 + (code[1] - b'0') as usize, params),
+        b"52" => vec![parse_osc_52(params)],
+        b"104" => parse_osc_104(params),
+        b"110" => vec![TerminalOutput::ResetDynamicColor(DynamicColor::Foreground)],
+        b"111" => vec![TerminalOutput::ResetDynamicColor(DynamicColor::Background)],
+        b"112" => vec![TerminalOutput::ResetDynamicColor(DynamicColor::Cursor)],
+        b"133" => vec![parse_osc_133(params)],
+        _ => {
+            warn!("Unhandled OSC {:?}", String::from_utf8_lossy(code));
+            Vec::new()
+        }
+    }
+}
+
+/// Handle a finished device control string, `data` is everything between ESC P and ST
+fn parse_dcs(data: &[u8]) -> Option<TerminalOutput<'static>> {
+    let Some(final_pos) = data.iter().position(|b| (0x40..=0x7e).contains(b)) else {
+        warn!("Device control string without a final byte");
+        return None;
+    };
+
+    let params = &data[..final_pos];
+    match data[final_pos] {
+        b'q' => Some(TerminalOutput::Image(sixel::decode(params, &data[final_pos + 1..]))),
+        b => {
+            warn!("Unhandled device control string {:?}", b as char);
+            None
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Charset {
+    Ascii,
+    // https://vt100.net/docs/vt100-ug/table3-9.html
+    DecSpecialGraphics,
+}
+
+impl Charset {
+    fn from_designator(b: u8) -> Charset {
+        match b {
+            b'0' => Charset::DecSpecialGraphics,
+            b'B' => Charset::Ascii,
+            _ => {
+                warn!("Unsupported charset {:?}, using ascii", b as char);
+                Charset::Ascii
+            }
+        }
+    }
+}
+
+/// G0/G1 designations and which of them is invoked with SO/SI
+#[derive(Clone, Copy)]
+struct CharsetState {
+    g0: Charset,
+    g1: Charset,
+    shift_out: bool,
+}
+
+impl Default for CharsetState {
+    fn default() -> CharsetState {
+        CharsetState {
+            g0: Charset::Ascii,
+            g1: Charset::Ascii,
+            shift_out: false,
+        }
+    }
+}
+
+impl CharsetState {
+    fn active(&self) -> Charset {
+        if self.shift_out {
+            self.g1
+        } else {
+            self.g0
+        }
+    }
+}
+
+fn dec_special_graphics(b: u8) -> Option<char> {
+    let c = match b {
+        b'`' => '◆',
+        b'a' => '▒',
+        b'b' => '␉',
+        b'c' => '␌',
+        b'd' => '␍',
+        b'e' => '␊',
+        b'f' => '°',
+        b'g' => '±',
+        b'h' => '␤',
+        b'i' => '␋',
+        b'j' => '┘',
+        b'k' => '┐',
+        b'l' => '┌',
+        b'm' => '└',
+        b'n' => '┼',
+        b'o' => '⎺',
+        b'p' => '⎻',
+        b'q' => '─',
+        b'r' => '⎼',
+        b's' => '⎽',
+        b't' => '├',
+        b'u' => '┤',
+        b'v' => '┴',
+        b'w' => '┬',
+        b'x' => '│',
+        b'y' => '≤',
+        b'z' => '≥',
+        b'{' => 'π',
+        b'|' => '≠',
+        b'}' => '£',
+        b'~' => '·',
+        _ => return None,
+    };
+    Some(c)
 }
 
 pub struct AnsiParser {
     inner: AnsiParserInner,
+    charsets: CharsetState,
+    saved_charsets: CharsetState,
+    /// UTF-8 continuation bytes still to come for the character being read, which may be split
+    /// across pushes. Until they have all come, bytes 0x80 to 0x9f are text and not C1 controls
+    utf8_continuations: u8,
+    /// DECLRMM, which makes CSI s set the left and right margins instead of saving the cursor.
+    /// The emulator has it too, it is followed here to save the charsets along with the cursor
+    left_right_margin_mode: bool,
+}
+/// Track where `b` is in a UTF-8 character, `continuations` being the bytes the current one still
+/// needs. True if `b` continues a character, which makes it text even in the C1 range
+fn continues_utf8(continuations: &mut u8, b: u8) -> bool {
+    if *continuations > 0 && (0x80..=0xbf).contains(&b) {
+        *continuations -= 1;
+        return true;
+    }
+    *continuations = match b {
+        0xc2..=0xdf => 1,
+        0xe0..=0xef => 2,
+        0xf0..=0xf4 => 3,
+        _ => 0,
+    };
+    false
 }
-fn push_data_if_non_empty(data: &mut Vec<u8>, output: &mut Vec<TerminalOutput>) {
-    if !data.is_empty() {
-        output.push(TerminalOutput::Data(std::mem::take(data)));
+
+/// Emit the pending run of plain text, which spans from `data_start` up to `end` of the input
+///
+/// Text is borrowed unless it has to be translated into another charset
+fn push_data_if_non_empty<'a, F: FnMut(TerminalOutput<'a>)>(
+    incoming: &'a [u8],
+    data_start: &mut Option<usize>,
+    end: usize,
+    charset: Charset,
+    emit: &mut F,
+) {
+    let Some(start) = data_start.take() else {
+        return;
+    };
+
+    let data = &incoming[start..end];
+    match charset {
+        Charset::Ascii => emit(TerminalOutput::DataBorrowed(data)),
+        Charset::DecSpecialGraphics => {
+            let mut translated = Vec::with_capacity(data.len());
+            for b in data {
+                match dec_special_graphics(*b) {
+                    Some(c) => translated.extend(c.encode_utf8(&mut [0; 4]).as_bytes()),
+                    None => translated.push(*b),
+                }
+            }
+            emit(TerminalOutput::Data(translated));
+        }
     }
 }
 impl AnsiParser {
     pub fn new() -> AnsiParser {
         AnsiParser {
             inner: AnsiParserInner::Empty,
+            charsets: CharsetState::default(),
+            saved_charsets: CharsetState::default(),
+            utf8_continuations: 0,
+            left_right_margin_mode: false,
         }
     }
 
-    pub fn push(&mut self, incoming: &[u8]) -> Vec<TerminalOutput> {
+    /// Parse `incoming`, collecting everything that was recognized
+    ///
+    /// Text is copied out of `incoming`, use [`Self::push_with`] to avoid that
+    pub fn push(&mut self, incoming: &[u8]) -> Vec<TerminalOutput<'static>> {
         let mut output = Vec::new();
-        let mut data_output = Vec::new();
-        for b in incoming {
+        self.push_with(incoming, |segment| output.push(segment.into_owned()));
+        output
+    }
+
+    /// Parse `incoming`, handing each output to `emit` as soon as it is recognized
+    ///
+    /// Incomplete sequences at the end of `incoming` are kept and finished by the next call. Plain
+    /// text is handed out as [`TerminalOutput::DataBorrowed`] slices of `incoming`
+    pub fn push_with<'a, F: FnMut(TerminalOutput<'a>)>(&mut self, incoming: &'a [u8], mut emit: F) {
+        let mut data_start = None;
+        for (i, b) in incoming.iter().enumerate() {
             match &mut self.inner {
                 AnsiParserInner::Empty => {
+                    if continues_utf8(&mut self.utf8_continuations, *b) {
+                        data_start.get_or_insert(i);
+                        continue;
+                    }
+
+                    // 8-bit C1 controls, the rest are dropped instead of being shown as text
+                    if (0x80..=0x9f).contains(b) {
+                        push_data_if_non_empty(incoming, &mut data_start, i, self.charsets.active(), &mut emit);
+                        match b {
+                            0x9b => self.inner = AnsiParserInner::Csi(CsiParser::new()),
+                            0x9d => self.inner = AnsiParserInner::Osc(Vec::new()),
+                            0x90 => self.inner = AnsiParserInner::Dcs(Vec::new()),
+                            0x88 => emit(TerminalOutput::SetTabStop),
+                            // NEL
+                            0x85 => {
+                                emit(TerminalOutput::CarriageReturn);
+                                emit(TerminalOutput::Newline);
+                            }
+                            _ => debug!("Ignoring C1 control {b:#04x}"),
+                        }
+                        continue;
+                    }
+
                     if *b == b'\x1b' {
+                        push_data_if_non_empty(incoming, &mut data_start, i, self.charsets.active(), &mut emit);
                         self.inner = AnsiParserInner::Escape;
                         continue;
                     }
                     if *b == b'\r' {
-                        push_data_if_non_empty(&mut data_output, &mut output);
-                        output.push(TerminalOutput::CarriageReturn);
+                        push_data_if_non_empty(incoming, &mut data_start, i, self.charsets.active(), &mut emit);
+                        emit(TerminalOutput::CarriageReturn);
                         continue;
                     }
 
                     if *b == b'\n' {
-                        push_data_if_non_empty(&mut data_output, &mut output);
-                        output.push(TerminalOutput::Newline);
+                        push_data_if_non_empty(incoming, &mut data_start, i, self.charsets.active(), &mut emit);
+                        emit(TerminalOutput::Newline);
+                        continue;
+                    }
+                    if *b == 0x07 {
+                        push_data_if_non_empty(incoming, &mut data_start, i, self.charsets.active(), &mut emit);
+                        emit(TerminalOutput::Bell);
+                        continue;
+                    }
+                    if *b == b'\t' {
+                        push_data_if_non_empty(incoming, &mut data_start, i, self.charsets.active(), &mut emit);
+                        emit(TerminalOutput::CursorForwardTab(1));
+                        continue;
+                    }
+                    // SO/SI
+                    if *b == 0x0e || *b == 0x0f {
+                        push_data_if_non_empty(incoming, &mut data_start, i, self.charsets.active(), &mut emit);
+                        self.charsets.shift_out = *b == 0x0e;
                         continue;
                     }
-                    // print the contents of the buffer
-                   // println!("Data: {:?}", data_output);
                     // Explicitly check for Backspace (0x08) and DEL (0x7f)
                     if *b == 0x08 || *b == 0x7f {
-                        push_data_if_non_empty(&mut data_output, &mut output);
-                        output.push(TerminalOutput::Backspace);
+                        push_data_if_non_empty(incoming, &mut data_start, i, self.charsets.active(), &mut emit);
+                        emit(TerminalOutput::Backspace);
+                        continue;
+                    }
+                    // The rest of C0 never makes it into the data, where it would take up a
+                    // column. Those without a meaning of their own, like NUL, are dropped
+                    if *b < 0x20 {
+                        push_data_if_non_empty(incoming, &mut data_start, i, self.charsets.active(), &mut emit);
+                        match b {
+                            0x0b => emit(TerminalOutput::VerticalTab),
+                            0x0c => emit(TerminalOutput::FormFeed),
+                            _ => debug!("Ignoring control character {b:#04x}"),
+                        }
                         continue;
                     }
 
-                    data_output.push(*b);
+                    data_start.get_or_insert(i);
                 }
                 AnsiParserInner::Escape => {
-                    if !data_output.is_empty() {
-                        output.push(TerminalOutput::Data(std::mem::take(&mut data_output)));
-                    }
-
                     match b {
+                        // Like in the states below, an ESC abandons the sequence and starts over
+                        b'\x1b' => (),
                         b'[' => {
                             self.inner = AnsiParserInner::Csi(CsiParser::new());
                         }
+                        b'(' | b')' => {
+                            self.inner = AnsiParserInner::Charset(*b);
+                        }
+                        b'#' => {
+                            self.inner = AnsiParserInner::Hash;
+                        }
+                        // Charsets are part of the saved cursor, but are applied here
+                        b'7' => {
+                            self.saved_charsets = self.charsets;
+                            emit(TerminalOutput::SaveCursor);
+                            self.inner = AnsiParserInner::Empty;
+                        }
+                        b'8' => {
+                            self.charsets = self.saved_charsets;
+                            emit(TerminalOutput::RestoreCursor);
+                            self.inner = AnsiParserInner::Empty;
+                        }
+                        b'P' => {
+                            self.inner = AnsiParserInner::Dcs(Vec::new());
+                        }
+                        b']' => {
+                            self.inner = AnsiParserInner::Osc(Vec::new());
+                        }
+                        // ST, the string it terminates was already handled at the ESC
+                        b'\\' => {
+                            self.inner = AnsiParserInner::Empty;
+                        }
+                        b'=' | b'>' => {
+                            emit(TerminalOutput::SetKeypadApplicationMode(*b == b'='));
+                            self.inner = AnsiParserInner::Empty;
+                        }
+                        b'H' => {
+                            emit(TerminalOutput::SetTabStop);
+                            self.inner = AnsiParserInner::Empty;
+                        }
+                        // NEL, a carriage return and line feed in one
+                        b'E' => {
+                            emit(TerminalOutput::CarriageReturn);
+                            emit(TerminalOutput::Newline);
+                            self.inner = AnsiParserInner::Empty;
+                        }
+                        b'c' => {
+                            self.charsets = CharsetState::default();
+                            self.saved_charsets = CharsetState::default();
+                            self.left_right_margin_mode = false;
+                            emit(TerminalOutput::FullReset);
+                            self.inner = AnsiParserInner::Empty;
+                        }
                         _ => {
                             let b_utf8 = std::char::from_u32(*b as u32);
                             warn!("Unhandled escape sequence {b_utf8:?} {b:x}");
@@ -365,116 +1099,188 @@ impl AnsiParser {
                         }
                     }
                 }
+                AnsiParserInner::Osc(data) => {
+                    // 0x9c is the 8-bit ST, unless it is part of a character
+                    let st = !continues_utf8(&mut self.utf8_continuations, *b) && *b == 0x9c;
+                    if *b == 0x07 || *b == b'\x1b' || st {
+                        for output in parse_osc(data) {
+                            emit(output);
+                        }
+                        self.inner = if *b == b'\x1b' {
+                            AnsiParserInner::Escape
+                        } else {
+                            AnsiParserInner::Empty
+                        };
+                        self.utf8_continuations = 0;
+                    } else if data.len() < MAX_STRING_LEN {
+                        data.push(*b);
+                    }
+                }
+                AnsiParserInner::Dcs(data) => {
+                    let st = !continues_utf8(&mut self.utf8_continuations, *b) && *b == 0x9c;
+                    if *b == b'\x1b' || st {
+                        if let Some(output) = parse_dcs(data) {
+                            emit(output);
+                        }
+                        self.inner = if st {
+                            AnsiParserInner::Empty
+                        } else {
+                            AnsiParserInner::Escape
+                        };
+                        self.utf8_continuations = 0;
+                    } else if data.len() < MAX_STRING_LEN {
+                        data.push(*b);
+                    }
+                }
+                AnsiParserInner::Charset(_) | AnsiParserInner::Hash | AnsiParserInner::Csi(_)
+                    if *b == b'\x1b' =>
+                {
+                    // A truncated sequence, what follows is a new one rather than more of it
+                    warn!("Escape sequence interrupted by ESC");
+                    emit(TerminalOutput::Invalid);
+                    self.inner = AnsiParserInner::Escape;
+                }
+                AnsiParserInner::Charset(g) => {
+                    let charset = Charset::from_designator(*b);
+                    if *g == b'(' {
+                        self.charsets.g0 = charset;
+                    } else {
+                        self.charsets.g1 = charset;
+                    }
+                    self.inner = AnsiParserInner::Empty;
+                }
+                AnsiParserInner::Hash => {
+                    if *b == b'8' {
+                        emit(TerminalOutput::AlignmentTest);
+                    } else {
+                        // Double width and height lines are not supported
+                        warn!("Unhandled ESC # {:?}", *b as char);
+                        emit(TerminalOutput::Invalid);
+                    }
+                    self.inner = AnsiParserInner::Empty;
+                }
                 AnsiParserInner::Csi(parser) => {
-                    parser.push(*b);
-                    match parser.state {
-                        CsiParserState::Finished(b'H') => {
+                    let parser = std::mem::replace(parser, CsiParser::new());
+                    let csi = match parser.push(*b) {
+                        CsiPush::Incomplete(parser) => {
+                            self.inner = AnsiParserInner::Csi(parser);
+                            continue;
+                        }
+                        CsiPush::Invalid => {
+                            warn!("Invalid CSI sequence");
+                            emit(TerminalOutput::Invalid);
+                            self.inner = AnsiParserInner::Empty;
+                            continue;
+                        }
+                        CsiPush::Finished(csi) => csi,
+                    };
+                    self.inner = AnsiParserInner::Empty;
+
+                    match csi.final_byte {
+                        // CUP and HVP, both are row;column
+                        b'H' | b'f' => {
                             let params =
-                                split_params_into_semicolon_delimited_usize(&parser.params);
+                                split_params_into_semicolon_delimited_usize(&csi.params);
 
                             let Ok(params) = params else {
                                 warn!("Invalid cursor set position sequence");
-                                output.push(TerminalOutput::Invalid);
-                                self.inner = AnsiParserInner::Empty;
+                                emit(TerminalOutput::Invalid);
                                 continue;
                             };
 
-                            output.push(TerminalOutput::SetCursorPos {
-                                x: Some(extract_param(0, &params).unwrap_or(1)),
-                                y: Some(extract_param(1, &params).unwrap_or(1)),
+                            emit(TerminalOutput::SetCursorPos {
+                                x: Some(extract_param(1, &params).unwrap_or(1)),
+                                y: Some(extract_param(0, &params).unwrap_or(1)),
                             });
-                            self.inner = AnsiParserInner::Empty;
                         }
-                        CsiParserState::Finished(b'K') => {
-                            let Ok(param) = parse_param_as_usize(&parser.params) else {
+                        b'K' => {
+                            let Ok(param) = parse_param_as_usize(&csi.params) else {
                                 warn!("Invalid erase in line command");
-                                output.push(TerminalOutput::Invalid);
-                                self.inner = AnsiParserInner::Empty;
+                                emit(TerminalOutput::Invalid);
                                 continue;
                             };
 
                             // ECMA-48 8.3.39
                             match param.unwrap_or(0) {
-                                0 => output.push(TerminalOutput::ClearLineForwards),
+                                0 => emit(TerminalOutput::ClearLineForwards),
+                                1 => emit(TerminalOutput::ClearLineBackwards),
                                 v => {
                                     warn!("Unsupported erase in line command ({v})");
-                                    output.push(TerminalOutput::Invalid);
+                                    emit(TerminalOutput::Invalid);
                                 }
                             }
-
-                            self.inner = AnsiParserInner::Empty;
                         }
 
-                        CsiParserState::Finished(b'G') => {
-                            let Ok(param) = parse_param_as_usize(&parser.params) else {
+                        b'G' => {
+                            let Ok(param) = parse_param_as_usize(&csi.params) else {
                                 warn!("Invalid cursor set position sequence");
-                                output.push(TerminalOutput::Invalid);
-                                self.inner = AnsiParserInner::Empty;
+                                emit(TerminalOutput::Invalid);
                                 continue;
                             };
 
                             let x_pos = param.unwrap_or(1);
 
-                            output.push(TerminalOutput::SetCursorPos {
+                            emit(TerminalOutput::SetCursorPos {
                                 x: Some(x_pos),
                                 y: None,
                             });
-                            self.inner = AnsiParserInner::Empty;
                         }
-                        CsiParserState::Finished(b'J') => {
-                            let Ok(param) = parse_param_as_usize(&parser.params) else {
+                        b'J' => {
+                            let Ok(param) = parse_param_as_usize(&csi.params) else {
                                 warn!("Invalid clear command");
-                                output.push(TerminalOutput::Invalid);
-                                self.inner = AnsiParserInner::Empty;
+                                emit(TerminalOutput::Invalid);
                                 continue;
                             };
 
                             let ret = match param.unwrap_or(0) {
                                 0 => TerminalOutput::ClearForwards,
-                             //   1 => TerminalOutput::ClearBackwards,
+                                1 => TerminalOutput::ClearBackwards,
                                 2 | 3 => TerminalOutput::ClearAll,
                                 _ => TerminalOutput::Invalid,
                             };
-                            output.push(ret);
-                            self.inner = AnsiParserInner::Empty;
-                        }
-                        CsiParserState::Finished(b'h') => {
-                            if parser.params == b"?1049" {
-                                output.push(TerminalOutput::EnterAltScreen);
-                            }else {
-                            output.push(TerminalOutput::SetMode(mode_from_params(&parser.params)));}
-                            self.inner = AnsiParserInner::Empty;
+                            emit(ret);
                         }
-                        CsiParserState::Finished(b'l') => {
-                            if parser.params == b"?1049" {
-                                output.push(TerminalOutput::ExitAltScreen);
-                            }else {
-                            output
-                                .push(TerminalOutput::ResetMode(mode_from_params(&parser.params)));}
-                            self.inner = AnsiParserInner::Empty;
+                        b'h' | b'l' => {
+                            for mode in modes_from_params(&csi.params) {
+                                match mode {
+                                    Ok(mode) => {
+                                        let enabled = csi.final_byte == b'h';
+                                        if mode == Mode::Dec(DecPrivateMode::Declrmm) {
+                                            self.left_right_margin_mode = enabled;
+                                        }
+                                        emit(if enabled {
+                                            TerminalOutput::SetMode(mode)
+                                        } else {
+                                            TerminalOutput::ResetMode(mode)
+                                        });
+                                    }
+                                    Err(()) => {
+                                        warn!("Invalid mode in {:?}", csi.params);
+                                        emit(TerminalOutput::Invalid);
+                                    }
+                                }
+                            }
                         }
-                        CsiParserState::Finished(b'P') => {
-                            let Ok(param) = parse_param_as_usize(&parser.params) else {
-                                println!("Invalid del command");
-                                output.push(TerminalOutput::Invalid);
-                                self.inner = AnsiParserInner::Empty;
+                        b'P' => {
+                            let Ok(param) = parse_param_as_usize(&csi.params) else {
+                                warn!("Invalid del command");
+                                emit(TerminalOutput::Invalid);
                                 continue;
                             };
 
-                            output.push(TerminalOutput::Delete(param.unwrap_or(1)));
-
-                            self.inner = AnsiParserInner::Empty;
+                            emit(TerminalOutput::Delete(param.unwrap_or(1)));
                         }
-                        CsiParserState::Finished(b'm') => {
-                            let params = match split_params_into_semicolon_delimited_usize(&parser.params) {
+                        b'm' => {
+                            let params = match split_params_into_semicolon_delimited_usize(&csi.params) {
                                 Ok(p) => p,
                                 Err(_) => {
-                                    output.push(TerminalOutput::Invalid);
-                                    self.inner = AnsiParserInner::Empty;
+                                    emit(TerminalOutput::Invalid);
                                     continue;
                                 }
                             };
 
+                            // Like xterm, every empty param is a 0, so `CSI m` is one reset,
+                            // `CSI ; m` is two and `CSI 31 ; m` ends in a reset
                             let mut i = 0;
                             while i < params.len() {
                                 let code = params[i].unwrap_or(0);
@@ -541,138 +1347,222 @@ impl AnsiParser {
                                         SelectGraphicRendition::from_usize(code, &params)
                                     }
                                 };
-                                output.push(TerminalOutput::Sgr(sgr));
+                                emit(TerminalOutput::Sgr(sgr));
                                 i += 1;
                             }
-                            self.inner = AnsiParserInner::Empty;
                         }
 
-                        CsiParserState::Finished(b'A') => {
-                            let Ok(param) = parse_param_as_usize(&parser.params) else {
+                        b'A' => {
+                            let Ok(param) = parse_param_as_usize(&csi.params) else {
                                 warn!("Invalid cursor up sequence");
-                                output.push(TerminalOutput::Invalid);
-                                self.inner = AnsiParserInner::Empty;
+                                emit(TerminalOutput::Invalid);
                                 continue;
                             };
-                            let lines = param.unwrap_or(1);
-                            output.push(TerminalOutput::CursorUp(lines));
-                            self.inner = AnsiParserInner::Empty;
+                            let lines = param.unwrap_or(1).max(1);
+                            emit(TerminalOutput::CursorUp(lines));
                         }
-                        CsiParserState::Finished(b'C') => {
+                        b'C' => {
                             // Cursor Forward
-                            let Ok(param) = parse_param_as_usize(&parser.params) else {
+                            let Ok(param) = parse_param_as_usize(&csi.params) else {
                                 warn!("Invalid cursor forward sequence");
-                                output.push(TerminalOutput::Invalid);
-                                self.inner = AnsiParserInner::Empty;
+                                emit(TerminalOutput::Invalid);
                                 continue;
                             };
-                            let columns = param.unwrap_or(1);
-                            output.push(TerminalOutput::CursorForward(columns));
-                            self.inner = AnsiParserInner::Empty;
+                            let columns = param.unwrap_or(1).max(1);
+                            emit(TerminalOutput::CursorForward(columns));
+                        }
+                        final_byte @ (b'I' | b'Z') => {
+                            // Cursor Forward Tabulation / Cursor Backward Tabulation
+                            let Ok(param) = parse_param_as_usize(&csi.params) else {
+                                warn!("Invalid cursor tabulation sequence");
+                                emit(TerminalOutput::Invalid);
+                                continue;
+                            };
+                            let stops = param.unwrap_or(1);
+                            if final_byte == b'I' {
+                                emit(TerminalOutput::CursorForwardTab(stops));
+                            } else {
+                                emit(TerminalOutput::CursorBackwardTab(stops));
+                            }
                         }
-                        CsiParserState::Finished(b'h') => {
-                            // Handle Set Mode
-                            // Implement set mode logic here
-                            if parser.params == b"?25" {
-                                output.push(TerminalOutput::SetCursorVisibility(true));
+                        b't' if csi.intermediates.is_empty() => {
+                            // Window manipulation, only the reports and the title stack are
+                            // supported. Moving, resizing and raising the window is left to the
+                            // user
+                            let params =
+                                split_params_into_semicolon_delimited_usize(&csi.params)
+                                    .unwrap_or_default();
+                            let op = extract_param(0, &params);
+                            // 22 and 23 take the icon name alone with 1, which there is none of
+                            let icon_only = extract_param(1, &params) == Some(1);
+                            let output = match op {
+                                Some(11) => Some(TerminalOutput::WindowReport(WindowReport::State)),
+                                Some(13) => Some(TerminalOutput::WindowReport(WindowReport::Position)),
+                                Some(14) => Some(TerminalOutput::WindowReport(WindowReport::TextAreaPixels)),
+                                Some(18) => Some(TerminalOutput::WindowReport(WindowReport::TextAreaChars)),
+                                Some(19) => Some(TerminalOutput::WindowReport(WindowReport::ScreenChars)),
+                                Some(22 | 23) if icon_only => continue,
+                                Some(22) => Some(TerminalOutput::PushTitle),
+                                Some(23) => Some(TerminalOutput::PopTitle),
+                                _ => None,
+                            };
+                            match output {
+                                Some(output) => emit(output),
+                                None => {
+                                    info!("Ignoring window manipulation {op:?}");
+                                    emit(TerminalOutput::Invalid);
+                                }
                             }
-
-                            self.inner = AnsiParserInner::Empty;
                         }
-                        CsiParserState::Finished(b'K') => {
-                            // Handle Erase in Line
-                            let Ok(param) = parse_param_as_usize(&parser.params) else {
-                                warn!("Invalid erase in line sequence");
-                                output.push(TerminalOutput::Invalid);
-                                self.inner = AnsiParserInner::Empty;
+                        b'g' => {
+                            // Tabulation Clear
+                            let Ok(param) = parse_param_as_usize(&csi.params) else {
+                                warn!("Invalid tabulation clear sequence");
+                                emit(TerminalOutput::Invalid);
                                 continue;
                             };
-
-                            let ret = match param.unwrap_or(0) {
-                                0 => TerminalOutput::ClearForwards,
-                                1 => TerminalOutput::Backspace,
-                                2 => TerminalOutput::ClearAll,
-                                _ => TerminalOutput::Invalid,
-                            };
-                            output.push(ret);
-                            self.inner = AnsiParserInner::Empty;
+                            match param.unwrap_or(0) {
+                                0 => emit(TerminalOutput::ClearTabStop),
+                                3 => emit(TerminalOutput::ClearAllTabStops),
+                                v => {
+                                    warn!("Unsupported tabulation clear ({v})");
+                                    emit(TerminalOutput::Invalid);
+                                }
+                            }
                         }
-                        CsiParserState::Finished(b'B') => {
+                        b'B' => {
                             // Cursor Down
-                            let Ok(param) = parse_param_as_usize(&parser.params) else {
+                            let Ok(param) = parse_param_as_usize(&csi.params) else {
                                 warn!("Invalid cursor down sequence");
-                                output.push(TerminalOutput::Invalid);
-                                self.inner = AnsiParserInner::Empty;
+                                emit(TerminalOutput::Invalid);
                                 continue;
                             };
-                            let lines = param.unwrap_or(1);
-                            output.push(TerminalOutput::CursorDown(lines));
-                            self.inner = AnsiParserInner::Empty;
+                            let lines = param.unwrap_or(1).max(1);
+                            emit(TerminalOutput::CursorDown(lines));
                         }
-                        CsiParserState::Finished(b'D') => {
+                        b'D' => {
                             // Cursor Backward
-                            let Ok(param) = parse_param_as_usize(&parser.params) else {
+                            let Ok(param) = parse_param_as_usize(&csi.params) else {
                                 warn!("Invalid cursor backward sequence");
-                                output.push(TerminalOutput::Invalid);
-                                self.inner = AnsiParserInner::Empty;
+                                emit(TerminalOutput::Invalid);
                                 continue;
                             };
-                            let columns = param.unwrap_or(1);
-                            output.push(TerminalOutput::CursorBackward(columns));
-                            self.inner = AnsiParserInner::Empty;
-                        }
-                        CsiParserState::Finished(b'l') => {
-                            if parser.params == b"?25" {
-                                output.push(TerminalOutput::SetCursorVisibility(false));
-                            }
-                            self.inner = AnsiParserInner::Empty;
-
-                            // Other CSI l handling...
+                            let columns = param.unwrap_or(1).max(1);
+                            emit(TerminalOutput::CursorBackward(columns));
                         }
-                        CsiParserState::Finished(b'@') => {
-                            let Ok(param) = parse_param_as_usize(&parser.params) else {
+                        b'@' => {
+                            let Ok(param) = parse_param_as_usize(&csi.params) else {
                                 warn!("Invalid ich command");
-                                output.push(TerminalOutput::Invalid);
-                                self.inner = AnsiParserInner::Empty;
+                                emit(TerminalOutput::Invalid);
                                 continue;
                             };
 
                             // ecma-48 8.3.64
-                            output.push(TerminalOutput::InsertSpaces(param.unwrap_or(1)));
-                            self.inner = AnsiParserInner::Empty;
+                            emit(TerminalOutput::InsertSpaces(param.unwrap_or(1)));
+                        }
+                        b'X' => {
+                            let Ok(param) = parse_param_as_usize(&csi.params) else {
+                                warn!("Invalid ech command");
+                                emit(TerminalOutput::Invalid);
+                                continue;
+                            };
+
+                            // ecma-48 8.3.38, 0 erases one character like 1
+                            emit(TerminalOutput::EraseChars(param.unwrap_or(1).max(1)));
+                        }
+                        b'r' if csi.intermediates.is_empty() => {
+                            let Ok(params) =
+                                split_params_into_semicolon_delimited_usize(&csi.params)
+                            else {
+                                warn!("Invalid scroll region sequence");
+                                emit(TerminalOutput::Invalid);
+                                continue;
+                            };
+
+                            emit(TerminalOutput::SetScrollRegion {
+                                top: extract_param(0, &params),
+                                bottom: extract_param(1, &params),
+                            });
+                        }
+                        b's' if csi.intermediates.is_empty() => {
+                            let Ok(params) =
+                                split_params_into_semicolon_delimited_usize(&csi.params)
+                            else {
+                                warn!("Invalid left/right margins sequence");
+                                emit(TerminalOutput::Invalid);
+                                continue;
+                            };
+
+                            if self.left_right_margin_mode {
+                                emit(TerminalOutput::SetLeftRightMargins {
+                                    left: extract_param(0, &params),
+                                    right: extract_param(1, &params),
+                                });
+                            } else {
+                                // SCOSC, which DECSLRM takes the place of while DECLRMM is set
+                                self.saved_charsets = self.charsets;
+                                emit(TerminalOutput::SaveCursor);
+                            }
+                        }
+                        // SCORC
+                        b'u' if csi.intermediates.is_empty() && csi.params.is_empty() => {
+                            self.charsets = self.saved_charsets;
+                            emit(TerminalOutput::RestoreCursor);
+                        }
+                        b'p' if csi.intermediates == b"$" => {
+                            // DECRQM takes a single mode, with or without the ?
+                            match modes_from_params(&csi.params).into_iter().next() {
+                                Some(Ok(mode)) => emit(TerminalOutput::RequestMode(mode)),
+                                _ => {
+                                    warn!("Invalid mode request {:?}", csi.params);
+                                    emit(TerminalOutput::Invalid);
+                                }
+                            }
+                        }
+                        b'p' if csi.intermediates == b"!" => {
+                            self.charsets = CharsetState::default();
+                            self.saved_charsets = CharsetState::default();
+                            emit(TerminalOutput::SoftReset);
+                        }
+                        b'q' if csi.intermediates == b" " => {
+                            let Ok(style) = parse_param_as_usize(&csi.params)
+                                .and_then(|param| CursorStyle::from_decscusr(param.unwrap_or(0)))
+                            else {
+                                warn!("Invalid cursor style sequence");
+                                emit(TerminalOutput::Invalid);
+                                continue;
+                            };
+
+                            emit(TerminalOutput::SetCursorStyle(style));
                         }
-                        CsiParserState::Finished(esc) => {
+                        esc => {
                             warn!(
         "Unhandled csi code: {:?} {esc:x} {}/{}",
                                 std::char::from_u32(esc as u32),
                                 esc >> 4,
                                 esc & 0xf,
                             );
-                            output.push(TerminalOutput::Invalid);
-                            self.inner = AnsiParserInner::Empty;
-                        }
-                        CsiParserState::Invalid => {
-                            warn!("Invalid CSI sequence");
-                            output.push(TerminalOutput::Invalid);
-                            self.inner = AnsiParserInner::Empty;
+                            emit(TerminalOutput::Invalid);
                         }
-                        _ => {}
                     }
                 }
             }
         }
 
-        if !data_output.is_empty() {
-            output.push(TerminalOutput::Data(data_output));
-        }
-
-        output
+        push_data_if_non_empty(
+            incoming,
+            &mut data_start,
+            incoming.len(),
+            self.charsets.active(),
+            &mut emit,
+        );
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::terminal_emulator::test::XorShift;
 
     #[test]
     fn test_set_cursor_position() {
@@ -682,8 +1572,8 @@ mod test {
         assert!(matches!(
             parsed[0],
             TerminalOutput::SetCursorPos {
-                x: Some(32),
-                y: Some(15)
+                x: Some(15),
+                y: Some(32)
             }
         ));
 
@@ -692,8 +1582,8 @@ mod test {
         assert!(matches!(
             parsed[0],
             TerminalOutput::SetCursorPos {
-                x: Some(1),
-                y: Some(32)
+                x: Some(32),
+                y: Some(1)
             }
         ));
 
@@ -702,8 +1592,8 @@ mod test {
         assert!(matches!(
             parsed[0],
             TerminalOutput::SetCursorPos {
-                x: Some(32),
-                y: Some(1)
+                x: Some(1),
+                y: Some(32)
             }
         ));
 
@@ -712,8 +1602,8 @@ mod test {
         assert!(matches!(
             parsed[0],
             TerminalOutput::SetCursorPos {
-                x: Some(32),
-                y: Some(1)
+                x: Some(1),
+                y: Some(32)
             }
         ));
 
@@ -736,6 +1626,16 @@ mod test {
                 y: Some(1)
             }
         ));
+
+        let parsed = output_buffer.push(b"\x1b[5;10f");
+        assert_eq!(parsed.len(), 1);
+        assert!(matches!(
+            parsed[0],
+            TerminalOutput::SetCursorPos {
+                x: Some(10),
+                y: Some(5)
+            }
+        ));
     }
 
     #[test]
@@ -750,7 +1650,10 @@ mod test {
         assert_eq!(parsed.len(), 1);
         assert!(matches!(parsed[0], TerminalOutput::ClearForwards,));
 
-
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"\x1b[1J");
+        assert_eq!(parsed.len(), 1);
+        assert!(matches!(parsed[0], TerminalOutput::ClearBackwards,));
 
         let mut output_buffer = AnsiParser::new();
         let parsed = output_buffer.push(b"\x1b[2J");
@@ -758,6 +1661,616 @@ mod test {
         assert!(matches!(parsed[0], TerminalOutput::ClearAll,));
     }
 
+    #[test]
+    fn test_clear_line() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"\x1b[K\x1b[0K\x1b[1K");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::ClearLineForwards,
+                TerminalOutput::ClearLineForwards,
+                TerminalOutput::ClearLineBackwards,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_erase_chars() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"\x1b[X\x1b[0X\x1b[5X");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::EraseChars(1),
+                TerminalOutput::EraseChars(1),
+                TerminalOutput::EraseChars(5),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cursor_moves_of_zero() {
+        // A count of 0 moves by one, like no count at all
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"\x1b[0A\x1b[0B\x1b[0C\x1b[0D");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::CursorUp(1),
+                TerminalOutput::CursorDown(1),
+                TerminalOutput::CursorForward(1),
+                TerminalOutput::CursorBackward(1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_scroll_region() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"\x1b[2;10r\x1b[r\x1b[;5r");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::SetScrollRegion {
+                    top: Some(2),
+                    bottom: Some(10)
+                },
+                TerminalOutput::SetScrollRegion {
+                    top: None,
+                    bottom: None
+                },
+                TerminalOutput::SetScrollRegion {
+                    top: None,
+                    bottom: Some(5)
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_left_right_margins() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"\x1b[?69h\x1b[5;40s\x1b[s\x1b[?1s\x1b[?69l\x1b[s\x1b[u");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::SetMode(Mode::Dec(DecPrivateMode::Declrmm)),
+                TerminalOutput::SetLeftRightMargins {
+                    left: Some(5),
+                    right: Some(40)
+                },
+                TerminalOutput::SetLeftRightMargins {
+                    left: None,
+                    right: None
+                },
+                TerminalOutput::Invalid,
+                TerminalOutput::ResetMode(Mode::Dec(DecPrivateMode::Declrmm)),
+                // SCOSC and SCORC without DECLRMM
+                TerminalOutput::SaveCursor,
+                TerminalOutput::RestoreCursor,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dec_special_graphics() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"\x1b(0lqk\x1b(B");
+        assert_eq!(parsed, &[TerminalOutput::Data("┌─┐".into())]);
+
+        // Back to ascii
+        let parsed = output_buffer.push(b"lqk");
+        assert_eq!(parsed, &[TerminalOutput::Data(b"lqk".into())]);
+
+        // G1 is only used while shifted out
+        let parsed = output_buffer.push(b"\x1b)0x\x0ex\x0fx");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::Data(b"x".into()),
+                TerminalOutput::Data("│".into()),
+                TerminalOutput::Data(b"x".into()),
+            ]
+        );
+
+        // Saved and restored along with the cursor, by SCOSC and SCORC too
+        for (save, restore) in [("\x1b7", "\x1b8"), ("\x1b[s", "\x1b[u")] {
+            let input = format!("\x1b(0{save}\x1b(Bj{restore}j\x1b(B");
+            let parsed = output_buffer.push(input.as_bytes());
+            assert_eq!(
+                parsed,
+                &[
+                    TerminalOutput::SaveCursor,
+                    TerminalOutput::Data(b"j".into()),
+                    TerminalOutput::RestoreCursor,
+                    TerminalOutput::Data("┘".into()),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"\x1b[!p\x1bc\x1b[p");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::SoftReset,
+                TerminalOutput::FullReset,
+                TerminalOutput::Invalid,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_c0_controls() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"a\x00b\x0cc");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::Data(b"a".into()),
+                TerminalOutput::Data(b"b".into()),
+                TerminalOutput::FormFeed,
+                TerminalOutput::Data(b"c".into()),
+            ]
+        );
+
+        let parsed = output_buffer.push(b"\x01\x0bx\x1f\x05");
+        assert_eq!(
+            parsed,
+            &[TerminalOutput::VerticalTab, TerminalOutput::Data(b"x".into())]
+        );
+
+        // Every control without a meaning of its own, none of them end up as data
+        let controls = (0u8..0x20)
+            .filter(|b| !b"\x07\x08\t\n\x0b\x0c\r\x0e\x0f\x1b".contains(b))
+            .collect::<Vec<_>>();
+        assert_eq!(output_buffer.push(&controls), &[]);
+    }
+
+    #[test]
+    fn test_cursor_style() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"\x1b[5 q\x1b[2 q\x1b[ q\x1b[7 q");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::SetCursorStyle(Some(CursorStyle {
+                    shape: CursorShape::Bar,
+                    blink: true,
+                })),
+                TerminalOutput::SetCursorStyle(Some(CursorStyle {
+                    shape: CursorShape::Block,
+                    blink: false,
+                })),
+                TerminalOutput::SetCursorStyle(None),
+                TerminalOutput::Invalid,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_alignment_test() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"\x1b#8a\x1b#3");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::AlignmentTest,
+                TerminalOutput::Data(b"a".into()),
+                TerminalOutput::Invalid,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_multiple_modes() {
+        let mut parser = AnsiParser::new();
+        assert_eq!(
+            parser.push(b"\x1b[?1;2004h"),
+            vec![
+                TerminalOutput::SetMode(Mode::Dec(DecPrivateMode::Decckm)),
+                TerminalOutput::SetMode(Mode::Dec(DecPrivateMode::BracketedPaste)),
+            ]
+        );
+
+        // Each mode is handled on its own, unknown and invalid ones included
+        assert_eq!(
+            parser.push(b"\x1b[?1049;9999;:;25;7l\x1b[4;20h"),
+            vec![
+                TerminalOutput::ResetMode(Mode::Dec(DecPrivateMode::AltScreen)),
+                TerminalOutput::ResetMode(Mode::Dec(DecPrivateMode::Unknown(9999))),
+                TerminalOutput::Invalid,
+                TerminalOutput::ResetMode(Mode::Dec(DecPrivateMode::Dectcem)),
+                TerminalOutput::ResetMode(Mode::Dec(DecPrivateMode::Decawm)),
+                TerminalOutput::SetMode(Mode::Ansi(AnsiMode::Irm)),
+                TerminalOutput::SetMode(Mode::Ansi(AnsiMode::Unknown(20))),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mode_numbers() {
+        let mut parser = AnsiParser::new();
+        let dec = [
+            (1, DecPrivateMode::Decckm),
+            (5, DecPrivateMode::Decscnm),
+            (6, DecPrivateMode::Decom),
+            (7, DecPrivateMode::Decawm),
+            (9, DecPrivateMode::MouseX10),
+            (12, DecPrivateMode::CursorBlink),
+            (25, DecPrivateMode::Dectcem),
+            (69, DecPrivateMode::Declrmm),
+            (1000, DecPrivateMode::MouseNormal),
+            (1002, DecPrivateMode::MouseButtonEvent),
+            (1003, DecPrivateMode::MouseAnyEvent),
+            (1004, DecPrivateMode::FocusEvents),
+            (1006, DecPrivateMode::MouseSgr),
+            (1049, DecPrivateMode::AltScreen),
+            (2004, DecPrivateMode::BracketedPaste),
+            (4, DecPrivateMode::Unknown(4)),
+        ];
+        for (number, mode) in dec {
+            assert_eq!(mode.number(), number);
+            assert_eq!(
+                parser.push(format!("\x1b[?{number}h").as_bytes()),
+                vec![TerminalOutput::SetMode(Mode::Dec(mode))]
+            );
+        }
+
+        // The same numbers without the ? are other modes
+        let ansi = [
+            (4, AnsiMode::Irm),
+            (1, AnsiMode::Unknown(1)),
+            (25, AnsiMode::Unknown(25)),
+        ];
+        for (number, mode) in ansi {
+            assert_eq!(mode.number(), number);
+            assert_eq!(
+                parser.push(format!("\x1b[{number}l").as_bytes()),
+                vec![TerminalOutput::ResetMode(Mode::Ansi(mode))]
+            );
+        }
+    }
+
+    #[test]
+    fn test_request_mode() {
+        let mut parser = AnsiParser::new();
+        assert_eq!(
+            parser.push(b"\x1b[?2004$p\x1b[4$p\x1b[?77$p"),
+            vec![
+                TerminalOutput::RequestMode(Mode::Dec(DecPrivateMode::BracketedPaste)),
+                TerminalOutput::RequestMode(Mode::Ansi(AnsiMode::Irm)),
+                TerminalOutput::RequestMode(Mode::Dec(DecPrivateMode::Unknown(77))),
+            ]
+        );
+        assert_eq!(parser.push(b"\x1b[?1:2$p"), vec![TerminalOutput::Invalid]);
+    }
+
+    #[test]
+    fn test_keypad_mode() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"\x1b=a\x1b>");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::SetKeypadApplicationMode(true),
+                TerminalOutput::Data(b"a".to_vec()),
+                TerminalOutput::SetKeypadApplicationMode(false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sixel() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"a\x1bP0;1;0q#1;2;100;0;0~~-@\x1b\\b");
+        assert_eq!(parsed.len(), 3);
+        assert_eq!(parsed[0], TerminalOutput::Data(b"a".to_vec()));
+        let TerminalOutput::Image(image) = &parsed[1] else {
+            panic!("Expected an image, got {:?}", parsed[1]);
+        };
+        assert_eq!((image.width, image.height), (2, 7));
+        assert_eq!(image.pixel(1, 0), [255, 0, 0, 255]);
+        assert_eq!(parsed[2], TerminalOutput::Data(b"b".to_vec()));
+
+        // Split across pushes
+        let mut output_buffer = AnsiParser::new();
+        assert!(output_buffer.push(b"\x1bPq~").is_empty());
+        let parsed = output_buffer.push(b"~\x1b\\");
+        assert!(matches!(&parsed[..], [TerminalOutput::Image(image)] if image.width == 2));
+    }
+
+    #[test]
+    fn test_osc_52() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(
+            b"\x1b]52;c;aGVsbG8=\x07\x1b]52;p;d29ybGQ=\x1b\\\x1b]52;c;?\x07\x1b]52;c;!!\x07",
+        );
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::SetClipboard {
+                    selection: ClipboardSelection::Clipboard,
+                    data: b"hello".to_vec(),
+                },
+                TerminalOutput::SetClipboard {
+                    selection: ClipboardSelection::Primary,
+                    data: b"world".to_vec(),
+                },
+                TerminalOutput::QueryClipboard(ClipboardSelection::Clipboard),
+                TerminalOutput::Invalid,
+            ]
+        );
+
+        // BEL ends the OSC instead of ringing the bell, and unknown commands are dropped
+        let parsed = output_buffer.push(b"\x1b]999;x\x07a");
+        assert_eq!(parsed, &[TerminalOutput::Data(b"a".to_vec())]);
+    }
+
+    #[test]
+    fn test_color_spec() {
+        assert_eq!(parse_color_spec(b"rgb:00/00/ff"), Some((0, 0, 255)));
+        assert_eq!(parse_color_spec(b"rgb:f/8/0"), Some((255, 136, 0)));
+        assert_eq!(parse_color_spec(b"rgb:ffff/8080/0000"), Some((255, 128, 0)));
+        assert_eq!(parse_color_spec(b"#0000ff"), Some((0, 0, 255)));
+        assert_eq!(parse_color_spec(b"#f80"), Some((240, 128, 0)));
+        assert_eq!(parse_color_spec(b"#ffff80800000"), Some((255, 128, 0)));
+
+        assert_eq!(parse_color_spec(b"rgb:00/00"), None);
+        assert_eq!(parse_color_spec(b"rgb:00/00/ff/00"), None);
+        assert_eq!(parse_color_spec(b"rgb:00/00/fffff"), None);
+        assert_eq!(parse_color_spec(b"#12345"), None);
+        assert_eq!(parse_color_spec(b"blue"), None);
+    }
+
+    #[test]
+    fn test_osc_colors() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(
+            b"\x1b]4;1;rgb:00/00/ff;200;#102030\x07\x1b]104;1;2\x07\x1b]104\x1b\\\x1b]4;1;?\x07",
+        );
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::SetPaletteColor {
+                    index: 1,
+                    rgb: (0, 0, 255),
+                },
+                TerminalOutput::SetPaletteColor {
+                    index: 200,
+                    rgb: (0x10, 0x20, 0x30),
+                },
+                TerminalOutput::ResetPaletteColor(Some(1)),
+                TerminalOutput::ResetPaletteColor(Some(2)),
+                TerminalOutput::ResetPaletteColor(None),
+            ]
+        );
+
+        // Extra specs after OSC 10 move on to the background and cursor colors
+        let parsed = output_buffer.push(b"\x1b]10;#ffffff;#000000\x07\x1b]12;rgb:0/f/0\x07\x1b]111\x07");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::SetDynamicColor {
+                    color: DynamicColor::Foreground,
+                    rgb: (255, 255, 255),
+                },
+                TerminalOutput::SetDynamicColor {
+                    color: DynamicColor::Background,
+                    rgb: (0, 0, 0),
+                },
+                TerminalOutput::SetDynamicColor {
+                    color: DynamicColor::Cursor,
+                    rgb: (0, 255, 0),
+                },
+                TerminalOutput::ResetDynamicColor(DynamicColor::Background),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_c1_controls() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"\x9b32;15H");
+        assert!(matches!(
+            &parsed[..],
+            [TerminalOutput::SetCursorPos {
+                x: Some(15),
+                y: Some(32)
+            }]
+        ));
+
+        assert_eq!(
+            output_buffer.push(b"a\x9d7;file://host/home\x07b\x88\x8fc"),
+            &[
+                TerminalOutput::Data(b"a".to_vec()),
+                TerminalOutput::SetWorkingDirectory(PathBuf::from("/home")),
+                TerminalOutput::Data(b"b".to_vec()),
+                TerminalOutput::SetTabStop,
+                TerminalOutput::Data(b"c".to_vec()),
+            ]
+        );
+
+        assert_eq!(
+            output_buffer.push(b"a\x85b\x1bEc"),
+            &[
+                TerminalOutput::Data(b"a".to_vec()),
+                TerminalOutput::CarriageReturn,
+                TerminalOutput::Newline,
+                TerminalOutput::Data(b"b".to_vec()),
+                TerminalOutput::CarriageReturn,
+                TerminalOutput::Newline,
+                TerminalOutput::Data(b"c".to_vec()),
+            ]
+        );
+
+        // 0x9b, 0x82 and 0x85 continue these characters instead of being controls, even when a
+        // character is split between pushes
+        let text = "ě€Å".as_bytes();
+        assert_eq!(output_buffer.push(text), &[TerminalOutput::Data(text.to_vec())]);
+        assert_eq!(output_buffer.push(b"\xc4"), &[TerminalOutput::Data(b"\xc4".to_vec())]);
+        assert_eq!(output_buffer.push(b"\x9b"), &[TerminalOutput::Data(b"\x9b".to_vec())]);
+
+        // Once the character is done they are controls again
+        let parsed = output_buffer.push(b"\xc4\x9b\x9b1;2H");
+        assert_eq!(parsed[0], TerminalOutput::Data("ě".as_bytes().to_vec()));
+        assert!(matches!(
+            parsed[1..],
+            [TerminalOutput::SetCursorPos {
+                x: Some(2),
+                y: Some(1)
+            }]
+        ));
+    }
+
+    #[test]
+    fn test_c1_string_terminator() {
+        let mut output_buffer = AnsiParser::new();
+        assert_eq!(
+            output_buffer.push(b"\x9d0;title\x9cvisible text"),
+            &[
+                TerminalOutput::SetTitle("title".to_string()),
+                TerminalOutput::Data(b"visible text".to_vec()),
+            ]
+        );
+
+        // The 0x9c ending the opening quote is part of the title, the one after it ends the string
+        let quoted = "\u{201c}quoted\u{201d}";
+        let mut input = b"\x9d2;".to_vec();
+        input.extend_from_slice(quoted.as_bytes());
+        input.extend_from_slice(b"\x9cafter");
+        assert_eq!(
+            output_buffer.push(&input),
+            &[
+                TerminalOutput::SetTitle(quoted.to_string()),
+                TerminalOutput::Data(b"after".to_vec()),
+            ]
+        );
+
+        // DCS ends the same way, whether or not it is understood
+        assert_eq!(
+            output_buffer.push(b"\x900;1|17/6162\x9cshown"),
+            &[TerminalOutput::Data(b"shown".to_vec())]
+        );
+    }
+
+    #[test]
+    fn test_titles() {
+        let mut output_buffer = AnsiParser::new();
+        assert_eq!(
+            output_buffer.push("\x1b]0;vim\x07\x1b]2;~/ü\x1b\\\x1b]1;icon\x07\x1b]2;\x07".as_bytes()),
+            &[
+                TerminalOutput::SetTitle("vim".to_string()),
+                TerminalOutput::SetTitle("~/ü".to_string()),
+                TerminalOutput::SetTitle(String::new()),
+            ]
+        );
+        assert_eq!(
+            output_buffer.push(b"\x1b[22;0t\x1b[22;2t\x1b[23t\x1b[22;1t\x1b[23;1t"),
+            &[
+                TerminalOutput::PushTitle,
+                TerminalOutput::PushTitle,
+                TerminalOutput::PopTitle,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_osc_7() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(
+            b"\x1b]7;file://host/home/me\x07\x1b]7;file:///tmp/a%20b%zz\x1b\\\x1b]7;/home\x07",
+        );
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::SetWorkingDirectory(PathBuf::from("/home/me")),
+                TerminalOutput::SetWorkingDirectory(PathBuf::from("/tmp/a b%zz")),
+                TerminalOutput::Invalid,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_osc_133() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(
+            b"\x1b]133;A;cl=m\x07$ \x1b]133;B\x07\x1b]133;C\x07\x1b]133;D;1\x07\x1b]133;D\x07\x1b]133;Z\x07",
+        );
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::PromptMark(PromptMarkKind::PromptStart),
+                TerminalOutput::Data(b"$ ".to_vec()),
+                TerminalOutput::PromptMark(PromptMarkKind::CommandStart),
+                TerminalOutput::PromptMark(PromptMarkKind::OutputStart),
+                TerminalOutput::PromptMark(PromptMarkKind::CommandEnd(Some(1))),
+                TerminalOutput::PromptMark(PromptMarkKind::CommandEnd(None)),
+                TerminalOutput::Invalid,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tab_sequences() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"a\tb\x1b[I\x1b[3I\x1b[Z\x1b[2Z\x1bH\x1b[g\x1b[0g\x1b[3g\x1b[1g");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::Data(b"a".to_vec()),
+                TerminalOutput::CursorForwardTab(1),
+                TerminalOutput::Data(b"b".to_vec()),
+                TerminalOutput::CursorForwardTab(1),
+                TerminalOutput::CursorForwardTab(3),
+                TerminalOutput::CursorBackwardTab(1),
+                TerminalOutput::CursorBackwardTab(2),
+                TerminalOutput::SetTabStop,
+                TerminalOutput::ClearTabStop,
+                TerminalOutput::ClearTabStop,
+                TerminalOutput::ClearAllTabStops,
+                TerminalOutput::Invalid,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_window_reports() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"\x1b[11t\x1b[13t\x1b[14t\x1b[18t\x1b[19t\x1b[2t\x1b[8;10;10t");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::WindowReport(WindowReport::State),
+                TerminalOutput::WindowReport(WindowReport::Position),
+                TerminalOutput::WindowReport(WindowReport::TextAreaPixels),
+                TerminalOutput::WindowReport(WindowReport::TextAreaChars),
+                TerminalOutput::WindowReport(WindowReport::ScreenChars),
+                TerminalOutput::Invalid,
+                TerminalOutput::Invalid,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bell() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"\x07ab\x07c");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::Bell,
+                TerminalOutput::Data(b"ab".to_vec()),
+                TerminalOutput::Bell,
+                TerminalOutput::Data(b"c".to_vec()),
+            ]
+        );
+    }
+
     #[test]
     fn test_invalid_clear() {
         let mut output_buffer = AnsiParser::new();
@@ -778,27 +2291,165 @@ mod test {
     }
 
     #[test]
-    fn test_parsing_unknown_csi() {
-        let mut parser = CsiParser::new();
-        for b in b"0123456789:;<=>?!\"#$%&'()*+,-./}" {
-            parser.push(*b);
+    fn test_esc_aborts_sequence() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"\x1b[31\x1b[32mX");
+        assert_eq!(
+            parsed,
+            [
+                TerminalOutput::Invalid,
+                TerminalOutput::Sgr(SelectGraphicRendition::ForegroundGreen),
+                TerminalOutput::Data(b"X".to_vec()),
+            ]
+        );
+
+        // Split across pushes, and from the other states
+        let mut output_buffer = AnsiParser::new();
+        assert_eq!(output_buffer.push(b"\x1b[1;"), []);
+        assert_eq!(
+            output_buffer.push(b"\x1b[2JX"),
+            [
+                TerminalOutput::Invalid,
+                TerminalOutput::ClearAll,
+                TerminalOutput::Data(b"X".to_vec()),
+            ]
+        );
+        for interrupted in [&b"\x1b\x1b"[..], b"\x1b(\x1b", b"\x1b#\x1b"] {
+            let parsed = output_buffer.push(interrupted);
+            let parsed_next = output_buffer.push(b"7X");
+            assert!(parsed.iter().all(|o| *o == TerminalOutput::Invalid));
+            assert_eq!(
+                parsed_next,
+                [TerminalOutput::SaveCursor, TerminalOutput::Data(b"X".to_vec())]
+            );
         }
+    }
 
-        assert_eq!(parser.params, b"0123456789:;<=>?");
-        assert_eq!(parser.intermediates, b"!\"#$%&'()*+,-./");
-        assert!(matches!(parser.state, CsiParserState::Finished(b'}')));
+    fn push_csi(bytes: &[u8]) -> CsiPush {
+        let mut push = CsiPush::Incomplete(CsiParser::new());
+        for b in bytes {
+            let CsiPush::Incomplete(parser) = push else {
+                panic!("CSI sequence ended before {:?}", *b as char);
+            };
+            push = parser.push(*b);
+        }
+        push
+    }
+
+    #[test]
+    fn test_parsing_unknown_csi() {
+        let CsiPush::Finished(csi) = push_csi(b"0123456789:;<=>?!\"#$%&'()*+,-./}") else {
+            panic!("CSI sequence did not finish");
+        };
+
+        assert_eq!(csi.params, b"0123456789:;<=>?");
+        assert_eq!(csi.intermediates, b"!\"#$%&'()*+,-./");
+        assert_eq!(csi.final_byte, b'}');
     }
 
     #[test]
     fn test_parsing_invalid_csi() {
-        let mut parser = CsiParser::new();
-        for b in b"0$0" {
-            parser.push(*b);
+        assert!(matches!(push_csi(b"0$"), CsiPush::Incomplete(_)));
+        assert!(matches!(push_csi(b"0$0"), CsiPush::Invalid));
+        assert!(matches!(push_csi(b"1\x1b"), CsiPush::Invalid));
+    }
+
+    #[test]
+    fn test_huge_params() {
+        let mut parser = AnsiParser::new();
+        let huge = "9".repeat(4 * 1024 * 1024);
+        assert_eq!(
+            parser.push(format!("\x1b[{huge}G\x1b[5;{huge}H\x1b[{huge}@").as_bytes()),
+            vec![
+                TerminalOutput::SetCursorPos {
+                    x: Some(MAX_PARAM),
+                    y: None,
+                },
+                TerminalOutput::SetCursorPos {
+                    x: Some(MAX_PARAM),
+                    y: Some(5),
+                },
+                TerminalOutput::InsertSpaces(MAX_PARAM),
+            ]
+        );
+
+        // However long the sequence, only so much of it is kept
+        let mut csi = CsiParser::new();
+        for b in huge.bytes().chain(huge.bytes().map(|_| b'$')) {
+            let CsiPush::Incomplete(parser) = csi.push(b) else {
+                panic!("sequence ended early");
+            };
+            csi = parser;
         }
+        assert_eq!(csi.params.len(), MAX_CSI_LEN);
+        assert_eq!(csi.intermediates.len(), MAX_CSI_LEN);
+
+        assert_eq!(
+            parser.push(b"\x1b[00012C\x1b[65535D\x1b[65536D"),
+            vec![
+                TerminalOutput::CursorForward(12),
+                TerminalOutput::CursorBackward(65535),
+                TerminalOutput::CursorBackward(MAX_PARAM),
+            ]
+        );
 
-        assert!(matches!(parser.state, CsiParserState::Invalid));
-        parser.push(b'm');
-        assert!(matches!(parser.state, CsiParserState::InvalidFinished));
+        // Parameters past the limit are ignored, however many there are
+        let many = "1;".repeat(1024 * 1024);
+        let output = parser.push(format!("\x1b[{many}m").as_bytes());
+        assert_eq!(output.len(), MAX_PARAMS);
+        assert!(output
+            .iter()
+            .all(|output| *output == TerminalOutput::Sgr(SelectGraphicRendition::Bold)));
+    }
+
+    #[test]
+    fn test_random_input_does_not_panic() {
+        // Mostly bytes that start or continue sequences, so that random input gets past the
+        // first few bytes of one
+        const INTERESTING: &[u8] = b"\x1b\x1b\x1b[[[]P#()0123456789;;:?>!$ \"mhlHJKrtqp\\\x07\x07\n\r";
+        let mut rng = XorShift(0x7e4a_1b5c);
+        for _ in 0..2000 {
+            let len = rng.below(256);
+            let input = (0..len)
+                .map(|_| match rng.below(4) {
+                    0 => rng.below(256) as u8,
+                    _ => INTERESTING[rng.below(INTERESTING.len())],
+                })
+                .collect::<Vec<u8>>();
+
+            let mut parser = AnsiParser::new();
+            let mut rest = &input[..];
+            while !rest.is_empty() {
+                let (chunk, next) = rest.split_at(1 + rng.below(rest.len()));
+                parser.push(chunk);
+                rest = next;
+            }
+
+            // BEL ends an OSC and aborts a CSI, ESC \ ends a DCS, and both are harmless anywhere
+            // else, so whatever state the input left the parser in it is back to text after.
+            // SI and ESC ( B undo line drawing charsets the input may have picked
+            let parsed = parser.push(b"\x07\x1b\\\x0f\x1b(Bx");
+            assert_eq!(
+                parsed.last(),
+                Some(&TerminalOutput::Data(b"x".to_vec())),
+                "parser stuck after {input:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_save_restore_cursor() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"a\x1b7b\x1b8");
+        assert_eq!(
+            parsed,
+            vec![
+                TerminalOutput::Data(b"a".to_vec()),
+                TerminalOutput::SaveCursor,
+                TerminalOutput::Data(b"b".to_vec()),
+                TerminalOutput::RestoreCursor,
+            ]
+        );
     }
 
     #[test]
@@ -811,6 +2462,47 @@ mod test {
         ));
     }
 
+    #[test]
+    fn test_sgr_empty_params_are_resets() {
+        let mut output_buffer = AnsiParser::new();
+        let sgrs = |parsed: Vec<TerminalOutput>| {
+            parsed
+                .into_iter()
+                .map(|output| match output {
+                    TerminalOutput::Sgr(sgr) => sgr,
+                    output => panic!("unexpected output {output:?}"),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            sgrs(output_buffer.push(b"\x1b[m")),
+            [SelectGraphicRendition::Reset]
+        );
+        assert_eq!(
+            sgrs(output_buffer.push(b"\x1b[;m")),
+            [SelectGraphicRendition::Reset, SelectGraphicRendition::Reset]
+        );
+        assert_eq!(
+            sgrs(output_buffer.push(b"\x1b[0;31m")),
+            [
+                SelectGraphicRendition::Reset,
+                SelectGraphicRendition::ForegroundRed
+            ]
+        );
+        assert_eq!(
+            sgrs(output_buffer.push(b"\x1b[31;m")),
+            [
+                SelectGraphicRendition::ForegroundRed,
+                SelectGraphicRendition::Reset
+            ]
+        );
+        assert_eq!(
+            sgrs(output_buffer.push(b"\x1b[;1m")),
+            [SelectGraphicRendition::Reset, SelectGraphicRendition::Bold]
+        );
+    }
+
     #[test]
     fn test_color_parsing() {
         let mut output_buffer = AnsiParser::new();
@@ -935,4 +2627,58 @@ mod test {
         TerminalOutput::Sgr(SelectGraphicRendition::BackgroundTrueColor(0, 255, 128))
     ));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_push_with_matches_push() {
+        // 1MB of plain text, as if from `cat` on a large file
+        let line = b"the quick brown fox jumps over the lazy dog 0123456789\r\n";
+        let input = line.repeat(1024 * 1024 / line.len());
+
+        let collected = AnsiParser::new().push(&input);
+
+        let mut streamed = Vec::new();
+        AnsiParser::new().push_with(&input, |segment| streamed.push(segment.into_owned()));
+
+        assert_eq!(collected.len(), streamed.len());
+        assert_eq!(collected, streamed);
+    }
+
+    #[test]
+    fn test_borrowed_data_matches_owned() {
+        let input = b"abc\x1b[31mdef\r\nghi\x1b[2Jjkl\x1b7mno\x08pq\x1b[5;10Hrs\x1b[?25ltu";
+
+        let owned = AnsiParser::new().push(input);
+
+        let mut borrowed = Vec::new();
+        AnsiParser::new().push_with(input, |segment| borrowed.push(segment));
+
+        // Every run of text comes straight from the input, the sequences around it intact
+        assert!(borrowed
+            .iter()
+            .all(|segment| !matches!(segment, TerminalOutput::Data(_))));
+        assert_eq!(
+            borrowed.iter().filter(|segment| matches!(segment, TerminalOutput::DataBorrowed(_))).count(),
+            8
+        );
+
+        let borrowed = borrowed.into_iter().map(TerminalOutput::into_owned).collect::<Vec<_>>();
+        assert_eq!(owned, borrowed);
+
+        // Data pending when a sequence is split across pushes is flushed before the sequence
+        let mut parser = AnsiParser::new();
+        let mut split = Vec::new();
+        for chunk in input.chunks(3) {
+            parser.push_with(chunk, |segment| split.push(segment.into_owned()));
+        }
+        let merged = split.into_iter().fold(Vec::new(), |mut acc: Vec<TerminalOutput>, segment| {
+            match (acc.last_mut(), segment) {
+                (Some(TerminalOutput::Data(prev)), TerminalOutput::Data(data)) => {
+                    prev.extend(data)
+                }
+                (_, segment) => acc.push(segment),
+            }
+            acc
+        });
+        assert_eq!(owned, merged);
+    }
+}
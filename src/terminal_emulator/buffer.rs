@@ -1,5 +1,5 @@
 use std::ops::Range;
-use crate::terminal_emulator::CursorPos;
+use crate::terminal_emulator::{CursorPos, TerminalData};
 
 pub struct TerminalBufferSetWinSizeResponse {
     pub changed: bool,
@@ -13,12 +13,20 @@ struct PadBufferForWriteResponse {
     inserted_padding: Range<usize>,
 }
 
+/// Start of the UTF-8 character that covers `pos`
+fn char_start(data: &[u8], mut pos: usize) -> usize {
+    while pos > 0 && data[pos] & 0xc0 == 0x80 {
+        pos -= 1;
+    }
+    pos
+}
+
 /// Calculate the indexes of the start and end of each line in the buffer given an input width.
 /// Ranges do not include newlines. If a newline appears past the width, it does not result in an
 /// extra line
 ///
 /// Example
-/// ```
+/// ```ignore
 /// let ranges = calc_line_ranges(b"12\n1234\n12345", 4);
 /// assert_eq!(ranges, [0..2, 3..7, 8..11, 12..13]);
 /// ```
@@ -49,6 +57,93 @@ fn calc_line_ranges(buf: &[u8], width: usize) -> Vec<Range<usize>> {
     ret
 }
 
+/// Characters that end a word besides whitespace, see [`word_at`]
+pub const DEFAULT_WORD_SEPARATORS: &str = ",`|:;\"'()[]{}<>";
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum CharClass {
+    Whitespace,
+    Separator,
+    Word,
+}
+
+/// Range of the word around `index` in `buf`, for double click selection
+///
+/// A word is a run of characters other than whitespace and `separators` on one line, soft wraps
+/// do not end it. Clicking whitespace or a separator gets the run of those instead. None when
+/// `index` is a newline or past the end, i.e. after the end of a line
+pub fn word_at(buf: &[u8], index: usize, separators: &str) -> Option<Range<usize>> {
+    let class = |b: u8| {
+        if b == b' ' || b == b'\t' {
+            CharClass::Whitespace
+        } else if b.is_ascii() && separators.as_bytes().contains(&b) {
+            CharClass::Separator
+        } else {
+            // Includes every byte of non-ASCII characters, so those are never split
+            CharClass::Word
+        }
+    };
+
+    let clicked = *buf.get(index).filter(|b| **b != b'\n')?;
+    let clicked_class = class(clicked);
+    let same = |b: &u8| *b != b'\n' && class(*b) == clicked_class;
+    let start = buf[..index]
+        .iter()
+        .rposition(|b| !same(b))
+        .map_or(0, |pos| pos + 1);
+    let end = buf[index..]
+        .iter()
+        .position(|b| !same(b))
+        .map_or(buf.len(), |pos| index + pos);
+    Some(start..end)
+}
+
+/// Range of the line `index` is on without its newline, a newline belongs to the line it ends.
+/// None past the end of `buf`
+pub fn line_at(buf: &[u8], index: usize) -> Option<Range<usize>> {
+    if index > buf.len() {
+        return None;
+    }
+    let start = buf[..index]
+        .iter()
+        .rposition(|b| *b == b'\n')
+        .map_or(0, |pos| pos + 1);
+    let end = buf[index..]
+        .iter()
+        .position(|b| *b == b'\n')
+        .map_or(buf.len(), |pos| index + pos);
+    Some(start..end)
+}
+
+/// `text` without the spaces at the end of each of its lines, which are mostly padding written to
+/// get the cursor to a column rather than anything a program printed. Spaces within a line stay
+pub fn trim_line_ends(text: &[u8]) -> Vec<u8> {
+    let lines = text.split(|b| *b == b'\n').map(|line| {
+        let len = line.iter().rposition(|b| *b != b' ').map_or(0, |pos| pos + 1);
+        &line[..len]
+    });
+    lines.collect::<Vec<_>>().join(&b'\n')
+}
+
+/// `data` split into rows of `width`, it has to start at the beginning of a row
+pub(crate) fn rows(data: &[u8], width: usize) -> Vec<&[u8]> {
+    calc_line_ranges(data, width)
+        .into_iter()
+        .map(|range| &data[range])
+        .collect()
+}
+
+/// Text of `data`, which starts at the beginning of a row, without trailing spaces. With
+/// `logical_lines` soft wrapped lines are one line, otherwise there is one line per row
+pub(crate) fn text(data: &[u8], width: usize, logical_lines: bool) -> String {
+    let lines = if logical_lines {
+        data.split(|b| *b == b'\n').collect::<Vec<_>>()
+    } else {
+        rows(data, width)
+    };
+    String::from_utf8_lossy(&trim_line_ends(&lines.join(&b'\n'))).into_owned()
+}
+
 #[derive(Debug, Eq, PartialEq)]
 struct InvalidBufPos {
     buf_pos: usize,
@@ -82,6 +177,26 @@ fn buf_to_cursor_pos(
         y: new_cursor_y,
     })
 }
+
+/// [`buf_to_cursor_pos`] for a position the caller just made sure exists. Should it not map to
+/// a row anyway, the position is past the last one, e.g. right after a trailing newline, so the
+/// cursor goes to the start of the row after it rather than bringing the terminal down
+fn buf_to_cursor_pos_or_last_row(
+    buf: &[u8],
+    width: usize,
+    height: usize,
+    buf_pos: usize,
+) -> CursorPos {
+    buf_to_cursor_pos(buf, width, height, buf_pos).unwrap_or_else(|e| {
+        error!("Failed to map {e:?} to a cursor position");
+        let line_ranges = calc_line_ranges(buf, width);
+        let num_rows = line_ranges_to_visible_line_ranges(&line_ranges, height).len();
+        CursorPos {
+            x: 0,
+            y: num_rows.min(height.saturating_sub(1)),
+        }
+    })
+}
 fn line_ranges_to_visible_line_ranges(
     line_ranges: &[Range<usize>],
     height: usize,
@@ -156,11 +271,7 @@ fn pad_buffer_for_write(
         padding_start_pos = Some(actual_end);
     }
 
-    let number_of_spaces = if desired_end > actual_end {
-        desired_end - actual_end
-    } else {
-        0
-    };
+    let number_of_spaces = desired_end.saturating_sub(actual_end);
 
     num_inserted_characters += number_of_spaces;
 
@@ -212,12 +323,85 @@ pub(crate) struct TerminalBufferInsertResponse {
     /// requested row for writing
     pub insertion_range: Range<usize>,
     pub(crate) new_cursor_pos: CursorPos,
+    /// Lines dropped off the front of scrollback after the write, in post-write positions. Any
+    /// formatting for this range has to be deleted after applying the ranges above
+    pub trimmed_range: Option<Range<usize>>,
+}
+
+pub(crate) struct TerminalBufferInsertBlanksResponse {
+    /// Blanks added at the cursor, shifts all data after it
+    pub insertion_range: Range<usize>,
+    /// Text pushed past the right margin, in post-insertion positions. Apply after the insertion
+    pub deleted_range: Option<Range<usize>>,
+}
+
+pub(crate) struct TerminalBufferDeleteCharsResponse {
+    /// Text removed at the cursor, shifts all data after it
+    pub deleted_range: Range<usize>,
+    /// Blanks added before the right margin, in post-deletion positions. Apply after the deletion
+    pub insertion_range: Option<Range<usize>>,
+}
+
+/// How [`TerminalBuffer::line_edit`] changes the row at the cursor
+enum LineEdit {
+    /// Shift the row right by this many blanks
+    Insert(usize),
+    /// Pull the row left over this many characters
+    Delete(usize),
+    /// Blank this many characters in place
+    Erase(usize),
+}
+
+enum LineEditResponse {
+    Insert(TerminalBufferInsertBlanksResponse),
+    Delete(TerminalBufferDeleteCharsResponse),
+    Erase(Range<usize>),
 }
 
+/// The part of the row at the cursor that ICH, DCH and ECH edit, see
+/// [`TerminalBuffer::line_edit_span`]
+struct LineEditSpan {
+    buf_pos: usize,
+    line_range: Range<usize>,
+    /// Columns from the cursor to the right margin
+    max_chars: usize,
+    /// Where the right margin is in the buffer, which may be past the end of the row
+    margin_pos: usize,
+    /// End of the text that moves, which is all of it unless the row goes past the margin
+    row_end: usize,
+}
+
+pub(crate) struct TerminalBufferScrollResponse {
+    /// Newlines added so that every row of the region exists
+    pub padding: Range<usize>,
+    /// Blank line added below the region
+    pub insertion_range: Range<usize>,
+    /// Line removed from the top of the region, nothing is removed when the whole screen scrolls
+    /// since the line moves into scrollback instead. Apply after the ranges above
+    pub deleted_range: Option<Range<usize>>,
+    /// See [`TerminalBufferInsertResponse::trimmed_range`]
+    pub trimmed_range: Option<Range<usize>>,
+}
+
+/// Number of scrollback lines kept when nothing else is configured
+pub const DEFAULT_SCROLLBACK_LINES: usize = 10000;
+
+/// Scrollback and screen as one run of text. Only a newline that was written ends a line, a line
+/// wider than the screen is split into rows at the width whenever rows are needed and has no
+/// newline where it wraps. That is what tells soft wraps from hard ones, see
+/// [`TerminalBuffer::row_wraps`], and what lets lines reflow when the width changes. The one
+/// place a wrap becomes a newline is [`TerminalBuffer::scroll_up`] opening a row below one that
+/// wraps
 pub(crate) struct TerminalBuffer {
     pub(crate) buf: Vec<u8>,
     pub(crate) width: usize,   // Make sure this is pub(crate)
     pub(crate) height: usize,  // Make sure this is pub(crate)
+    max_scrollback_lines: usize,
+    /// DECAWM, when unset writes past the right margin overwrite the last column
+    auto_wrap: bool,
+    /// Where the cursor was left on the last column by a write that filled it. The next write
+    /// from there starts on the next row, anything else done to the buffer cancels the wrap
+    pending_wrap: Option<CursorPos>,
 }
 
 
@@ -227,42 +411,58 @@ impl TerminalBuffer {
                 buf: vec![],
                 width,
                 height,
+                max_scrollback_lines: DEFAULT_SCROLLBACK_LINES,
+                auto_wrap: true,
+                pending_wrap: None,
             }
 
         }
     pub(crate) fn dimensions(&self) -> (usize, usize) {
         (self.width, self.height)
     }
-    pub fn width(&self) -> usize {
-        self.width
+
+    pub fn auto_wrap(&self) -> bool {
+        self.auto_wrap
     }
 
-    pub fn height(&self) -> usize {
-        self.height
+    pub fn set_auto_wrap(&mut self, auto_wrap: bool) {
+        self.pending_wrap = None;
+        self.auto_wrap = auto_wrap;
     }
 
-    pub(crate) fn delete_forwards(
-        &mut self,
-        cursor_pos: &CursorPos,
-        num_chars: usize,
-    ) -> Option<Range<usize>> {
-        let Some((buf_pos, line_range)) =
-            cursor_to_buf_pos(&self.buf, cursor_pos, self.width, self.height)
-        else {
-            return None;
-        };
+    /// Forget a wrap left by a write that filled the last column, for when the cursor was put
+    /// somewhere explicitly. Even back on the same cell the next write overwrites it, like xterm
+    pub(crate) fn cancel_pending_wrap(&mut self) {
+        self.pending_wrap = None;
+    }
 
-        let mut delete_range = buf_pos..buf_pos + num_chars;
+    /// Whether a write at `cursor_pos` starts on the next row, see [`Self::cancel_pending_wrap`]
+    pub(crate) fn has_pending_wrap(&self, cursor_pos: &CursorPos) -> bool {
+        self.pending_wrap.as_ref() == Some(cursor_pos)
+    }
 
-        if delete_range.end > line_range.end && self.buf.get(line_range.end) != Some(&b'\n') {
-            self.buf.insert(line_range.end, b'\n');
-        }
+    /// Limit the number of lines kept in scrollback. Takes effect on the next write
+    pub fn set_scrollback_limit(&mut self, max_scrollback_lines: usize) {
+        self.max_scrollback_lines = max_scrollback_lines;
+    }
 
-        delete_range.end = line_range.end.min(delete_range.end);
+    /// Drop rows from the front of scrollback until it fits in the configured limit
+    ///
+    /// Rows are counted like they are shown, so a long line that wrapped loses its first rows and
+    /// keeps the rest. Returns the removed range
+    fn trim_scrollback(&mut self) -> Option<Range<usize>> {
+        let line_ranges = calc_line_ranges(&self.buf, self.width);
+        let num_scrollback_rows = line_ranges.len().saturating_sub(self.height);
+        if num_scrollback_rows <= self.max_scrollback_lines {
+            return None;
+        }
 
-        self.buf.drain(delete_range.clone());
-        Some(delete_range)
+        // Up to the first row that is kept, with the newline ending the row before it
+        let trim_end = line_ranges[num_scrollback_rows - self.max_scrollback_lines].start;
+        self.buf.drain(0..trim_end);
+        Some(0..trim_end)
     }
+
     pub fn set_win_size(
         &mut self,
         width: usize,
@@ -281,12 +481,28 @@ impl TerminalBuffer {
         // Ensure that the cursor position has a valid buffer position. That way when we resize we
         // can just look up where the cursor is supposed to be and map it back to it's new cursor
         // position
+        // A pending wrap is kept, from after the last character of the row wherever that ends up
+        let pending_wrap = self.pending_wrap.take().filter(|pos| pos == cursor_pos);
+        let wrapped_pos;
+        let cursor_pos = match pending_wrap {
+            Some(_) => {
+                wrapped_pos = CursorPos {
+                    x: self.width,
+                    y: cursor_pos.y,
+                };
+                &wrapped_pos
+            }
+            None => cursor_pos,
+        };
         let pad_response =
             pad_buffer_for_write(&mut self.buf, self.width, cursor_pos,self.height, 0);
         let buf_pos = pad_response.write_idx;
         let inserted_padding = pad_response.inserted_padding;
-        let new_cursor_pos = buf_to_cursor_pos(&self.buf, width, height, buf_pos)
-            .expect("buf pos should exist in buffer");
+        let mut new_cursor_pos = buf_to_cursor_pos_or_last_row(&self.buf, width, height, buf_pos);
+        if pending_wrap.is_some() && new_cursor_pos.x >= width {
+            new_cursor_pos.x = width - 1;
+            self.pending_wrap = Some(new_cursor_pos.clone());
+        }
         self.width = width;
         self.height = height;
 
@@ -298,6 +514,23 @@ impl TerminalBuffer {
     }
 
     pub(crate) fn insert_data(&mut self, cursor_pos: &CursorPos, data: &[u8]) -> TerminalBufferInsertResponse {
+        let pending_wrap = self.pending_wrap.take();
+        if !self.auto_wrap {
+            return self.insert_data_no_wrap(cursor_pos, data);
+        }
+
+        // Past the right margin is where the implicit wrap in the buffer puts the write
+        let wrapped_pos;
+        let cursor_pos = if pending_wrap.as_ref() == Some(cursor_pos) {
+            wrapped_pos = CursorPos {
+                x: self.width,
+                y: cursor_pos.y,
+            };
+            &wrapped_pos
+        } else {
+            cursor_pos
+        };
+
         let PadBufferForWriteResponse {
             write_idx,
             inserted_padding,
@@ -311,14 +544,75 @@ impl TerminalBuffer {
         );
         let write_range = write_idx..write_idx + data.len();
         self.buf[write_range.clone()].copy_from_slice(data);
-        let new_cursor_pos = buf_to_cursor_pos(&self.buf, self.width, self.height, write_range.end).expect("buf pos should exist in buffer");;
+        let mut new_cursor_pos =
+            buf_to_cursor_pos_or_last_row(&self.buf, self.width, self.height, write_range.end);
+        // Filling the last column leaves the cursor on it rather than past the right margin
+        if new_cursor_pos.x >= self.width && !data.is_empty() {
+            new_cursor_pos.x = self.width - 1;
+            self.pending_wrap = Some(new_cursor_pos.clone());
+        }
+        // Cursor position is relative to the visible area, so it is unaffected by the trim
+        let trimmed_range = self.trim_scrollback();
+        TerminalBufferInsertResponse {
+            written_range: write_range,
+            insertion_range: inserted_padding,
+            new_cursor_pos,
+            trimmed_range,
+        }
+    }
+    /// Insert data with auto wrap disabled
+    ///
+    /// Everything that does not fit before the right margin lands on the last column, so only the
+    /// final character of the overflow is visible. The cursor stays on the last column
+    fn insert_data_no_wrap(&mut self, cursor_pos: &CursorPos, data: &[u8]) -> TerminalBufferInsertResponse {
+        let last_column = self.width.saturating_sub(1);
+        let cursor_pos = CursorPos {
+            x: cursor_pos.x.min(last_column),
+            y: cursor_pos.y,
+        };
+
+        // Whatever does not fit is dropped, except that the final character still lands in the
+        // last column if it fits there. Cuts are on character boundaries so no partial sequences
+        // get written
+        let available = self.width - cursor_pos.x;
+        let mut clipped;
+        let data = if data.len() > available {
+            let last_start = char_start(data, data.len() - 1);
+            let last_char = &data[last_start..];
+            if last_char.len() > available {
+                clipped = data[..char_start(data, available)].to_vec();
+            } else {
+                let head_len = (available - last_char.len()).min(last_start);
+                clipped = data[..char_start(data, head_len)].to_vec();
+                clipped.extend(last_char);
+            }
+            &clipped[..]
+        } else {
+            data
+        };
+
+        let PadBufferForWriteResponse {
+            write_idx,
+            inserted_padding,
+        } = pad_buffer_for_write(&mut self.buf, self.width, &cursor_pos, self.height, data.len());
+        let write_range = write_idx..write_idx + data.len();
+        self.buf[write_range.clone()].copy_from_slice(data);
+
+        let mut new_cursor_pos =
+            buf_to_cursor_pos_or_last_row(&self.buf, self.width, self.height, write_range.end);
+        new_cursor_pos.x = new_cursor_pos.x.min(last_column);
+
+        let trimmed_range = self.trim_scrollback();
         TerminalBufferInsertResponse {
             written_range: write_range,
             insertion_range: inserted_padding,
             new_cursor_pos,
+            trimmed_range,
         }
     }
+
     pub fn clear_line_forwards(&mut self, cursor_pos: &CursorPos) -> Option<Range<usize>> {
+        self.pending_wrap = None;
         // Can return early if none, we didn't delete anything if there is nothing to delete
         let (buf_pos, line_range) =
             cursor_to_buf_pos(&self.buf, cursor_pos, self.width, self.height)?;
@@ -327,68 +621,262 @@ impl TerminalBuffer {
         self.buf.drain(del_range.clone());
         Some(del_range)
     }
-    /// Inserts data, but will not wrap. If line end is hit, data stops
-    pub fn insert_spaces(
+    /// Whether the visible `row` continues onto the next row because it hit the right margin,
+    /// rather than ending in a newline
+    pub(crate) fn row_wraps(&self, row: usize) -> bool {
+        let line_ranges = calc_line_ranges(&self.buf, self.width);
+        let visible_line_ranges = line_ranges_to_visible_line_ranges(&line_ranges, self.height);
+        match visible_line_ranges.get(row) {
+            Some(range) => range.end < self.buf.len() && self.buf[range.end] != b'\n',
+            None => false,
+        }
+    }
+
+    /// Scroll the rows in `region` up by one, leaving a blank row at the bottom of it
+    ///
+    /// Scrolling the whole screen pushes the top row into scrollback, scrolling a smaller region
+    /// discards it. A bottom row that soft wraps is broken off with a newline to make room for
+    /// the blank row, so it no longer [wraps](Self::row_wraps) and will not reflow with the rest of
+    /// its line
+    pub(crate) fn scroll_up(&mut self, region: Range<usize>) -> TerminalBufferScrollResponse {
+        self.pending_wrap = None;
+        let bottom = region.end - 1;
+        let padding = pad_buffer_for_write(
+            &mut self.buf,
+            self.width,
+            &CursorPos { x: 0, y: bottom },
+            self.height,
+            0,
+        )
+        .inserted_padding;
+
+        let (top_range, bottom_range) = {
+            let line_ranges = calc_line_ranges(&self.buf, self.width);
+            let visible_line_ranges = line_ranges_to_visible_line_ranges(&line_ranges, self.height);
+            (
+                visible_line_ranges[region.start].clone(),
+                visible_line_ranges[bottom].clone(),
+            )
+        };
+
+        // A row ending in a newline only needs one more for a blank row after it. A row that
+        // soft wraps into the next needs to be broken off first
+        let num_newlines = if self.buf.get(bottom_range.end) == Some(&b'\n') {
+            1
+        } else {
+            2
+        };
+        let insertion_range = bottom_range.end..bottom_range.end + num_newlines;
+        self.buf
+            .splice(insertion_range.start..insertion_range.start, std::iter::repeat_n(b'\n', num_newlines));
+
+        let deleted_range = if region.start == 0 && region.end == self.height {
+            None
+        } else {
+            // Only take the newline with the row if that does not merge it with the row above
+            let starts_line = top_range.start == 0 || self.buf[top_range.start - 1] == b'\n';
+            let ends_line = self.buf[top_range.end] == b'\n';
+            let delete_range = if starts_line && ends_line {
+                top_range.start..top_range.end + 1
+            } else {
+                top_range
+            };
+            self.buf.drain(delete_range.clone());
+            Some(delete_range)
+        };
+
+        let trimmed_range = self.trim_scrollback();
+        TerminalBufferScrollResponse {
+            padding,
+            insertion_range,
+            deleted_range,
+            trimmed_range,
+        }
+    }
+
+    /// Overwrite the current line up to and including the cursor with spaces
+    ///
+    /// Returns the overwritten range, nothing moves so formatting only needs replacing
+    pub fn clear_line_backwards(&mut self, cursor_pos: &CursorPos) -> Option<Range<usize>> {
+        self.pending_wrap = None;
+        let line_ranges = calc_line_ranges(&self.buf, self.width);
+        let visible_line_ranges = line_ranges_to_visible_line_ranges(&line_ranges, self.height);
+        let line_range = visible_line_ranges.get(cursor_pos.y)?;
+
+        let x = cursor_pos.x.min(self.width.saturating_sub(1));
+        let clear_range = line_range.start..(line_range.start + x + 1).min(line_range.end);
+        self.buf[clear_range.clone()].fill(b' ');
+        Some(clear_range)
+    }
+
+    /// Overwrite everything from the top of the screen up to and including the cursor with spaces
+    ///
+    /// Newlines are kept so the line structure does not change. Returns the overwritten range
+    pub fn clear_backwards(&mut self, cursor_pos: &CursorPos) -> Option<Range<usize>> {
+        self.pending_wrap = None;
+        let screen_start = {
+            let line_ranges = calc_line_ranges(&self.buf, self.width);
+            line_ranges_to_visible_line_ranges(&line_ranges, self.height)
+                .first()?
+                .start
+        };
+
+        let line_clear_range = match self.clear_line_backwards(cursor_pos) {
+            Some(range) => range,
+            // Cursor is below any content, the whole screen is before it
+            None => self.buf.len()..self.buf.len(),
+        };
+
+        let clear_range = screen_start..line_clear_range.end;
+        for b in &mut self.buf[clear_range.clone()] {
+            if *b != b'\n' {
+                *b = b' ';
+            }
+        }
+        Some(clear_range)
+    }
+
+    /// Find the row at the cursor for an edit moving text only up to `right_margin`, the column
+    /// the edited part of the row ends before. Text from there on stays where it is, including
+    /// on the next row when this one wraps. None if there is no text at or after the cursor
+    fn line_edit_span(
+        &mut self,
+        cursor_pos: &CursorPos,
+        right_margin: usize,
+    ) -> Option<LineEditSpan> {
+        self.pending_wrap = None;
+        let (buf_pos, line_range) =
+            cursor_to_buf_pos(&self.buf, cursor_pos, self.width, self.height)?;
+        if buf_pos == line_range.end {
+            return None;
+        }
+
+        let right_margin = right_margin.min(self.width);
+        let margin_pos = line_range.start + right_margin;
+        Some(LineEditSpan {
+            buf_pos,
+            max_chars: right_margin.saturating_sub(cursor_pos.x),
+            margin_pos,
+            row_end: line_range.end.min(margin_pos),
+            line_range,
+        })
+    }
+
+    /// Apply `edit` to the row at the cursor, moving text only up to `right_margin`. See
+    /// [`Self::line_edit_span`]
+    fn line_edit(
         &mut self,
         cursor_pos: &CursorPos,
-        mut num_spaces: usize,
-    ) -> TerminalBufferInsertResponse {
-        num_spaces = self.width.min(num_spaces);
-
-        let buf_pos = cursor_to_buf_pos(&self.buf, cursor_pos, self.width, self.height);
-        match buf_pos {
-            Some((buf_pos, line_range)) => {
-                // Insert spaces until either we hit num_spaces, or the line width is too long
-                let line_len = line_range.end - line_range.start;
-                let num_inserted = (num_spaces).min(self.width - line_len);
-
-                // Overwrite existing with spaces until we hit num_spaces or we hit the line end
-                let num_overwritten = (num_spaces - num_inserted).min(line_range.end - buf_pos);
-
-                // NOTE: We do the overwrite first so we don't have to worry about adjusting
-                // indices for the newly inserted data
-                self.buf[buf_pos..buf_pos + num_overwritten].fill(b' ');
+        right_margin: usize,
+        edit: LineEdit,
+    ) -> Option<LineEditResponse> {
+        let span = self.line_edit_span(cursor_pos, right_margin)?;
+        let num = match edit {
+            LineEdit::Insert(num) | LineEdit::Delete(num) | LineEdit::Erase(num) => {
+                num.min(span.max_chars)
+            }
+        };
+        // The characters a deletion or erase covers, which never go past the text that moves
+        let edit_range = span.buf_pos..(span.buf_pos + num).min(span.row_end);
+
+        let response = match edit {
+            LineEdit::Insert(_) => {
                 self.buf
-                    .splice(buf_pos..buf_pos, std::iter::repeat(b' ').take(num_inserted));
+                    .splice(span.buf_pos..span.buf_pos, std::iter::repeat_n(b' ', num));
 
-                let used_spaces = num_inserted + num_overwritten;
-                TerminalBufferInsertResponse {
-                    written_range: buf_pos..buf_pos + used_spaces,
-                    insertion_range: buf_pos..buf_pos + num_inserted,
-                    new_cursor_pos: cursor_pos.clone(),
+                // What was in the last `num` columns before the margin is now just past it
+                let deleted_range = span.margin_pos..span.row_end + num;
+                let deleted_range = (!deleted_range.is_empty()).then_some(deleted_range);
+                if let Some(range) = &deleted_range {
+                    self.buf.drain(range.clone());
                 }
+
+                LineEditResponse::Insert(TerminalBufferInsertBlanksResponse {
+                    insertion_range: span.buf_pos..span.buf_pos + num,
+                    deleted_range,
+                })
             }
-            None => {
-                let PadBufferForWriteResponse {
-                    write_idx,
-                    inserted_padding,
-                } = pad_buffer_for_write(
-                    &mut self.buf,
-                    self.width,
-                    cursor_pos,
-                    self.height,
-                    num_spaces,
-                );
-                TerminalBufferInsertResponse {
-                    written_range: write_idx..write_idx + num_spaces,
-                    insertion_range: inserted_padding,
-                    new_cursor_pos: cursor_pos.clone(),
-                }
+            LineEdit::Delete(_) => {
+                // Blanks are only needed to keep text past the margin in place, which includes
+                // the rest of the line when the row wraps at the edge of the screen
+                let text_after_margin = span.line_range.end >= span.margin_pos
+                    && self.buf.get(span.margin_pos).is_some_and(|b| *b != b'\n');
+
+                self.buf.drain(edit_range.clone());
+                let insertion_range = text_after_margin.then(|| {
+                    let insert_pos = span.margin_pos - edit_range.len();
+                    self.buf.splice(
+                        insert_pos..insert_pos,
+                        std::iter::repeat_n(b' ', edit_range.len()),
+                    );
+                    insert_pos..span.margin_pos
+                });
+
+                LineEditResponse::Delete(TerminalBufferDeleteCharsResponse {
+                    deleted_range: edit_range,
+                    insertion_range,
+                })
+            }
+            LineEdit::Erase(_) => {
+                self.buf[edit_range.clone()].fill(b' ');
+                LineEditResponse::Erase(edit_range)
             }
+        };
+        Some(response)
+    }
+
+    /// ICH, shift the row right of the cursor over by `num_blanks` spaces, dropping whatever goes
+    /// past `right_margin`. See [`Self::line_edit`]
+    pub(crate) fn insert_blanks(
+        &mut self,
+        cursor_pos: &CursorPos,
+        num_blanks: usize,
+        right_margin: usize,
+    ) -> Option<TerminalBufferInsertBlanksResponse> {
+        match self.line_edit(cursor_pos, right_margin, LineEdit::Insert(num_blanks))? {
+            LineEditResponse::Insert(response) => Some(response),
+            _ => unreachable!("insert edits respond with an insertion"),
+        }
+    }
+
+    /// DCH, delete `num_chars` from the cursor, pulling the row up to `right_margin` back over
+    /// them and filling in with blanks before the margin. See [`Self::line_edit`]
+    pub(crate) fn delete_chars(
+        &mut self,
+        cursor_pos: &CursorPos,
+        num_chars: usize,
+        right_margin: usize,
+    ) -> Option<TerminalBufferDeleteCharsResponse> {
+        match self.line_edit(cursor_pos, right_margin, LineEdit::Delete(num_chars))? {
+            LineEditResponse::Delete(response) => Some(response),
+            _ => unreachable!("delete edits respond with a deletion"),
+        }
+    }
+
+    /// ECH, overwrite up to `num_chars` from the cursor with spaces, stopping before
+    /// `right_margin`. Returns the overwritten range, nothing moves so formatting only needs
+    /// replacing. See [`Self::line_edit`]
+    pub(crate) fn erase_chars(
+        &mut self,
+        cursor_pos: &CursorPos,
+        num_chars: usize,
+        right_margin: usize,
+    ) -> Option<Range<usize>> {
+        match self.line_edit(cursor_pos, right_margin, LineEdit::Erase(num_chars))? {
+            LineEditResponse::Erase(range) => Some(range),
+            _ => unreachable!("erase edits respond with the erased range"),
         }
     }
 
     pub fn clear_forwards(&mut self, cursor_pos: &CursorPos) -> Option<usize> {
+        self.pending_wrap = None;
         let line_ranges = calc_line_ranges(&self.buf, self.width);
         let visible_line_ranges = line_ranges_to_visible_line_ranges(&line_ranges, self.height);
 
-        let Some((buf_pos, _)) =
-            cursor_to_buf_pos_from_visible_line_ranges(cursor_pos, visible_line_ranges)
-        else {
-            return None;
-        };
+        let (buf_pos, _) =
+            cursor_to_buf_pos_from_visible_line_ranges(cursor_pos, visible_line_ranges)?;
 
-        let previous_last_char = self.buf[buf_pos];
+        let previous_last_char = self.buf.get(buf_pos).copied();
         self.buf.truncate(buf_pos);
 
         // If we truncate at the start of a line, and the previous line did not end with a newline,
@@ -400,7 +888,7 @@ impl TerminalBuffer {
         //
         // If we truncated a newline it's the same situation
         if cursor_pos.x == 0 && buf_pos > 0 && self.buf[buf_pos - 1] != b'\n'
-            || previous_last_char == b'\n'
+            || previous_last_char == Some(b'\n')
         {
             self.buf.push(b'\n');
         }
@@ -411,46 +899,158 @@ impl TerminalBuffer {
             }
         }
 
-        let new_cursor_pos =
-            buf_to_cursor_pos(&self.buf, self.width, self.height, buf_pos).map(|mut pos| {
-                // NOTE: buf to cursor pos may put the cursor one past the end of the line. In this
-                // case it's ok because there are two valid cursor positions and we only care about one
-                // of them
-                if pos.x == self.width {
-                    pos.x = 0;
-                    pos.y += 1;
-                }
-                pos
-            });
-
-        assert_eq!(new_cursor_pos, Ok(cursor_pos.clone()));
+        // NOTE: The end of a full row is also the start of the next one, either is where the
+        // cursor was. After a resize it is left just past the last column of the row
+        let next_row_if_past_end = |mut pos: CursorPos| {
+            if pos.x >= self.width {
+                pos.x = 0;
+                pos.y += 1;
+            }
+            pos
+        };
+        let new_cursor_pos = buf_to_cursor_pos(&self.buf, self.width, self.height, buf_pos)
+            .map(next_row_if_past_end);
+        let expected_cursor_pos = next_row_if_past_end(cursor_pos.clone());
+        if new_cursor_pos.as_ref() != Ok(&expected_cursor_pos) {
+            warn!("Clearing moved the cursor from {cursor_pos:?} to {new_cursor_pos:?}");
+        }
         Some(buf_pos)
     }
 
     pub(crate) fn clear_all(&mut self) {
+        self.pending_wrap = None;
         self.buf.clear();
     }
 
-    pub(crate) fn data(&self) -> crate::terminal_emulator::TerminalData<&[u8]> {
-        let line_ranges = calc_line_ranges(&self.buf, self.width);
-        let visible_line_ranges = line_ranges_to_visible_line_ranges(&line_ranges, self.height);
+    /// Byte ranges of the scrollback and visible regions
+    ///
+    /// The buffer always ends in a newline, and scrollback ends in the newline that separates it
+    /// from the visible region. Neither is content, so both are trimmed here to avoid consumers
+    /// rendering blank lines. The cursor position is unaffected, a cursor after the last newline
+    /// is still on the row below the trimmed content
+    pub(crate) fn data_ranges(&self) -> TerminalData<Range<usize>> {
         if self.buf.is_empty() {
-            return crate::terminal_emulator::TerminalData {
-                scrollback: &[],
-                visible: &self.buf,
+            return TerminalData {
+                scrollback: 0..0,
+                visible: 0..0,
             };
         }
+
+        let line_ranges = calc_line_ranges(&self.buf, self.width);
+        let visible_line_ranges = line_ranges_to_visible_line_ranges(&line_ranges, self.height);
         let start = visible_line_ranges[0].start;
-        crate::terminal_emulator::TerminalData {
-            scrollback: &self.buf[0..start],
-            visible: &self.buf[start..],
+
+        let trim_newline = |range: Range<usize>| {
+            if range.end > range.start && self.buf[range.end - 1] == b'\n' {
+                range.start..range.end - 1
+            } else {
+                range
+            }
+        };
+
+        TerminalData {
+            scrollback: trim_newline(0..start),
+            visible: trim_newline(start..self.buf.len()),
         }
     }
 
+    pub(crate) fn data(&self) -> TerminalData<&[u8]> {
+        let ranges = self.data_ranges();
+        TerminalData {
+            scrollback: &self.buf[ranges.scrollback],
+            visible: &self.buf[ranges.visible],
+        }
+    }
+
+    /// Screen position of the character at buffer index `index`, None if it is not on screen
+    pub(crate) fn visible_pos(&self, index: usize) -> Option<CursorPos> {
+        let line_ranges = calc_line_ranges(&self.buf, self.width);
+        let visible = line_ranges_to_visible_line_ranges(&line_ranges, self.height);
+        let y = visible.iter().position(|line| line.contains(&index))?;
+        Some(CursorPos {
+            x: index - visible[y].start,
+            y,
+        })
+    }
+
+    /// Buffer index and character of the visible cell at `x`, `y`
+    ///
+    /// None past the end of the row or screen. A column in the middle of a multi-byte character
+    /// reads as `'\0'`, and invalid UTF-8 as the replacement character
+    pub(crate) fn visible_cell(&self, x: usize, y: usize) -> Option<(usize, char)> {
+        let visible = self.data_ranges().visible;
+        let row = calc_line_ranges(&self.buf[visible.clone()], self.width)
+            .into_iter()
+            .nth(y)?;
+        if x >= row.len() {
+            return None;
+        }
+
+        let index = visible.start + row.start + x;
+        let ch = if self.buf[index] & 0b1100_0000 == 0b1000_0000 {
+            '\0'
+        } else {
+            let end = (index + 4).min(self.buf.len());
+            String::from_utf8_lossy(&self.buf[index..end])
+                .chars()
+                .next()
+                .unwrap_or(char::REPLACEMENT_CHARACTER)
+        };
+        Some((index, ch))
+    }
+
+    /// [`text`] of `range`, which starts at the beginning of a row
+    pub(crate) fn text(&self, range: Range<usize>, logical_lines: bool) -> String {
+        text(&self.buf[range], self.width, logical_lines)
+    }
+
+    /// Content of each visible row, soft wrapped lines are split at the screen width
+    pub(crate) fn visible_rows(&self) -> Vec<&[u8]> {
+        rows(self.data().visible, self.width)
+    }
+
 }
 #[cfg(test)]
 mod test {
     use super::*;
+    #[test]
+    fn test_word_at() {
+        let buf = "ls -la /tmp\n(foo, bar)  héllo\n\nend".as_bytes();
+        let word = |index| word_at(buf, index, DEFAULT_WORD_SEPARATORS).map(|r| &buf[r]);
+
+        assert_eq!(word(0), Some(&b"ls"[..]));
+        assert_eq!(word(4), Some(&b"-la"[..]));
+        assert_eq!(word(9), Some(&b"/tmp"[..]));
+        // Separators end words and are selected as a run of their own
+        assert_eq!(word(13), Some(&b"foo"[..]));
+        assert_eq!(word(12), Some(&b"("[..]));
+        assert_eq!(word(16), Some(&b","[..]));
+        assert_eq!(word(18), Some(&b"bar"[..]));
+        assert_eq!(word(22), Some(&b"  "[..]));
+        assert_eq!(word(25), Some("héllo".as_bytes()));
+        // After the end of a line, on an empty line and past the end there is no word
+        assert_eq!(word(11), None);
+        assert_eq!(word(31), None);
+        assert_eq!(word(buf.len()), None);
+        assert_eq!(word(buf.len() - 1), Some(&b"end"[..]));
+
+        // With no separators only whitespace splits
+        assert_eq!(word_at(buf, 13, "").map(|r| &buf[r]), Some(&b"(foo,"[..]));
+    }
+
+    #[test]
+    fn test_line_at() {
+        let buf = b"first\n\nthird line";
+        assert_eq!(line_at(buf, 0), Some(0..5));
+        assert_eq!(line_at(buf, 3), Some(0..5));
+        // A newline belongs to the line it ends
+        assert_eq!(line_at(buf, 5), Some(0..5));
+        assert_eq!(line_at(buf, 6), Some(6..6));
+        assert_eq!(line_at(buf, 7), Some(7..buf.len()));
+        assert_eq!(line_at(buf, buf.len()), Some(7..buf.len()));
+        assert_eq!(line_at(buf, buf.len() + 1), None);
+    }
+
     #[test]
     fn test_calc_line_ranges() {
         let line_starts = calc_line_ranges(b"asdf\n0123456789\n012345678901", 10);
@@ -466,7 +1066,8 @@ mod test {
         let mut buffer = TerminalBuffer::new(5, 5);
         buffer.insert_data(&CursorPos { x: 0, y: 0 }, b"012\n3456789");
         buffer.clear_forwards(&CursorPos { x: 1, y: 1 });
-        assert_eq!(buffer.data().visible, b"012\n3");
+        // Same amount of lines should be present before and after clear
+        assert_eq!(buffer.data().visible, b"012\n3\n");
     }
 
     #[test]
@@ -477,28 +1078,53 @@ mod test {
         assert_eq!(buffer.data().visible, &[]);
     }
 
+    #[test]
+    fn test_cursor_after_trailing_newline() {
+        // Nothing ends at or after the position past a trailing newline
+        assert_eq!(
+            buf_to_cursor_pos(b"abc\n", 5, 5, 4),
+            Err(InvalidBufPos {
+                buf_pos: 4,
+                buf_len: 4
+            })
+        );
+        assert_eq!(buf_to_cursor_pos_or_last_row(b"abc\n", 5, 5, 4), CursorPos { x: 0, y: 1 });
+        assert_eq!(buf_to_cursor_pos_or_last_row(b"", 5, 5, 0), CursorPos { x: 0, y: 0 });
+        assert_eq!(buf_to_cursor_pos_or_last_row(b"a\nb\n", 5, 2, 4), CursorPos { x: 0, y: 1 });
+
+        // Which is where a write ending in one leaves it
+        for auto_wrap in [true, false] {
+            let mut buffer = TerminalBuffer::new(5, 5);
+            buffer.set_auto_wrap(auto_wrap);
+            buffer.insert_data(&CursorPos { x: 0, y: 0 }, b"abc");
+            let response = buffer.insert_data(&CursorPos { x: 0, y: 0 }, b"ab\n");
+            assert_eq!(buffer.data().visible, b"ab\n");
+            assert_eq!(response.new_cursor_pos, CursorPos { x: 0, y: 1 });
+        }
+    }
+
     #[test]
     fn test_terminal_buffer_overwrite_early_newline() {
         let mut buffer = TerminalBuffer::new(5, 5);
         buffer.insert_data(&CursorPos { x: 0, y: 0 }, b"012\n3456789");
-        assert_eq!(buffer.data().visible, b"012\n3456789\n");
+        assert_eq!(buffer.data().visible, b"012\n3456789");
 
         // Cursor pos should be calculated based off wrapping at column 5, but should not result in
         // an extra newline
         buffer.insert_data(&CursorPos { x: 2, y: 1 }, b"test");
-        assert_eq!(buffer.data().visible, b"012\n34test9\n");
+        assert_eq!(buffer.data().visible, b"012\n34test9");
     }
 
     #[test]
     fn test_terminal_buffer_overwrite_no_newline() {
         let mut buffer = TerminalBuffer::new(5, 5);
         buffer.insert_data(&CursorPos { x: 0, y: 0 }, b"0123456789");
-        assert_eq!(buffer.data().visible, b"0123456789\n");
+        assert_eq!(buffer.data().visible, b"0123456789");
 
         // Cursor pos should be calculated based off wrapping at column 5, but should not result in
         // an extra newline
         buffer.insert_data(&CursorPos { x: 2, y: 1 }, b"test");
-        assert_eq!(buffer.data().visible, b"0123456test\n");
+        assert_eq!(buffer.data().visible, b"0123456test");
     }
 
     #[test]
@@ -507,26 +1133,294 @@ mod test {
         // neline between lines 1 and 2
         let mut buffer = TerminalBuffer::new(5, 5);
         buffer.insert_data(&CursorPos { x: 0, y: 0 }, b"01234\n56789");
-        assert_eq!(buffer.data().visible, b"01234\n56789\n");
+        assert_eq!(buffer.data().visible, b"01234\n56789");
 
         buffer.insert_data(&CursorPos { x: 2, y: 1 }, b"test");
-        assert_eq!(buffer.data().visible, b"01234\n56test\n");
+        assert_eq!(buffer.data().visible, b"01234\n56test");
     }
 
     #[test]
     fn test_terminal_buffer_insert_unallocated_data() {
         let mut buffer = TerminalBuffer::new(10, 10);
         buffer.insert_data(&CursorPos { x: 4, y: 5 }, b"hello world");
-        assert_eq!(buffer.data().visible, b"\n\n\n\n\n    hello world\n");
+        assert_eq!(buffer.data().visible, b"\n\n\n\n\n    hello world");
 
         buffer.insert_data(&CursorPos { x: 3, y: 2 }, b"hello world");
         assert_eq!(
             buffer.data().visible,
-            b"\n\n   hello world\n\n\n    hello world\n"
+            b"\n\n   hello world\n\n\n    hello world"
         );
     }
 
+    #[test]
+    fn test_clear_line_backwards() {
+        let mut buffer = TerminalBuffer::new(10, 5);
+        buffer.insert_data(&CursorPos { x: 0, y: 0 }, b"abcdef\nghi");
+
+        // Third column, the cursor cell is cleared too
+        let range = buffer.clear_line_backwards(&CursorPos { x: 2, y: 0 });
+        assert_eq!(range, Some(0..3));
+        assert_eq!(buffer.data().visible, b"   def\nghi");
+
+        // Past the end of the line only the existing content is cleared
+        let range = buffer.clear_line_backwards(&CursorPos { x: 8, y: 1 });
+        assert_eq!(range, Some(7..10));
+        assert_eq!(buffer.data().visible, b"   def\n   ");
+    }
+
+    #[test]
+    fn test_clear_backwards() {
+        let mut buffer = TerminalBuffer::new(10, 5);
+        buffer.insert_data(&CursorPos { x: 0, y: 0 }, b"abc\ndefgh\nijk");
+
+        let range = buffer.clear_backwards(&CursorPos { x: 2, y: 1 });
+        assert_eq!(range, Some(0..7));
+        assert_eq!(buffer.data().visible, b"   \n   gh\nijk");
+    }
+
+    #[test]
+    fn test_row_wraps() {
+        let mut buffer = TerminalBuffer::new(5, 5);
+        buffer.insert_data(&CursorPos { x: 0, y: 0 }, b"0123456\nab\n01234\nc");
+        assert!(buffer.row_wraps(0));
+        assert!(!buffer.row_wraps(1));
+        assert!(!buffer.row_wraps(2));
+        // Exactly filling a row before a newline is not a wrap
+        assert!(!buffer.row_wraps(3));
+        assert!(!buffer.row_wraps(4));
+        assert!(!buffer.row_wraps(10));
+
+        // The wraps follow the width, newlines stay where they were written
+        buffer.set_win_size(10, 5, &CursorPos { x: 1, y: 4 });
+        assert_eq!(buffer.visible_rows(), [&b"0123456"[..], b"ab", b"01234", b"c"]);
+        assert!((0..4).all(|row| !buffer.row_wraps(row)));
+        buffer.set_win_size(3, 10, &CursorPos { x: 1, y: 3 });
+        let wraps = (0..8).map(|row| buffer.row_wraps(row)).collect::<Vec<_>>();
+        assert_eq!(wraps, [true, true, false, false, true, false, false, false]);
+
+        // Opening a row under one that wraps breaks the line there
+        buffer.scroll_up(0..2);
+        assert_eq!(buffer.visible_rows(), [&b"345"[..], b"", b"6", b"ab", b"012", b"34", b"c"]);
+        assert!(!buffer.row_wraps(0));
+    }
+
+    #[test]
+    fn test_scroll_up() {
+        let mut buffer = TerminalBuffer::new(10, 3);
+        buffer.insert_data(&CursorPos { x: 0, y: 0 }, b"a\nb\nc");
+
+        buffer.scroll_up(0..3);
+        assert_eq!(buffer.data().scrollback, b"a");
+        assert_eq!(buffer.data().visible, b"b\nc\n");
+
+        // Rows that do not exist yet are created before scrolling
+        let mut buffer = TerminalBuffer::new(10, 3);
+        buffer.insert_data(&CursorPos { x: 0, y: 0 }, b"a");
+        buffer.scroll_up(0..3);
+        assert_eq!(buffer.data().scrollback, b"a");
+        assert_eq!(buffer.data().visible, b"\n\n");
+    }
+
+    #[test]
+    fn test_scroll_up_region() {
+        let mut buffer = TerminalBuffer::new(10, 4);
+        buffer.insert_data(&CursorPos { x: 0, y: 0 }, b"a\nb\nc\nd");
+
+        let response = buffer.scroll_up(1..3);
+        assert_eq!(response.deleted_range, Some(2..4));
+        assert_eq!(buffer.data().scrollback, b"");
+        assert_eq!(buffer.data().visible, b"a\nc\n\nd");
+
+        // Soft wrapped rows keep wrapping the same way
+        let mut buffer = TerminalBuffer::new(4, 4);
+        buffer.insert_data(&CursorPos { x: 0, y: 0 }, b"aaaabbbbcc\nd");
+        buffer.scroll_up(1..3);
+        assert_eq!(buffer.data().visible, b"aaaacc\n\nd");
+    }
+
+    #[test]
+    fn test_auto_wrap_enabled() {
+        let mut buffer = TerminalBuffer::new(5, 5);
+        assert!(buffer.auto_wrap());
+
+        let response = buffer.insert_data(&CursorPos { x: 0, y: 0 }, b"0123456");
+        assert_eq!(buffer.data().visible, b"0123456");
+        assert_eq!(response.new_cursor_pos, CursorPos { x: 2, y: 1 });
+    }
+
+    #[test]
+    fn test_auto_wrap_disabled() {
+        let mut buffer = TerminalBuffer::new(5, 5);
+        buffer.set_auto_wrap(false);
+
+        let response = buffer.insert_data(&CursorPos { x: 0, y: 0 }, b"0123456");
+        assert_eq!(buffer.data().visible, b"01236");
+        assert_eq!(response.written_range, 0..5);
+        assert_eq!(response.new_cursor_pos, CursorPos { x: 4, y: 0 });
+
+        // Further writes keep overwriting the last column
+        let response = buffer.insert_data(&response.new_cursor_pos, b"xy");
+        assert_eq!(buffer.data().visible, b"0123y");
+        assert_eq!(response.new_cursor_pos, CursorPos { x: 4, y: 0 });
+
+        // Writing past the margin on a line with content after it leaves the next line alone
+        buffer.insert_data(&CursorPos { x: 0, y: 1 }, b"abc");
+        buffer.insert_data(&CursorPos { x: 3, y: 0 }, b"WXYZ");
+        assert_eq!(buffer.data().visible, b"012WZ\nabc");
+    }
 
+    #[test]
+    fn test_pending_wrap() {
+        let mut buffer = TerminalBuffer::new(5, 4);
+        let response = buffer.insert_data(&CursorPos { x: 0, y: 0 }, b"12345");
+        assert_eq!(response.new_cursor_pos, CursorPos { x: 4, y: 0 });
+
+        // A newline from the last column moves down one row, without a blank row in between
+        let response = buffer.insert_data(&CursorPos { x: 4, y: 1 }, b"6");
+        assert_eq!(buffer.visible_rows(), [&b"12345"[..], b"    6"]);
+        assert_eq!(response.new_cursor_pos, CursorPos { x: 4, y: 1 });
+
+        // Writing on wraps to the next row
+        let response = buffer.insert_data(&response.new_cursor_pos, b"7");
+        assert_eq!(buffer.visible_rows(), [&b"12345"[..], b"    6", b"7"]);
+        assert_eq!(response.new_cursor_pos, CursorPos { x: 1, y: 2 });
+
+        // Anything else done to the buffer cancels the wrap
+        let response = buffer.insert_data(&CursorPos { x: 0, y: 3 }, b"abcde");
+        buffer.clear_line_forwards(&response.new_cursor_pos);
+        buffer.insert_data(&response.new_cursor_pos, b"f");
+        assert_eq!(buffer.visible_rows()[3], b"abcdf");
+    }
+
+    #[test]
+    fn test_insert_blanks() {
+        let mut buffer = TerminalBuffer::new(5, 5);
+        buffer.insert_data(&CursorPos { x: 0, y: 0 }, b"abc\n01234");
+
+        let response = buffer.insert_blanks(&CursorPos { x: 1, y: 0 }, 1, 5).unwrap();
+        assert_eq!(buffer.data().visible, b"a bc\n01234");
+        assert_eq!(response.insertion_range, 1..2);
+        assert_eq!(response.deleted_range, None);
+
+        // A full row loses its end
+        let response = buffer.insert_blanks(&CursorPos { x: 3, y: 1 }, 4, 5).unwrap();
+        assert_eq!(buffer.data().visible, b"a bc\n012  ");
+        assert_eq!(response.insertion_range, 8..10);
+        assert_eq!(response.deleted_range, Some(10..12));
+
+        // Nothing to push at the end of a row
+        assert!(buffer.insert_blanks(&CursorPos { x: 4, y: 0 }, 1, 5).is_none());
+        assert!(buffer.insert_blanks(&CursorPos { x: 0, y: 3 }, 1, 5).is_none());
+    }
+
+    #[test]
+    fn test_blanks_and_deletes_stop_at_margin() {
+        let mut buffer = TerminalBuffer::new(8, 5);
+        buffer.insert_data(&CursorPos { x: 0, y: 0 }, b"abcdefgh\nabcdefgh\nabc");
+
+        let response = buffer.insert_blanks(&CursorPos { x: 1, y: 0 }, 2, 5).unwrap();
+        assert_eq!(buffer.visible_rows()[0], b"a  bcfgh");
+        assert_eq!(response.insertion_range, 1..3);
+        assert_eq!(response.deleted_range, Some(5..7));
+
+        let response = buffer.delete_chars(&CursorPos { x: 1, y: 1 }, 2, 5).unwrap();
+        assert_eq!(buffer.visible_rows()[1], b"ade  fgh");
+        assert_eq!(response.deleted_range, 10..12);
+        assert_eq!(response.insertion_range, Some(12..14));
+
+        // Nothing past the margin to keep in place
+        let response = buffer.delete_chars(&CursorPos { x: 0, y: 2 }, 9, 5).unwrap();
+        assert_eq!(buffer.data().visible, b"a  bcfgh\nade  fgh\n");
+        assert_eq!(response.deleted_range, 18..21);
+        assert_eq!(response.insertion_range, None);
+        assert!(buffer.delete_chars(&CursorPos { x: 0, y: 2 }, 1, 5).is_none());
+    }
+
+    #[test]
+    fn test_insert_then_delete_is_identity() {
+        // Full rows, one wrapping onto the next, short ones and an empty one
+        let text = b"abcdefgh\nabcdefghabc\nab\n";
+        for right_margin in 1..=8 {
+            for x in 0..right_margin {
+                for y in 0..5 {
+                    for n in 1..10 {
+                        let mut buffer = TerminalBuffer::new(8, 5);
+                        buffer.insert_data(&CursorPos { x: 0, y: 0 }, text);
+                        let before = buffer.buf.clone();
+
+                        let cursor = CursorPos { x, y };
+                        let Some(inserted) = buffer.insert_blanks(&cursor, n, right_margin) else {
+                            continue;
+                        };
+                        buffer.delete_chars(&cursor, n, right_margin).unwrap();
+
+                        // Text pushed past the margin does not come back
+                        if inserted.deleted_range.is_none() {
+                            assert_eq!(
+                                buffer.buf, before,
+                                "{n} at {x},{y} with margin {right_margin}"
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_delete_keeps_wrap() {
+        let mut buffer = TerminalBuffer::new(5, 5);
+        buffer.insert_data(&CursorPos { x: 0, y: 0 }, b"abcdefgh");
+
+        // The next row does not move up
+        let response = buffer.delete_chars(&CursorPos { x: 1, y: 0 }, 2, 5).unwrap();
+        assert_eq!(buffer.data().visible, b"ade  fgh");
+        assert_eq!(response.deleted_range, 1..3);
+        assert_eq!(response.insertion_range, Some(3..5));
+        assert!(buffer.row_wraps(0));
+    }
+
+    #[test]
+    fn test_erase_chars() {
+        let mut buffer = TerminalBuffer::new(5, 5);
+        buffer.insert_data(&CursorPos { x: 0, y: 0 }, b"abcdefgh");
+
+        assert_eq!(buffer.erase_chars(&CursorPos { x: 3, y: 0 }, 9, 5), Some(3..5));
+        assert_eq!(buffer.data().visible, b"abc  fgh");
+        assert_eq!(buffer.erase_chars(&CursorPos { x: 1, y: 1 }, 1, 5), Some(6..7));
+        assert_eq!(buffer.data().visible, b"abc  f h");
+        assert!(buffer.row_wraps(0));
+
+        // Nothing from the right margin on is erased
+        assert_eq!(buffer.erase_chars(&CursorPos { x: 0, y: 0 }, 9, 2), Some(0..2));
+        assert_eq!(buffer.data().visible, b"  c  f h");
+
+        // Past the end of the text there is nothing to erase
+        assert_eq!(buffer.erase_chars(&CursorPos { x: 3, y: 1 }, 1, 5), None);
+        assert_eq!(buffer.erase_chars(&CursorPos { x: 0, y: 3 }, 1, 5), None);
+    }
+
+    #[test]
+    fn test_resize_reflows_wrapped_lines() {
+        let mut buffer = TerminalBuffer::new(10, 5);
+        let response = buffer.insert_data(&CursorPos { x: 0, y: 0 }, b"abcdefghijklmnopqrstuvwxyz0123");
+        assert_eq!(buffer.visible_rows().len(), 3);
+        assert_eq!(response.new_cursor_pos, CursorPos { x: 9, y: 2 });
+
+        let response = buffer.set_win_size(15, 5, &response.new_cursor_pos);
+        assert_eq!(
+            buffer.visible_rows(),
+            [&b"abcdefghijklmno"[..], b"pqrstuvwxyz0123"]
+        );
+        // Still on the last character, waiting to wrap
+        assert_eq!(response.new_cursor_pos, CursorPos { x: 14, y: 1 });
+        assert_eq!(response.insertion_range, 30..30);
+
+        // Mid line the cursor stays on the same character
+        let response = buffer.set_win_size(4, 10, &CursorPos { x: 7, y: 1 });
+        assert_eq!(response.new_cursor_pos, CursorPos { x: 2, y: 5 });
+        assert_eq!(buffer.visible_rows().len(), 8);
+    }
 
     #[test]
     fn test_canvas_scrolling() {
@@ -548,7 +1442,48 @@ mod test {
         let mut response = canvas.insert_data(&response.new_cursor_pos, b"5678");
         crlf(&mut response.new_cursor_pos);
 
-        assert_eq!(canvas.data().scrollback, b"asdf\n");
-        assert_eq!(canvas.data().visible, b"xyzw\n1234\n5678\n");
+        assert_eq!(canvas.data().scrollback, b"asdf");
+        assert_eq!(canvas.data().visible, b"xyzw\n1234\n5678");
+    }
+
+    #[test]
+    fn test_scrollback_limit() {
+        let mut canvas = TerminalBuffer::new(10, 3);
+        canvas.set_scrollback_limit(100);
+
+        let mut cursor_pos = CursorPos { x: 0, y: 0 };
+        for i in 0..20000 {
+            let response = canvas.insert_data(&cursor_pos, i.to_string().as_bytes());
+            cursor_pos = response.new_cursor_pos;
+            cursor_pos.y += 1;
+            cursor_pos.x = 0;
+        }
+
+        let data = canvas.data();
+        let scrollback_lines = data.scrollback.split(|b| *b == b'\n').collect::<Vec<_>>();
+        assert_eq!(scrollback_lines.len(), 100);
+        assert_eq!(scrollback_lines[0], b"19897");
+        assert_eq!(scrollback_lines[99], b"19996");
+        assert_eq!(data.visible, b"19997\n19998\n19999");
+    }
+
+    #[test]
+    fn test_scrollback_limit_splits_long_lines() {
+        let mut canvas = TerminalBuffer::new(10, 3);
+        canvas.set_scrollback_limit(2);
+
+        // One line that never ends is trimmed a row at a time
+        let line = (0..100_000).map(|i| b'a' + (i / 10 % 26) as u8).collect::<Vec<_>>();
+        let response = canvas.insert_data(&CursorPos { x: 0, y: 0 }, &line);
+        assert_eq!(response.trimmed_range, Some(0..line.len() - 50));
+        let data = canvas.data();
+        assert_eq!(data.scrollback, &line[line.len() - 50..line.len() - 30]);
+        assert_eq!(data.visible, &line[line.len() - 30..]);
+
+        // Writing on from the end keeps the limit, and the next line starts on a new row
+        canvas.insert_data(&response.new_cursor_pos, b"b\nc");
+        let data = canvas.data();
+        assert_eq!(data.scrollback, &line[line.len() - 30..line.len() - 10]);
+        assert_eq!(data.visible, [&line[line.len() - 10..], b"b\nc"].concat());
     }
 }
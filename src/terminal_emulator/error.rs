@@ -0,0 +1,59 @@
+use std::fmt;
+use std::io;
+
+/// Errors that can occur while setting up or talking to the shell
+#[derive(Debug)]
+pub enum TerminalError {
+    /// Opening the pseudo terminal pair failed
+    OpenPty(io::Error),
+    /// The shell could not be forked/executed
+    Fork(io::Error),
+    /// An ioctl on the pty failed (e.g. setting the window size)
+    Ioctl(io::Error),
+    /// Putting the pty into non-blocking mode failed
+    NonBlocking(io::Error),
+    /// The child closed its end of the pty, usually because it exited
+    ChildExited,
+    /// Any other I/O failure on the pty
+    Io(io::Error),
+}
+
+impl TerminalError {
+    /// Classify an error returned from reading/writing the pty
+    ///
+    /// Linux reports EIO on the master once every slave fd is closed, which is how we learn
+    /// that the shell has gone away
+    pub(crate) fn from_pty_io(e: io::Error) -> TerminalError {
+        if e.raw_os_error() == Some(libc::EIO) {
+            TerminalError::ChildExited
+        } else {
+            TerminalError::Io(e)
+        }
+    }
+}
+
+impl fmt::Display for TerminalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TerminalError::OpenPty(e) => write!(f, "Failed to open pty: {e}"),
+            TerminalError::Fork(e) => write!(f, "Failed to spawn shell: {e}"),
+            TerminalError::Ioctl(e) => write!(f, "Pty ioctl failed: {e}"),
+            TerminalError::NonBlocking(e) => write!(f, "Failed to make pty non-blocking: {e}"),
+            TerminalError::ChildExited => write!(f, "Shell exited"),
+            TerminalError::Io(e) => write!(f, "Pty I/O error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for TerminalError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TerminalError::OpenPty(e)
+            | TerminalError::Fork(e)
+            | TerminalError::Ioctl(e)
+            | TerminalError::NonBlocking(e)
+            | TerminalError::Io(e) => Some(e),
+            TerminalError::ChildExited => None,
+        }
+    }
+}
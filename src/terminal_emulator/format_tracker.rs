@@ -1,5 +1,7 @@
 use std::ops::Range;
-use super::{BlinkMode, CursorState, TerminalColor};
+use std::sync::Arc;
+use super::ansi::PromptMarkKind;
+use super::{CursorState, TerminalColor, TerminalImage, TextAttributes};
 
 
 struct ColorRangeAdjustment {
@@ -73,9 +75,7 @@ fn adjust_existing_format_range(
                 end: existing_elem.end,
                 fg_color: existing_elem.fg_color,  // Changed
                 bg_color: existing_elem.bg_color,  // Changed
-                bold: existing_elem.bold,
-                italic: existing_elem.italic,
-                blink: existing_elem.blink,
+                attributes: existing_elem.attributes,
             });
         }
 
@@ -120,36 +120,50 @@ fn adjust_existing_format_ranges(existing: &mut Vec<FormatTag>, range: &Range<us
     }
 
     delete_items_from_vec(to_delete, existing);
-    existing.extend(to_push);
-}
-pub fn buffer_index_to_cursor_pos(buf: &[u8], index: usize) -> (usize, usize) {
-    let mut y = 0;
-    let mut current = 0;
-    for line in buf.split(|&b| b == b'\n') {
-        let line_len = line.len();
-        if current + line_len >= index {
-            return (index - current, y);
-        }
-        current += line_len + 1; // +1 for the newline character
-        y += 1;
+    for item in to_push {
+        insert_sorted(existing, item);
     }
-    (0, y)
 }
 
+/// Insert keeping tags ordered by start. Tags never overlap so the start alone is enough
+fn insert_sorted(tags: &mut Vec<FormatTag>, tag: FormatTag) {
+    let idx = tags.partition_point(|existing| existing.start < tag.start);
+    tags.insert(idx, tag);
+}
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct FormatTag {
     pub start: usize,
     pub end: usize,
-    pub blink: bool,
     pub fg_color: TerminalColor,  // Changed from 'color'
     pub bg_color: TerminalColor,  // Added
-    pub bold: bool,
-    pub italic: bool,
+    pub attributes: TextAttributes,
+}
+
+/// An image drawn over the cells below and right of the character at `start`
+#[derive(Clone, Debug)]
+pub(crate) struct ImageTag {
+    pub start: usize,
+    pub id: u64,
+    pub cells_wide: usize,
+    pub cells_high: usize,
+    pub image: Arc<TerminalImage>,
+}
+
+/// Shell integration boundary reported with OSC 133, at buffer index `start`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PromptMark {
+    pub start: usize,
+    pub kind: PromptMarkKind,
 }
 
 pub(crate) struct FormatTracker {
     color_info: Vec<FormatTag>,
+    /// Images move along with the text they cover, and go away once the character they are
+    /// anchored to is overwritten or deleted
+    images: Vec<ImageTag>,
+    /// Sorted by start. Unlike images these survive being overwritten, shells redraw prompts
+    prompt_marks: Vec<PromptMark>,
 }
 
 impl FormatTracker {
@@ -160,10 +174,10 @@ impl FormatTracker {
                 end: usize::MAX,
                 fg_color: TerminalColor::Default,  // Changed
                 bg_color: TerminalColor::Default,  // Added
-                bold: false,
-                italic: false,
-                blink: false,
+                attributes: TextAttributes::default(),
             }],
+            images: Vec::new(),
+            prompt_marks: Vec::new(),
         }
 
     }
@@ -173,10 +187,10 @@ impl FormatTracker {
             end: usize::MAX,
             fg_color: TerminalColor::Default,  // Changed
             bg_color: TerminalColor::Default,  // Added
-            bold: false,
-            italic: false,
-            blink: false,
+            attributes: TextAttributes::default(),
         }];
+        self.images.clear();
+        self.prompt_marks.clear();
     }
 
     /// Move all tags > range.start to range.start + range.len
@@ -197,24 +211,54 @@ impl FormatTracker {
                 info.end += range_len;
             }
         }
+
+        for image in &mut self.images {
+            if image.start >= range.start {
+                image.start += range_len;
+            }
+        }
+
+        for mark in &mut self.prompt_marks {
+            if mark.start >= range.start {
+                mark.start += range_len;
+            }
+        }
     }
 
     pub(crate) fn push_range(&mut self, cursor: &CursorState, range: Range<usize>) {
         adjust_existing_format_ranges(&mut self.color_info, &range);
+        self.images.retain(|image| !range.contains(&image.start));
 
-        self.color_info.push(FormatTag {
+        let tag = FormatTag {
             start: range.start,
             end: range.end,
             fg_color: cursor.fg_color,  // Changed
             bg_color: cursor.bg_color,  // Changed
-            bold: cursor.bold,
-            italic: cursor.italic,
-            blink: cursor.blink_mode != BlinkMode::NoBlink,
+            attributes: cursor.attributes,
+        };
+        insert_sorted(&mut self.color_info, tag);
+        self.coalesce();
+    }
+
+    /// Merge neighboring tags that format text identically into a single tag
+    pub(crate) fn coalesce(&mut self) {
+        self.color_info.dedup_by(|next, prev| {
+            let same_format = prev.fg_color == next.fg_color
+                && prev.bg_color == next.bg_color
+                && prev.attributes == next.attributes;
+            if same_format && prev.end == next.start {
+                prev.end = next.end;
+                true
+            } else {
+                false
+            }
         });
+    }
 
-        // FIXME: Insertion sort
-        // FIXME: Merge adjacent
-        self.color_info.sort_by(|a, b| a.start.cmp(&b.start));
+    /// The tag formatting the character at buffer index `index`
+    pub(crate) fn tag_at(&self, index: usize) -> Option<&FormatTag> {
+        let idx = self.color_info.partition_point(|tag| tag.end <= index);
+        self.color_info.get(idx).filter(|tag| tag.start <= index)
     }
 
     pub(crate) fn tags(&self) -> Vec<FormatTag> {
@@ -261,8 +305,166 @@ impl FormatTracker {
         for i in to_delete.into_iter().rev() {
             self.color_info.remove(i);
         }
+        self.coalesce();
+
+        self.images.retain(|image| !range.contains(&image.start));
+        for image in &mut self.images {
+            if image.start >= range.end {
+                image.start -= del_size;
+            }
+        }
+
+        // Marks in deleted text move to where it was, e.g. to the top when scrollback is trimmed
+        for mark in &mut self.prompt_marks {
+            if mark.start >= range.end {
+                mark.start -= del_size;
+            } else if mark.start > range.start {
+                mark.start = range.start;
+            }
+        }
+        self.prompt_marks.dedup();
+    }
+
+    pub(crate) fn push_image(&mut self, image: ImageTag) {
+        self.images.push(image);
+    }
+
+    pub(crate) fn images(&self) -> &[ImageTag] {
+        &self.images
+    }
+
+    pub(crate) fn push_prompt_mark(&mut self, mark: PromptMark) {
+        let idx = self.prompt_marks.partition_point(|m| m.start <= mark.start);
+        self.prompt_marks.insert(idx, mark);
+    }
+
+    pub(crate) fn prompt_marks(&self) -> &[PromptMark] {
+        &self.prompt_marks
     }
 
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn cursor_with_color(fg_color: TerminalColor) -> CursorState {
+        CursorState {
+            fg_color,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_push_range_keeps_tags_sorted() {
+        let mut tracker = FormatTracker::new();
+        tracker.push_range(&cursor_with_color(TerminalColor::ForegroundRed), 10..20);
+        tracker.push_range(&cursor_with_color(TerminalColor::ForegroundBlue), 0..5);
+        tracker.push_range(&cursor_with_color(TerminalColor::ForegroundGreen), 12..14);
+
+        let ranges = tracker
+            .tags()
+            .iter()
+            .map(|tag| (tag.start..tag.end, tag.fg_color))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ranges,
+            [
+                (0..5, TerminalColor::ForegroundBlue),
+                (5..10, TerminalColor::Default),
+                (10..12, TerminalColor::ForegroundRed),
+                (12..14, TerminalColor::ForegroundGreen),
+                (14..20, TerminalColor::ForegroundRed),
+                (20..usize::MAX, TerminalColor::Default),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_coalesce_repeated_colors() {
+        let mut tracker = FormatTracker::new();
+        let colors = [TerminalColor::ForegroundRed, TerminalColor::ForegroundGreen];
 
+        // Runs of 1000 single character writes with the same color, as if every character came
+        // with its own SGR sequence
+        for i in 0..10000 {
+            let cursor = cursor_with_color(colors[(i / 1000) % 2]);
+            tracker.push_range(&cursor, i..i + 1);
+        }
+
+        let tags = tracker.tags();
+        assert_eq!(tags.len(), 11);
+        for (i, tag) in tags.iter().take(10).enumerate() {
+            assert_eq!(tag.start..tag.end, i * 1000..(i + 1) * 1000);
+            assert_eq!(tag.fg_color, colors[i % 2]);
+        }
+        assert_eq!(tags[10].start..tags[10].end, 10000..usize::MAX);
+    }
+
+    #[test]
+    fn test_images_follow_text() {
+        let mut tracker = FormatTracker::new();
+        let image = |start| ImageTag {
+            start,
+            id: start as u64,
+            cells_wide: 1,
+            cells_high: 1,
+            image: Arc::new(TerminalImage {
+                width: 0,
+                height: 0,
+                rgba: Vec::new(),
+            }),
+        };
+        tracker.push_image(image(10));
+        tracker.push_image(image(20));
+        tracker.push_image(image(30));
+
+        tracker.push_range_adjustment(5..7);
+        tracker.delete_range(0..4);
+        tracker.push_range(&CursorState::default(), 18..19);
+        let starts = tracker.images().iter().map(|i| i.start).collect::<Vec<_>>();
+        assert_eq!(starts, [8, 28]);
+
+        tracker.reset();
+        assert!(tracker.images().is_empty());
+    }
+
+    #[test]
+    fn test_prompt_marks_follow_text() {
+        let mut tracker = FormatTracker::new();
+        for start in [10, 20, 30] {
+            tracker.push_prompt_mark(PromptMark {
+                start,
+                kind: PromptMarkKind::PromptStart,
+            });
+        }
+
+        // Overwriting keeps marks, deleting text under them moves them to the start of the hole
+        tracker.push_range(&CursorState::default(), 18..21);
+        tracker.push_range_adjustment(5..7);
+        tracker.delete_range(0..15);
+        let starts = tracker.prompt_marks().iter().map(|m| m.start).collect::<Vec<_>>();
+        assert_eq!(starts, [0, 7, 17]);
+
+        // Marks squashed together by a delete are merged
+        tracker.delete_range(0..10);
+        let starts = tracker.prompt_marks().iter().map(|m| m.start).collect::<Vec<_>>();
+        assert_eq!(starts, [0, 7]);
+    }
+
+    #[test]
+    fn test_coalesce_after_delete() {
+        let mut tracker = FormatTracker::new();
+        let red = cursor_with_color(TerminalColor::ForegroundRed);
+        tracker.push_range(&red, 0..5);
+        tracker.push_range(&cursor_with_color(TerminalColor::ForegroundBlue), 5..10);
+        tracker.push_range(&red, 10..15);
+
+        // Removing the blue section leaves the red ones touching
+        tracker.delete_range(5..10);
+        let tags = tracker.tags();
+        assert_eq!(tags.len(), 2);
+        assert_eq!(tags[0].start..tags[0].end, 0..10);
+        assert_eq!(tags[0].fg_color, TerminalColor::ForegroundRed);
+    }
+}
@@ -0,0 +1,1389 @@
+//! Screen stored as rows of cells, with scrollback in a ring buffer
+//!
+//! `Screen` in screen.rs keeps everything in one `Vec<u8>` and the formatting in a
+//! [`FormatTracker`](super::format_tracker::FormatTracker) next to it, so writes move everything
+//! after them and line wrapping is worked out again on every call. Here a write stores cells in
+//! place and scrolling moves rows, neither depends on how much output came before. The emulator
+//! runs on this instead when built with the `grid` feature
+//!
+//! Columns are bytes like in the buffer, a multi-byte character takes up as many cells as it has
+//! bytes and the cells after the first one hold `'\0'`. That way [`Grid::data`] lays out text the
+//! same way and everything reading it does not have to care which one is in use
+
+use std::cell::OnceCell;
+use std::collections::VecDeque;
+use std::ops::Range;
+use std::sync::Arc;
+
+use super::ansi::PromptMarkKind;
+use super::buffer::{rows, text, trim_line_ends, DEFAULT_SCROLLBACK_LINES};
+use super::{
+    split_format_data_for_scrollback, Cell, CursorPos, CursorState, FormatTag, ImagePlacement,
+    PromptMark, TerminalColor, TerminalData, TerminalImage, TextAttributes,
+};
+
+/// Stands in for each column of input that was not valid UTF-8, it never is so it reads back as
+/// the replacement character like the raw bytes the buffer keeps
+const INVALID_BYTE: u8 = 0xff;
+
+fn blank_cell() -> Cell {
+    Cell {
+        ch: ' ',
+        fg: TerminalColor::Default,
+        bg: TerminalColor::Default,
+        attrs: TextAttributes::default(),
+    }
+}
+
+fn formatted_cell(ch: char, format: &CursorState) -> Cell {
+    Cell {
+        ch,
+        fg: format.fg_color,
+        bg: format.bg_color,
+        attrs: format.attributes,
+    }
+}
+
+/// Characters of `data` with the number of columns they take up. Every byte that is not part of
+/// a valid character is a column of its own
+fn decode(data: &[u8]) -> Vec<(char, usize)> {
+    let mut chars = Vec::new();
+    for chunk in data.utf8_chunks() {
+        chars.extend(chunk.valid().chars().map(|ch| (ch, ch.len_utf8())));
+        chars.extend(chunk.invalid().iter().map(|_| (char::REPLACEMENT_CHARACTER, 1)));
+    }
+    chars
+}
+
+/// Length of the start of a character at the end of `data` that the rest has not arrived for
+fn incomplete_tail_len(data: &[u8]) -> usize {
+    let Some(start) = data.iter().rposition(|b| b & 0xc0 != 0x80) else {
+        return 0;
+    };
+    let needed = match data[start] {
+        0xc2..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf4 => 4,
+        _ => return 0,
+    };
+    let tail_len = data.len() - start;
+    if tail_len < needed {
+        tail_len
+    } else {
+        0
+    }
+}
+
+/// An image drawn over the cells below and right of the cell in column `x` of its row
+#[derive(Clone, Debug)]
+struct RowImage {
+    x: usize,
+    id: u64,
+    cells_wide: usize,
+    cells_high: usize,
+    image: Arc<TerminalImage>,
+}
+
+#[derive(Clone, Debug, Default)]
+struct Row {
+    /// Cells that were written, the columns after them are blank
+    cells: Vec<Cell>,
+    /// Text ran past the right margin and continues on the next row
+    wrapped: bool,
+    /// Images go away once the cell they are anchored to is overwritten or cleared
+    images: Vec<RowImage>,
+    /// OSC 133 marks by column. Unlike images these survive being overwritten, shells redraw
+    /// prompts
+    prompt_marks: Vec<(usize, PromptMarkKind)>,
+}
+
+impl Row {
+    /// Blank a character that is cut in two at column `x`, half of one is no use to anyone
+    fn break_char_at(&mut self, x: usize) {
+        if x == 0 || self.cells.get(x).is_none_or(|cell| cell.ch != '\0') {
+            return;
+        }
+        let start = self.cells[..x]
+            .iter()
+            .rposition(|cell| cell.ch != '\0')
+            .unwrap_or(0);
+        let end = self.cells[x..]
+            .iter()
+            .position(|cell| cell.ch != '\0')
+            .map_or(self.cells.len(), |len| x + len);
+        for cell in &mut self.cells[start..end] {
+            cell.ch = ' ';
+        }
+    }
+
+    /// Make sure there are cells up to `len`, new ones are blanks formatted like `fill`
+    fn pad(&mut self, len: usize, fill: &CursorState) {
+        if self.cells.len() < len {
+            self.cells.resize(len, formatted_cell(' ', fill));
+        }
+    }
+
+    /// Blank the cells in `range` that exist, formatted like `erased`
+    fn blank(&mut self, range: Range<usize>, erased: &CursorState) {
+        let end = range.end.min(self.cells.len());
+        let start = range.start.min(end);
+        self.break_char_at(start);
+        self.break_char_at(end);
+        self.cells[start..end].fill(formatted_cell(' ', erased));
+        self.images.retain(|image| !(start..end).contains(&image.x));
+    }
+
+    /// Drop the cells from column `x` on
+    fn truncate(&mut self, x: usize) {
+        self.break_char_at(x);
+        self.cells.truncate(x);
+        self.images.retain(|image| image.x < x);
+        self.wrapped = false;
+    }
+
+    /// Empty the row, only the prompt marks stay
+    fn clear(&mut self) {
+        self.cells.clear();
+        self.wrapped = false;
+        self.images.clear();
+    }
+}
+
+/// Where a write started, counted from the bottom row so that it is still found after the write
+/// scrolled it up. See [`Grid::insert_data`]
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Anchor {
+    rows_from_bottom: usize,
+    x: usize,
+}
+
+/// What [`Grid::data`] and the like hand out, laid out like the buffer does
+struct Rendered {
+    buf: Vec<u8>,
+    ranges: TerminalData<Range<usize>>,
+    tags: Vec<FormatTag>,
+    prompt_marks: Vec<PromptMark>,
+}
+
+pub(crate) struct Grid {
+    width: usize,
+    height: usize,
+    /// Always `height` rows
+    screen: Vec<Row>,
+    /// Screen rows up to the last one that was written or scrolled into, like the lines of a
+    /// `TerminalBuffer` only these end up in [`Grid::data`]
+    used_rows: usize,
+    /// Oldest row first
+    scrollback: VecDeque<Row>,
+    max_scrollback_lines: usize,
+    /// DECAWM, when unset writes past the right margin overwrite the last column
+    auto_wrap: bool,
+    /// Where the cursor was left on the last column by a write that filled it, the next write
+    /// from there starts on the next row
+    pending_wrap: Option<CursorPos>,
+    /// Start of a character the last write ended in the middle of, finished by a write at the
+    /// position it would have gone to
+    partial_char: Option<(CursorPos, Vec<u8>)>,
+    /// Formatting of the columns past the end of each row, as left by the last clear
+    fill: CursorState,
+    /// Made on first use after a change
+    rendered: OnceCell<Rendered>,
+}
+
+impl Grid {
+    pub(crate) fn new(width: usize, height: usize) -> Grid {
+        Grid {
+            width,
+            height,
+            screen: vec![Row::default(); height],
+            used_rows: 0,
+            scrollback: VecDeque::new(),
+            max_scrollback_lines: DEFAULT_SCROLLBACK_LINES,
+            auto_wrap: true,
+            pending_wrap: None,
+            partial_char: None,
+            fill: CursorState::default(),
+            rendered: OnceCell::new(),
+        }
+    }
+
+    pub(crate) fn dimensions(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    pub(crate) fn auto_wrap(&self) -> bool {
+        self.auto_wrap
+    }
+
+    pub(crate) fn set_auto_wrap(&mut self, auto_wrap: bool) {
+        self.pending_wrap = None;
+        self.auto_wrap = auto_wrap;
+    }
+
+    /// Forget a wrap left by a write that filled the last column, for when the cursor was put
+    /// somewhere explicitly
+    pub(crate) fn cancel_pending_wrap(&mut self) {
+        self.pending_wrap = None;
+    }
+
+    /// Whether a write at `cursor_pos` starts on the next row, see [`Self::cancel_pending_wrap`]
+    pub(crate) fn has_pending_wrap(&self, cursor_pos: &CursorPos) -> bool {
+        self.pending_wrap.as_ref() == Some(cursor_pos)
+    }
+
+    /// Limit the number of rows kept in scrollback, the oldest ones are dropped first
+    pub(crate) fn set_scrollback_limit(&mut self, max_scrollback_lines: usize) {
+        self.max_scrollback_lines = max_scrollback_lines;
+        self.trim_scrollback();
+    }
+
+    fn trim_scrollback(&mut self) {
+        let excess = self.scrollback.len().saturating_sub(self.max_scrollback_lines);
+        if excess > 0 {
+            self.changed();
+            self.scrollback.drain(..excess);
+        }
+    }
+
+    /// Drop what was made for [`Grid::data`] and the like, for after the rows changed
+    fn changed(&mut self) {
+        self.rendered.take();
+    }
+
+    fn mark_used(&mut self, row: usize) {
+        self.used_rows = self.used_rows.max(row + 1);
+    }
+
+    /// The row under `y`, scrolling the whole screen if it is past the bottom. Returns where the
+    /// row is
+    fn next_row(&mut self, y: usize) -> usize {
+        if y + 1 < self.height {
+            y + 1
+        } else {
+            // Unlike opening a row under it, a line wrapping onto the new row stays one line
+            let wrapped = self.screen[y].wrapped;
+            self.scroll_up(0..self.height);
+            if let Some(row) = y.checked_sub(1) {
+                self.screen[row].wrapped = wrapped;
+            }
+            y
+        }
+    }
+
+    /// Store `ch` at `x`, `y` taking up `len` columns
+    fn put_char(&mut self, x: usize, y: usize, ch: char, len: usize, format: &CursorState) {
+        self.mark_used(y);
+        let fill = self.fill.clone();
+        let row = &mut self.screen[y];
+        row.pad(x + len, &fill);
+        row.break_char_at(x);
+        row.break_char_at(x + len);
+        row.cells[x] = formatted_cell(ch, format);
+        row.cells[x + 1..x + len].fill(formatted_cell('\0', format));
+        row.images.retain(|image| !(x..x + len).contains(&image.x));
+    }
+
+    /// Write `data` at `cursor_pos` formatted like `cursor`. A newline in `data` starts the next
+    /// row. Returns the cursor position after the write and where the data ended up
+    pub(crate) fn insert_data(
+        &mut self,
+        cursor_pos: &CursorPos,
+        data: &[u8],
+        cursor: &CursorState,
+    ) -> (CursorPos, Anchor) {
+        self.changed();
+        let pending_wrap = self.pending_wrap.take();
+        let mut pos = cursor_pos.clone();
+
+        let joined;
+        let data = match self.partial_char.take() {
+            Some((partial_pos, mut partial)) if partial_pos == pos => {
+                partial.extend_from_slice(data);
+                joined = partial;
+                &joined[..]
+            }
+            _ => data,
+        };
+        let complete_len = data.len() - incomplete_tail_len(data);
+        let chars = decode(&data[..complete_len]);
+
+        let bottom = self.height.saturating_sub(1);
+        let mut start = None;
+        let mut scrolls = 0;
+        if chars.is_empty() || self.width == 0 || self.height == 0 {
+            self.pending_wrap = pending_wrap;
+        } else if self.auto_wrap {
+            if pending_wrap.as_ref() == Some(&pos) {
+                pos.x = self.width;
+            }
+            for (ch, len) in chars {
+                if ch == '\n' {
+                    self.screen[pos.y].wrapped = false;
+                    pos.x = 0;
+                    scrolls += usize::from(pos.y == bottom);
+                    pos.y = self.next_row(pos.y);
+                    continue;
+                }
+                if len > self.width {
+                    continue;
+                }
+
+                if pos.x + len > self.width {
+                    let fill = self.fill.clone();
+                    self.mark_used(pos.y);
+                    let row = &mut self.screen[pos.y];
+                    row.pad(self.width, &fill);
+                    row.wrapped = true;
+                    pos.x = 0;
+                    scrolls += usize::from(pos.y == bottom);
+                    pos.y = self.next_row(pos.y);
+                }
+                start.get_or_insert((pos.clone(), scrolls));
+                self.put_char(pos.x, pos.y, ch, len, cursor);
+                pos.x += len;
+            }
+
+            // Filling the last column leaves the cursor on it rather than past the right margin
+            if pos.x >= self.width {
+                pos.x = self.width - 1;
+                self.pending_wrap = Some(pos.clone());
+            }
+        } else {
+            pos.x = pos.x.min(self.width - 1);
+            for (ch, len) in clip_to_width(&chars, self.width - pos.x) {
+                start.get_or_insert((pos.clone(), scrolls));
+                self.put_char(pos.x, pos.y, ch, len, cursor);
+                pos.x += len;
+            }
+            pos.x = pos.x.min(self.width - 1);
+        }
+
+        if complete_len < data.len() {
+            self.partial_char = Some((pos.clone(), data[complete_len..].to_vec()));
+        }
+
+        let (start, start_scrolls) = start.unwrap_or((cursor_pos.clone(), 0));
+        let anchor = Anchor {
+            rows_from_bottom: bottom - start.y.min(bottom) + scrolls - start_scrolls,
+            x: start.x,
+        };
+        (pos, anchor)
+    }
+
+    fn anchored_row(&mut self, anchor: &Anchor) -> Option<&mut Row> {
+        let num_rows = self.scrollback.len() + self.height;
+        let index = num_rows.checked_sub(anchor.rows_from_bottom + 1)?;
+        match index.checked_sub(self.scrollback.len()) {
+            Some(y) => self.screen.get_mut(y),
+            None => self.scrollback.get_mut(index),
+        }
+    }
+
+    pub(crate) fn push_prompt_mark(&mut self, anchor: Anchor, kind: PromptMarkKind) {
+        self.changed();
+        if let Some(row) = self.anchored_row(&anchor) {
+            let idx = row.prompt_marks.partition_point(|(x, _)| *x <= anchor.x);
+            row.prompt_marks.insert(idx, (anchor.x, kind));
+        }
+    }
+
+    /// Draw `image` over the cells below and right of where `anchor` is
+    pub(crate) fn push_image(
+        &mut self,
+        anchor: Anchor,
+        id: u64,
+        cells_wide: usize,
+        cells_high: usize,
+        image: Arc<TerminalImage>,
+    ) {
+        if let Some(row) = self.anchored_row(&anchor) {
+            row.images.push(RowImage {
+                x: anchor.x,
+                id,
+                cells_wide,
+                cells_high,
+                image,
+            });
+        }
+    }
+
+    /// Move rows in `region` up by one, the top one goes into scrollback when the region is the
+    /// whole screen and is dropped otherwise. The row that ends up above the new blank one no
+    /// longer wraps, it has been moved away from what it wrapped into
+    pub(crate) fn scroll_up(&mut self, region: Range<usize>) {
+        let region = region.start..region.end.min(self.height);
+        if region.is_empty() {
+            return;
+        }
+        self.changed();
+        self.pending_wrap = None;
+
+        let top = std::mem::take(&mut self.screen[region.start]);
+        self.screen[region.clone()].rotate_left(1);
+        if region.len() > 1 {
+            self.screen[region.end - 2].wrapped = false;
+        }
+        self.mark_used(region.end - 1);
+        if region.start == 0 && region.end == self.height {
+            self.scrollback.push_back(top);
+            self.trim_scrollback();
+        }
+    }
+
+    /// ICH, shift the row right of the cursor over by `num_blanks` blanks, dropping whatever goes
+    /// past `right_margin`. Nothing happens with no text at or after the cursor
+    pub(crate) fn insert_blanks(
+        &mut self,
+        cursor_pos: &CursorPos,
+        num_blanks: usize,
+        right_margin: usize,
+    ) {
+        self.pending_wrap = None;
+        let right_margin = right_margin.min(self.width);
+        let Some(row) = self.screen.get_mut(cursor_pos.y) else {
+            return;
+        };
+        let x = cursor_pos.x;
+        if x >= row.cells.len() || x >= right_margin {
+            return;
+        }
+        self.rendered.take();
+
+        let num_blanks = num_blanks.min(right_margin - x);
+        let row_end = row.cells.len().min(right_margin);
+        row.break_char_at(x);
+        row.break_char_at(right_margin);
+        row.break_char_at(right_margin - num_blanks);
+        // The blanks take on the formatting of what they push along
+        let blank = Cell {
+            ch: ' ',
+            ..row.cells[x].clone()
+        };
+        row.cells.splice(x..x, std::iter::repeat_n(blank, num_blanks));
+        if row_end + num_blanks > right_margin {
+            row.cells.drain(right_margin..row_end + num_blanks);
+        }
+        row.images.retain_mut(|image| {
+            if !(x..row_end).contains(&image.x) {
+                return true;
+            }
+            image.x += num_blanks;
+            image.x < right_margin
+        });
+    }
+
+    /// DCH, delete `num_chars` from the cursor, pulling the row up to `right_margin` back over
+    /// them and filling in with blanks before the margin. Nothing happens with no text at or
+    /// after the cursor
+    pub(crate) fn delete_chars(
+        &mut self,
+        cursor_pos: &CursorPos,
+        num_chars: usize,
+        right_margin: usize,
+    ) {
+        self.pending_wrap = None;
+        let right_margin = right_margin.min(self.width);
+        let Some(row) = self.screen.get_mut(cursor_pos.y) else {
+            return;
+        };
+        let x = cursor_pos.x;
+        if x >= row.cells.len() || x >= right_margin {
+            return;
+        }
+        self.rendered.take();
+
+        // Blanks are only needed to keep text past the margin in place, which includes the rest
+        // of the line when the row wraps at the edge of the screen
+        let text_after_margin = row.cells.len() > right_margin || row.wrapped;
+        let end = (x + num_chars).min(right_margin).min(row.cells.len());
+        row.break_char_at(x);
+        row.break_char_at(end);
+        row.break_char_at(right_margin);
+        row.cells.drain(x..end);
+        let num_deleted = end - x;
+        if text_after_margin {
+            let insert_pos = right_margin - num_deleted;
+            row.cells.splice(
+                insert_pos..insert_pos,
+                std::iter::repeat_n(blank_cell(), num_deleted),
+            );
+        }
+        row.images.retain_mut(|image| {
+            if (x..end).contains(&image.x) {
+                return false;
+            }
+            if (end..right_margin).contains(&image.x) {
+                image.x -= num_deleted;
+            }
+            true
+        });
+    }
+
+    /// ECH, blank up to `num_chars` from the cursor formatted like `erased`, stopping before
+    /// `right_margin`
+    pub(crate) fn erase_chars(
+        &mut self,
+        cursor_pos: &CursorPos,
+        num_chars: usize,
+        right_margin: usize,
+        erased: &CursorState,
+    ) {
+        self.pending_wrap = None;
+        let right_margin = right_margin.min(self.width);
+        if let Some(row) = self.screen.get_mut(cursor_pos.y) {
+            let end = cursor_pos.x.saturating_add(num_chars).min(right_margin);
+            row.blank(cursor_pos.x..end, erased);
+            self.rendered.take();
+        }
+    }
+
+    /// Clear from the cursor to the end of the screen, the rows stay in use. Columns that are not
+    /// written to are formatted like `cursor` until something else is set
+    pub(crate) fn clear_forwards(&mut self, cursor_pos: &CursorPos, cursor: &CursorState) {
+        self.changed();
+        self.pending_wrap = None;
+        self.fill = cursor.clone();
+        let Some(row) = self.screen.get_mut(cursor_pos.y) else {
+            return;
+        };
+        row.truncate(cursor_pos.x);
+        for row in &mut self.screen[cursor_pos.y + 1..] {
+            row.clear();
+        }
+    }
+
+    pub(crate) fn clear_line_forwards(&mut self, cursor_pos: &CursorPos) {
+        self.changed();
+        self.pending_wrap = None;
+        if let Some(row) = self.screen.get_mut(cursor_pos.y) {
+            row.truncate(cursor_pos.x);
+        }
+    }
+
+    /// Blank the row up to and including the cursor, formatted like `erased`
+    pub(crate) fn clear_line_backwards(&mut self, cursor_pos: &CursorPos, erased: &CursorState) {
+        self.changed();
+        self.pending_wrap = None;
+        let x = cursor_pos.x.min(self.width.saturating_sub(1));
+        if let Some(row) = self.screen.get_mut(cursor_pos.y) {
+            row.blank(0..x + 1, erased);
+        }
+    }
+
+    /// Blank the screen up to and including the cursor, formatted like `erased`
+    pub(crate) fn clear_backwards(&mut self, cursor_pos: &CursorPos, erased: &CursorState) {
+        let y = cursor_pos.y.min(self.height);
+        for row in &mut self.screen[..y] {
+            row.blank(0..usize::MAX, erased);
+        }
+        self.clear_line_backwards(cursor_pos, erased);
+    }
+
+    /// Empty the screen and scrollback, columns that are not written to are formatted like
+    /// `cursor` until something else is set
+    pub(crate) fn clear_all(&mut self, cursor: &CursorState) {
+        self.changed();
+        self.pending_wrap = None;
+        self.partial_char = None;
+        self.fill = cursor.clone();
+        self.screen.fill(Row::default());
+        self.scrollback.clear();
+        self.used_rows = 0;
+    }
+
+    /// Back to how a new grid starts out, keeping the size and scrollback limit
+    pub(crate) fn reset(&mut self) {
+        self.clear_all(&CursorState::default());
+        self.auto_wrap = true;
+    }
+
+    /// Whether the visible `row` continues onto the next row because it hit the right margin
+    pub(crate) fn row_wraps(&self, row: usize) -> bool {
+        self.screen.get(row).is_some_and(|row| row.wrapped)
+    }
+
+    /// Change the size, lines that wrapped are wrapped again at the new width. Like the buffer the
+    /// screen shows the last rows, anything above goes into scrollback. Returns where the cursor
+    /// ended up, None if the size did not change
+    pub(crate) fn set_win_size(
+        &mut self,
+        width: usize,
+        height: usize,
+        cursor_pos: &CursorPos,
+    ) -> Option<CursorPos> {
+        if width == self.width && height == self.height {
+            return None;
+        }
+        self.changed();
+        self.partial_char = None;
+
+        // A pending wrap is kept, from after the last character of the row wherever that ends up
+        let pending_wrap = self.pending_wrap.take().filter(|pos| pos == cursor_pos);
+        let cursor_x = match pending_wrap {
+            Some(_) => self.width,
+            None => cursor_pos.x,
+        };
+
+        // The cursor always has a row and column to go back to, like the buffer pads up to it
+        let fill = self.fill.clone();
+        self.mark_used(cursor_pos.y);
+        self.screen[cursor_pos.y].pad(cursor_x, &fill);
+
+        let cursor_row = self.scrollback.len() + cursor_pos.y;
+        let old_rows = self
+            .scrollback
+            .drain(..)
+            .chain(self.screen.drain(..self.used_rows));
+        let mut new_rows = Vec::new();
+        let mut cursor = None;
+        let mut line = Row::default();
+        let mut cursor_offset = None;
+        for (i, row) in old_rows.enumerate() {
+            if i == cursor_row {
+                cursor_offset = Some(line.cells.len() + cursor_x);
+            }
+            append_row(&mut line, row, self.width);
+            if !line.wrapped {
+                let line = std::mem::take(&mut line);
+                let line_cursor = rewrap_line(line, width, cursor_offset.take(), &mut new_rows);
+                cursor = cursor.or(line_cursor);
+            }
+        }
+        // Only when the last row wrapped, nothing follows so it no longer does
+        if !line.cells.is_empty() {
+            cursor = cursor.or(rewrap_line(line, width, cursor_offset, &mut new_rows));
+        }
+
+        let num_visible = new_rows.len().min(height);
+        let first_visible = new_rows.len() - num_visible;
+        self.screen = new_rows.split_off(first_visible);
+        self.screen.resize(height, Row::default());
+        self.scrollback = new_rows.into();
+        self.used_rows = num_visible;
+        self.width = width;
+        self.height = height;
+        self.trim_scrollback();
+
+        let (row, x) = cursor.unwrap_or((0, 0));
+        let mut new_cursor_pos = match row.checked_sub(first_visible) {
+            Some(y) => CursorPos { x, y },
+            None => {
+                info!("Old cursor position no longer on screen");
+                CursorPos { x: 0, y: 0 }
+            }
+        };
+        // Without a wrap pending the cursor can end up just past the last column, where the next
+        // write wraps anyway
+        if pending_wrap.is_some() && new_cursor_pos.x >= width {
+            new_cursor_pos.x = width.saturating_sub(1);
+            self.pending_wrap = Some(new_cursor_pos.clone());
+        }
+        Some(new_cursor_pos)
+    }
+
+    fn rendered(&self) -> &Rendered {
+        self.rendered.get_or_init(|| self.render())
+    }
+
+    /// Text, formatting and prompt marks of all rows laid out like the buffer does. Rows that did
+    /// not wrap end in a newline, except for the last one
+    fn render(&self) -> Rendered {
+        let mut buf = Vec::new();
+        let mut tags: Vec<FormatTag> = Vec::new();
+        let mut prompt_marks = Vec::new();
+        let mut push = |buf: &mut Vec<u8>, bytes: &[u8], cell: &Cell| {
+            let start = buf.len();
+            buf.extend_from_slice(bytes);
+            match tags.last_mut() {
+                Some(tag)
+                    if tag.fg_color == cell.fg
+                        && tag.bg_color == cell.bg
+                        && tag.attributes == cell.attrs =>
+                {
+                    tag.end = buf.len();
+                }
+                _ => tags.push(FormatTag {
+                    start,
+                    end: buf.len(),
+                    fg_color: cell.fg,
+                    bg_color: cell.bg,
+                    attributes: cell.attrs,
+                }),
+            }
+        };
+
+        let mut scrollback_end = 0;
+        let mut visible_start = 0;
+        let all_rows = self.scrollback.iter().chain(&self.screen[..self.used_rows]);
+        let mut previous: Option<&Row> = None;
+        for (i, row) in all_rows.enumerate() {
+            if i == self.scrollback.len() {
+                scrollback_end = buf.len();
+            }
+            if previous.is_some_and(|row| !row.wrapped) {
+                // Not part of either row, so it gets what is past the end of a row
+                push(&mut buf, b"\n", &formatted_cell(' ', &self.fill));
+            }
+            if i == self.scrollback.len() {
+                visible_start = buf.len();
+            }
+
+            let row_start = buf.len();
+            let mut cells = row.cells.iter().peekable();
+            while let Some(cell) = cells.next() {
+                let mut len = 1;
+                while cells.next_if(|cell| cell.ch == '\0').is_some() {
+                    len += 1;
+                }
+                if cell.ch.len_utf8() == len && cell.ch != '\0' {
+                    push(&mut buf, cell.ch.encode_utf8(&mut [0; 4]).as_bytes(), cell);
+                } else {
+                    push(&mut buf, &vec![INVALID_BYTE; len], cell);
+                }
+            }
+            if row.wrapped {
+                for _ in row.cells.len()..self.width {
+                    push(&mut buf, b" ", &formatted_cell(' ', &self.fill));
+                }
+            }
+            let row_len = buf.len() - row_start;
+            prompt_marks.extend(row.prompt_marks.iter().map(|(x, kind)| PromptMark {
+                start: row_start + (*x).min(row_len),
+                kind: *kind,
+            }));
+            previous = Some(row);
+        }
+        if self.used_rows == 0 {
+            scrollback_end = buf.len();
+            visible_start = buf.len();
+        }
+
+        // Columns past the end of the text, like the tag the buffer always ends in
+        push(&mut buf, b"", &formatted_cell(' ', &self.fill));
+        if let Some(tag) = tags.last_mut() {
+            tag.end = usize::MAX;
+        }
+
+        Rendered {
+            ranges: TerminalData {
+                scrollback: 0..scrollback_end,
+                visible: visible_start..buf.len(),
+            },
+            buf,
+            tags,
+            prompt_marks,
+        }
+    }
+
+    pub(crate) fn data(&self) -> TerminalData<&[u8]> {
+        let rendered = self.rendered();
+        TerminalData {
+            scrollback: &rendered.buf[rendered.ranges.scrollback.clone()],
+            visible: &rendered.buf[rendered.ranges.visible.clone()],
+        }
+    }
+
+    pub(crate) fn format_data(&self) -> TerminalData<Vec<FormatTag>> {
+        let rendered = self.rendered();
+        split_format_data_for_scrollback(rendered.tags.clone(), &rendered.ranges)
+    }
+
+    /// Prompt marks as indices into the text that [`Grid::data`] splits into scrollback and the
+    /// visible screen
+    pub(crate) fn prompt_marks(&self) -> &[PromptMark] {
+        &self.rendered().prompt_marks
+    }
+
+    /// The cell at `x`, `y`, None past the end of the row or screen
+    pub(crate) fn cell(&self, x: usize, y: usize) -> Option<Cell> {
+        if y >= self.used_rows {
+            return None;
+        }
+        self.screen[y].cells.get(x).cloned()
+    }
+
+    /// One line per visible row without trailing spaces
+    pub(crate) fn screen_text(&self) -> String {
+        let rows = rows(self.data().visible, self.width).join(&b'\n');
+        String::from_utf8_lossy(&trim_line_ends(&rows)).into_owned()
+    }
+
+    /// See [`text`]
+    pub(crate) fn visible_text(&self, logical_lines: bool) -> String {
+        text(self.data().visible, self.width, logical_lines)
+    }
+
+    /// [`Grid::visible_text`] of the scrollback and the screen
+    pub(crate) fn all_text(&self, logical_lines: bool) -> String {
+        let rendered = self.rendered();
+        text(&rendered.buf, self.width, logical_lines)
+    }
+
+    /// Images whose top left corner is on screen
+    pub(crate) fn visible_images(&self) -> Vec<ImagePlacement> {
+        let rows = self.screen.iter().enumerate();
+        rows.flat_map(|(y, row)| {
+            row.images.iter().map(move |image| ImagePlacement {
+                id: image.id,
+                pos: CursorPos { x: image.x, y },
+                cells_wide: image.cells_wide,
+                cells_high: image.cells_high,
+                image: Arc::clone(&image.image),
+            })
+        })
+        .collect()
+    }
+}
+
+/// What of `chars` is written without auto wrap into `available` columns. Whatever does not fit
+/// is dropped, except that the final character still lands in the last column if it fits there
+fn clip_to_width(chars: &[(char, usize)], available: usize) -> Vec<(char, usize)> {
+    let fitting = |chars: &[(char, usize)], available: usize| {
+        let mut used = 0;
+        chars
+            .iter()
+            .take_while(|(_, len)| {
+                used += len;
+                used <= available
+            })
+            .copied()
+            .collect::<Vec<_>>()
+    };
+
+    let total = chars.iter().map(|(_, len)| len).sum::<usize>();
+    let Some((&last, head)) = chars.split_last() else {
+        return Vec::new();
+    };
+    if total <= available {
+        chars.to_vec()
+    } else if last.1 > available {
+        fitting(chars, available)
+    } else {
+        let mut clipped = fitting(head, available - last.1);
+        clipped.push(last);
+        clipped
+    }
+}
+
+/// Add `row` to the end of the logical line it continues, `line`
+fn append_row(line: &mut Row, mut row: Row, width: usize) {
+    let offset = line.cells.len();
+    if row.wrapped {
+        row.cells.resize(width, blank_cell());
+    }
+    line.cells.append(&mut row.cells);
+    line.images.extend(row.images.into_iter().map(|image| RowImage {
+        x: image.x + offset,
+        ..image
+    }));
+    let marks = row.prompt_marks.into_iter();
+    line.prompt_marks.extend(marks.map(|(x, kind)| (x + offset, kind)));
+    line.wrapped = row.wrapped;
+}
+
+/// Split `line` into rows of `width`, adding them to `rows`. A character is never split over
+/// two rows. Returns the row and column of `cursor_offset`, on the last row past the end if it is
+/// right after the end of a full row that nothing follows
+fn rewrap_line(
+    line: Row,
+    width: usize,
+    cursor_offset: Option<usize>,
+    rows: &mut Vec<Row>,
+) -> Option<(usize, usize)> {
+    let mut row = Row::default();
+    let mut cells = line.cells.into_iter().enumerate().peekable();
+    let mut offsets = Vec::new();
+    while let Some((offset, cell)) = cells.next() {
+        let mut char_cells = vec![cell];
+        while let Some((_, cell)) = cells.next_if(|(_, cell)| cell.ch == '\0') {
+            char_cells.push(cell);
+        }
+        if char_cells.len() > width {
+            continue;
+        }
+        if row.cells.len() + char_cells.len() > width {
+            row.cells.resize(width, blank_cell());
+            row.wrapped = true;
+            rows.push(std::mem::take(&mut row));
+        }
+        for i in 0..char_cells.len() {
+            offsets.push((offset + i, rows.len(), row.cells.len() + i));
+        }
+        row.cells.append(&mut char_cells);
+    }
+    rows.push(row);
+
+    let position = |offset: usize| {
+        offsets
+            .iter()
+            .find(|(o, _, _)| *o == offset)
+            .map(|(_, row, x)| (*row, *x))
+    };
+    let end = (rows.len() - 1, rows[rows.len() - 1].cells.len());
+    let cursor = cursor_offset.map(|offset| position(offset).unwrap_or(end));
+    for image in line.images {
+        if let Some((row, x)) = position(image.x) {
+            rows[row].images.push(RowImage { x, ..image });
+        }
+    }
+    for (offset, kind) in line.prompt_marks {
+        let (row, x) = position(offset).unwrap_or(end);
+        rows[row].prompt_marks.push((x, kind));
+    }
+    cursor
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write(grid: &mut Grid, x: usize, y: usize, data: &[u8]) -> CursorPos {
+        grid.insert_data(&CursorPos { x, y }, data, &CursorState::default()).0
+    }
+
+    fn red() -> CursorState {
+        CursorState {
+            fg_color: TerminalColor::ForegroundRed,
+            ..CursorState::default()
+        }
+    }
+
+    #[test]
+    fn test_clear_forwards() {
+        let mut grid = Grid::new(5, 5);
+        write(&mut grid, 0, 0, b"012\n3456789");
+        grid.clear_forwards(&CursorPos { x: 1, y: 1 }, &CursorState::default());
+        // Same amount of lines should be present before and after clear
+        assert_eq!(grid.data().visible, b"012\n3\n");
+    }
+
+    #[test]
+    fn test_clear_all() {
+        let mut grid = Grid::new(5, 2);
+        write(&mut grid, 0, 0, b"0123456789abc");
+        assert_eq!(grid.data().scrollback, b"01234");
+
+        // What is written next takes the format, even past the end of it
+        grid.clear_all(&red());
+        assert_eq!(grid.data().scrollback, b"");
+        assert_eq!(grid.data().visible, b"");
+        let format_data = grid.format_data();
+        assert_eq!(format_data.visible.len(), 1);
+        assert_eq!(format_data.visible[0].fg_color, TerminalColor::ForegroundRed);
+    }
+
+    #[test]
+    fn test_overwrite_early_newline() {
+        let mut grid = Grid::new(5, 5);
+        write(&mut grid, 0, 0, b"012\n3456789");
+        assert_eq!(grid.data().visible, b"012\n3456789");
+
+        write(&mut grid, 2, 1, b"test");
+        assert_eq!(grid.data().visible, b"012\n34test9");
+    }
+
+    #[test]
+    fn test_overwrite_no_newline() {
+        let mut grid = Grid::new(5, 5);
+        write(&mut grid, 0, 0, b"0123456789");
+        assert_eq!(grid.data().visible, b"0123456789");
+
+        write(&mut grid, 2, 1, b"test");
+        assert_eq!(grid.data().visible, b"0123456test");
+    }
+
+    #[test]
+    fn test_overwrite_late_newline() {
+        let mut grid = Grid::new(5, 5);
+        write(&mut grid, 0, 0, b"01234\n56789");
+        assert_eq!(grid.data().visible, b"01234\n56789");
+
+        write(&mut grid, 2, 1, b"test");
+        assert_eq!(grid.data().visible, b"01234\n56test");
+    }
+
+    #[test]
+    fn test_insert_unallocated_data() {
+        let mut grid = Grid::new(10, 10);
+        write(&mut grid, 4, 5, b"hello world");
+        assert_eq!(grid.data().visible, b"\n\n\n\n\n    hello world");
+
+        // Unlike the buffer, wrapping overwrites the next row instead of pushing it down
+        write(&mut grid, 3, 2, b"hello world");
+        assert_eq!(grid.data().visible, b"\n\n   hello world\n\n    hello world");
+    }
+
+    #[test]
+    fn test_clear_line_backwards() {
+        let mut grid = Grid::new(10, 5);
+        write(&mut grid, 0, 0, b"abcdef\nghi");
+
+        grid.clear_line_backwards(&CursorPos { x: 2, y: 0 }, &CursorState::default());
+        assert_eq!(grid.data().visible, b"   def\nghi");
+
+        // Past the end of the line only the existing content is cleared
+        grid.clear_line_backwards(&CursorPos { x: 8, y: 1 }, &CursorState::default());
+        assert_eq!(grid.data().visible, b"   def\n   ");
+    }
+
+    #[test]
+    fn test_clear_backwards() {
+        let mut grid = Grid::new(10, 5);
+        write(&mut grid, 0, 0, b"abc\ndefgh\nijk");
+
+        grid.clear_backwards(&CursorPos { x: 2, y: 1 }, &CursorState::default());
+        assert_eq!(grid.data().visible, b"   \n   gh\nijk");
+    }
+
+    #[test]
+    fn test_row_wraps() {
+        let mut grid = Grid::new(5, 5);
+        write(&mut grid, 0, 0, b"0123456\nab\n01234\nc");
+        assert!(grid.row_wraps(0));
+        assert!(!grid.row_wraps(1));
+        assert!(!grid.row_wraps(2));
+        // Exactly filling a row before a newline is not a wrap
+        assert!(!grid.row_wraps(3));
+        assert!(!grid.row_wraps(4));
+        assert!(!grid.row_wraps(10));
+
+        // Opening a row under one that wraps breaks the line there
+        let mut grid = Grid::new(5, 5);
+        write(&mut grid, 0, 0, b"0123456789ab");
+        grid.scroll_up(0..2);
+        assert!(!grid.row_wraps(0));
+        assert_eq!(grid.data().visible, b"56789\n\nab");
+    }
+
+    #[test]
+    fn test_scroll_up() {
+        let mut grid = Grid::new(10, 3);
+        write(&mut grid, 0, 0, b"a\nb\nc");
+
+        grid.scroll_up(0..3);
+        assert_eq!(grid.data().scrollback, b"a");
+        assert_eq!(grid.data().visible, b"b\nc\n");
+
+        // Rows that were never written still take part in the scroll
+        let mut grid = Grid::new(10, 3);
+        write(&mut grid, 0, 0, b"a");
+        grid.scroll_up(0..3);
+        assert_eq!(grid.data().scrollback, b"a");
+        assert_eq!(grid.data().visible, b"\n\n");
+    }
+
+    #[test]
+    fn test_scroll_up_region() {
+        let mut grid = Grid::new(10, 4);
+        write(&mut grid, 0, 0, b"a\nb\nc\nd");
+
+        grid.scroll_up(1..3);
+        assert_eq!(grid.data().scrollback, b"");
+        assert_eq!(grid.data().visible, b"a\nc\n\nd");
+
+        // Rows outside the region keep wrapping the same way
+        let mut grid = Grid::new(4, 4);
+        write(&mut grid, 0, 0, b"aaaabbbbcc\nd");
+        grid.scroll_up(1..3);
+        assert_eq!(grid.data().visible, b"aaaacc\n\nd");
+    }
+
+    #[test]
+    fn test_auto_wrap_enabled() {
+        let mut grid = Grid::new(5, 5);
+        assert!(grid.auto_wrap());
+
+        let pos = write(&mut grid, 0, 0, b"0123456");
+        assert_eq!(grid.data().visible, b"0123456");
+        assert_eq!(pos, CursorPos { x: 2, y: 1 });
+    }
+
+    #[test]
+    fn test_auto_wrap_disabled() {
+        let mut grid = Grid::new(5, 5);
+        grid.set_auto_wrap(false);
+
+        let pos = write(&mut grid, 0, 0, b"0123456");
+        assert_eq!(grid.data().visible, b"01236");
+        assert_eq!(pos, CursorPos { x: 4, y: 0 });
+
+        // Further writes keep overwriting the last column
+        let pos = write(&mut grid, pos.x, pos.y, b"xy");
+        assert_eq!(grid.data().visible, b"0123y");
+        assert_eq!(pos, CursorPos { x: 4, y: 0 });
+
+        // Writing past the margin on a line with content after it leaves the next line alone
+        write(&mut grid, 0, 1, b"abc");
+        write(&mut grid, 3, 0, b"WXYZ");
+        assert_eq!(grid.data().visible, b"012WZ\nabc");
+
+        // A character that does not fit is dropped rather than split
+        write(&mut grid, 3, 1, "éé".as_bytes());
+        assert_eq!(grid.data().visible, "012WZ\nabcé".as_bytes());
+    }
+
+    #[test]
+    fn test_pending_wrap() {
+        let mut grid = Grid::new(5, 4);
+        let pos = write(&mut grid, 0, 0, b"12345");
+        assert_eq!(pos, CursorPos { x: 4, y: 0 });
+
+        // Writing on from there wraps to the next row
+        let pos = write(&mut grid, pos.x, pos.y, b"6");
+        assert_eq!(grid.data().visible, b"123456");
+        assert_eq!(pos, CursorPos { x: 1, y: 1 });
+        assert!(grid.row_wraps(0));
+
+        // Anything else done to the grid cancels the wrap
+        let pos = write(&mut grid, 0, 3, b"abcde");
+        grid.clear_line_forwards(&pos);
+        write(&mut grid, pos.x, pos.y, b"f");
+        assert_eq!(grid.screen_text(), "12345\n6\n\nabcdf");
+    }
+
+    #[test]
+    fn test_partial_chars() {
+        let mut grid = Grid::new(6, 2);
+        let bytes = "aé€".as_bytes();
+
+        // Characters split across writes are put back together, and take a column per byte
+        let mut pos = CursorPos { x: 0, y: 0 };
+        for byte in bytes {
+            pos = write(&mut grid, pos.x, pos.y, &[*byte]);
+        }
+        assert_eq!(grid.data().visible, bytes);
+        assert_eq!(pos, CursorPos { x: 5, y: 0 });
+        assert_eq!(grid.cell(1, 0).unwrap().ch, 'é');
+        assert_eq!(grid.cell(3, 0).unwrap().ch, '€');
+
+        // Overwriting part of one blanks the rest
+        write(&mut grid, 2, 0, b"x");
+        assert_eq!(grid.data().visible, "a x€".as_bytes());
+
+        // Invalid bytes still take up their column
+        let pos = write(&mut grid, 0, 1, b"\xffb");
+        assert_eq!(pos, CursorPos { x: 2, y: 1 });
+        assert_eq!(grid.screen_text(), "a x€\n\u{fffd}b");
+    }
+
+    #[test]
+    fn test_insert_blanks() {
+        let mut grid = Grid::new(5, 5);
+        write(&mut grid, 0, 0, b"abc\n01234");
+
+        grid.insert_blanks(&CursorPos { x: 1, y: 0 }, 1, 5);
+        assert_eq!(grid.data().visible, b"a bc\n01234");
+
+        // A full row loses its end
+        grid.insert_blanks(&CursorPos { x: 3, y: 1 }, 4, 5);
+        assert_eq!(grid.data().visible, b"a bc\n012  ");
+
+        // Nothing to push at the end of a row
+        grid.insert_blanks(&CursorPos { x: 4, y: 0 }, 1, 5);
+        grid.insert_blanks(&CursorPos { x: 0, y: 3 }, 1, 5);
+        assert_eq!(grid.data().visible, b"a bc\n012  ");
+    }
+
+    #[test]
+    fn test_blanks_and_deletes_stop_at_margin() {
+        let mut grid = Grid::new(8, 5);
+        write(&mut grid, 0, 0, b"abcdefgh\nabcdefgh\nabc");
+
+        grid.insert_blanks(&CursorPos { x: 1, y: 0 }, 2, 5);
+        grid.delete_chars(&CursorPos { x: 1, y: 1 }, 2, 5);
+        grid.delete_chars(&CursorPos { x: 0, y: 2 }, 9, 5);
+        assert_eq!(grid.data().visible, b"a  bcfgh\nade  fgh\n");
+    }
+
+    #[test]
+    fn test_delete_keeps_wrap() {
+        let mut grid = Grid::new(5, 5);
+        write(&mut grid, 0, 0, b"abcdefgh");
+
+        // The next row does not move up
+        grid.delete_chars(&CursorPos { x: 1, y: 0 }, 2, 5);
+        assert_eq!(grid.data().visible, b"ade  fgh");
+        assert!(grid.row_wraps(0));
+    }
+
+    #[test]
+    fn test_erase_chars() {
+        let mut grid = Grid::new(5, 5);
+        write(&mut grid, 0, 0, b"abcdefgh");
+
+        grid.erase_chars(&CursorPos { x: 3, y: 0 }, 9, 5, &CursorState::default());
+        assert_eq!(grid.data().visible, b"abc  fgh");
+        grid.erase_chars(&CursorPos { x: 1, y: 1 }, 1, 5, &red());
+        assert_eq!(grid.data().visible, b"abc  f h");
+        assert!(grid.row_wraps(0));
+        assert_eq!(grid.cell(1, 1).unwrap().fg, TerminalColor::ForegroundRed);
+
+        // Nothing from the right margin on is erased
+        grid.erase_chars(&CursorPos { x: 0, y: 0 }, 9, 2, &CursorState::default());
+        assert_eq!(grid.data().visible, b"  c  f h");
+    }
+
+    #[test]
+    fn test_canvas_scrolling() {
+        let mut grid = Grid::new(10, 3);
+
+        // Simulate real terminal usage where newlines are injected with cursor moves
+        let mut pos = CursorPos { x: 0, y: 0 };
+        for line in [&b"asdf"[..], b"xyzw", b"1234", b"5678"] {
+            pos = write(&mut grid, pos.x, pos.y, line);
+            if pos.y + 1 < 3 {
+                pos = CursorPos { x: 0, y: pos.y + 1 };
+            } else {
+                grid.scroll_up(0..3);
+                pos.x = 0;
+            }
+        }
+
+        assert_eq!(grid.data().scrollback, b"asdf\nxyzw");
+        assert_eq!(grid.data().visible, b"1234\n5678\n");
+    }
+
+    #[test]
+    fn test_scrollback_limit() {
+        let mut grid = Grid::new(10, 3);
+        grid.set_scrollback_limit(100);
+
+        let mut pos = CursorPos { x: 0, y: 0 };
+        for i in 0..20000 {
+            let data = format!("{}{i}", if i == 0 { "" } else { "\n" });
+            pos = write(&mut grid, pos.x, pos.y, data.as_bytes());
+        }
+
+        let data = grid.data();
+        let scrollback_lines = data.scrollback.split(|b| *b == b'\n').collect::<Vec<_>>();
+        assert_eq!(scrollback_lines.len(), 100);
+        assert_eq!(scrollback_lines[0], b"19897");
+        assert_eq!(scrollback_lines[99], b"19996");
+        assert_eq!(data.visible, b"19997\n19998\n19999");
+
+        // Long lines are trimmed a row at a time
+        let mut grid = Grid::new(10, 3);
+        grid.set_scrollback_limit(2);
+        let line = (0..100_000).map(|i| b'a' + (i / 10 % 26) as u8).collect::<Vec<_>>();
+        write(&mut grid, 0, 0, &line);
+        let data = grid.data();
+        assert_eq!(data.scrollback, &line[line.len() - 50..line.len() - 30]);
+        assert_eq!(data.visible, &line[line.len() - 30..]);
+    }
+
+    #[test]
+    fn test_resize() {
+        let mut grid = Grid::new(10, 4);
+        let pos = write(&mut grid, 0, 0, b"a\nb\nc\nd123456");
+
+        // Same size, nothing to do
+        assert!(grid.set_win_size(10, 4, &pos).is_none());
+
+        // Shrinking keeps the cursor row on screen by pushing the top into scrollback, and the
+        // last line wraps again
+        let pos = grid.set_win_size(5, 2, &pos).unwrap();
+        assert_eq!(pos, CursorPos { x: 2, y: 1 });
+        assert_eq!(grid.data().scrollback, b"a\nb\nc");
+        assert_eq!(grid.data().visible, b"d123456");
+        assert!(grid.row_wraps(0));
+
+        // Growing back joins it again, the top rows stay in scrollback like with the buffer
+        let pos = grid.set_win_size(10, 4, &pos).unwrap();
+        assert_eq!(pos, CursorPos { x: 7, y: 3 });
+        assert_eq!(grid.data().scrollback, b"");
+        assert_eq!(grid.data().visible, b"a\nb\nc\nd123456");
+        assert!(!grid.row_wraps(0));
+    }
+
+    #[test]
+    fn test_resize_reflows_wrapped_lines() {
+        let mut grid = Grid::new(10, 5);
+        let pos = write(&mut grid, 0, 0, b"abcdefghijklmnopqrstuvwxyz0123");
+        assert_eq!(pos, CursorPos { x: 9, y: 2 });
+
+        let pos = grid.set_win_size(15, 5, &pos).unwrap();
+        assert_eq!(grid.screen_text(), "abcdefghijklmno\npqrstuvwxyz0123");
+        // Still on the last character, waiting to wrap
+        assert_eq!(pos, CursorPos { x: 14, y: 1 });
+        let pos = write(&mut grid, pos.x, pos.y, b"4");
+        assert_eq!(pos, CursorPos { x: 1, y: 2 });
+
+        // Mid line the cursor stays on the same character
+        let pos = grid.set_win_size(4, 10, &CursorPos { x: 7, y: 1 }).unwrap();
+        assert_eq!(pos, CursorPos { x: 2, y: 5 });
+        assert_eq!(grid.screen_text().lines().count(), 8);
+    }
+
+    #[test]
+    fn test_format_data() {
+        let mut grid = Grid::new(10, 3);
+        let pos = write(&mut grid, 0, 0, b"ab");
+        let (pos, _) = grid.insert_data(&pos, "cé\nd".as_bytes(), &red());
+        write(&mut grid, pos.x, pos.y, b"e");
+
+        let format_data = grid.format_data();
+        let ranges = format_data
+            .visible
+            .iter()
+            .map(|tag| (tag.start..tag.end, tag.fg_color))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ranges,
+            [
+                (0..2, TerminalColor::Default),
+                (2..5, TerminalColor::ForegroundRed),
+                (5..6, TerminalColor::Default),
+                (6..7, TerminalColor::ForegroundRed),
+                (7..usize::MAX, TerminalColor::Default),
+            ]
+        );
+        assert_eq!(grid.data().visible, "abcé\nde".as_bytes());
+        assert_eq!(grid.cell(3, 0).unwrap().ch, 'é');
+        assert_eq!(grid.cell(2, 1).map(|cell| cell.ch), None);
+    }
+
+    #[test]
+    fn test_anchors_follow_scrolling() {
+        let mut grid = Grid::new(10, 2);
+        let (pos, prompt) = grid.insert_data(
+            &CursorPos { x: 0, y: 0 },
+            b"$ ",
+            &CursorState::default(),
+        );
+        grid.push_prompt_mark(prompt, PromptMarkKind::PromptStart);
+        let (_, output) = grid.insert_data(&pos, b"ls\nout", &CursorState::default());
+        grid.push_prompt_mark(output, PromptMarkKind::CommandStart);
+
+        // Marks are where the writes started in the text, whatever scrolled since
+        write(&mut grid, 0, 1, b"\n\n");
+        let data = grid.data();
+        assert_eq!(data.scrollback, b"$ ls\nout");
+        let marks = grid
+            .prompt_marks()
+            .iter()
+            .map(|mark| (mark.start, mark.kind))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            marks,
+            [(0, PromptMarkKind::PromptStart), (2, PromptMarkKind::CommandStart)]
+        );
+
+        // Images are only visible while their row is
+        let image = Arc::new(TerminalImage {
+            width: 1,
+            height: 1,
+            rgba: vec![0; 4],
+        });
+        let (_, anchor) = grid.insert_data(
+            &CursorPos { x: 3, y: 1 },
+            b"x",
+            &CursorState::default(),
+        );
+        grid.push_image(anchor, 1, 2, 1, Arc::clone(&image));
+        let images = grid.visible_images();
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].pos, CursorPos { x: 3, y: 1 });
+        write(&mut grid, 0, 1, b"\n\n");
+        assert!(grid.visible_images().is_empty());
+    }
+}
@@ -0,0 +1,168 @@
+//! Drives a [`TerminalEmulator`] on a background thread, for programs embedding it without egui
+//!
+//! Three threads are involved. The pty reader (see [`TerminalEmulator::spawn_reader_thread`])
+//! parses what the shell writes, the terminal thread applies that to the emulator and writes
+//! input to the shell, and the embedder only looks at the emulator while holding
+//! [`TerminalHandle::emulator`]'s lock, which the terminal thread also takes to apply output. The
+//! embedder learns about changes from [`TerminalHandle::events`] instead of polling
+
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crossbeam::channel::{self, Receiver, Sender};
+
+use super::tty::Options;
+use super::{TerminalEmulator, TerminalError, TerminalInput};
+
+#[derive(Debug)]
+pub enum TerminalEvent {
+    /// Output was applied since the last one was received. Bursts are coalesced, at most one of
+    /// these is waiting at a time
+    Changed,
+    /// The shell went away, [`TerminalError::ChildExited`] if it just exited. Nothing is sent
+    /// after this
+    Closed(TerminalError),
+}
+
+pub struct TerminalHandle {
+    emulator: Arc<Mutex<TerminalEmulator>>,
+    input: Sender<TerminalInput>,
+    events: Receiver<TerminalEvent>,
+}
+
+impl TerminalHandle {
+    /// Spawn the shell and the threads driving it. They stop once the handle is dropped, which
+    /// also hangs up on the shell
+    pub fn spawn(options: Options) -> Result<TerminalHandle, TerminalError> {
+        let mut emulator = TerminalEmulator::new(options)?;
+        // A wakeup already waiting covers any output queued after it
+        let (woken, wakeups) = channel::bounded(1);
+        emulator.spawn_reader_thread(move || {
+            let _ = woken.try_send(());
+        })?;
+
+        let emulator = Arc::new(Mutex::new(emulator));
+        let (input, inputs) = channel::unbounded();
+        let (notify, events) = channel::bounded(1);
+        let thread_emulator = Arc::clone(&emulator);
+        std::thread::Builder::new()
+            .name("terminal".to_string())
+            .spawn(move || drive(&thread_emulator, wakeups, &inputs, &notify))
+            .map_err(TerminalError::Io)?;
+
+        Ok(TerminalHandle {
+            emulator,
+            input,
+            events,
+        })
+    }
+
+    /// Lock the emulator to look at or change it. Output is not applied while the guard is held
+    pub fn emulator(&self) -> MutexGuard<'_, TerminalEmulator> {
+        lock(&self.emulator)
+    }
+
+    /// Input sent here is written to the shell in order, see [`TerminalEmulator::write`]
+    pub fn input(&self) -> &Sender<TerminalInput> {
+        &self.input
+    }
+
+    pub fn events(&self) -> &Receiver<TerminalEvent> {
+        &self.events
+    }
+}
+
+/// A panic while the lock was held leaves the emulator as it was, which is still worth showing
+fn lock(emulator: &Mutex<TerminalEmulator>) -> MutexGuard<'_, TerminalEmulator> {
+    emulator.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// The terminal thread, runs until the shell goes away or the handle is dropped
+fn drive(
+    emulator: &Mutex<TerminalEmulator>,
+    mut wakeups: Receiver<()>,
+    inputs: &Receiver<TerminalInput>,
+    notify: &Sender<TerminalEvent>,
+) {
+    loop {
+        let result = channel::select! {
+            recv(wakeups) -> woken => {
+                // The reader thread ends after queueing its error, reading now reports it. If
+                // the emulator is paused it does not, so stop listening rather than spin
+                if woken.is_err() {
+                    wakeups = channel::never();
+                }
+                let result = lock(emulator).read();
+                if result.is_ok() {
+                    let _ = notify.try_send(TerminalEvent::Changed);
+                }
+                result
+            }
+            recv(inputs) -> to_write => match to_write {
+                Ok(to_write) => lock(emulator).write(to_write),
+                Err(_) => return,
+            },
+        };
+
+        if let Err(e) = result {
+            let _ = notify.send(TerminalEvent::Closed(e));
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::terminal_emulator::tty::Shell;
+
+    fn spawn_cat() -> TerminalHandle {
+        let options = Options {
+            shell: Some(Shell::new("cat".to_string(), vec![])),
+            ..Default::default()
+        };
+        TerminalHandle::spawn(options).unwrap()
+    }
+
+    #[test]
+    fn test_input_is_echoed() {
+        let handle = spawn_cat();
+        for b in b"hello" {
+            handle.input().send(TerminalInput::Ascii(*b)).unwrap();
+        }
+        handle.input().send(TerminalInput::Enter).unwrap();
+
+        // The pty's echo and cat's copy
+        let hellos = |handle: &TerminalHandle| {
+            let emulator = handle.emulator();
+            let visible = emulator.data().visible;
+            visible.windows(5).filter(|w| w == b"hello").count()
+        };
+        while hellos(&handle) < 2 {
+            let event = handle
+                .events()
+                .recv_timeout(Duration::from_secs(5))
+                .expect("output should be reported");
+            assert!(matches!(event, TerminalEvent::Changed));
+        }
+    }
+
+    #[test]
+    fn test_exit_is_reported() {
+        let handle = spawn_cat();
+        // Ctrl+D on an empty line ends cat
+        handle.input().send(TerminalInput::Ctrl(b'd')).unwrap();
+        loop {
+            match handle.events().recv_timeout(Duration::from_secs(5)) {
+                Ok(TerminalEvent::Changed) => continue,
+                Ok(TerminalEvent::Closed(e)) => {
+                    assert!(matches!(e, TerminalError::ChildExited));
+                    break;
+                }
+                Err(e) => panic!("exit should be reported: {e}"),
+            }
+        }
+        assert!(handle.events().recv_timeout(Duration::from_millis(100)).is_err());
+    }
+}
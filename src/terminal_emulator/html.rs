@@ -0,0 +1,117 @@
+//! Output as an HTML `<pre>` block, for sharing it with its colors
+
+use std::fmt::Write;
+
+use super::{FormatTag, TerminalColor};
+
+/// Colors output is drawn with when it does not pick its own
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct HtmlColors {
+    pub foreground: (u8, u8, u8),
+    pub background: (u8, u8, u8),
+}
+
+fn color_to_rgb(color: &TerminalColor, palette: &[(u8, u8, u8); 256]) -> Option<(u8, u8, u8)> {
+    match color {
+        TerminalColor::Default => None,
+        TerminalColor::ForegroundRgb(r, g, b) | TerminalColor::BackgroundTrueColor(r, g, b) => {
+            Some((*r, *g, *b))
+        }
+        color => color.palette_index().map(|index| palette[index as usize]),
+    }
+}
+
+fn css_color((r, g, b): (u8, u8, u8)) -> String {
+    format!("#{r:02x}{g:02x}{b:02x}")
+}
+
+/// Inline style of the text `tag` covers, empty when it is drawn with the defaults
+fn tag_style(tag: &FormatTag, palette: &[(u8, u8, u8); 256], defaults: &HtmlColors) -> String {
+    let mut fg = color_to_rgb(&tag.fg_color, palette);
+    let mut bg = color_to_rgb(&tag.bg_color, palette);
+    if tag.attributes.reverse {
+        (fg, bg) = (
+            Some(bg.unwrap_or(defaults.background)),
+            Some(fg.unwrap_or(defaults.foreground)),
+        );
+    }
+
+    let mut style = Vec::new();
+    if let Some(fg) = fg {
+        style.push(format!("color:{}", css_color(fg)));
+    }
+    if let Some(bg) = bg {
+        style.push(format!("background:{}", css_color(bg)));
+    }
+    if tag.attributes.bold {
+        style.push("font-weight:bold".to_string());
+    }
+    if tag.attributes.faint {
+        style.push("opacity:0.6".to_string());
+    }
+    if tag.attributes.italic {
+        style.push("font-style:italic".to_string());
+    }
+    if tag.attributes.underline {
+        style.push("text-decoration:underline".to_string());
+    }
+    if tag.attributes.conceal {
+        style.push("visibility:hidden".to_string());
+    }
+    style.join(";")
+}
+
+fn push_escaped(html: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            c => html.push(c),
+        }
+    }
+}
+
+/// `data` in a `<pre>` with the defaults, and a `<span>` for every run `tags` give a style
+pub(crate) fn to_html(
+    data: &[u8],
+    tags: &[FormatTag],
+    palette: &[(u8, u8, u8); 256],
+    defaults: &HtmlColors,
+) -> String {
+    let mut html = String::new();
+    let _ = write!(
+        html,
+        "<pre style=\"color:{};background:{}\">",
+        css_color(defaults.foreground),
+        css_color(defaults.background)
+    );
+
+    let mut pos = 0;
+    for tag in tags {
+        let start = tag.start.clamp(pos, data.len());
+        let end = tag.end.clamp(start, data.len());
+        // Text between tags is not formatted
+        push_escaped(&mut html, &String::from_utf8_lossy(&data[pos..start]));
+        pos = start;
+        if start == end {
+            continue;
+        }
+
+        let text = String::from_utf8_lossy(&data[start..end]);
+        let style = tag_style(tag, palette, defaults);
+        if style.is_empty() {
+            push_escaped(&mut html, &text);
+        } else {
+            let _ = write!(html, "<span style=\"{style}\">");
+            push_escaped(&mut html, &text);
+            html.push_str("</span>");
+        }
+        pos = end;
+    }
+    push_escaped(&mut html, &String::from_utf8_lossy(&data[pos..]));
+
+    html.push_str("</pre>");
+    html
+}
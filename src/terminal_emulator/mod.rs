@@ -1,37 +1,170 @@
-use nix::{errno::Errno, ioctl_write_ptr_bad, unistd::ForkResult};
-use std::{ffi::CStr, fmt, ops::Range, os::fd::{AsRawFd, OwnedFd}};
-use std::os::fd::FromRawFd;
-use ansi::{AnsiParser, SelectGraphicRendition, TerminalOutput};
-use buffer::TerminalBuffer;
+use std::io::{ErrorKind, Read, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
+use ansi::{AnsiParser, SelectGraphicRendition, TerminalOutput, WindowReport};
+use event::WindowSize;
+use reader_thread::ReaderThread;
+use recording::Recording;
+#[cfg(feature = "grid")]
+use grid::{Anchor, Grid as Screen};
+#[cfg(not(feature = "grid"))]
+use screen::{Anchor, Screen};
+use tab_stops::TabStops;
+use trace::Tracer;
+use tty::{EventedReadWrite, Options, Pty};
+
+pub use ansi::{ClipboardSelection, CursorShape, CursorStyle, DynamicColor, PromptMarkKind};
+pub use buffer::{line_at, trim_line_ends, word_at, DEFAULT_WORD_SEPARATORS};
+pub use error::TerminalError;
+pub use format_tracker::{FormatTag, PromptMark};
+pub use handle::{TerminalEvent, TerminalHandle};
+pub use html::HtmlColors;
+pub use sixel::TerminalImage;
+pub use trace::Trace;
+
 mod ansi;
+mod base64;
+// Only parts of these are used with the grid, the rest is the fallback without it
+#[cfg_attr(feature = "grid", allow(dead_code))]
 mod buffer;
+mod error;
+pub mod event;
+#[cfg_attr(feature = "grid", allow(dead_code))]
 mod format_tracker;
+#[cfg(feature = "grid")]
+mod grid;
+mod handle;
+mod html;
+mod reader_thread;
+mod recording;
+#[cfg(not(feature = "grid"))]
+mod screen;
+mod sixel;
+mod tab_stops;
+mod trace;
+pub mod tty;
 
 pub const TERMINAL_WIDTH: u16 = 80;
 pub const TERMINAL_HEIGHT: u16 = 24;
+/// Pixel size of a character cell until the GUI says otherwise
+const DEFAULT_CELL_SIZE: (usize, usize) = (8, 16);
+/// Titles saved with CSI 22 t beyond this push out the oldest, like xterm
+const MAX_TITLE_STACK: usize = 10;
+
+/// Modes set and reset with SM and RM, `CSI Pn h` and `CSI Pn l`
+#[derive(Debug, Eq, PartialEq)]
+enum AnsiMode {
+    // Insert/replace mode
+    // https://vt100.net/docs/vt510-rm/IRM.html
+    Irm,
+    Unknown(usize),
+}
 
+impl AnsiMode {
+    fn from_number(number: usize) -> AnsiMode {
+        match number {
+            4 => AnsiMode::Irm,
+            _ => AnsiMode::Unknown(number),
+        }
+    }
 
-#[derive(Eq, PartialEq)]
-enum Mode {
+    fn number(&self) -> usize {
+        match self {
+            AnsiMode::Irm => 4,
+            AnsiMode::Unknown(number) => *number,
+        }
+    }
+}
+
+/// Modes set and reset with DECSET and DECRST, `CSI ? Pn h` and `CSI ? Pn l`
+#[derive(Debug, Eq, PartialEq)]
+enum DecPrivateMode {
     // Cursor keys mode
     // https://vt100.net/docs/vt100-ug/chapter3.html
     Decckm,
-    Unknown(Vec<u8>),
+    // Screen mode, the whole screen in reverse video
+    // https://vt100.net/docs/vt510-rm/DECSCNM.html
+    Decscnm,
+    // Origin mode
+    // https://vt100.net/docs/vt510-rm/DECOM.html
+    Decom,
+    // Auto wrap mode
+    // https://vt100.net/docs/vt510-rm/DECAWM.html
+    Decawm,
+    // https://invisible-island.net/xterm/ctlseqs/ctlseqs.html#h2-Mouse-Tracking
+    MouseX10,
+    // Left/right margin mode, lets DECSLRM set them
+    // https://vt100.net/docs/vt510-rm/DECLRMM.html
+    Declrmm,
+    // Blinking cursor, xterm's rather than DEC's
+    CursorBlink,
+    // Text cursor enable mode
+    // https://vt100.net/docs/vt510-rm/DECTCEM.html
+    Dectcem,
+    MouseNormal,
+    MouseButtonEvent,
+    MouseAnyEvent,
+    FocusEvents,
+    MouseSgr,
+    // Alternate screen, saving the cursor on the way in and restoring it on the way out
+    AltScreen,
+    // https://invisible-island.net/xterm/ctlseqs/ctlseqs.html#h2-Bracketed-Paste-Mode
+    BracketedPaste,
+    Unknown(usize),
 }
 
-impl fmt::Debug for Mode {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+impl DecPrivateMode {
+    fn from_number(number: usize) -> DecPrivateMode {
+        match number {
+            1 => DecPrivateMode::Decckm,
+            5 => DecPrivateMode::Decscnm,
+            6 => DecPrivateMode::Decom,
+            7 => DecPrivateMode::Decawm,
+            9 => DecPrivateMode::MouseX10,
+            12 => DecPrivateMode::CursorBlink,
+            25 => DecPrivateMode::Dectcem,
+            69 => DecPrivateMode::Declrmm,
+            1000 => DecPrivateMode::MouseNormal,
+            1002 => DecPrivateMode::MouseButtonEvent,
+            1003 => DecPrivateMode::MouseAnyEvent,
+            1004 => DecPrivateMode::FocusEvents,
+            1006 => DecPrivateMode::MouseSgr,
+            1049 => DecPrivateMode::AltScreen,
+            2004 => DecPrivateMode::BracketedPaste,
+            _ => DecPrivateMode::Unknown(number),
+        }
+    }
+
+    fn number(&self) -> usize {
         match self {
-            Mode::Decckm => f.write_str("Decckm"),
-            Mode::Unknown(params) => {
-                let params_s = std::str::from_utf8(params)
-                    .expect("parameter parsing should not allow non-utf8 characters here");
-                f.write_fmt(format_args!("Unknown({})", params_s))
-            }
+            DecPrivateMode::Decckm => 1,
+            DecPrivateMode::Decscnm => 5,
+            DecPrivateMode::Decom => 6,
+            DecPrivateMode::Decawm => 7,
+            DecPrivateMode::MouseX10 => 9,
+            DecPrivateMode::CursorBlink => 12,
+            DecPrivateMode::Dectcem => 25,
+            DecPrivateMode::Declrmm => 69,
+            DecPrivateMode::MouseNormal => 1000,
+            DecPrivateMode::MouseButtonEvent => 1002,
+            DecPrivateMode::MouseAnyEvent => 1003,
+            DecPrivateMode::FocusEvents => 1004,
+            DecPrivateMode::MouseSgr => 1006,
+            DecPrivateMode::AltScreen => 1049,
+            DecPrivateMode::BracketedPaste => 2004,
+            DecPrivateMode::Unknown(number) => *number,
         }
     }
 }
 
+#[derive(Debug, Eq, PartialEq)]
+enum Mode {
+    Ansi(AnsiMode),
+    Dec(DecPrivateMode),
+}
+
 fn char_to_ctrl_code(c: u8) -> u8 {
     // https://catern.com/posts/terminal_quirks.html
     // man ascii
@@ -41,7 +174,52 @@ fn char_to_ctrl_code(c: u8) -> u8 {
 #[derive(Eq, PartialEq, Debug)]
 enum TerminalInputPayload {
     Single(u8),
-    Many(&'static [u8]),
+    Many(Vec<u8>),
+}
+
+/// Shift, Alt and Ctrl held together with a key
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct KeyModifiers {
+    pub shift: bool,
+    pub alt: bool,
+    pub ctrl: bool,
+}
+
+impl KeyModifiers {
+    /// xterm modifier parameter, 1 + shift (1) + alt (2) + ctrl (4). None without modifiers
+    fn param(self) -> Option<u8> {
+        let bits = self.shift as u8 | (self.alt as u8) << 1 | (self.ctrl as u8) << 2;
+        (bits != 0).then_some(bits + 1)
+    }
+}
+
+/// Escape sequence shapes of keys that do not produce characters
+///
+/// With modifiers held, all of them turn into CSI sequences with the modifier as the second
+/// parameter
+/// https://invisible-island.net/xterm/ctlseqs/ctlseqs.html#h2-PC-Style-Function-Keys
+enum KeySequence {
+    /// CSI <final>, SS3 <final> in application cursor key mode
+    Cursor(u8),
+    /// SS3 <final>
+    Ss3(u8),
+    /// CSI <code> ~
+    Tilde(u8),
+}
+
+impl KeySequence {
+    fn encode(&self, modifiers: KeyModifiers, decckm_mode: bool) -> Vec<u8> {
+        match (self, modifiers.param()) {
+            (KeySequence::Cursor(c), None) if decckm_mode => vec![b'\x1b', b'O', *c],
+            (KeySequence::Cursor(c), None) => vec![b'\x1b', b'[', *c],
+            (KeySequence::Ss3(c), None) => vec![b'\x1b', b'O', *c],
+            (KeySequence::Cursor(c) | KeySequence::Ss3(c), Some(m)) => {
+                format!("\x1b[1;{m}{}", *c as char).into_bytes()
+            }
+            (KeySequence::Tilde(code), None) => format!("\x1b[{code}~").into_bytes(),
+            (KeySequence::Tilde(code), Some(m)) => format!("\x1b[{code};{m}~").into_bytes(),
+        }
+    }
 }
 
 pub enum TerminalInput {
@@ -51,283 +229,217 @@ pub enum TerminalInput {
     Ctrl(u8),
     Enter,
     Backspace,
-    ArrowRight,
-    ArrowLeft,
-    ArrowUp,
-    ArrowDown,
-    Home,
-    End,
+    Tab,
+    /// Shift+Tab
+    BackTab,
+    ArrowRight(KeyModifiers),
+    ArrowLeft(KeyModifiers),
+    ArrowUp(KeyModifiers),
+    ArrowDown(KeyModifiers),
+    Home(KeyModifiers),
+    End(KeyModifiers),
+    Insert(KeyModifiers),
+    Delete(KeyModifiers),
+    PageUp(KeyModifiers),
+    PageDown(KeyModifiers),
+    /// F1 to F12, other numbers send nothing
+    Function(u8, KeyModifiers),
+    /// Numeric keypad key, given as the character it types: digits, `. + - * / =` or `\n` for
+    /// Enter. Other characters send nothing in application keypad mode
+    ///
+    /// egui reports keypad keys as their main keyboard equivalents, so the GUI cannot send these
+    /// yet
+    Keypad(u8),
+}
+
+/// SS3 final byte sent by a keypad key in application keypad mode
+/// https://vt100.net/docs/vt100-ug/chapter3.html Table 3-8, with the xterm additions
+fn keypad_application_final(c: u8) -> Option<u8> {
+    let final_byte = match c {
+        b'0'..=b'9' => b'p' + (c - b'0'),
+        b'*' => b'j',
+        b'+' => b'k',
+        b',' => b'l',
+        b'-' => b'm',
+        b'.' => b'n',
+        b'/' => b'o',
+        b'=' => b'X',
+        b'\n' => b'M',
+        _ => return None,
+    };
+    Some(final_byte)
 }
 
 impl TerminalInput {
-    fn to_payload(&self, decckm_mode: bool) -> TerminalInputPayload {
-        match self {
-            TerminalInput::Ascii(c) => TerminalInputPayload::Single(*c),
-            TerminalInput::Ctrl(c) => TerminalInputPayload::Single(char_to_ctrl_code(*c)),
-            TerminalInput::Enter => TerminalInputPayload::Single(b'\n'),
+    fn to_payload(&self, decckm_mode: bool, keypad_application_mode: bool) -> TerminalInputPayload {
+        let (sequence, modifiers) = match self {
+            TerminalInput::Ascii(c) => return TerminalInputPayload::Single(*c),
+            TerminalInput::Ctrl(c) => return TerminalInputPayload::Single(char_to_ctrl_code(*c)),
+            TerminalInput::Enter => return TerminalInputPayload::Single(b'\n'),
             // Hard to tie back, but check default VERASE in terminfo definition
-            TerminalInput::Backspace => TerminalInputPayload::Single(0x7f),
+            TerminalInput::Backspace => return TerminalInputPayload::Single(0x7f),
+            TerminalInput::Tab => return TerminalInputPayload::Single(b'\t'),
+            // CBT, xterm's backtab
+            TerminalInput::BackTab => return TerminalInputPayload::Many(b"\x1b[Z".to_vec()),
             // https://vt100.net/docs/vt100-ug/chapter3.html
             // Table 3-6
-            TerminalInput::ArrowRight => match decckm_mode {
-                true => TerminalInputPayload::Many(b"\x1bOC"),
-                false => TerminalInputPayload::Many(b"\x1b[C"),
-            },
-            TerminalInput::ArrowLeft => match decckm_mode {
-                true => TerminalInputPayload::Many(b"\x1bOD"),
-                false => TerminalInputPayload::Many(b"\x1b[D"),
-            },
-            TerminalInput::ArrowUp => match decckm_mode {
-                true => TerminalInputPayload::Many(b"\x1bOA"),
-                false => TerminalInputPayload::Many(b"\x1b[A"),
-            },
-            TerminalInput::ArrowDown => match decckm_mode {
-                true => TerminalInputPayload::Many(b"\x1bOB"),
-                false => TerminalInputPayload::Many(b"\x1b[B"),
-            },
-            TerminalInput::Home => match decckm_mode {
-                true => TerminalInputPayload::Many(b"\x1bOH"),
-                false => TerminalInputPayload::Many(b"\x1b[H"),
-            },
-            TerminalInput::End => match decckm_mode {
-                true => TerminalInputPayload::Many(b"\x1bOF"),
-                false => TerminalInputPayload::Many(b"\x1b[F"),
+            TerminalInput::ArrowRight(m) => (KeySequence::Cursor(b'C'), m),
+            TerminalInput::ArrowLeft(m) => (KeySequence::Cursor(b'D'), m),
+            TerminalInput::ArrowUp(m) => (KeySequence::Cursor(b'A'), m),
+            TerminalInput::ArrowDown(m) => (KeySequence::Cursor(b'B'), m),
+            TerminalInput::Home(m) => (KeySequence::Cursor(b'H'), m),
+            TerminalInput::End(m) => (KeySequence::Cursor(b'F'), m),
+            TerminalInput::Insert(m) => (KeySequence::Tilde(2), m),
+            TerminalInput::Delete(m) => (KeySequence::Tilde(3), m),
+            TerminalInput::PageUp(m) => (KeySequence::Tilde(5), m),
+            TerminalInput::PageDown(m) => (KeySequence::Tilde(6), m),
+            TerminalInput::Function(n, m) => {
+                let sequence = match n {
+                    1 => KeySequence::Ss3(b'P'),
+                    2 => KeySequence::Ss3(b'Q'),
+                    3 => KeySequence::Ss3(b'R'),
+                    4 => KeySequence::Ss3(b'S'),
+                    5 => KeySequence::Tilde(15),
+                    6 => KeySequence::Tilde(17),
+                    7 => KeySequence::Tilde(18),
+                    8 => KeySequence::Tilde(19),
+                    9 => KeySequence::Tilde(20),
+                    10 => KeySequence::Tilde(21),
+                    11 => KeySequence::Tilde(23),
+                    12 => KeySequence::Tilde(24),
+                    _ => return TerminalInputPayload::Many(Vec::new()),
+                };
+                (sequence, m)
+            }
+            TerminalInput::Keypad(c) if !keypad_application_mode => {
+                return TerminalInputPayload::Single(*c)
+            }
+            TerminalInput::Keypad(c) => match keypad_application_final(*c) {
+                Some(final_byte) => (KeySequence::Ss3(final_byte), &KeyModifiers::default()),
+                None => return TerminalInputPayload::Many(Vec::new()),
             },
-        }
-    }
-}
+        };
 
-/// Spawn a shell in a child process and return the file descriptor used for I/O
-fn spawn_shell() -> OwnedFd {
-    unsafe {
-        let res = nix::pty::forkpty(None, None).unwrap();
-        match res.fork_result {
-            ForkResult::Parent { .. } => (),
-            ForkResult::Child => {
-                let shell_name = CStr::from_bytes_with_nul(b"bash\0")
-                    .expect("Should always have null terminator");
-                let args: &[&[u8]] = &[b"bash\0",];
-
-                let args: Vec<&'static CStr> = args
-                    .iter()
-                    .map(|v| {
-                        CStr::from_bytes_with_nul(v).expect("Should always have null terminator")
-                    })
-                    .collect::<Vec<_>>();
-
-                // Temporary workaround to avoid rendering issues
-                std::env::remove_var("PROMPT_COMMAND");
-                std::env::set_var("PS1", "$ ");
-                nix::unistd::execvp(shell_name, &args).unwrap();
-                // Should never run
-                std::process::exit(1);
-            }
-        }
-        res.master
+        TerminalInputPayload::Many(sequence.encode(*modifiers, decckm_mode))
     }
 }
 
-fn update_cursor(incoming: &[u8], cursor: &mut CursorState) {
-    for c in incoming {
-        match c {
-            b'\n' => {
-                cursor.pos.x = 0;
-                cursor.pos.y += 1;
-            }
-            _ => {
-                cursor.pos.x += 1;
-            }
-        }
+/// Bytes sent to the shell for pasted `text`
+///
+/// Newlines are sent as carriage returns, like pressing enter. With `filter_escape` set, ESC is
+/// dropped so that pasted text cannot smuggle in control sequences (e.g. ending a bracketed paste
+/// early)
+fn paste_payload(text: &str, bracketed: bool, filter_escape: bool) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(text.len() + 12);
+    if bracketed {
+        payload.extend_from_slice(b"\x1b[200~");
     }
-}
-
-fn set_nonblock(fd: &OwnedFd) {
-    let flags = nix::fcntl::fcntl(fd.as_raw_fd(), nix::fcntl::FcntlArg::F_GETFL).unwrap();
-    let mut flags =
-        nix::fcntl::OFlag::from_bits(flags & nix::fcntl::OFlag::O_ACCMODE.bits()).unwrap();
-    flags.set(nix::fcntl::OFlag::O_NONBLOCK, true);
-
-    nix::fcntl::fcntl(fd.as_raw_fd(), nix::fcntl::FcntlArg::F_SETFL(flags)).unwrap();
-}
-
 
-pub fn cursor_to_buffer_position(cursor_pos: &CursorState, buf: &[u8]) -> usize {
-    let line_start = buf
-        .split(|b| *b == b'\n')
-        .take(cursor_pos.pos.y)
-        .fold(0, |acc, item| acc + item.len() + 1);
-    line_start + cursor_pos.pos.x
-}
-
-/// Inserts data at position in buf, extending if necessary
-fn insert_data_at_position(data: &[u8], pos: usize, buf: &mut Vec<u8>) {
-    assert!(
-        pos <= buf.len(),
-        "assume pos is never more than 1 past the end of the buffer"
+    let text = text.replace("\r\n", "\r").replace('\n', "\r");
+    payload.extend(
+        text.bytes()
+            .filter(|b| !filter_escape || *b != b'\x1b'),
     );
 
-    if pos >= buf.len() {
-        assert_eq!(pos, buf.len());
-        buf.extend_from_slice(data);
-        return;
-    }
-
-    let amount_that_fits = buf.len() - pos;
-    let (data_to_copy, data_to_push): (&[u8], &[u8]) = if amount_that_fits > data.len() {
-        (&data, &[])
-    } else {
-        data.split_at(amount_that_fits)
-    };
-
-    buf[pos..pos + data_to_copy.len()].copy_from_slice(data_to_copy);
-    buf.extend_from_slice(data_to_push);
-}
-
-fn delete_items_from_vec<T>(mut to_delete: Vec<usize>, vec: &mut Vec<T>) {
-    to_delete.sort();
-    for idx in to_delete.iter().rev() {
-        vec.remove(*idx);
+    if bracketed {
+        payload.extend_from_slice(b"\x1b[201~");
     }
+    payload
 }
 
-struct ColorRangeAdjustment {
-    // If a range adjustment results in a 0 width element we need to delete it
-    should_delete: bool,
-    // If a range was split we need to insert a new one
-    to_insert: Option<FormatTag>,
+/// Where the emulator reads output from and writes input to
+enum Backend {
+    Pty(Pty),
+    /// Caller supplied streams, for driving the emulator without a shell
+    Io {
+        reader: Box<dyn Read + Send>,
+        writer: Box<dyn Write + Send>,
+    },
 }
 
-/// if a and b overlap like
-/// a:  [         ]
-/// b:      [  ]
-fn range_fully_conatins(a: &Range<usize>, b: &Range<usize>) -> bool {
-    a.start <= b.start && a.end >= b.end
-}
+impl Backend {
+    fn reader(&mut self) -> &mut dyn Read {
+        match self {
+            Backend::Pty(pty) => pty.reader(),
+            Backend::Io { reader, .. } => reader,
+        }
+    }
 
-/// if a and b overlap like
-/// a:     [      ]
-/// b:  [     ]
-fn range_starts_overlapping(a: &Range<usize>, b: &Range<usize>) -> bool {
-    a.start > b.start && a.end > b.end
-}
+    fn writer(&mut self) -> &mut dyn Write {
+        match self {
+            Backend::Pty(pty) => pty.writer(),
+            Backend::Io { writer, .. } => writer,
+        }
+    }
 
-/// if a and b overlap like
-/// a: [      ]
-/// b:    [      ]
-fn range_ends_overlapping(a: &Range<usize>, b: &Range<usize>) -> bool {
-    range_starts_overlapping(b, a)
-}
+    fn set_window_size(&mut self, window_size: WindowSize) -> Result<(), TerminalError> {
+        match self {
+            Backend::Pty(pty) => pty.set_window_size(window_size),
+            Backend::Io { .. } => Ok(()),
+        }
+    }
 
-fn adjust_existing_format_range(
-    existing_elem: &mut FormatTag,
-    range: &Range<usize>,
-) -> ColorRangeAdjustment {
-    let mut ret = ColorRangeAdjustment {
-        should_delete: false,
-        to_insert: None,
-    };
+    fn flow_control_enabled(&self) -> bool {
+        match self {
+            Backend::Pty(pty) => pty.flow_control_enabled(),
+            Backend::Io { .. } => false,
+        }
+    }
 
-    let existing_range = existing_elem.start..existing_elem.end;
-    if range_fully_conatins(range, &existing_range) {
-        ret.should_delete = true;
-    } else if range_fully_conatins(&existing_range, range) {
-        if existing_elem.start == range.start {
-            ret.should_delete = true;
-        }
-
-        if range.end != existing_elem.end {
-            ret.to_insert = Some(FormatTag {
-                start: range.end,
-                end: existing_elem.end,
-                fg_color: existing_elem.fg_color,  // CHANGED
-                bg_color: existing_elem.bg_color,  // CHANGED
-                bold: existing_elem.bold,
-                italic: existing_elem.italic,
-                blink: existing_elem.blink,
-            });
-        }
-
-        existing_elem.end = range.start;
-    } else if range_starts_overlapping(range, &existing_range) {
-        existing_elem.end = range.start;
-        if existing_elem.start == existing_elem.end {
-            ret.should_delete = true;
-        }
-    } else if range_ends_overlapping(range, &existing_range) {
-        existing_elem.start = range.end;
-        if existing_elem.start == existing_elem.end {
-            ret.should_delete = true;
-        }
-    } else {
-        panic!(
-            "Unhandled case {}-{}, {}-{}",
-            existing_elem.start, existing_elem.end, range.start, range.end
-        );
+    fn hang_up(&mut self) {
+        if let Backend::Pty(pty) = self {
+            pty.hang_up();
+        }
     }
 
-    ret
+    fn reap(&mut self, deadline: Instant) {
+        if let Backend::Pty(pty) = self {
+            pty.reap(deadline);
+        }
+    }
 }
 
-fn adjust_existing_format_ranges(existing: &mut Vec<FormatTag>, range: &Range<usize>) {
-    let mut effected_infos = existing
-        .iter_mut()
-        .enumerate()
-        .filter(|(_i, item)| ranges_overlap(item.start..item.end, range.clone()))
-        .collect::<Vec<_>>();
-
-    let mut to_delete = Vec::new();
-    let mut to_push = Vec::new();
-    for info in &mut effected_infos {
-        let adjustment = adjust_existing_format_range(info.1, range);
-        if adjustment.should_delete {
-            to_delete.push(info.0);
-        }
-        if let Some(item) = adjustment.to_insert {
-            to_push.push(item);
+/// Write all of `to_write`, retrying while the non-blocking writer is not ready
+fn write_all_nonblocking<W: Write + ?Sized>(writer: &mut W, mut to_write: &[u8]) -> Result<(), TerminalError> {
+    while !to_write.is_empty() {
+        match writer.write(to_write) {
+            Ok(written) => to_write = &to_write[written..],
+            Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(TerminalError::from_pty_io(e)),
         }
     }
-
-    delete_items_from_vec(to_delete, existing);
-    existing.extend(to_push);
-}
-pub fn buffer_index_to_cursor_pos(buf: &[u8], index: usize) -> (usize, usize) {
-    let mut y = 0;
-    let mut current = 0;
-    for line in buf.split(|&b| b == b'\n') {
-        let line_len = line.len();
-        if current + line_len >= index {
-            return (index - current, y);
-        }
-        current += line_len + 1; // +1 for the newline character
-        y += 1;
-    }
-    (0, y)
+    Ok(())
 }
 
+/// Split format tags to match [`TerminalEmulator::data`], clamping them to the trimmed content
 fn split_format_data_for_scrollback(
     tags: Vec<FormatTag>,
-    scrollback_split: usize,
+    ranges: &TerminalData<Range<usize>>,
 ) -> TerminalData<Vec<FormatTag>> {
+    let scrollback_end = ranges.scrollback.end;
     let scrollback_tags = tags
         .iter()
-        .filter(|tag| tag.start < scrollback_split)
+        .filter(|tag| tag.start < scrollback_end)
         .cloned()
         .map(|mut tag| {
-            tag.end = tag.end.min(scrollback_split);
+            tag.end = tag.end.min(scrollback_end);
             tag
         })
         .collect();
 
+    let visible_start = ranges.visible.start;
+    let visible_len = ranges.visible.len();
     let canvas_tags = tags
         .into_iter()
-        .filter(|tag| tag.end > scrollback_split)
+        .filter(|tag| tag.end > visible_start)
         .map(|mut tag| {
-            tag.start = tag.start.saturating_sub(scrollback_split);
+            tag.start = tag.start.saturating_sub(visible_start);
             if tag.end != usize::MAX {
-                tag.end -= scrollback_split;
+                tag.end = (tag.end - visible_start).min(visible_len);
             }
             tag
         })
+        .filter(|tag| tag.start < tag.end)
         .collect();
 
     TerminalData {
@@ -342,38 +454,61 @@ pub struct CursorPos {
     pub x: usize,
     pub y: usize,
 }
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[allow(clippy::enum_variant_names)]
 pub enum BlinkMode {
+    #[default]
     NoBlink,
     SlowBlink,
     RapidBlink,
 }
+
+/// Text attributes set through SGR, everything but the colors
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct TextAttributes {
+    pub bold: bool,
+    pub faint: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub reverse: bool,
+    pub conceal: bool,
+    pub blink: BlinkMode,
+}
+
 #[derive(Clone)]
 pub struct CursorState {
     pos: CursorPos,
-    pub(crate) blink_mode: BlinkMode,
     pub(crate) visible: bool,
-    pub(crate) bold: bool,
-    pub(crate) italic: bool,
+    pub(crate) attributes: TextAttributes,
     pub(crate) fg_color: TerminalColor,  // Separate field for foreground
     pub(crate) bg_color: TerminalColor,
 
 }
 
+impl Default for CursorState {
+    fn default() -> CursorState {
+        CursorState {
+            pos: CursorPos { x: 0, y: 0 },
+            // Terminals start out showing the cursor, DECTCEM can hide it
+            visible: true,
+            attributes: TextAttributes::default(),
+            fg_color: TerminalColor::Default,
+            bg_color: TerminalColor::Default,
+        }
+    }
+}
+
+/// State captured by DECSC (`ESC 7`) and put back by DECRC (`ESC 8`)
+#[derive(Clone)]
+struct SavedCursorState {
+    cursor: CursorState,
+    /// DECOM
+    origin_mode: bool,
+}
+
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum TerminalColor {
     Default,
-    Faint,          // 2
-    Italic,         // 3
-    Underline,      // 4
-    BlinkSlow,      // 5
-    BlinkRapid,     // 6
-    Reverse,        // 7
-    Conceal,        // 8
-    Reveal,         // 28 (companion to 8)
-    NotItalic,      // 23
-    NotUnderline,   // 24
-    NormalIntensity,// 22
     ForegroundBlack,
     ForegroundRed,
     ForegroundGreen,
@@ -409,6 +544,7 @@ pub enum TerminalColor {
     BackgroundBrightWhite,
     BackgroundTrueColor(u8, u8, u8),
     Foreground8Bit(u8),
+    Background8Bit(u8),
 }
 
 impl TerminalColor {
@@ -451,160 +587,99 @@ impl TerminalColor {
             SelectGraphicRendition::Foreground8Bit(n) => {
                 Some(TerminalColor::Foreground8Bit(n))
             },
-            SelectGraphicRendition::BlinkSlow => Some(TerminalColor::BlinkSlow),
-            SelectGraphicRendition::BlinkRapid => Some(TerminalColor::BlinkRapid),
+            SelectGraphicRendition::Background8Bit(n) => Some(TerminalColor::Background8Bit(n)),
             _ => None,
         }
     }
 
 
-   pub fn index_to_rgb(&self, index: u32) -> (u8, u8, u8) {
-        if index >= 16 && index <= 231 {
-            // Convert index to RGB in the 6x6x6 color cube
-            let index = index - 16;
-            let r = ((index / 36) % 6) * 51;
-            let g = ((index / 6) % 6) * 51;
-            let b = (index % 6) * 51;
-            (r as u8, g as u8, b as u8)
-        } else if index >= 232 && index <= 255 {
-            // Grayscale range
-            let gray = 8 + (index - 232) * 10;
-            (gray as u8, gray as u8, gray as u8)
-        } else {
-            // Default to white for invalid indices
-            (255, 255, 255)
-        }
-    }
-}
-
-fn ranges_overlap(a: Range<usize>, b: Range<usize>) -> bool {
-    if a.end <= b.start {
-        return false;
-    }
-
-    if a.start >= b.end {
-        return false;
+    /// Palette entry the color is drawn with, None for the default and direct RGB colors
+    pub fn palette_index(&self) -> Option<u8> {
+        let index = match self {
+            TerminalColor::Default
+            | TerminalColor::ForegroundRgb(..)
+            | TerminalColor::BackgroundTrueColor(..) => return None,
+            TerminalColor::ForegroundBlack | TerminalColor::BackgroundBlack => 0,
+            TerminalColor::ForegroundRed | TerminalColor::BackgroundRed => 1,
+            TerminalColor::ForegroundGreen | TerminalColor::BackgroundGreen => 2,
+            TerminalColor::ForegroundYellow | TerminalColor::BackgroundYellow => 3,
+            TerminalColor::ForegroundBlue | TerminalColor::BackgroundBlue => 4,
+            TerminalColor::ForegroundMagenta | TerminalColor::BackgroundMagenta => 5,
+            TerminalColor::ForegroundCyan | TerminalColor::BackgroundCyan => 6,
+            TerminalColor::ForegroundWhite | TerminalColor::BackgroundWhite => 7,
+            TerminalColor::ForegroundBrightBlack | TerminalColor::BackgroundBrightBlack => 8,
+            TerminalColor::ForegroundBrightRed | TerminalColor::BackgroundBrightRed => 9,
+            TerminalColor::ForegroundBrightGreen | TerminalColor::BackgroundBrightGreen => 10,
+            TerminalColor::ForegroundBrightYellow | TerminalColor::BackgroundBrightYellow => 11,
+            TerminalColor::ForegroundBrightBlue | TerminalColor::BackgroundBrightBlue => 12,
+            TerminalColor::ForegroundBrightMagenta | TerminalColor::BackgroundBrightMagenta => 13,
+            TerminalColor::ForegroundBrightCyan | TerminalColor::BackgroundBrightCyan => 14,
+            TerminalColor::ForegroundBrightWhite | TerminalColor::BackgroundBrightWhite => 15,
+            TerminalColor::Foreground8Bit(n) | TerminalColor::Background8Bit(n) => *n,
+        };
+        Some(index)
     }
 
-    true
-}
-
-#[derive(Clone, Debug, Eq, PartialEq)]
-pub struct FormatTag {
-    pub start: usize,
-    pub end: usize,
-    pub blink: bool,
-    pub fg_color: TerminalColor,
-    pub bg_color: TerminalColor,
-    pub bold: bool,
-    pub italic: bool,
-}
-
-struct FormatTracker {
-    color_info: Vec<FormatTag>,
-}
-
-impl FormatTracker {
-    fn new() -> FormatTracker {
-        FormatTracker {
-            color_info: vec![FormatTag {
-                start: 0,
-                end: usize::MAX,
-                fg_color: TerminalColor::Default,  // CHANGED
-                bg_color: TerminalColor::Default,
-                bold: false,
-                italic: false,
-                blink: false,
-            }],
-        }
-    }
-    /// Move all tags > range.start to range.start + range.len
-    /// No gaps in coloring data, so one range must expand instead of just be adjusted
-    pub fn push_range_adjustment(&mut self, range: Range<usize>) {
-        let range_len = range.end - range.start;
-        for info in &mut self.color_info {
-            if info.end <= range.start {
-                continue;
+    /// RGB value of an entry in the 256 color palette, using xterm's defaults
+    pub fn index_to_rgb(index: u8) -> (u8, u8, u8) {
+        match index {
+            // Standard and bright colors
+            0 => (0, 0, 0),
+            1 => (205, 0, 0),
+            2 => (0, 205, 0),
+            3 => (205, 205, 0),
+            4 => (0, 0, 238),
+            5 => (205, 0, 205),
+            6 => (0, 205, 205),
+            7 => (229, 229, 229),
+            8 => (127, 127, 127),
+            9 => (255, 0, 0),
+            10 => (0, 255, 0),
+            11 => (255, 255, 0),
+            12 => (92, 92, 255),
+            13 => (255, 0, 255),
+            14 => (0, 255, 255),
+            15 => (255, 255, 255),
+            // 6x6x6 color cube, the first step is bigger than the others
+            16..=231 => {
+                const LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+                let index = (index - 16) as usize;
+                (LEVELS[index / 36], LEVELS[(index / 6) % 6], LEVELS[index % 6])
             }
-
-            if info.start > range.start {
-                info.start += range_len;
-                if info.end != usize::MAX {
-                    info.end += range_len;
-                }
-            } else if info.end != usize::MAX {
-                info.end += range_len;
+            // Grayscale ramp
+            232..=255 => {
+                let gray = 8 + (index - 232) * 10;
+                (gray, gray, gray)
             }
         }
     }
+}
 
-    fn push_range(&mut self, cursor: &CursorState, range: Range<usize>) {
-        adjust_existing_format_ranges(&mut self.color_info, &range);
-
-        self.color_info.push(FormatTag {
-            start: range.start,
-            end: range.end,
-            fg_color: cursor.fg_color,
-            bg_color: cursor.bg_color,
-            bold: cursor.bold,
-            italic: cursor.italic,
-            blink: cursor.blink_mode != BlinkMode::NoBlink,
-        });
-
-        // FIXME: Insertion sort
-        // FIXME: Merge adjacent
-        self.color_info.sort_by(|a, b| a.start.cmp(&b.start));
-    }
-
-    fn tags(&self) -> Vec<FormatTag> {
-        self.color_info.clone()
-    }
-    fn delete_range(&mut self, range: Range<usize>) {
-        let mut to_delete = Vec::new();
-        let del_size = range.end - range.start;
-
-        for (i, info) in &mut self.color_info.iter_mut().enumerate() {
-            let info_range = info.start..info.end;
-            if info.end <= range.start {
-                continue;
-            }
-
-            if ranges_overlap(range.clone(), info_range.clone()) {
-                if range_fully_conatins(&range, &info_range) {
-                    to_delete.push(i);
-                } else if range_starts_overlapping(&range, &info_range) {
-                    if info.end != usize::MAX {
-                        info.end = range.start;
-                    }
-                } else if range_ends_overlapping(&range, &info_range) {
-                    info.start = range.start;
-                    if info.end != usize::MAX {
-                        info.end -= del_size;
-                    }
-                } else if range_fully_conatins(&info_range, &range) {
-                    if info.end != usize::MAX {
-                        info.end -= del_size;
-                    }
-                } else {
-                    panic!("Unhandled overlap");
-                }
-            } else {
-                assert!(!ranges_overlap(range.clone(), info_range.clone()));
-                info.start -= del_size;
-                if info.end != usize::MAX {
-                    info.end -= del_size;
-                }
-            }
-        }
-
-        for i in to_delete.into_iter().rev() {
-            self.color_info.remove(i);
-        }
-    }
+fn default_palette() -> [(u8, u8, u8); 256] {
+    std::array::from_fn(|i| TerminalColor::index_to_rgb(i as u8))
 }
 
+/// Where an image is on screen, see [`TerminalEmulator::visible_images`]
+#[derive(Clone, Debug)]
+pub struct ImagePlacement {
+    /// Stays the same for as long as the image is shown
+    pub id: u64,
+    /// Cell of the top left corner
+    pub pos: CursorPos,
+    pub cells_wide: usize,
+    pub cells_high: usize,
+    pub image: Arc<TerminalImage>,
+}
 
-ioctl_write_ptr_bad!(set_window_size, nix::libc::TIOCSWINSZ, nix::pty::Winsize);
+/// One character on the screen with its formatting, see [`TerminalEmulator::cell`]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Cell {
+    /// Columns are bytes, so the columns after a multi-byte character hold `'\0'`
+    pub ch: char,
+    pub fg: TerminalColor,
+    pub bg: TerminalColor,
+    pub attrs: TextAttributes,
+}
 
 pub struct TerminalData<T> {
     pub scrollback: T,
@@ -612,79 +687,185 @@ pub struct TerminalData<T> {
 }
 pub struct TerminalEmulator {
     output_buf: AnsiParser,
-    buf:TerminalBuffer,
+    screen: Screen,
     decckm_mode: bool,
-    format_tracker: FormatTracker,
+    /// DECKPAM, the keypad sends escape sequences instead of characters
+    keypad_application_mode: bool,
+    /// IRM, printed text pushes the rest of the line right instead of overwriting it
+    insert_mode: bool,
+    /// DECOM, row positions are relative to the scroll region and the cursor stays inside it
+    origin_mode: bool,
+    /// DECSCNM, the default foreground and background are swapped on the whole screen
+    reverse_screen: bool,
+    tab_stops: TabStops,
+    bracketed_paste_mode: bool,
+    filter_paste_escapes: bool,
     pub(crate) cursor_state: CursorState,
-    fd: OwnedFd,
+    saved_cursor: Option<SavedCursorState>,
+    /// DECSTBM rows, None when the whole screen scrolls
+    scroll_region: Option<Range<usize>>,
+    /// DECLRMM, CSI s sets `column_margins` rather than saving the cursor while on
+    left_right_margin_mode: bool,
+    /// DECSLRM columns, None when insertions and deletions go up to the edge of the screen
+    column_margins: Option<Range<usize>>,
+    backend: Backend,
     alt_screen_active: bool,
-    main_buf: Option<TerminalBuffer>,
-    main_format_tracker: Option<FormatTracker>,
+    main_screen: Option<Screen>,
     main_cursor_state: Option<CursorState>,
+    /// Output is left in the pty while set, see [`TerminalEmulator::set_paused`]
+    paused: bool,
+    /// BEL was received and the GUI has not reacted to it yet
+    bell_pending: bool,
+    /// Output was applied or the size changed since the GUI last looked, see
+    /// [`TerminalEmulator::take_dirty`]
+    dirty: bool,
+    /// Set once [`TerminalEmulator::spawn_reader_thread`] took over reading the pty
+    reader_thread: Option<ReaderThread>,
+    /// See [`TerminalEmulator::start_recording`]
+    recording: Recording,
+    /// See [`TerminalEmulator::set_tracing`], shared with the reader thread
+    tracer: Tracer,
+    /// Pixel size of a character cell, used to size images in cells
+    cell_size: (usize, usize),
+    next_image_id: u64,
+    /// Let programs set (OSC 52) and read (OSC 52 `?`) the clipboard, both off by default since
+    /// anything that can write to the terminal could use them, e.g. `cat` on a downloaded file
+    allow_clipboard_write: bool,
+    allow_clipboard_read: bool,
+    /// OSC 52 requests waiting for the GUI
+    clipboard_write: Option<(ClipboardSelection, String)>,
+    clipboard_query: Option<ClipboardSelection>,
+    /// 256 color palette as changed by OSC 4, see [`TerminalColor::palette_index`]
+    palette: [(u8, u8, u8); 256],
+    /// What `palette` is reset to, see [`TerminalEmulator::set_ansi_colors`]
+    base_palette: [(u8, u8, u8); 256],
+    /// Set by OSC 10, 11 and 12, None leaves the choice to the GUI
+    foreground_color: Option<(u8, u8, u8)>,
+    background_color: Option<(u8, u8, u8)>,
+    cursor_color: Option<(u8, u8, u8)>,
+    /// Set by DECSCUSR, None leaves the choice to the GUI
+    cursor_style: Option<CursorStyle>,
+    /// Last directory the shell reported with OSC 7
+    working_directory: Option<PathBuf>,
+    /// Set by OSC 0 and 2, saved and restored with CSI 22 t and CSI 23 t
+    title: String,
+    title_stack: Vec<String>,
+    /// OSC 133 marks waiting for the text they come before, the cursor may not be on a part of
+    /// the buffer that exists yet when they arrive
+    pending_prompt_marks: Vec<PromptMarkKind>,
 }
 
 impl TerminalEmulator {
-    pub fn new() -> TerminalEmulator {
-        let fd = spawn_shell();
-        set_nonblock(&fd);
-        let win_size = nix::pty::Winsize {
-            ws_row: TERMINAL_HEIGHT,
-            ws_col: TERMINAL_WIDTH,
-            ws_xpixel: 0,
-            ws_ypixel: 0,
+    pub fn new(options: Options) -> Result<TerminalEmulator, TerminalError> {
+        let window_size = WindowSize {
+            num_lines: TERMINAL_HEIGHT,
+            num_cols: TERMINAL_WIDTH,
+            cell_width: 0,
+            cell_height: 0,
         };
+        let pty = tty::new(&options, window_size, 0)?;
+        Ok(TerminalEmulator::with_backend(Backend::Pty(pty)))
+    }
 
-        unsafe {
-            set_window_size(fd.as_raw_fd(), &win_size).unwrap();
-        }
+    /// Run on top of arbitrary streams instead of spawning a shell
+    ///
+    /// Output is taken from `reader` by [`TerminalEmulator::read`], the other end closing it is
+    /// reported as [`TerminalError::ChildExited`]. Input is written to `writer`
+    pub fn with_io<R, W>(reader: R, writer: W) -> TerminalEmulator
+    where
+        R: Read + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        TerminalEmulator::with_backend(Backend::Io {
+            reader: Box::new(reader),
+            writer: Box::new(writer),
+        })
+    }
 
+    fn with_backend(backend: Backend) -> TerminalEmulator {
         TerminalEmulator {
             output_buf: AnsiParser::new(),
-            buf: TerminalBuffer::new(TERMINAL_WIDTH as usize, TERMINAL_HEIGHT as usize),
-            format_tracker: FormatTracker::new(),
-            cursor_state: CursorState {
-                pos: CursorPos { x: 0, y: 0 },
-                visible: false,
-                bold: false,
-                fg_color: TerminalColor::Default,
-                bg_color: TerminalColor::Default,
-                italic: false,
-                blink_mode: BlinkMode::NoBlink,
-            },
+            screen: Screen::new(TERMINAL_WIDTH as usize, TERMINAL_HEIGHT as usize),
+            cursor_state: CursorState::default(),
+            saved_cursor: None,
+            scroll_region: None,
+            left_right_margin_mode: false,
+            column_margins: None,
             decckm_mode: false,
-            fd,
+            keypad_application_mode: false,
+            insert_mode: false,
+            origin_mode: false,
+            reverse_screen: false,
+            tab_stops: TabStops::new(TERMINAL_WIDTH as usize),
+            bracketed_paste_mode: false,
+            filter_paste_escapes: true,
+            backend,
             alt_screen_active: false,
-            main_buf: None,
-            main_format_tracker: None,
+            main_screen: None,
             main_cursor_state: None,
+            paused: false,
+            bell_pending: false,
+            dirty: false,
+            reader_thread: None,
+            recording: Recording::default(),
+            tracer: Tracer::default(),
+            cell_size: DEFAULT_CELL_SIZE,
+            next_image_id: 0,
+            allow_clipboard_write: false,
+            allow_clipboard_read: false,
+            clipboard_write: None,
+            clipboard_query: None,
+            palette: default_palette(),
+            base_palette: default_palette(),
+            foreground_color: None,
+            background_color: None,
+            cursor_color: None,
+            cursor_style: None,
+            working_directory: None,
+            title: String::new(),
+            title_stack: Vec::new(),
+            pending_prompt_marks: Vec::new(),
         }
     }
     fn move_cursor_relative(&mut self, dx: isize, dy: isize) {
-        let (width, height) = self.buf.dimensions();
+        self.screen.cancel_pending_wrap();
+        let pos = &mut self.cursor_state.pos;
+        pos.x = pos.x.saturating_add_signed(dx);
+
+        let mut new_y = pos.y.saturating_add_signed(dy);
+        // Vertical moves that start inside the scroll region stop at its margins
+        if let Some(region) = &self.scroll_region {
+            if region.contains(&pos.y) {
+                new_y = new_y.clamp(region.start, region.end - 1);
+            }
+        }
+        pos.y = new_y;
 
-        // Calculate new position
-        let new_x = (self.cursor_state.pos.x as isize + dx).max(0).min(width as isize - 1);
-        let new_y = (self.cursor_state.pos.y as isize + dy).max(0).min(height as isize - 1);
+        self.clamp_cursor();
+    }
 
-        self.cursor_state.pos.x = new_x as usize;
-        self.cursor_state.pos.y = new_y as usize;
+    /// Pull the cursor back onto the screen after it was moved
+    ///
+    /// Writes never leave it off screen either, filling the last column keeps the cursor on it
+    /// and the buffer remembers that the next character wraps
+    fn clamp_cursor(&mut self) {
+        let (width, height) = self.screen.dimensions();
+        let pos = &mut self.cursor_state.pos;
+        pos.x = pos.x.min(width.saturating_sub(1));
+        pos.y = pos.y.min(height.saturating_sub(1));
     }
     fn enter_alt_screen(&mut self) {
         if self.alt_screen_active {
             return;
         }
 
-        let width = self.buf.width;
-        let height = self.buf.height;
+        let (width, height) = self.screen.dimensions();
 
-        self.main_buf = Some(std::mem::replace(
-            &mut self.buf,
-            TerminalBuffer::new(width, height)
-        ));
-        self.main_format_tracker = Some(std::mem::replace(
-            &mut self.format_tracker,
-            FormatTracker::new()
-        ));
+        // Wrap mode is terminal wide, not per screen
+        let mut alt_screen = Screen::new(width, height);
+        alt_screen.set_auto_wrap(self.screen.auto_wrap());
+
+        self.main_screen = Some(std::mem::replace(&mut self.screen, alt_screen));
         self.main_cursor_state = Some(self.cursor_state.clone());
 
         self.cursor_state.pos = CursorPos { x: 0, y: 0 };
@@ -697,11 +878,9 @@ impl TerminalEmulator {
         }
 
         // Restore saved state
-        if let Some(main_buf) = self.main_buf.take() {
-            self.buf = main_buf;
-        }
-        if let Some(main_format_tracker) = self.main_format_tracker.take() {
-            self.format_tracker = main_format_tracker;
+        if let Some(mut main_screen) = self.main_screen.take() {
+            main_screen.set_auto_wrap(self.screen.auto_wrap());
+            self.screen = main_screen;
         }
         if let Some(main_cursor_state) = self.main_cursor_state.take() {
             self.cursor_state = main_cursor_state;
@@ -710,342 +889,2757 @@ impl TerminalEmulator {
         self.alt_screen_active = false;
     }
 
-    pub fn write(&mut self, to_write: TerminalInput) {
-        match to_write.to_payload(self.decckm_mode) {
-            TerminalInputPayload::Single(c) => {
-                let mut written = 0;
-                while written == 0 {
-                    written = nix::unistd::write(self.fd.as_raw_fd(), &[c]).unwrap();
-                }
-            }
-            TerminalInputPayload::Many(mut to_write) => {
-                while !to_write.is_empty() {
-                    let written = nix::unistd::write(self.fd.as_raw_fd(), to_write).unwrap();
-                    to_write = &to_write[written..];
-                }
-            }
-        };
-    }
+    /// Move down a row, scrolling if the cursor is on the bottom row of the scroll region
+    fn newline(&mut self) {
+        let (_, height) = self.screen.dimensions();
+        let region = self.scroll_region.clone().unwrap_or(0..height);
 
+        if self.cursor_state.pos.y + 1 == region.end {
+            self.screen.scroll_up(region);
+        } else if self.cursor_state.pos.y + 1 < height {
+            self.cursor_state.pos.y += 1;
+        }
+    }
 
-    pub fn read(&mut self) {
-            let mut buf = vec![0u8; 4096];
-            let mut ret = Ok(0);
-            while ret.is_ok() {
-                ret = nix::unistd::read(self.fd.as_raw_fd(), &mut buf);
-                let Ok(read_size) = ret else {
-                    break;
-                };
+    fn set_scroll_region(&mut self, top: Option<usize>, bottom: Option<usize>) {
+        let (_, height) = self.screen.dimensions();
+        let top = top.unwrap_or(1).max(1) - 1;
+        let bottom = bottom.unwrap_or(height).min(height);
 
-                let incoming = &buf[0..read_size];
-                debug!("Incoming data: {:?}", std::str::from_utf8(incoming));
-                let parsed = self.output_buf.push(incoming);
-                for segment in parsed {
-                    match segment {
-                        TerminalOutput::CursorUp(lines) => {
-                            self.move_cursor_relative(0, -(lines as isize));
-                        }
-                        TerminalOutput::CursorDown(lines) => {
-                            self.move_cursor_relative(0, lines as isize);
-                        }
-                        TerminalOutput::CursorForward(cols) => {
-                            self.move_cursor_relative(cols as isize, 0);
-                        }
-                        TerminalOutput::CursorBackward(cols) => {
-                            self.move_cursor_relative(-(cols as isize), 0);
-                        }
-                        TerminalOutput::EnterAltScreen => {
-                            self.enter_alt_screen();
-                        }
-                        TerminalOutput::ExitAltScreen => {
-                            self.exit_alt_screen();
-                        }
-                        TerminalOutput::Data(data) => {
-                            let response = self
-                                .buf
-                                .insert_data(&self.cursor_state.pos, &data);
-                            self.format_tracker.push_range_adjustment(response.insertion_range);
-                            self.format_tracker
-                                .push_range(&self.cursor_state, response.written_range);
-                            self.cursor_state.pos = response.new_cursor_pos;
-                        }
-                        TerminalOutput::SetCursorVisibility(visible) => {
-                            self.cursor_state.visible = visible;
-                        }
-                        TerminalOutput::SetCursorPos { x, y } => {
-                            if let Some(x) = x {
-                                self.cursor_state.pos.x = x - 1;
-                            }
-                            if let Some(y) = y {
-                                self.cursor_state.pos.y = y - 1;
-                            }
-                        }
-                        TerminalOutput::ClearForwards => {
-                            if let Some(buf_pos) =
-                                self.buf.clear_forwards(&self.cursor_state.pos)
-                            {
-                                self.format_tracker
-                                    .push_range(&self.cursor_state, buf_pos..usize::MAX);
-                            }
-                        }
-                        
-                        TerminalOutput::CarriageReturn => {
-                            self.cursor_state.pos.x = 0;
-                        }
-                        TerminalOutput::Newline => {
-                            self.cursor_state.pos.y += 1;
-                        }
-                        TerminalOutput::Backspace => {
-                            if self.cursor_state.pos.x >= 1 {
-                                self.cursor_state.pos.x -= 1;
-                            }
-                        }
-                        TerminalOutput::Delete(num_chars) => {
-                            let deleted_buf_range = self
-                                .buf
-                                .delete_forwards(&self.cursor_state.pos, num_chars);
-                            if let Some(range) = deleted_buf_range {
-                                self.format_tracker.delete_range(range);
-                            }
-                        }
-                        TerminalOutput::InsertSpaces(num_spaces) => {
-                            let response = self
-                                .buf
-                                .insert_spaces(&self.cursor_state.pos, num_spaces);
-
-                            self.format_tracker
-                                .push_range_adjustment(response.insertion_range);
-                        }
-                        TerminalOutput::ClearLineForwards => {
-                            if let Some(range) = self
-                                .buf
-                                .clear_line_forwards(&self.cursor_state.pos)
-                            {
-                                self.format_tracker.delete_range(range);
-                            }
-                        }
-
-                        TerminalOutput::ClearAll => {
-                            self.format_tracker
-                                .push_range(&self.cursor_state, 0..usize::MAX);
-                            self.buf.clear_all();
-                        }
-                        TerminalOutput::Sgr(sgr) => {
-                            if let Some(color) = TerminalColor::from_sgr(sgr) {
-                                // Determine if it's foreground or background
-                                match color {
-                                    TerminalColor::BackgroundBlack
-                                    | TerminalColor::BackgroundRed
-                                    | TerminalColor::BackgroundGreen
-                                    | TerminalColor::BackgroundYellow
-                                    | TerminalColor::BackgroundBlue
-                                    | TerminalColor::BackgroundMagenta
-                                    | TerminalColor::BackgroundCyan
-                                    | TerminalColor::BackgroundWhite
-                                    | TerminalColor::BackgroundBrightBlack
-                                    | TerminalColor::BackgroundBrightRed
-                                    | TerminalColor::BackgroundBrightGreen
-                                    | TerminalColor::BackgroundBrightYellow
-                                    | TerminalColor::BackgroundBrightBlue
-                                    | TerminalColor::BackgroundBrightMagenta
-                                    | TerminalColor::BackgroundBrightCyan
-                                    | TerminalColor::BackgroundBrightWhite
-                                    | TerminalColor::BackgroundTrueColor(_, _, _) => {
-                                        self.cursor_state.bg_color = color;
-                                    }
-                                    _ => {
-                                        self.cursor_state.fg_color = color;
-                                    }
-                                }
-                            } else if sgr == SelectGraphicRendition::Reset {
-                                self.cursor_state.fg_color = TerminalColor::Default;
-                                self.cursor_state.bg_color = TerminalColor::Default;
-                                self.cursor_state.bold = false;
-                                self.cursor_state.italic = false;
-                                self.cursor_state.blink_mode = BlinkMode::NoBlink;
-                            } else if sgr == SelectGraphicRendition::ForegroundDefault {
-                                self.cursor_state.fg_color = TerminalColor::Default;
-                            } else if sgr == SelectGraphicRendition::BackgroundDefault {
-                                self.cursor_state.bg_color = TerminalColor::Default;
-                            } else if sgr == SelectGraphicRendition::Bold {
-                                self.cursor_state.bold = true;
-                            } else if sgr == SelectGraphicRendition::Italic {
-                                self.cursor_state.italic = true;
-                            } else if sgr == SelectGraphicRendition::BlinkSlow {
-                                self.cursor_state.blink_mode = BlinkMode::SlowBlink;
-                            } else if sgr == SelectGraphicRendition::BlinkRapid {
-                                self.cursor_state.blink_mode = BlinkMode::RapidBlink;
-                            } else {
-                                warn!("Unhandled sgr: {:?}", sgr);
-                            }
-                        }
-                        TerminalOutput::SetMode(mode) => match mode {
-                            Mode::Decckm => {
-                                self.decckm_mode = true;
-                            }
-                            _ => {
-                                warn!("unhandled set mode: {mode:?}");
-                            }
-                        },
-                        TerminalOutput::ResetMode(mode) => match mode {
-                            Mode::Decckm => {
-                                self.decckm_mode = false;
-                            }
-                            _ => {
-                                warn!("unhandled set mode: {mode:?}");
-                            }
-                        },
-                        TerminalOutput::Invalid => {}
-                    }
-                }
-            }
+        // A region has to be at least two rows, otherwise the request is ignored
+        if top + 1 >= bottom {
+            warn!("Ignoring invalid scroll region {top}..{bottom}");
+            return;
+        }
 
+        self.scroll_region = if top == 0 && bottom == height {
+            None
+        } else {
+            Some(top..bottom)
+        };
+        self.home_cursor();
+    }
 
+    fn set_column_margins(&mut self, left: Option<usize>, right: Option<usize>) {
+        let (width, _) = self.screen.dimensions();
+        let left = left.unwrap_or(1).max(1) - 1;
+        let right = right.unwrap_or(width).min(width);
 
-            if let Err(e) = ret {
-                if e != Errno::EAGAIN {
-                    error!("Failed to read: {e}");
-                }
-            }
+        // Like the scroll region the margins have to be at least two columns apart
+        if left + 1 >= right {
+            warn!("Ignoring invalid column margins {left}..{right}");
+            return;
         }
 
-    pub fn is_alt_screen_active(&self) -> bool {
-        self.alt_screen_active
+        self.column_margins = if left == 0 && right == width {
+            None
+        } else {
+            Some(left..right)
+        };
+        self.home_cursor();
     }
-    pub fn data(&self) -> TerminalData<&[u8]> {
-        self.buf.data()
+
+    /// The column ICH, DCH and ECH stop before, None if the cursor is outside the margins and ICH
+    /// and DCH do nothing
+    fn cursor_right_margin(&self) -> Option<usize> {
+        match &self.column_margins {
+            Some(margins) => margins
+                .contains(&self.cursor_state.pos.x)
+                .then_some(margins.end),
+            None => Some(self.screen.dimensions().0),
+        }
     }
 
-    pub fn format_data(&self) -> TerminalData<Vec<FormatTag>> {
-        let offset = self.buf.data().scrollback.len();
-        split_format_data_for_scrollback(self.format_tracker.tags(), offset)
+    /// Rows the cursor can be put on, the scroll region in origin mode and the screen otherwise
+    fn cursor_row_range(&self) -> Range<usize> {
+        match &self.scroll_region {
+            Some(region) if self.origin_mode => region.clone(),
+            _ => 0..self.screen.dimensions().1,
+        }
     }
-    pub fn cursor_pos(&self) -> CursorPos {
-        self.cursor_state.pos.clone()
+
+    fn home_cursor(&mut self) {
+        self.cursor_state.pos = CursorPos {
+            x: 0,
+            y: self.cursor_row_range().start,
+        };
     }
-    pub fn set_win_size(&mut self, width_chars: usize, height_chars: usize) {
-        let response =
-            self.buf
-                .set_win_size(width_chars, height_chars, &self.cursor_state.pos);
-        self.cursor_state.pos = response.new_cursor_pos;
-
-        if response.changed {
-            let win_size = nix::pty::Winsize {
-                ws_row: height_chars as u16,
-                ws_col: width_chars as u16,
-                ws_xpixel: 0,
-                ws_ypixel: 0,
-            };
 
-            unsafe {
-                set_window_size(self.fd.as_raw_fd(), &win_size).unwrap();
-            }
-        }
+    /// DECSTR, put modes and attributes back to their defaults but keep the screen contents
+    fn soft_reset(&mut self) {
+        self.cursor_state = CursorState {
+            pos: self.cursor_state.pos.clone(),
+            visible: true,
+            ..Default::default()
+        };
+        self.saved_cursor = None;
+        self.scroll_region = None;
+        self.column_margins = None;
+        self.decckm_mode = false;
+        self.keypad_application_mode = false;
+        self.insert_mode = false;
+        self.origin_mode = false;
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    /// RIS, back to the state the terminal started in with an empty screen and scrollback
+    fn full_reset(&mut self) {
+        self.exit_alt_screen();
+        self.soft_reset();
+        self.cursor_state.pos = CursorPos { x: 0, y: 0 };
+        self.screen.reset();
+        self.tab_stops.reset();
+        self.bracketed_paste_mode = false;
+        self.left_right_margin_mode = false;
+        self.reverse_screen = false;
+        self.palette = self.base_palette;
+        self.foreground_color = None;
+        self.background_color = None;
+        self.cursor_color = None;
+        self.cursor_style = None;
+    }
 
-    #[test]
-    fn test_cursor_movement_parsing() {
-        let mut parser = AnsiParser::new();
+    /// DECALN, fill the screen with `E` and home the cursor. Like xterm the margins are reset and
+    /// the fill does not use the current colors, which are kept for what comes after
+    fn alignment_test(&mut self) {
+        let (width, height) = self.screen.dimensions();
+        self.scroll_region = None;
+        self.column_margins = None;
+        self.origin_mode = false;
+
+        let saved = std::mem::take(&mut self.cursor_state);
+        self.screen.clear_all(&self.cursor_state);
+        let row = vec![b'E'; width];
+        for y in 0..height {
+            self.cursor_state.pos = CursorPos { x: 0, y };
+            self.insert_data(&row);
+        }
 
-        // Test cursor up
-        let output = parser.push(b"\x1b[5A");
-        assert_eq!(output.len(), 1);
-        assert!(matches!(output[0], TerminalOutput::CursorUp(5)));
+        self.cursor_state = CursorState {
+            pos: CursorPos { x: 0, y: 0 },
+            ..saved
+        };
+    }
 
-        // Test cursor down with default parameter
-        let output = parser.push(b"\x1b[B");
-        assert_eq!(output.len(), 1);
-        assert!(matches!(output[0], TerminalOutput::CursorDown(1)));
+    fn save_cursor(&mut self) {
+        self.saved_cursor = Some(SavedCursorState {
+            cursor: self.cursor_state.clone(),
+            origin_mode: self.origin_mode,
+        });
+    }
 
-        // Test cursor forward
-        let output = parser.push(b"\x1b[10C");
-        assert_eq!(output.len(), 1);
-        assert!(matches!(output[0], TerminalOutput::CursorForward(10)));
+    fn restore_cursor(&mut self) {
+        // Restoring without a prior save resets to the power on state, same as xterm
+        let saved = self.saved_cursor.clone().unwrap_or_else(|| SavedCursorState {
+            cursor: CursorState::default(),
+            origin_mode: false,
+        });
 
-        // Test cursor backward
-        let output = parser.push(b"\x1b[3D");
-        assert_eq!(output.len(), 1);
-        assert!(matches!(output[0], TerminalOutput::CursorBackward(3)));
+        // Visibility is not part of the saved state
+        let visible = self.cursor_state.visible;
+        self.cursor_state = saved.cursor;
+        self.cursor_state.visible = visible;
+        self.origin_mode = saved.origin_mode;
     }
 
-    #[test]
-    fn test_cursor_movement_bounds() {
-        let mut emulator = TerminalEmulator::new();
+    pub fn write(&mut self, to_write: TerminalInput) -> Result<(), TerminalError> {
+        match to_write.to_payload(self.decckm_mode, self.keypad_application_mode) {
+            TerminalInputPayload::Single(c) => self.write_bytes(&[c]),
+            TerminalInputPayload::Many(to_write) => self.write_bytes(&to_write),
+        }
+    }
 
-        // Start at 0,0
-        assert_eq!(emulator.cursor_state.pos, CursorPos { x: 0, y: 0 });
+    /// Send pasted text to the shell, bracketed if the application asked for it
+    pub fn paste(&mut self, text: &str) -> Result<(), TerminalError> {
+        let payload = paste_payload(text, self.bracketed_paste_mode, self.filter_paste_escapes);
+        self.write_bytes(&payload)
+    }
 
-        // Try to move up (should stay at 0)
-        emulator.move_cursor_relative(0, -1);
-        assert_eq!(emulator.cursor_state.pos.y, 0);
+    /// Whether the program asked for pastes to be bracketed, so that it can tell them from typing
+    pub fn bracketed_paste_mode(&self) -> bool {
+        self.bracketed_paste_mode
+    }
 
-        // Try to move left (should stay at 0)
-        emulator.move_cursor_relative(-1, 0);
-        assert_eq!(emulator.cursor_state.pos.x, 0);
+    /// Whether the program put the screen in reverse video with DECSCNM, which swaps the default
+    /// foreground and background for all of the output
+    pub fn reverse_screen(&self) -> bool {
+        self.reverse_screen
+    }
 
-        // Move right
-        emulator.move_cursor_relative(5, 0);
-        assert_eq!(emulator.cursor_state.pos.x, 5);
+    /// Whether ESC is stripped from pasted text, on by default
+    pub fn set_filter_paste_escapes(&mut self, filter: bool) {
+        self.filter_paste_escapes = filter;
+    }
 
-        // Move down
-        emulator.move_cursor_relative(0, 3);
-        assert_eq!(emulator.cursor_state.pos.y, 3);
+    /// Stop reading output from the shell (XOFF). Unread output stays in the pty, so the kernel
+    /// blocks the shell once its buffer fills up. A reader thread gets a little ahead before
+    /// it stops too
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
 
-        // Move back
-        emulator.move_cursor_relative(-2, -1);
-        assert_eq!(emulator.cursor_state.pos, CursorPos { x: 3, y: 2 });
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
 
-        // Try to move beyond width (should clamp)
-        let (width, height) = emulator.buf.dimensions();
-        emulator.move_cursor_relative(width as isize * 2, 0);
-        assert_eq!(emulator.cursor_state.pos.x, width - 1);
+    /// Whether BEL was received since the last call
+    pub fn take_bell(&mut self) -> bool {
+        std::mem::take(&mut self.bell_pending)
+    }
 
-        // Try to move beyond height (should clamp)
-        emulator.cursor_state.pos.y = 0;
-        emulator.move_cursor_relative(0, height as isize * 2);
-        assert_eq!(emulator.cursor_state.pos.y, height - 1);
+    /// Whether anything on screen may have changed since the last call, i.e. output was applied
+    /// by [`TerminalEmulator::read`] or [`TerminalEmulator::feed`], or the window was resized
+    pub fn take_dirty(&mut self) -> bool {
+        std::mem::take(&mut self.dirty)
     }
 
-    #[test]
+    pub fn set_allow_clipboard_write(&mut self, allow: bool) {
+        self.allow_clipboard_write = allow;
+    }
+
+    pub fn set_allow_clipboard_read(&mut self, allow: bool) {
+        self.allow_clipboard_read = allow;
+    }
+
+    /// Text a program asked to put on the clipboard since the last call
+    pub fn take_clipboard_write(&mut self) -> Option<(ClipboardSelection, String)> {
+        self.clipboard_write.take()
+    }
+
+    /// Clipboard a program asked to read since the last call, answer with
+    /// [`Self::reply_clipboard_query`]
+    pub fn take_clipboard_query(&mut self) -> Option<ClipboardSelection> {
+        self.clipboard_query.take()
+    }
+
+    pub fn reply_clipboard_query(
+        &mut self,
+        selection: ClipboardSelection,
+        text: &str,
+    ) -> Result<(), TerminalError> {
+        let selection = match selection {
+            ClipboardSelection::Clipboard => 'c',
+            ClipboardSelection::Primary => 'p',
+        };
+        let reply = format!("\x1b]52;{selection};{}\x07", base64::encode(text.as_bytes()));
+        self.write_bytes(reply.as_bytes())
+    }
+
+    /// Prompt and command boundaries reported with OSC 133, as indices into the buffer that
+    /// [`Self::data`] splits into scrollback and the visible screen. Sorted by position
+    pub fn prompt_marks(&self) -> &[PromptMark] {
+        self.screen.prompt_marks()
+    }
+
+    /// Directory the shell is in, if it reports it with OSC 7
+    pub fn working_directory(&self) -> Option<&Path> {
+        self.working_directory.as_deref()
+    }
+
+    /// Window title set with OSC 0 or 2, empty until a program sets one
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    fn push_title(&mut self) {
+        if self.title_stack.len() == MAX_TITLE_STACK {
+            self.title_stack.remove(0);
+        }
+        self.title_stack.push(self.title.clone());
+    }
+
+    /// Use `colors` for the 16 ANSI colors, normal then bright, instead of xterm's. Programs can
+    /// still change them with OSC 4, resetting goes back to these
+    pub fn set_ansi_colors(&mut self, colors: [(u8, u8, u8); 16]) {
+        self.base_palette[..16].copy_from_slice(&colors);
+        self.palette[..16].copy_from_slice(&colors);
+    }
+
+    /// Current 256 color palette, index it with [`TerminalColor::palette_index`]
+    pub fn palette(&self) -> &[(u8, u8, u8); 256] {
+        &self.palette
+    }
+
+    /// Color a program picked with OSC 10, 11 or 12, None if the GUI should use its own
+    pub fn dynamic_color(&self, color: DynamicColor) -> Option<(u8, u8, u8)> {
+        match color {
+            DynamicColor::Foreground => self.foreground_color,
+            DynamicColor::Background => self.background_color,
+            DynamicColor::Cursor => self.cursor_color,
+        }
+    }
+
+    /// Cursor style a program picked with DECSCUSR, None if the GUI should use its own
+    pub fn cursor_style(&self) -> Option<CursorStyle> {
+        self.cursor_style
+    }
+
+    fn dynamic_color_mut(&mut self, color: DynamicColor) -> &mut Option<(u8, u8, u8)> {
+        match color {
+            DynamicColor::Foreground => &mut self.foreground_color,
+            DynamicColor::Background => &mut self.background_color,
+            DynamicColor::Cursor => &mut self.cursor_color,
+        }
+    }
+
+    /// Answer a CSI t query. There is no window to speak of here, so it is always open at the
+    /// top left and exactly as big as the text area
+    fn report_window(&mut self, report: WindowReport) {
+        let (width, height) = self.screen.dimensions();
+        let (cell_width, cell_height) = self.cell_size;
+        let reply = match report {
+            WindowReport::State => "\x1b[1t".to_string(),
+            WindowReport::Position => "\x1b[3;0;0t".to_string(),
+            WindowReport::TextAreaPixels => {
+                format!("\x1b[4;{};{}t", height * cell_height, width * cell_width)
+            }
+            WindowReport::TextAreaChars => format!("\x1b[8;{height};{width}t"),
+            WindowReport::ScreenChars => format!("\x1b[9;{height};{width}t"),
+        };
+        if let Err(e) = self.write_bytes(reply.as_bytes()) {
+            warn!("Failed to answer window report: {e}");
+        }
+    }
+
+    /// Whether a mode is set, for DECRQM. This is read from the same state set and reset
+    /// changes, so it can't disagree with what the terminal does. Modes that are accepted but not
+    /// acted on, like mouse tracking, are reported as not recognized rather than as set
+    fn mode_state(&self, mode: &Mode) -> Option<bool> {
+        Some(match mode {
+            Mode::Ansi(AnsiMode::Irm) => self.insert_mode,
+            Mode::Dec(DecPrivateMode::Decckm) => self.decckm_mode,
+            Mode::Dec(DecPrivateMode::Decscnm) => self.reverse_screen,
+            Mode::Dec(DecPrivateMode::Decom) => self.origin_mode,
+            Mode::Dec(DecPrivateMode::Decawm) => self.screen.auto_wrap(),
+            Mode::Dec(DecPrivateMode::Dectcem) => self.cursor_state.visible,
+            Mode::Dec(DecPrivateMode::Declrmm) => self.left_right_margin_mode,
+            Mode::Dec(DecPrivateMode::AltScreen) => self.alt_screen_active,
+            Mode::Dec(DecPrivateMode::BracketedPaste) => self.bracketed_paste_mode,
+            Mode::Dec(
+                DecPrivateMode::MouseX10
+                | DecPrivateMode::CursorBlink
+                | DecPrivateMode::MouseNormal
+                | DecPrivateMode::MouseButtonEvent
+                | DecPrivateMode::MouseAnyEvent
+                | DecPrivateMode::FocusEvents
+                | DecPrivateMode::MouseSgr
+                | DecPrivateMode::Unknown(_),
+            )
+            | Mode::Ansi(AnsiMode::Unknown(_)) => return None,
+        })
+    }
+
+    /// Answer DECRQM with `CSI ? Pn ; Pm $ y`, or without the `?` for ANSI modes, where Pm is 1
+    /// for set, 2 for reset and 0 for a mode that isn't recognized
+    fn report_mode(&mut self, mode: Mode) {
+        let value = match self.mode_state(&mode) {
+            Some(true) => 1,
+            Some(false) => 2,
+            None => 0,
+        };
+        let reply = match &mode {
+            Mode::Ansi(mode) => format!("\x1b[{};{value}$y", mode.number()),
+            Mode::Dec(mode) => format!("\x1b[?{};{value}$y", mode.number()),
+        };
+        if let Err(e) = self.write_bytes(reply.as_bytes()) {
+            warn!("Failed to answer mode request: {e}");
+        }
+    }
+
+    /// Whether Ctrl+S and Ctrl+Q should pause and resume output instead of going to the shell
+    pub fn flow_control_enabled(&self) -> bool {
+        self.backend.flow_control_enabled()
+    }
+
+    /// Send the shell SIGHUP, see [`TerminalEmulator::reap`]. Dropping the emulator does this too
+    pub fn hang_up(&mut self) {
+        self.backend.hang_up();
+    }
+
+    /// Hang up on the shell and wait until `deadline` for it to exit, killing it if it did not.
+    /// Dropping the emulator does this on a thread of its own instead, which does not get to
+    /// finish if the process exits first
+    pub fn reap(&mut self, deadline: Instant) {
+        self.backend.reap(deadline);
+    }
+
+    fn write_bytes(&mut self, to_write: &[u8]) -> Result<(), TerminalError> {
+        write_all_nonblocking(self.backend.writer(), to_write)
+    }
+
+    /// Read the shell's output on a background thread that sleeps until there is some, instead of
+    /// trying the pty on every [`TerminalEmulator::read`]. `wake` is called from that thread when
+    /// output is waiting, e.g. to repaint so that `read` gets to apply it
+    ///
+    /// Streams given to [`TerminalEmulator::with_io`] keep being read by `read` itself
+    pub fn spawn_reader_thread<F>(&mut self, wake: F) -> Result<(), TerminalError>
+    where
+        F: Fn() + Send + 'static,
+    {
+        let Backend::Pty(pty) = &mut self.backend else {
+            return Ok(());
+        };
+        let file = pty.reader().try_clone().map_err(TerminalError::Io)?;
+        self.reader_thread = Some(ReaderThread::spawn(
+            file,
+            self.recording.clone(),
+            self.tracer.clone(),
+            wake,
+        )?);
+        Ok(())
+    }
+
+    /// Whether output is read by [`TerminalEmulator::spawn_reader_thread`]'s thread, rather than
+    /// only when [`TerminalEmulator::read`] is called
+    pub fn has_reader_thread(&self) -> bool {
+        self.reader_thread.is_some()
+    }
+
+    /// Process everything the shell has written so far
+    ///
+    /// Returns [`TerminalError::ChildExited`] once the shell has gone away
+    pub fn read(&mut self) -> Result<(), TerminalError> {
+        if self.paused {
+            return Ok(());
+        }
+
+        if self.reader_thread.is_some() {
+            return self.apply_reader_thread_output();
+        }
+
+        let mut buf = vec![0u8; 4096];
+        loop {
+            let read_size = match self.backend.reader().read(&mut buf) {
+                Ok(0) => return Err(TerminalError::ChildExited),
+                Ok(read_size) => read_size,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(TerminalError::from_pty_io(e)),
+            };
+
+            let incoming = &buf[0..read_size];
+            debug!("Incoming data: {:?}", std::str::from_utf8(incoming));
+            self.recording.write(incoming);
+            self.feed(incoming);
+        }
+    }
+
+    /// Write everything read from the shell to `path` from now on, replacing what is there. The
+    /// file has the raw output, so it can be given to [`TerminalEmulator::replay`] or printed in
+    /// another terminal
+    pub fn start_recording(&mut self, path: &Path) -> Result<(), TerminalError> {
+        self.recording.start(path)
+    }
+
+    pub fn stop_recording(&mut self) {
+        self.recording.stop();
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_active()
+    }
+
+    /// Keep the last bytes read from the shell and what they were parsed into, for
+    /// [`TerminalEmulator::trace`]. Turning it off drops what was kept
+    pub fn set_tracing(&mut self, enabled: bool) {
+        self.tracer.set_active(enabled);
+    }
+
+    /// What was traced since [`TerminalEmulator::set_tracing`], `None` if it is off. With a
+    /// reader thread, bytes it read may not have been parsed into outputs here yet
+    pub fn trace(&self) -> Option<Trace> {
+        self.tracer.snapshot()
+    }
+
+    /// Apply output recorded with [`TerminalEmulator::start_recording`], as if the shell had
+    /// written it
+    pub fn replay(&mut self, path: &Path) -> Result<(), TerminalError> {
+        let recorded = std::fs::read(path).map_err(TerminalError::Io)?;
+        self.feed(&recorded);
+        Ok(())
+    }
+
+    /// Apply what the reader thread parsed since the last call
+    fn apply_reader_thread_output(&mut self) -> Result<(), TerminalError> {
+        while let Some(reader_thread) = &self.reader_thread {
+            let Some(outputs) = reader_thread.try_recv()? else {
+                break;
+            };
+            self.dirty = true;
+            for output in outputs {
+                self.handle_output(output);
+            }
+        }
+        Ok(())
+    }
+
+    fn set_mode(&mut self, mode: Mode, enabled: bool) {
+        match mode {
+            Mode::Ansi(AnsiMode::Irm) => self.insert_mode = enabled,
+            Mode::Dec(DecPrivateMode::Decckm) => self.decckm_mode = enabled,
+            Mode::Dec(DecPrivateMode::Decom) => {
+                self.origin_mode = enabled;
+                self.home_cursor();
+            }
+            Mode::Dec(DecPrivateMode::Decscnm) => self.reverse_screen = enabled,
+            Mode::Dec(DecPrivateMode::Decawm) => self.screen.set_auto_wrap(enabled),
+            Mode::Dec(DecPrivateMode::Dectcem) => self.cursor_state.visible = enabled,
+            Mode::Dec(DecPrivateMode::Declrmm) => {
+                self.left_right_margin_mode = enabled;
+                // Leaving the mode also drops the margins, as in xterm
+                if !enabled {
+                    self.column_margins = None;
+                }
+            }
+            Mode::Dec(DecPrivateMode::AltScreen) => {
+                if enabled {
+                    self.enter_alt_screen();
+                } else {
+                    self.exit_alt_screen();
+                    // The screen may have been resized since the main cursor was stored
+                    self.clamp_cursor();
+                }
+            }
+            Mode::Dec(DecPrivateMode::BracketedPaste) => self.bracketed_paste_mode = enabled,
+            Mode::Dec(
+                DecPrivateMode::MouseX10
+                | DecPrivateMode::CursorBlink
+                | DecPrivateMode::MouseNormal
+                | DecPrivateMode::MouseButtonEvent
+                | DecPrivateMode::MouseAnyEvent
+                | DecPrivateMode::FocusEvents
+                | DecPrivateMode::MouseSgr
+                | DecPrivateMode::Unknown(_),
+            )
+            | Mode::Ansi(AnsiMode::Unknown(_)) => {
+                let action = if enabled { "set" } else { "reset" };
+                warn!("unhandled {action} mode: {mode:?}");
+            }
+        }
+    }
+
+    fn apply_sgr(&mut self, sgr: SelectGraphicRendition) {
+        if let Some(color) = TerminalColor::from_sgr(sgr) {
+            // Determine if it's foreground or background
+            match color {
+                TerminalColor::BackgroundBlack
+                | TerminalColor::BackgroundRed
+                | TerminalColor::BackgroundGreen
+                | TerminalColor::BackgroundYellow
+                | TerminalColor::BackgroundBlue
+                | TerminalColor::BackgroundMagenta
+                | TerminalColor::BackgroundCyan
+                | TerminalColor::BackgroundWhite
+                | TerminalColor::BackgroundBrightBlack
+                | TerminalColor::BackgroundBrightRed
+                | TerminalColor::BackgroundBrightGreen
+                | TerminalColor::BackgroundBrightYellow
+                | TerminalColor::BackgroundBrightBlue
+                | TerminalColor::BackgroundBrightMagenta
+                | TerminalColor::BackgroundBrightCyan
+                | TerminalColor::BackgroundBrightWhite
+                | TerminalColor::BackgroundTrueColor(_, _, _)
+                | TerminalColor::Background8Bit(_) => {
+                    self.cursor_state.bg_color = color;
+                }
+                _ => {
+                    self.cursor_state.fg_color = color;
+                }
+            }
+            return;
+        }
+
+        let attributes = &mut self.cursor_state.attributes;
+        match sgr {
+            SelectGraphicRendition::Reset => {
+                self.cursor_state.fg_color = TerminalColor::Default;
+                self.cursor_state.bg_color = TerminalColor::Default;
+                *attributes = TextAttributes::default();
+            }
+            SelectGraphicRendition::ForegroundDefault => {
+                self.cursor_state.fg_color = TerminalColor::Default;
+            }
+            SelectGraphicRendition::BackgroundDefault => {
+                self.cursor_state.bg_color = TerminalColor::Default;
+            }
+            SelectGraphicRendition::Bold => attributes.bold = true,
+            SelectGraphicRendition::Faint => attributes.faint = true,
+            SelectGraphicRendition::NormalIntensity => {
+                attributes.bold = false;
+                attributes.faint = false;
+            }
+            SelectGraphicRendition::Italic => attributes.italic = true,
+            SelectGraphicRendition::NotItalic => attributes.italic = false,
+            SelectGraphicRendition::Underline => attributes.underline = true,
+            SelectGraphicRendition::NotUnderline => attributes.underline = false,
+            SelectGraphicRendition::BlinkSlow => attributes.blink = BlinkMode::SlowBlink,
+            SelectGraphicRendition::BlinkRapid => attributes.blink = BlinkMode::RapidBlink,
+            SelectGraphicRendition::NotBlinking => attributes.blink = BlinkMode::NoBlink,
+            SelectGraphicRendition::Reverse => attributes.reverse = true,
+            SelectGraphicRendition::NotReverse => attributes.reverse = false,
+            SelectGraphicRendition::Conceal => attributes.conceal = true,
+            SelectGraphicRendition::Reveal => attributes.conceal = false,
+            _ => warn!("Unhandled sgr: {:?}", sgr),
+        }
+    }
+
+    /// Returns where the data ended up
+    fn insert_data(&mut self, data: &[u8]) -> Anchor {
+        // Make room so that the write below only replaces blanks
+        if self.insert_mode {
+            let pos = self.cursor_state.pos.clone();
+            // A write from a pending wrap starts on the next row, so that is where room is made.
+            // Unless the line already goes on there the write starts a row of its own instead,
+            // and nothing has to move
+            let make_room = if !self.screen.has_pending_wrap(&pos) {
+                true
+            } else if self.screen.row_wraps(pos.y) {
+                self.screen.cancel_pending_wrap();
+                self.cursor_state.pos = CursorPos { x: 0, y: pos.y + 1 };
+                true
+            } else {
+                false
+            };
+            if make_room {
+                let right_margin =
+                    self.cursor_right_margin().unwrap_or(self.screen.dimensions().0);
+                self.screen
+                    .insert_blanks(&self.cursor_state.pos, data.len(), right_margin);
+            }
+        }
+
+        let (new_cursor_pos, anchor) =
+            self.screen
+                .insert_data(&self.cursor_state.pos, data, &self.cursor_state);
+        for kind in self.pending_prompt_marks.drain(..) {
+            self.screen.push_prompt_mark(anchor, kind);
+        }
+        self.cursor_state.pos = new_cursor_pos;
+        anchor
+    }
+
+    /// Place an image at the cursor, blanking the cells it covers so that text flows around it.
+    /// The cursor ends up below the image, in the column it started in
+    fn insert_image(&mut self, image: TerminalImage) {
+        let (width, _) = self.screen.dimensions();
+        let x = self.cursor_state.pos.x.min(width - 1);
+        let (cell_width, cell_height) = self.cell_size;
+        let cells_wide = image.width.div_ceil(cell_width).clamp(1, width - x);
+        let cells_high = image.height.div_ceil(cell_height).max(1);
+
+        let mut image = Some(Arc::new(image));
+        let blank = vec![b' '; cells_wide];
+        for _ in 0..cells_high {
+            self.cursor_state.pos.x = x;
+            let anchor = self.insert_data(&blank);
+            // Anchored to the top left cell, the rows below have to be written after it so the
+            // anchor moves along if writing them scrolls
+            if let Some(image) = image.take() {
+                self.screen
+                    .push_image(anchor, self.next_image_id, cells_wide, cells_high, image);
+                self.next_image_id += 1;
+            }
+            self.newline();
+        }
+        self.cursor_state.pos.x = x;
+    }
+
+    /// Formatting of cells that were blanked in place. Erased cells keep only the current
+    /// background, like xterm
+    fn erased_format(&self) -> CursorState {
+        CursorState {
+            bg_color: self.cursor_state.bg_color,
+            ..Default::default()
+        }
+    }
+
+    /// [`Self::feed`] for text that is already known to be UTF-8
+    pub fn feed_str(&mut self, incoming: &str) {
+        self.feed(incoming.as_bytes());
+    }
+
+    /// Parse and apply output as if it had been read from the shell
+    ///
+    /// Malformed or unsupported sequences are dropped rather than reported, and out of range
+    /// positions are clamped to the screen
+    pub fn feed(&mut self, incoming: &[u8]) {
+        if incoming.is_empty() {
+            return;
+        }
+        self.dirty = true;
+        self.tracer.write_bytes(incoming);
+
+        // The parser is swapped out so outputs can be applied while it is borrowed
+        let mut parser = std::mem::replace(&mut self.output_buf, AnsiParser::new());
+        parser.push_with(incoming, |segment| self.handle_output(segment));
+        self.output_buf = parser;
+    }
+
+    fn handle_output(&mut self, segment: TerminalOutput<'_>) {
+        self.tracer.push_output(&segment);
+        match segment {
+            TerminalOutput::CursorUp(lines) => {
+                self.move_cursor_relative(0, -(lines as isize));
+            }
+            TerminalOutput::CursorDown(lines) => {
+                self.move_cursor_relative(0, lines as isize);
+            }
+            TerminalOutput::CursorForward(cols) => {
+                self.move_cursor_relative(cols as isize, 0);
+            }
+            TerminalOutput::CursorBackward(cols) => {
+                self.move_cursor_relative(-(cols as isize), 0);
+            }
+            TerminalOutput::CursorForwardTab(stops) => {
+                self.screen.cancel_pending_wrap();
+                let pos = &mut self.cursor_state.pos;
+                pos.x = self.tab_stops.forward(pos.x, stops);
+            }
+            TerminalOutput::CursorBackwardTab(stops) => {
+                self.screen.cancel_pending_wrap();
+                let pos = &mut self.cursor_state.pos;
+                pos.x = self.tab_stops.backward(pos.x, stops);
+            }
+            TerminalOutput::WindowReport(report) => self.report_window(report),
+            TerminalOutput::RequestMode(mode) => self.report_mode(mode),
+            TerminalOutput::SetTabStop => self.tab_stops.set(self.cursor_state.pos.x),
+            TerminalOutput::ClearTabStop => self.tab_stops.clear(self.cursor_state.pos.x),
+            TerminalOutput::ClearAllTabStops => self.tab_stops.clear_all(),
+            TerminalOutput::Data(data) => {
+                self.insert_data(&data);
+            }
+            TerminalOutput::DataBorrowed(data) => {
+                self.insert_data(data);
+            }
+            TerminalOutput::SetCursorPos { x, y } => {
+                self.screen.cancel_pending_wrap();
+                // Positions are 1-based, a 0 that some programs send means 1 as well
+                if let Some(x) = x {
+                    self.cursor_state.pos.x = x.saturating_sub(1);
+                }
+                if let Some(y) = y {
+                    let rows = self.cursor_row_range();
+                    self.cursor_state.pos.y =
+                        (rows.start + y.saturating_sub(1)).min(rows.end.saturating_sub(1));
+                }
+                self.clamp_cursor();
+            }
+            TerminalOutput::ClearForwards => {
+                self.screen
+                    .clear_forwards(&self.cursor_state.pos, &self.cursor_state);
+            }
+            TerminalOutput::CarriageReturn => {
+                self.cursor_state.pos.x = 0;
+            }
+            // Line feeds in xterm
+            TerminalOutput::Newline | TerminalOutput::FormFeed | TerminalOutput::VerticalTab => {
+                self.newline()
+            }
+            TerminalOutput::SetScrollRegion { top, bottom } => {
+                self.set_scroll_region(top, bottom);
+            }
+            // The parser only gives these while DECLRMM is set, CSI s is SCOSC otherwise
+            TerminalOutput::SetLeftRightMargins { left, right } => {
+                if self.left_right_margin_mode {
+                    self.set_column_margins(left, right);
+                }
+            }
+            TerminalOutput::Backspace => {
+                let (width, _) = self.screen.dimensions();
+                let pos = &mut self.cursor_state.pos;
+                if pos.x >= 1 {
+                    pos.x -= 1;
+                } else if pos.y > 0 && self.screen.row_wraps(pos.y - 1) {
+                    // Follow the soft wrap back onto the end of the previous row
+                    pos.x = width - 1;
+                    pos.y -= 1;
+                }
+            }
+            TerminalOutput::Delete(num_chars) => {
+                let Some(right_margin) = self.cursor_right_margin() else {
+                    return;
+                };
+                self.screen
+                    .delete_chars(&self.cursor_state.pos, num_chars, right_margin);
+            }
+            TerminalOutput::InsertSpaces(num_spaces) => {
+                if let Some(right_margin) = self.cursor_right_margin() {
+                    self.screen
+                        .insert_blanks(&self.cursor_state.pos, num_spaces, right_margin);
+                }
+            }
+            TerminalOutput::EraseChars(num_chars) => {
+                // Outside the margins it goes up to the edge of the screen
+                let right_margin = self.cursor_right_margin().unwrap_or(self.screen.dimensions().0);
+                let erased = self.erased_format();
+                self.screen
+                    .erase_chars(&self.cursor_state.pos, num_chars, right_margin, &erased);
+            }
+            TerminalOutput::ClearLineForwards => {
+                self.screen.clear_line_forwards(&self.cursor_state.pos);
+            }
+
+            TerminalOutput::ClearLineBackwards => {
+                let erased = self.erased_format();
+                self.screen
+                    .clear_line_backwards(&self.cursor_state.pos, &erased);
+            }
+            TerminalOutput::ClearBackwards => {
+                let erased = self.erased_format();
+                self.screen.clear_backwards(&self.cursor_state.pos, &erased);
+            }
+
+            TerminalOutput::ClearAll => {
+                self.screen.clear_all(&self.cursor_state);
+            }
+            TerminalOutput::Sgr(sgr) => self.apply_sgr(sgr),
+            TerminalOutput::SetMode(mode) => self.set_mode(mode, true),
+            TerminalOutput::ResetMode(mode) => self.set_mode(mode, false),
+            TerminalOutput::SoftReset => self.soft_reset(),
+            TerminalOutput::FullReset => self.full_reset(),
+            TerminalOutput::AlignmentTest => self.alignment_test(),
+            TerminalOutput::SetCursorStyle(style) => self.cursor_style = style,
+            TerminalOutput::Bell => self.bell_pending = true,
+            TerminalOutput::SetKeypadApplicationMode(enabled) => {
+                self.keypad_application_mode = enabled;
+            }
+            TerminalOutput::Image(image) => self.insert_image(image),
+            TerminalOutput::SetClipboard { selection, data } => {
+                if self.allow_clipboard_write {
+                    self.clipboard_write =
+                        Some((selection, String::from_utf8_lossy(&data).into_owned()));
+                } else {
+                    info!("Ignoring clipboard write, not allowed");
+                }
+            }
+            TerminalOutput::QueryClipboard(selection) => {
+                if self.allow_clipboard_read {
+                    self.clipboard_query = Some(selection);
+                } else {
+                    info!("Ignoring clipboard query, not allowed");
+                }
+            }
+            TerminalOutput::SetPaletteColor { index, rgb } => {
+                self.palette[index as usize] = rgb;
+            }
+            TerminalOutput::ResetPaletteColor(Some(index)) => {
+                self.palette[index as usize] = self.base_palette[index as usize];
+            }
+            TerminalOutput::ResetPaletteColor(None) => self.palette = self.base_palette,
+            TerminalOutput::SetDynamicColor { color, rgb } => {
+                *self.dynamic_color_mut(color) = Some(rgb);
+            }
+            TerminalOutput::ResetDynamicColor(color) => *self.dynamic_color_mut(color) = None,
+            TerminalOutput::SetWorkingDirectory(path) => self.working_directory = Some(path),
+            TerminalOutput::SetTitle(title) => self.title = title,
+            TerminalOutput::PushTitle => self.push_title(),
+            TerminalOutput::PopTitle => {
+                // Popping more than was pushed keeps the title
+                if let Some(title) = self.title_stack.pop() {
+                    self.title = title;
+                }
+            }
+            TerminalOutput::PromptMark(kind) => self.pending_prompt_marks.push(kind),
+            TerminalOutput::SaveCursor => self.save_cursor(),
+            TerminalOutput::RestoreCursor => {
+                self.restore_cursor();
+                self.clamp_cursor();
+            }
+            TerminalOutput::Invalid => {}
+        }
+    }
+
+    /// Limit how many lines of scrollback are kept, older lines are dropped as new output arrives
+    pub fn set_scrollback_limit(&mut self, max_scrollback_lines: usize) {
+        self.screen.set_scrollback_limit(max_scrollback_lines);
+    }
+
+    pub fn is_alt_screen_active(&self) -> bool {
+        self.alt_screen_active
+    }
+    pub fn data(&self) -> TerminalData<&[u8]> {
+        self.screen.data()
+    }
+
+    /// The visible character at column `x` and row `y`, None past the end of the row or screen
+    pub fn cell(&self, x: usize, y: usize) -> Option<Cell> {
+        self.screen.cell(x, y)
+    }
+
+    /// The visible screen as text, one line per row without trailing spaces
+    pub fn screen_text(&self) -> String {
+        self.screen.screen_text()
+    }
+
+    /// The visible screen as text for copying, without trailing spaces. With `logical_lines` soft
+    /// wrapped lines are joined back together, otherwise there is a line per row like
+    /// [`TerminalEmulator::screen_text`]
+    pub fn visible_text(&self, logical_lines: bool) -> String {
+        self.screen.visible_text(logical_lines)
+    }
+
+    /// [`TerminalEmulator::visible_text`] of the scrollback and the screen
+    pub fn all_text(&self, logical_lines: bool) -> String {
+        self.screen.all_text(logical_lines)
+    }
+
+    /// Scrollback and screen as HTML with their colors, see [`html::to_html`]. Colors set with
+    /// OSC 10 and 11 take the place of `defaults`
+    pub fn to_html(&self, defaults: HtmlColors) -> String {
+        let data = self.data();
+        let format_data = self.format_data();
+        let mut joined = data.scrollback.to_vec();
+        let mut tags = format_data.scrollback;
+        if !joined.is_empty() {
+            joined.push(b'\n');
+        }
+        let offset = joined.len();
+        joined.extend_from_slice(data.visible);
+        tags.extend(format_data.visible.into_iter().map(|tag| FormatTag {
+            start: tag.start + offset,
+            end: tag.end.saturating_add(offset),
+            ..tag
+        }));
+
+        let defaults = HtmlColors {
+            foreground: self.foreground_color.unwrap_or(defaults.foreground),
+            background: self.background_color.unwrap_or(defaults.background),
+        };
+        html::to_html(&joined, &tags, &self.palette, &defaults)
+    }
+
+    /// Images whose top left corner is on screen
+    pub fn visible_images(&self) -> Vec<ImagePlacement> {
+        self.screen.visible_images()
+    }
+
+    /// Pixel size of a character cell, images are sized in cells with it
+    pub fn set_cell_size(&mut self, width: usize, height: usize) {
+        self.cell_size = (width.max(1), height.max(1));
+    }
+
+    pub fn format_data(&self) -> TerminalData<Vec<FormatTag>> {
+        self.screen.format_data()
+    }
+    pub fn cursor_pos(&self) -> CursorPos {
+        self.cursor_state.pos.clone()
+    }
+
+    /// Whether the cursor should be drawn, programs hide it with DECTCEM (`CSI ? 25 l`)
+    pub fn cursor_visible(&self) -> bool {
+        self.cursor_state.visible
+    }
+
+    /// Resize to `width_chars` by `height_chars`, each at least 1 since there is nowhere to put
+    /// the cursor on a screen without rows or columns
+    pub fn set_win_size(
+        &mut self,
+        width_chars: usize,
+        height_chars: usize,
+    ) -> Result<(), TerminalError> {
+        let width_chars = width_chars.max(1);
+        let height_chars = height_chars.max(1);
+        let Some(new_cursor_pos) =
+            self.screen
+                .set_win_size(width_chars, height_chars, &self.cursor_state.pos)
+        else {
+            return Ok(());
+        };
+        self.cursor_state.pos = new_cursor_pos;
+        // The old region and margins may not fit anymore, xterm drops them on resize too
+        self.dirty = true;
+        self.scroll_region = None;
+        self.column_margins = None;
+        self.tab_stops.resize(width_chars);
+
+        self.backend.set_window_size(WindowSize {
+            num_lines: height_chars as u16,
+            num_cols: width_chars as u16,
+            cell_width: self.cell_size.0 as u16,
+            cell_height: self.cell_size.1 as u16,
+        })?;
+
+        Ok(())
+    }
+}
+
+/// An 80x24 terminal with no shell behind it, output is only what is given to
+/// [`TerminalEmulator::feed`]
+impl Default for TerminalEmulator {
+    fn default() -> TerminalEmulator {
+        TerminalEmulator::with_io(std::io::empty(), std::io::sink())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    fn payload(input: TerminalInput) -> Vec<u8> {
+        match input.to_payload(false, false) {
+            TerminalInputPayload::Single(c) => vec![c],
+            TerminalInputPayload::Many(bytes) => bytes,
+        }
+    }
+
+    #[test]
+    fn test_function_key_payloads() {
+        let none = KeyModifiers::default();
+        assert_eq!(payload(TerminalInput::Function(1, none)), b"\x1bOP");
+        assert_eq!(payload(TerminalInput::Function(5, none)), b"\x1b[15~");
+        assert_eq!(payload(TerminalInput::Function(12, none)), b"\x1b[24~");
+        assert_eq!(payload(TerminalInput::Function(13, none)), b"");
+        assert_eq!(payload(TerminalInput::Delete(none)), b"\x1b[3~");
+        assert_eq!(payload(TerminalInput::PageUp(none)), b"\x1b[5~");
+
+        // Only the cursor keys change with DECCKM
+        assert_eq!(
+            TerminalInput::Function(1, none).to_payload(true, false),
+            TerminalInputPayload::Many(b"\x1bOP".to_vec())
+        );
+        assert_eq!(
+            TerminalInput::Delete(none).to_payload(true, false),
+            TerminalInputPayload::Many(b"\x1b[3~".to_vec())
+        );
+        assert_eq!(
+            TerminalInput::ArrowUp(none).to_payload(true, false),
+            TerminalInputPayload::Many(b"\x1bOA".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_modified_key_payloads() {
+        let ctrl = KeyModifiers {
+            ctrl: true,
+            ..Default::default()
+        };
+        let shift = KeyModifiers {
+            shift: true,
+            ..Default::default()
+        };
+        let all = KeyModifiers {
+            shift: true,
+            alt: true,
+            ctrl: true,
+        };
+        assert_eq!(payload(TerminalInput::ArrowRight(ctrl)), b"\x1b[1;5C");
+        assert_eq!(payload(TerminalInput::Home(shift)), b"\x1b[1;2H");
+        assert_eq!(payload(TerminalInput::Function(1, shift)), b"\x1b[1;2P");
+        assert_eq!(payload(TerminalInput::Function(5, ctrl)), b"\x1b[15;5~");
+        assert_eq!(payload(TerminalInput::Delete(all)), b"\x1b[3;8~");
+
+        // Modified cursor keys are CSI even in application cursor key mode
+        assert_eq!(
+            TerminalInput::ArrowUp(ctrl).to_payload(true, false),
+            TerminalInputPayload::Many(b"\x1b[1;5A".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_keypad_payloads() {
+        let keypad = |c, application| match TerminalInput::Keypad(c).to_payload(false, application) {
+            TerminalInputPayload::Single(c) => vec![c],
+            TerminalInputPayload::Many(bytes) => bytes,
+        };
+        assert_eq!(keypad(b'\n', false), b"\n");
+        assert_eq!(keypad(b'\n', true), b"\x1bOM");
+        assert_eq!(keypad(b'+', false), b"+");
+        assert_eq!(keypad(b'+', true), b"\x1bOk");
+        assert_eq!(keypad(b'7', true), b"\x1bOw");
+    }
+
+    #[test]
+    fn test_keypad_application_mode() {
+        let mut emulator = emulator_without_shell(80, 24);
+        emulator.feed_str("\x1b=");
+        assert!(emulator.keypad_application_mode);
+        emulator.feed_str("\x1b>");
+        assert!(!emulator.keypad_application_mode);
+
+        emulator.feed_str("\x1b=\x1b[!p");
+        assert!(!emulator.keypad_application_mode);
+    }
+
+    #[test]
+    fn test_cursor_movement_parsing() {
+        let mut parser = AnsiParser::new();
+
+        // Test cursor up
+        let output = parser.push(b"\x1b[5A");
+        assert_eq!(output.len(), 1);
+        assert!(matches!(output[0], TerminalOutput::CursorUp(5)));
+
+        // Test cursor down with default parameter
+        let output = parser.push(b"\x1b[B");
+        assert_eq!(output.len(), 1);
+        assert!(matches!(output[0], TerminalOutput::CursorDown(1)));
+
+        // Test cursor forward
+        let output = parser.push(b"\x1b[10C");
+        assert_eq!(output.len(), 1);
+        assert!(matches!(output[0], TerminalOutput::CursorForward(10)));
+
+        // Test cursor backward
+        let output = parser.push(b"\x1b[3D");
+        assert_eq!(output.len(), 1);
+        assert!(matches!(output[0], TerminalOutput::CursorBackward(3)));
+    }
+
+    #[test]
+    fn test_cursor_movement_bounds() {
+        let mut emulator = emulator_without_shell(80, 24);
+
+        // Start at 0,0
+        assert_eq!(emulator.cursor_state.pos, CursorPos { x: 0, y: 0 });
+
+        // Try to move up (should stay at 0)
+        emulator.move_cursor_relative(0, -1);
+        assert_eq!(emulator.cursor_state.pos.y, 0);
+
+        // Try to move left (should stay at 0)
+        emulator.move_cursor_relative(-1, 0);
+        assert_eq!(emulator.cursor_state.pos.x, 0);
+
+        // Move right
+        emulator.move_cursor_relative(5, 0);
+        assert_eq!(emulator.cursor_state.pos.x, 5);
+
+        // Move down
+        emulator.move_cursor_relative(0, 3);
+        assert_eq!(emulator.cursor_state.pos.y, 3);
+
+        // Move back
+        emulator.move_cursor_relative(-2, -1);
+        assert_eq!(emulator.cursor_state.pos, CursorPos { x: 3, y: 2 });
+
+        // Try to move beyond width (should clamp)
+        let (width, height) = emulator.screen.dimensions();
+        emulator.move_cursor_relative(width as isize * 2, 0);
+        assert_eq!(emulator.cursor_state.pos.x, width - 1);
+
+        // Try to move beyond height (should clamp)
+        emulator.cursor_state.pos.y = 0;
+        emulator.move_cursor_relative(0, height as isize * 2);
+        assert_eq!(emulator.cursor_state.pos.y, height - 1);
+    }
+
+    #[test]
     fn test_alt_screen_parsing() {
         let mut parser = AnsiParser::new();
 
-        // Test entering alt screen
-        let output = parser.push(b"\x1b[?1049h");
-        assert_eq!(output.len(), 1);
-        assert!(matches!(output[0], TerminalOutput::EnterAltScreen));
+        // Test entering alt screen
+        let output = parser.push(b"\x1b[?1049h");
+        assert_eq!(output.len(), 1);
+        assert!(matches!(
+            output[0],
+            TerminalOutput::SetMode(Mode::Dec(DecPrivateMode::AltScreen))
+        ));
+
+        // Test exiting alt screen
+        let output = parser.push(b"\x1b[?1049l");
+        assert_eq!(output.len(), 1);
+        assert!(matches!(
+            output[0],
+            TerminalOutput::ResetMode(Mode::Dec(DecPrivateMode::AltScreen))
+        ));
+    }
+
+    #[test]
+    fn test_alt_screen_switching() {
+        let mut emulator = TerminalEmulator::new(Options::default()).unwrap();
+
+        // Write some data to main screen
+        emulator.read().unwrap(); // Process any initial output
+        assert!(!emulator.is_alt_screen_active());
+
+        // Enter alt screen
+        emulator.enter_alt_screen();
+        assert!(emulator.is_alt_screen_active());
+
+        // Alt screen should be empty
+        let data = emulator.data();
+        assert_eq!(data.visible, b"");
+
+        // Exit alt screen
+        emulator.exit_alt_screen();
+        assert!(!emulator.is_alt_screen_active());
+}
+
+    #[test]
+    fn test_record_and_replay() {
+        let session = b"\x1b[1;32muser\x1b[0m:~$ ls\r\nfile\x1b[3Gx\r\n\x1b[?1049halt\x1b[?1049l";
+        let path = std::env::temp_dir().join(format!("terminaux-record-{}", std::process::id()));
+
+        // Whatever size the reads come in, the file ends up with all of them
+        let mut recorded = TerminalEmulator::with_io(&session[..], std::io::sink());
+        recorded.start_recording(&path).unwrap();
+        assert!(recorded.is_recording());
+        assert!(matches!(recorded.read(), Err(TerminalError::ChildExited)));
+        recorded.stop_recording();
+        assert_eq!(std::fs::read(&path).unwrap(), session);
+
+        let mut replayed = emulator_without_shell(80, 24);
+        replayed.replay(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(replayed.data().visible, recorded.data().visible);
+        assert_eq!(replayed.data().scrollback, recorded.data().scrollback);
+        assert_eq!(replayed.format_data().visible, recorded.format_data().visible);
+        assert_eq!(replayed.cursor_pos(), recorded.cursor_pos());
+    }
+
+    #[test]
+    fn test_trace() {
+        let mut emulator = TerminalEmulator::with_io(&b"a\x07b"[..], std::io::sink());
+        assert_eq!(emulator.trace(), None);
+        emulator.set_tracing(true);
+        assert!(matches!(emulator.read(), Err(TerminalError::ChildExited)));
+
+        let trace = emulator.trace().unwrap();
+        assert_eq!(trace.bytes, b"a\x07b");
+        assert_eq!(trace.outputs, ["Data(\"a\")", "Bell", "Data(\"b\")"]);
+
+        emulator.set_tracing(false);
+        assert_eq!(emulator.trace(), None);
+    }
+
+    #[test]
+    fn test_reader_thread_delivers_output() {
+        let options = Options {
+            shell: Some(tty::Shell::new("cat".to_string(), vec![])),
+            ..Default::default()
+        };
+        let mut emulator = TerminalEmulator::new(options).unwrap();
+        let (woken, wakeups) = crossbeam::channel::unbounded();
+        emulator
+            .spawn_reader_thread(move || {
+                let _ = woken.send(());
+            })
+            .unwrap();
+
+        emulator.write_bytes(b"hello\n").unwrap();
+        while !emulator.data().visible.starts_with(b"hello") {
+            wakeups
+                .recv_timeout(std::time::Duration::from_secs(5))
+                .expect("reader thread should wake us up");
+            emulator.read().unwrap();
+        }
+        assert!(emulator.take_dirty());
+    }
+
+    #[test]
+    fn test_reader_thread_idle() {
+        let options = Options {
+            shell: Some(tty::Shell::new("cat".to_string(), vec![])),
+            ..Default::default()
+        };
+        let mut emulator = TerminalEmulator::new(options).unwrap();
+        let (woken, wakeups) = crossbeam::channel::unbounded();
+        emulator
+            .spawn_reader_thread(move || {
+                let _ = woken.send(());
+            })
+            .unwrap();
+        assert!(emulator.has_reader_thread());
+
+        // The echo and cat's copy
+        emulator.write_bytes(b"hello\n").unwrap();
+        let hellos = |emulator: &TerminalEmulator| {
+            emulator.data().visible.windows(5).filter(|w| w == b"hello").count()
+        };
+        while hellos(&emulator) < 2 {
+            wakeups
+                .recv_timeout(std::time::Duration::from_secs(5))
+                .expect("reader thread should wake us up");
+            emulator.read().unwrap();
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        wakeups.try_iter().for_each(drop);
+        emulator.read().unwrap();
+        emulator.take_dirty();
+
+        // Nothing is written, so nothing wakes the GUI up to repaint
+        let idle = wakeups.recv_timeout(std::time::Duration::from_millis(500));
+        assert!(idle.is_err());
+        emulator.read().unwrap();
+        assert!(!emulator.take_dirty());
+
+        // Until there is output again
+        emulator.write_bytes(b"again\n").unwrap();
+        wakeups
+            .recv_timeout(std::time::Duration::from_secs(5))
+            .expect("reader thread should wake us up");
+    }
+
+    #[test]
+    fn test_configured_shell_is_spawned() {
+        let options = Options {
+            shell: Some(tty::Shell::new(
+                "sh".to_string(),
+                vec!["-c".to_string(), "echo hello; sleep 1".to_string()],
+            )),
+            ..Default::default()
+        };
+        let mut emulator = TerminalEmulator::new(options).unwrap();
+
+        let start = std::time::Instant::now();
+        while !emulator.data().visible.starts_with(b"hello")
+            && start.elapsed() < std::time::Duration::from_secs(5)
+        {
+            // The shell exiting is reported as an error once its output is drained
+            let _ = emulator.read();
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        assert!(emulator.data().visible.starts_with(b"hello"));
+    }
+
+    #[test]
+    fn test_term_is_passed_to_shell() {
+        let options = Options {
+            shell: Some(tty::Shell::new(
+                "sh".to_string(),
+                vec![
+                    "-c".to_string(),
+                    "echo \"$TERM ${COLORTERM-unset}\"; sleep 1".to_string(),
+                ],
+            )),
+            term: "screen-256color".to_string(),
+            colorterm: None,
+            ..Default::default()
+        };
+        let mut emulator = TerminalEmulator::new(options).unwrap();
+
+        let expected = b"screen-256color unset";
+        let start = std::time::Instant::now();
+        while !emulator.data().visible.starts_with(expected)
+            && start.elapsed() < std::time::Duration::from_secs(5)
+        {
+            let _ = emulator.read();
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        assert!(emulator.data().visible.starts_with(expected));
+    }
+
+    #[test]
+    fn test_paused_read_leaves_output() {
+        let options = Options {
+            shell: Some(tty::Shell::new(
+                "sh".to_string(),
+                vec!["-c".to_string(), "echo hello; sleep 1".to_string()],
+            )),
+            ..Default::default()
+        };
+        let mut emulator = TerminalEmulator::new(options).unwrap();
+        emulator.set_paused(true);
+
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        emulator.read().unwrap();
+        assert!(emulator.data().visible.is_empty());
+
+        // Everything written while paused is still there to be read
+        emulator.set_paused(false);
+        let start = std::time::Instant::now();
+        while !emulator.data().visible.starts_with(b"hello")
+            && start.elapsed() < std::time::Duration::from_secs(5)
+        {
+            let _ = emulator.read();
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert!(emulator.data().visible.starts_with(b"hello"));
+    }
+
+    /// A `width` by `height` emulator with nothing to read and writes going nowhere
+    pub(crate) fn emulator_without_shell(width: usize, height: usize) -> TerminalEmulator {
+        let mut emulator = TerminalEmulator::with_io(std::io::empty(), std::io::sink());
+        emulator.set_win_size(width, height).unwrap();
+        emulator
+    }
+
+    /// How [`render_to_grid`] shows the columns holding the rest of a multi-byte character
+    const CONTINUATION: char = '·';
+
+    /// Feed `input` to a fresh `width` by `height` screen, for golden tests of what it shows.
+    /// Returns each row padded with spaces to the width, and the cell under every column of it,
+    /// None past the end of the row's text. Every row has exactly `width` characters, see
+    /// [`CONTINUATION`]
+    fn render_to_grid(
+        input: &[u8],
+        width: usize,
+        height: usize,
+    ) -> (Vec<String>, Vec<Vec<Option<Cell>>>) {
+        let mut emulator = emulator_without_shell(width, height);
+        emulator.feed(input);
+
+        let cells = (0..height)
+            .map(|y| (0..width).map(|x| emulator.cell(x, y)).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        let rows = cells
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|cell| match cell {
+                        Some(Cell { ch: '\0', .. }) => CONTINUATION,
+                        Some(cell) => cell.ch,
+                        None => ' ',
+                    })
+                    .collect()
+            })
+            .collect();
+        (rows, cells)
+    }
+
+    /// Collects what the emulator writes to the shell
+    #[derive(Clone, Default)]
+    pub(crate) struct SharedWriter(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for SharedWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedWriter {
+        /// What was written since the last call
+        pub(crate) fn take(&self) -> Vec<u8> {
+            std::mem::take(&mut self.0.lock().unwrap())
+        }
+    }
+
+    #[test]
+    fn test_window_reports() {
+        let writer = SharedWriter::default();
+        let mut emulator = TerminalEmulator::with_io(std::io::empty(), writer.clone());
+        emulator.feed_str("\x1b[18t");
+        assert_eq!(writer.take(), b"\x1b[8;24;80t");
+
+        emulator.set_cell_size(9, 17);
+        emulator.set_win_size(100, 30).unwrap();
+        emulator.feed_str("\x1b[14t\x1b[19t");
+        assert_eq!(writer.take(), b"\x1b[4;510;900t\x1b[9;30;100t");
+        emulator.feed_str("\x1b[11t\x1b[13t");
+        assert_eq!(writer.take(), b"\x1b[1t\x1b[3;0;0t");
+
+        // Iconifying and the like are not done
+        emulator.feed_str("\x1b[2t\x1b[5t");
+        assert_eq!(writer.take(), b"");
+    }
+
+    /// Emulator running `script` with `sh`, and its shell's pid
+    fn spawn_script(script: &str) -> (TerminalEmulator, nix::unistd::Pid) {
+        let options = Options {
+            shell: Some(tty::Shell {
+                program: "sh".to_string(),
+                args: vec!["-c".to_string(), script.to_string()],
+            }),
+            ..Options::default()
+        };
+        let emulator = TerminalEmulator::new(options).unwrap();
+        let Backend::Pty(pty) = &emulator.backend else {
+            panic!("Expected a pty");
+        };
+        let pid = nix::unistd::Pid::from_raw(pty.child().id() as i32);
+        (emulator, pid)
+    }
+
+    fn is_running(pid: nix::unistd::Pid) -> bool {
+        nix::sys::signal::kill(pid, None) != Err(nix::errno::Errno::ESRCH)
+    }
+
+    #[test]
+    fn test_drop_ends_child() {
+        // The second one ignores SIGHUP, so it has to be killed
+        for script in ["sleep 30", "trap '' HUP; exec sleep 30"] {
+            let (emulator, pid) = spawn_script(script);
+            // Let the shell set up its trap
+            std::thread::sleep(std::time::Duration::from_millis(200));
+
+            // The grace period is waited out on another thread
+            let start = std::time::Instant::now();
+            drop(emulator);
+            assert!(start.elapsed() < tty::HANGUP_GRACE_PERIOD);
+
+            // Reaped, so there is no such process anymore
+            while is_running(pid) {
+                assert!(start.elapsed() < std::time::Duration::from_secs(5));
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+        }
+    }
+
+    #[test]
+    fn test_reap_shares_deadline() {
+        let mut shells: Vec<_> = (0..3)
+            .map(|_| spawn_script("trap '' HUP; exec sleep 30"))
+            .collect();
+        std::thread::sleep(std::time::Duration::from_millis(200));
+
+        let start = std::time::Instant::now();
+        for (emulator, _) in &mut shells {
+            emulator.hang_up();
+        }
+        let deadline = start + tty::HANGUP_GRACE_PERIOD;
+        for (emulator, pid) in &mut shells {
+            emulator.reap(deadline);
+            assert!(!is_running(*pid));
+        }
+        // Waiting for each in turn would have taken three grace periods
+        assert!(start.elapsed() < tty::HANGUP_GRACE_PERIOD * 2);
+    }
+
+    #[test]
+    fn test_title_stack() {
+        let mut emulator = emulator_without_shell(80, 24);
+        assert_eq!(emulator.title(), "");
+        emulator.feed_str("\x1b]2;bash\x07");
+        assert_eq!(emulator.title(), "bash");
+
+        emulator.feed_str("\x1b[22;0t\x1b]2;vim file.rs\x07");
+        assert_eq!(emulator.title(), "vim file.rs");
+        emulator.feed_str("\x1b[23;0t");
+        assert_eq!(emulator.title(), "bash");
+        emulator.feed_str("\x1b[23;0t");
+        assert_eq!(emulator.title(), "bash");
+
+        // Only the latest pushes are kept
+        for i in 0..=MAX_TITLE_STACK {
+            emulator.feed_str(&format!("\x1b]0;{i}\x07\x1b[22t"));
+        }
+        for _ in 0..=MAX_TITLE_STACK {
+            emulator.feed_str("\x1b[23t");
+        }
+        assert_eq!(emulator.title(), "1");
+    }
+
+    #[test]
+    fn test_request_mode() {
+        let writer = SharedWriter::default();
+        let mut emulator = TerminalEmulator::with_io(std::io::empty(), writer.clone());
+        emulator.feed_str("\x1b[?2004$p");
+        assert_eq!(writer.take(), b"\x1b[?2004;2$y");
+        emulator.feed_str("\x1b[?2004h\x1b[?2004$p");
+        assert_eq!(writer.take(), b"\x1b[?2004;1$y");
+
+        // Auto wrap and the cursor start out set
+        emulator.feed_str("\x1b[?7$p\x1b[?25$p\x1b[?25l\x1b[?25$p");
+        assert_eq!(writer.take(), b"\x1b[?7;1$y\x1b[?25;1$y\x1b[?25;2$y");
+        emulator.feed_str("\x1b[4h\x1b[4$p\x1b[?1049h\x1b[?1049$p");
+        assert_eq!(writer.take(), b"\x1b[4;1$y\x1b[?1049;1$y");
+        emulator.feed_str("\x1b[?69$p\x1b[?69h\x1b[?69$p");
+        assert_eq!(writer.take(), b"\x1b[?69;2$y\x1b[?69;1$y");
+
+        // Mouse tracking is accepted but not done, so it isn't claimed to be on
+        emulator.feed_str("\x1b[?1000h\x1b[?1000$p\x1b[?9999$p\x1b[20$p");
+        assert_eq!(writer.take(), b"\x1b[?1000;0$y\x1b[?9999;0$y\x1b[20;0$y");
+    }
+
+    #[test]
+    fn test_read_from_io() {
+        let mut emulator =
+            TerminalEmulator::with_io(&b"hello\r\n\x1b[31mworld"[..], std::io::sink());
+        assert!(matches!(emulator.read(), Err(TerminalError::ChildExited)));
+        assert_eq!(emulator.screen_text(), "hello\nworld");
+        assert_eq!(
+            emulator.format_data().visible[1].fg_color,
+            TerminalColor::ForegroundRed
+        );
+    }
 
-        // Test exiting alt screen
-        let output = parser.push(b"\x1b[?1049l");
-        assert_eq!(output.len(), 1);
-        assert!(matches!(output[0], TerminalOutput::ExitAltScreen));
+    #[test]
+    fn test_feed_overwrite_early_newline() {
+        // Same as the buffer test of the same name, driven through escape sequences
+        let mut emulator = emulator_without_shell(5, 5);
+        emulator.feed_str("012\r\n3456789");
+        assert_eq!(emulator.screen_text(), "012\n34567\n89");
+
+        emulator.feed_str("\x1b[2;3Htest");
+        assert_eq!(emulator.screen_text(), "012\n34tes\nt9");
+        assert_eq!(emulator.data().visible, b"012\n34test9");
     }
 
     #[test]
-    fn test_alt_screen_switching() {
-        let mut emulator = TerminalEmulator::new();
+    fn test_feed_scrolling() {
+        // Same as the buffer test canvas_scrolling
+        let mut emulator = emulator_without_shell(10, 3);
+        emulator.feed_str("asdf\r\nxyzw\r\n1234\r\n5678");
+        assert_eq!(emulator.data().scrollback, b"asdf");
+        assert_eq!(emulator.screen_text(), "xyzw\n1234\n5678");
+    }
 
-        // Write some data to main screen
-        emulator.read(); // Process any initial output
-        assert!(!emulator.is_alt_screen_active());
+    #[test]
+    fn test_cell() {
+        let mut emulator = emulator_without_shell(10, 5);
+        emulator.feed_str("ab\x1b[31;44mcd\x1b[0m\r\n\x1b[1mxy\x1b[0m\x1b[3;5H\x1b[4mz");
+
+        let cell = |x, y| emulator.cell(x, y);
+        assert_eq!(cell(0, 0).unwrap().ch, 'a');
+        assert_eq!(cell(0, 0).unwrap().fg, TerminalColor::Default);
+        assert_eq!(
+            cell(2, 0),
+            Some(Cell {
+                ch: 'c',
+                fg: TerminalColor::ForegroundRed,
+                bg: TerminalColor::BackgroundBlue,
+                attrs: TextAttributes::default(),
+            })
+        );
+        assert!(cell(1, 1).unwrap().attrs.bold);
+        assert_eq!(cell(0, 2).unwrap().ch, ' ');
+        assert_eq!(cell(4, 2).unwrap().ch, 'z');
+        assert!(cell(4, 2).unwrap().attrs.underline);
+
+        // Past the end of a row or the screen
+        assert_eq!(cell(4, 0), None);
+        assert_eq!(cell(5, 2), None);
+        assert_eq!(cell(0, 3), None);
+    }
 
-        // Enter alt screen
-        emulator.enter_alt_screen();
-        assert!(emulator.is_alt_screen_active());
+    #[test]
+    fn test_cell_wrapped_and_multibyte() {
+        let mut emulator = emulator_without_shell(10, 5);
+        emulator.feed_str("0123456789aé");
+        assert_eq!(emulator.cell(9, 0).unwrap().ch, '9');
+        assert_eq!(emulator.cell(0, 1).unwrap().ch, 'a');
+        assert_eq!(emulator.cell(1, 1).unwrap().ch, 'é');
+        assert_eq!(emulator.cell(2, 1).unwrap().ch, '\0');
+        assert_eq!(emulator.cell(3, 1), None);
+    }
 
-        // Alt screen should be empty
+    #[test]
+    fn test_sixel_image_placement() {
+        let mut emulator = emulator_without_shell(10, 5);
+        emulator.set_cell_size(2, 4);
+        // 5x7 pixels, 3x2 cells
+        emulator.feed_str("ab\x1bPq!5~-!5@\x1b\\c");
+
+        let images = emulator.visible_images();
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].pos, CursorPos { x: 2, y: 0 });
+        assert_eq!((images[0].cells_wide, images[0].cells_high), (3, 2));
+        assert_eq!((images[0].image.width, images[0].image.height), (5, 7));
+        // Text continues below the image
+        assert_eq!(emulator.screen_text(), "ab\n\n  c");
+
+        // Scrolls along with the text
+        emulator.feed_str("\n");
+        assert_eq!(emulator.visible_images()[0].pos, CursorPos { x: 2, y: 0 });
+        emulator.feed_str("\n");
+        assert_eq!(emulator.visible_images()[0].pos, CursorPos { x: 2, y: 0 });
+        emulator.feed_str("\n");
+        assert!(emulator.visible_images().is_empty());
+
+        emulator.feed_str("\x1b[H\x1bPq~\x1b\\");
+        assert_eq!(emulator.visible_images().len(), 1);
+        emulator.feed_str("\x1b[2J");
+        assert!(emulator.visible_images().is_empty());
+    }
+
+    #[test]
+    fn test_osc_52_clipboard() {
+        let set_hello = "\x1b]52;c;aGVsbG8gd29ybGQ=\x07";
+
+        // Off unless enabled
+        let mut emulator = emulator_without_shell(10, 5);
+        emulator.feed_str(set_hello);
+        emulator.feed_str("\x1b]52;c;?\x07");
+        assert_eq!(emulator.take_clipboard_write(), None);
+        assert_eq!(emulator.take_clipboard_query(), None);
+
+        emulator.set_allow_clipboard_write(true);
+        emulator.feed_str(set_hello);
+        assert_eq!(
+            emulator.take_clipboard_write(),
+            Some((ClipboardSelection::Clipboard, "hello world".to_string()))
+        );
+        assert_eq!(emulator.take_clipboard_write(), None);
+
+        emulator.set_allow_clipboard_read(true);
+        emulator.feed_str("\x1b]52;p;?\x1b\\");
+        assert_eq!(
+            emulator.take_clipboard_query(),
+            Some(ClipboardSelection::Primary)
+        );
+        assert_eq!(emulator.data().visible, b"");
+    }
+
+    #[test]
+    fn test_osc_palette() {
+        let mut emulator = emulator_without_shell(10, 5);
+        let rgb_of = |emulator: &TerminalEmulator, x| {
+            let fg = emulator.cell(x, 0).unwrap().fg;
+            emulator.palette()[fg.palette_index().unwrap() as usize]
+        };
+
+        emulator.feed_str("\x1b[31ma\x1b]4;1;rgb:00/00/ff\x07b\x1b[48;5;1m");
+        assert_eq!(emulator.cell(1, 0).unwrap().fg, TerminalColor::ForegroundRed);
+        assert_eq!(rgb_of(&emulator, 1), (0, 0, 255));
+        // Already written text changes too, the palette is looked up when drawing
+        assert_eq!(rgb_of(&emulator, 0), (0, 0, 255));
+        assert_eq!(emulator.cursor_state.bg_color, TerminalColor::Background8Bit(1));
+
+        emulator.feed_str("\x1b]4;1;#00ff00\x07");
+        assert_eq!(rgb_of(&emulator, 0), (0, 255, 0));
+
+        emulator.feed_str("\x1b]104;1\x07");
+        assert_eq!(rgb_of(&emulator, 0), TerminalColor::index_to_rgb(1));
+
+        emulator.feed_str("\x1b]10;#ffffff\x07\x1b]12;rgb:ff/00/00\x07");
+        assert_eq!(emulator.dynamic_color(DynamicColor::Foreground), Some((255, 255, 255)));
+        assert_eq!(emulator.dynamic_color(DynamicColor::Background), None);
+        assert_eq!(emulator.dynamic_color(DynamicColor::Cursor), Some((255, 0, 0)));
+        emulator.feed_str("\x1b]110\x07");
+        assert_eq!(emulator.dynamic_color(DynamicColor::Foreground), None);
+
+        emulator.feed_str("\x1b]4;7;#000000\x07\x1bc");
+        assert_eq!(emulator.palette()[7], TerminalColor::index_to_rgb(7));
+        assert_eq!(emulator.dynamic_color(DynamicColor::Cursor), None);
+    }
+
+    #[test]
+    fn test_ansi_colors() {
+        let mut emulator = emulator_without_shell(10, 5);
+        let mut colors = [(1, 2, 3); 16];
+        colors[2] = (10, 20, 30);
+        emulator.set_ansi_colors(colors);
+        assert_eq!(emulator.palette()[2], (10, 20, 30));
+        assert_eq!(emulator.palette()[16], TerminalColor::index_to_rgb(16));
+
+        // Resets go back to the configured colors rather than xterm's
+        emulator.feed_str("\x1b]4;2;#ffffff\x07\x1b]104;2\x07");
+        assert_eq!(emulator.palette()[2], (10, 20, 30));
+        emulator.feed_str("\x1b]4;3;#ffffff\x07\x1bc");
+        assert_eq!(emulator.palette()[3], (1, 2, 3));
+    }
+
+    #[test]
+    fn test_working_directory() {
+        let mut emulator = emulator_without_shell(10, 5);
+        assert_eq!(emulator.working_directory(), None);
+
+        emulator.feed_str("\x1b]7;file://host/home/me\x07");
+        assert_eq!(emulator.working_directory(), Some(Path::new("/home/me")));
+
+        emulator.feed_str("\x1b]7;file:///home/me/My%20Files\x1b\\");
+        assert_eq!(emulator.working_directory(), Some(Path::new("/home/me/My Files")));
+        assert_eq!(emulator.data().visible, b"");
+    }
+
+    #[test]
+    fn test_prompt_marks() {
+        let mut emulator = emulator_without_shell(10, 5);
+        emulator.feed_str("\x1b]133;A\x07$ ls\r\nfile\r\n\x1b]133;A\x07$ \r\n\x1b]133;A\x07$ ");
+        let starts = |emulator: &TerminalEmulator| {
+            emulator.prompt_marks().iter().map(|m| m.start).collect::<Vec<_>>()
+        };
+        assert_eq!(starts(&emulator), [0, 10, 13]);
+        assert!(emulator
+            .prompt_marks()
+            .iter()
+            .all(|m| m.kind == PromptMarkKind::PromptStart));
+
+        // Marks stay with their prompts as text is written before them
+        emulator.feed_str("\x1b[1;1Hab\x1b[2@");
+        assert_eq!(starts(&emulator), [0, 12, 15]);
+
+        // Redrawing a prompt in place keeps its mark
+        emulator.feed_str("\x1b[4;1H\x1b]133;D;0\x07% ");
+        assert_eq!(
+            emulator.prompt_marks().last(),
+            Some(&PromptMark {
+                start: 15,
+                kind: PromptMarkKind::CommandEnd(Some(0)),
+            })
+        );
+    }
+
+    #[test]
+    fn test_insert_mode() {
+        let mut emulator = emulator_without_shell(10, 5);
+        emulator.feed_str("abc\x1b[4h\x1b[1;2HX");
+        assert_eq!(emulator.screen_text(), "aXbc");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 2, y: 0 });
+
+        // Formatting moves with the text, and text pushed past the margin is lost
+        emulator.feed_str("\x1b[31mYYYYYYY");
+        assert_eq!(emulator.screen_text(), "aXYYYYYYYb");
+        assert_eq!(emulator.cell(9, 0).unwrap().fg, TerminalColor::Default);
+        assert_eq!(emulator.cell(8, 0).unwrap().fg, TerminalColor::ForegroundRed);
+
+        emulator.feed_str("\x1b[4l\x1b[1;1HZ");
+        assert_eq!(emulator.screen_text(), "ZXYYYYYYYb");
+
+        // DECSTR turns it off too
+        emulator.feed_str("\x1b[4h\x1b[!p\x1b[1;1HW");
+        assert_eq!(emulator.screen_text(), "WXYYYYYYYb");
+    }
+
+    #[test]
+    fn test_insert_mode_pending_wrap() {
+        // Writing up to the right margin leaves the next write for the next row
+        let mut emulator = emulator_without_shell(5, 5);
+        emulator.feed_str("12345\x1b[4h6");
+        assert_eq!(emulator.screen_text(), "12345\n6");
+        assert!(emulator.screen.row_wraps(0));
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 1, y: 1 });
+
+        // Where the line goes on, its text on the next row moves over
+        let mut emulator = emulator_without_shell(5, 5);
+        emulator.feed_str("123456789\x1b[1;1H1234\x1b[4h5");
+        emulator.feed_str("X");
+        assert_eq!(emulator.screen_text(), "12345\nX6789");
+        assert!(emulator.screen.row_wraps(0));
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 1, y: 1 });
+    }
+
+    #[test]
+    fn test_left_right_margins() {
+        let mut emulator = emulator_without_shell(50, 5);
+
+        // Without DECLRMM CSI s saves the cursor, for CSI u to restore
+        emulator.feed_str("\x1b[2;3H\x1b[s\x1b[5;6H\x1b[u");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 2, y: 1 });
+        assert_eq!(emulator.column_margins, None);
+
+        emulator.feed_str("\x1b[?69h\x1b[5;40s");
+        assert_eq!(emulator.column_margins, Some(4..40));
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 0, y: 0 });
+
+        // Invalid margins are ignored, ones covering the whole row clear them
+        emulator.feed_str("\x1b[40;40s");
+        assert_eq!(emulator.column_margins, Some(4..40));
+        emulator.feed_str("\x1b[s");
+        assert_eq!(emulator.column_margins, None);
+
+        // ICH and DCH only move text up to the right margin, and do nothing outside the margins
+        let row: String = ('a'..='z').chain('A'..='Z').take(50).collect();
+        emulator.feed_str(&format!("\x1b[1;10s\x1b[H{row}"));
+        emulator.feed_str("\x1b[1;3H\x1b[2@");
+        assert_eq!(&emulator.screen_text()[..12], "ab  cdefghkl");
+        emulator.feed_str("\x1b[1;3H\x1b[3P");
+        assert_eq!(&emulator.screen_text()[..12], "abdefgh   kl");
+        emulator.feed_str("\x1b[1;12H\x1b[P\x1b[@");
+        assert_eq!(&emulator.screen_text()[..12], "abdefgh   kl");
+
+        // Leaving the mode drops the margins, CSI s saves the cursor again
+        emulator.feed_str("\x1b[?69l");
+        assert_eq!(emulator.column_margins, None);
+        emulator.feed_str("\x1b[1;1H\x1b[P");
+        assert!(emulator.screen_text().starts_with("bdefgh   klm"));
+    }
+
+    #[test]
+    fn test_dirty() {
+        let mut emulator = emulator_without_shell(10, 5);
+        assert!(emulator.take_dirty());
+        assert!(!emulator.take_dirty());
+
+        emulator.feed(b"");
+        assert!(!emulator.take_dirty());
+        emulator.feed_str("a");
+        assert!(emulator.take_dirty());
+
+        emulator.set_win_size(10, 5).unwrap();
+        assert!(!emulator.take_dirty());
+        emulator.set_win_size(12, 5).unwrap();
+        assert!(emulator.take_dirty());
+    }
+
+    #[test]
+    fn test_resize_reflows() {
+        let mut emulator = emulator_without_shell(10, 5);
+        emulator.feed_str("\x1b[31mabcdefghijklmnopqrstuvwxyz0123\x1b[0m\r\nshort\r\n\x1b[32mlast");
+        emulator.feed_str("\x1b[1;5H");
+        assert_eq!(
+            emulator.screen_text(),
+            "abcdefghij\nklmnopqrst\nuvwxyz0123\nshort\nlast"
+        );
+
+        emulator.set_win_size(15, 5).unwrap();
+        assert_eq!(
+            emulator.screen_text(),
+            "abcdefghijklmno\npqrstuvwxyz0123\nshort\nlast"
+        );
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 4, y: 0 });
+        assert_eq!(emulator.cell(14, 1).unwrap().fg, TerminalColor::ForegroundRed);
+        assert_eq!(emulator.cell(0, 2).unwrap().fg, TerminalColor::Default);
+
+        // Padding the cursor row out to the cursor moves the text after it along with its colors
+        emulator.feed_str("\x1b[3;9H");
+        emulator.set_win_size(10, 5).unwrap();
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 8, y: 3 });
+        assert_eq!(emulator.cell(7, 3).unwrap().fg, TerminalColor::Default);
+        assert_eq!(emulator.cell(0, 4).unwrap().ch, 'l');
+        assert_eq!(emulator.cell(0, 4).unwrap().fg, TerminalColor::ForegroundGreen);
+    }
+
+    #[test]
+    fn test_resize_to_zero() {
+        let mut emulator = emulator_without_shell(10, 5);
+        emulator.feed_str("abcdefghijklmn\r\nop");
+        emulator.set_win_size(0, 5).unwrap();
+        emulator.feed_str("qr\r\nst");
+        // One column wide, so every character is a row of its own
+        assert_eq!(emulator.screen_text(), "p\nq\nr\ns\nt");
+
+        let mut emulator = emulator_without_shell(10, 5);
+        emulator.feed_str("abc\r\nde");
+        emulator.set_win_size(10, 0).unwrap();
+        emulator.feed_str("fg\r\nhi");
+        // One row high, everything before the last line scrolled off
+        assert_eq!(emulator.screen_text(), "hi");
+    }
+
+    #[test]
+    fn test_origin_mode() {
+        let mut emulator = emulator_without_shell(10, 10);
+        emulator.feed_str("\x1b[3;6r\x1b[?6h");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 0, y: 2 });
+
+        emulator.feed_str("\x1b[5;5H\x1b[1;1H");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 0, y: 2 });
+
+        // Rows past the bottom of the region stop at it
+        emulator.feed_str("\x1b[3;4H");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 3, y: 4 });
+        emulator.feed_str("\x1b[99;1H");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 0, y: 5 });
+
+        // Changing the region homes to its top
+        emulator.feed_str("\x1b[2;8r");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 0, y: 1 });
+
+        emulator.feed_str("\x1b[4;4H\x1b[?6l");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 0, y: 0 });
+        emulator.feed_str("\x1b[1;1H");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 0, y: 0 });
+
+        // Without a region origin mode positions against the screen
+        emulator.feed_str("\x1b[r\x1b[?6h\x1b[9;1H");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 0, y: 8 });
+    }
+
+    #[test]
+    fn test_tab_stops() {
+        let mut emulator = emulator_without_shell(30, 5);
+        emulator.feed_str("a\tb");
+        assert_eq!(emulator.screen_text(), "a       b");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 9, y: 0 });
+
+        // Stops at 4, 10 and 20 only
+        emulator.feed_str("\x1b[3g\x1b[1;5H\x1bH\x1b[1;11H\x1bH\x1b[1;21H\x1bH\x1b[1;1H");
+        emulator.feed_str("\x1b[I");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 4, y: 0 });
+        emulator.feed_str("\x1b[2I");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 20, y: 0 });
+        emulator.feed_str("\x1b[5I");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 29, y: 0 });
+
+        emulator.feed_str("\x1b[Z");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 20, y: 0 });
+        emulator.feed_str("\x1b[2Z");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 4, y: 0 });
+        emulator.feed_str("\x1b[Z\x1b[Z");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 0, y: 0 });
+
+        // Clearing the stop under the cursor, tabs never change the text they move over
+        emulator.feed_str("\x1b[1;11H\x1b[g\x1b[1;1H\x1b[2I");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 20, y: 0 });
+        assert_eq!(emulator.screen_text(), "a       b");
+    }
+
+    #[test]
+    fn test_save_restore_cursor_attributes() {
+        let mut emulator = emulator_without_shell(80, 24);
+
+        emulator.feed(b"\x1b[31m\x1b[1m\x1b[4;4H\x1b7");
+        emulator.feed(b"\x1b[0m\x1b[10;10H");
+        assert_eq!(emulator.cursor_state.fg_color, TerminalColor::Default);
+        assert!(!emulator.cursor_state.attributes.bold);
+
+        emulator.feed(b"\x1b8");
+        assert_eq!(emulator.cursor_state.fg_color, TerminalColor::ForegroundRed);
+        assert!(emulator.cursor_state.attributes.bold);
+        assert!(emulator.cursor_pos() == CursorPos { x: 3, y: 3 });
+    }
+
+    #[test]
+    fn test_restore_cursor_without_save() {
+        let mut emulator = emulator_without_shell(80, 24);
+
+        emulator.feed(b"\x1b[32m\x1b[5;5H\x1b8");
+        assert_eq!(emulator.cursor_state.fg_color, TerminalColor::Default);
+        assert!(emulator.cursor_pos() == CursorPos { x: 0, y: 0 });
+    }
+
+    #[test]
+    fn test_save_restore_origin_mode() {
+        let mut emulator = emulator_without_shell(10, 8);
+
+        // Restoring puts origin mode back, so homing goes to the top margin again
+        emulator.feed(b"\x1b[3;6r\x1b[?6h\x1b7\x1b[?6l\x1b8\x1b[1;1Hx");
+        assert_eq!(emulator.screen_text(), "\n\nx");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 1, y: 2 });
+
+        // And off again when it was off when saved
+        emulator.feed(b"\x1b[?6l\x1b7\x1b[?6h\x1b8\x1b[1;1Hy");
+        assert_eq!(emulator.screen_text(), "y\n\nx");
+
+        // Without a save it is off
+        emulator.feed(b"\x1b[?6h\x1bc\x1b[3;6r\x1b[?6h\x1b8\x1b[1;1Hz");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 1, y: 0 });
+    }
+
+    #[test]
+    fn test_bogus_shell_is_an_error() {
+        let options = Options {
+            shell: Some(tty::Shell::new(
+                "/this/shell/does/not/exist".to_string(),
+                Vec::new(),
+            )),
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            TerminalEmulator::new(options),
+            Err(TerminalError::Fork(_))
+        ));
+    }
+
+    #[test]
+    fn test_data_trims_trailing_newline() {
+        let mut emulator = emulator_without_shell(80, 24);
+        emulator.set_win_size(10, 3).unwrap();
+
+        emulator.feed(b"\x1b[31mfirst\r\nsecond\r\n\x1b[32mthird\r\n");
         let data = emulator.data();
-        assert_eq!(data.visible, b"");
+        assert_eq!(data.scrollback, b"first");
+        assert_eq!(data.visible, b"second\nthird\n");
+
+        let format_data = emulator.format_data();
+        let last_scrollback_tag = format_data.scrollback.last().unwrap();
+        assert_eq!(last_scrollback_tag.end, data.scrollback.len());
+        for tag in &format_data.visible {
+            assert!(tag.end == usize::MAX || tag.end <= data.visible.len());
+        }
+        let green_tag = format_data
+            .visible
+            .iter()
+            .find(|tag| tag.fg_color == TerminalColor::ForegroundGreen)
+            .unwrap();
+        assert_eq!((green_tag.start, green_tag.end), (7, 12));
+
+        // The cursor sits on the row after the trimmed newline
+        assert!(emulator.cursor_pos() == CursorPos { x: 0, y: 2 });
+    }
+
+    #[test]
+    fn test_scrollback_limit_keeps_format_aligned() {
+        let mut emulator = emulator_without_shell(80, 24);
+        emulator.set_win_size(10, 2).unwrap();
+        emulator.set_scrollback_limit(2);
+
+        emulator.feed(b"aa\r\nbb\r\n\x1b[31mcc\x1b[0m\r\ndd\r\nee");
+        let data = emulator.data();
+        assert_eq!(data.scrollback, b"bb\ncc");
+        assert_eq!(data.visible, b"dd\nee");
+
+        let format_data = emulator.format_data();
+        let red_tag = format_data
+            .scrollback
+            .iter()
+            .find(|tag| tag.fg_color == TerminalColor::ForegroundRed)
+            .unwrap();
+        assert_eq!((red_tag.start, red_tag.end), (3, 5));
+    }
+
+    /// Writer that accepts a few bytes at a time, and is sometimes not ready at all
+    struct MockWriter {
+        written: Vec<u8>,
+        calls: usize,
+    }
+
+    impl Write for MockWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.calls += 1;
+            if self.calls.is_multiple_of(2) {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            let len = buf.len().min(3);
+            self.written.extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_paste() {
+        let mut writer = MockWriter {
+            written: Vec::new(),
+            calls: 0,
+        };
+        let payload = paste_payload("echo hi\nls\r\nexit\x1b[201~", false, true);
+        write_all_nonblocking(&mut writer, &payload).unwrap();
+        assert_eq!(writer.written, b"echo hi\rls\rexit[201~");
+
+        let payload = paste_payload("a\nb", true, true);
+        assert_eq!(payload, b"\x1b[200~a\rb\x1b[201~");
+
+        let payload = paste_payload("\x1b[A", false, false);
+        assert_eq!(payload, b"\x1b[A");
+    }
+
+    #[test]
+    fn test_bracketed_paste_mode() {
+        let mut emulator = emulator_without_shell(80, 24);
+        assert!(!emulator.bracketed_paste_mode);
+        emulator.feed(b"\x1b[?2004h");
+        assert!(emulator.bracketed_paste_mode);
+        emulator.feed(b"\x1b[?2004l");
+        assert!(!emulator.bracketed_paste_mode);
+    }
+
+    #[test]
+    fn test_index_to_rgb() {
+        let cases = [
+            (0, (0, 0, 0)),
+            (7, (229, 229, 229)),
+            (8, (127, 127, 127)),
+            (15, (255, 255, 255)),
+            (16, (0, 0, 0)),
+            (17, (0, 0, 95)),
+            (21, (0, 0, 255)),
+            (59, (95, 95, 95)),
+            (102, (135, 135, 135)),
+            (145, (175, 175, 175)),
+            (188, (215, 215, 215)),
+            (196, (255, 0, 0)),
+            (208, (255, 135, 0)),
+            (231, (255, 255, 255)),
+            (232, (8, 8, 8)),
+            (244, (128, 128, 128)),
+            (255, (238, 238, 238)),
+        ];
+
+        for (index, expected) in cases {
+            assert_eq!(TerminalColor::index_to_rgb(index), expected, "index {index}");
+        }
+    }
+
+    #[test]
+    fn test_sgr_default_colors() {
+        let mut emulator = emulator_without_shell(80, 24);
+
+        emulator.feed(b"\x1b[31;44m\x1b[39mtext");
+        let format_data = emulator.format_data();
+        let text_tag = format_data.visible.iter().find(|tag| tag.start == 0).unwrap();
+        assert_eq!(text_tag.fg_color, TerminalColor::Default);
+        assert_eq!(text_tag.bg_color, TerminalColor::BackgroundBlue);
+
+        emulator.feed(b"\x1b[31m\x1b[49m");
+        assert_eq!(emulator.cursor_state.fg_color, TerminalColor::ForegroundRed);
+        assert_eq!(emulator.cursor_state.bg_color, TerminalColor::Default);
+    }
+
+    #[test]
+    fn test_truncated_sgr_is_abandoned() {
+        let mut emulator = emulator_without_shell(80, 24);
+
+        emulator.feed(b"\x1b[31\x1b[32mX");
+        assert_eq!(emulator.screen_text().lines().next(), Some("X"));
+        let format_data = emulator.format_data();
+        let text_tag = format_data.visible.iter().find(|tag| tag.start == 0).unwrap();
+        assert_eq!(text_tag.fg_color, TerminalColor::ForegroundGreen);
+    }
+
+    #[test]
+    fn test_text_attributes_round_trip() {
+        let mut emulator = emulator_without_shell(80, 24);
+
+        let on = |f: fn(&mut TextAttributes)| {
+            let mut attributes = TextAttributes::default();
+            f(&mut attributes);
+            attributes
+        };
+        let cases = [
+            ("1", "22", on(|a| a.bold = true)),
+            ("2", "22", on(|a| a.faint = true)),
+            ("3", "23", on(|a| a.italic = true)),
+            ("4", "24", on(|a| a.underline = true)),
+            ("5", "25", on(|a| a.blink = BlinkMode::SlowBlink)),
+            ("6", "25", on(|a| a.blink = BlinkMode::RapidBlink)),
+            ("7", "27", on(|a| a.reverse = true)),
+            ("8", "28", on(|a| a.conceal = true)),
+        ];
+
+        for (set, unset, expected) in cases {
+            emulator.feed_str(&format!("\x1b[2J\x1b[H\x1b[31m\x1b[{set}mon\x1b[{unset}moff"));
+            let tags = emulator.format_data().visible;
+
+            let tag = tags.iter().find(|tag| tag.start == 0).unwrap();
+            assert_eq!(tag.attributes, expected, "SGR {set} not applied");
+            // Attributes never leak into the color
+            assert_eq!(tag.fg_color, TerminalColor::ForegroundRed);
+
+            let tag = tags.iter().find(|tag| tag.start == 2).unwrap();
+            assert_eq!(tag.attributes, TextAttributes::default(), "SGR {unset} not applied");
+            assert_eq!(tag.fg_color, TerminalColor::ForegroundRed);
+
+            emulator.feed(b"\x1b[0m");
+        }
+    }
+
+    #[test]
+    fn test_decawm() {
+        let mut emulator = emulator_without_shell(80, 24);
+        let (width, _) = emulator.screen.dimensions();
+        let line = "a".repeat(width - 1) + "bcd";
+
+        emulator.feed_str("\x1b[?7l");
+        emulator.feed_str(&line);
+        assert_eq!(emulator.data().visible, ("a".repeat(width - 1) + "d").as_bytes());
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: width - 1, y: 0 });
+
+        // Survives a trip through the alternate screen
+        emulator.feed_str("\x1b[?1049h\x1b[?1049l");
+        assert!(!emulator.screen.auto_wrap());
+
+        emulator.feed_str("\x1b[?7h\x1b[2J\x1b[H");
+        emulator.feed_str(&line);
+        assert_eq!(emulator.data().visible, line.as_bytes());
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 2, y: 1 });
+
+        // Multi-byte characters are never cut in half
+        let mut emulator = emulator_without_shell(3, 2);
+        emulator.feed_str("\x1b[?7léééé");
+        assert_eq!(emulator.data().visible, "é".as_bytes());
+        emulator.feed_str("\r\nééz");
+        assert_eq!(emulator.data().visible, "é\néz".as_bytes());
+        emulator.feed_str("\x1b[1;3Hxéé");
+        assert_eq!(emulator.data().visible, "éx\néz".as_bytes());
+    }
+
+    #[test]
+    fn test_set_cursor_pos_row_column() {
+        let mut emulator = emulator_without_shell(80, 24);
+        emulator.feed_str("\x1b[5;10H");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 9, y: 4 });
+
+        emulator.feed_str("X");
+        let visible = emulator.data().visible;
+        let lines = visible.split(|b| *b == b'\n').collect::<Vec<_>>();
+        assert_eq!(lines[4], b"         X");
+    }
+
+    #[test]
+    fn test_line_feeds() {
+        let mut emulator = emulator_without_shell(10, 5);
+
+        // FF and VT move down like LF, staying in the column
+        emulator.feed_str("ab\x0cc\x0bd");
+        assert_eq!(emulator.screen_text(), "ab\n  c\n   d");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 4, y: 2 });
+
+        // NEL goes to the start of the next line
+        emulator.feed(b"\x85e\x1bEf");
+        assert_eq!(emulator.screen_text(), "ab\n  c\n   d\ne\nf");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 1, y: 4 });
+    }
+
+    #[test]
+    fn test_snapshot_colored_listing() {
+        let input =
+            b"$ ls\r\n\x1b[0m\x1b[01;34mdir\x1b[0m  file.txt  \x1b[01;32mrun.sh\x1b[0m\r\n$ ";
+        let (rows, cells) = render_to_grid(input, 24, 4);
+        assert_eq!(
+            rows,
+            [
+                "$ ls                    ",
+                "dir  file.txt  run.sh   ",
+                "$                       ",
+                "                        ",
+            ]
+        );
+
+        let fg = |x: usize| cells[1][x].as_ref().map(|cell| cell.fg);
+        let bold = |x: usize| cells[1][x].as_ref().is_some_and(|cell| cell.attrs.bold);
+        assert!((0..3).all(|x| fg(x) == Some(TerminalColor::ForegroundBlue) && bold(x)));
+        assert!((3..15).all(|x| fg(x) == Some(TerminalColor::Default) && !bold(x)));
+        assert!((15..21).all(|x| fg(x) == Some(TerminalColor::ForegroundGreen) && bold(x)));
+        assert!((21..24).all(|x| fg(x).is_none()));
+    }
+
+    #[test]
+    fn test_snapshot_cursor_addressed_box() {
+        let input = b"\x1b[2J\x1b[2;3H+----+\x1b[3;3H|\x1b[3;8H|\x1b[4;3H+----+\x1b[3;5H\x1b[7mhi";
+        let (rows, cells) = render_to_grid(input, 10, 5);
+        assert_eq!(
+            rows,
+            [
+                "          ",
+                "  +----+  ",
+                "  | hi |  ",
+                "  +----+  ",
+                "          ",
+            ]
+        );
+
+        let reverse = |x: usize, y: usize| {
+            cells[y][x]
+                .as_ref()
+                .is_some_and(|cell| cell.attrs.reverse)
+        };
+        assert!(reverse(4, 2) && reverse(5, 2));
+        assert!(!reverse(3, 2) && !reverse(6, 2) && !reverse(4, 1));
+    }
+
+    #[test]
+    fn test_snapshot_wide_characters() {
+        let (rows, cells) = render_to_grid("日本 ok\r\n\x1b[1mÅ".as_bytes(), 10, 3);
+        assert_eq!(rows, ["日··本·· ok ", "Å·        ", "          "]);
+        assert!(rows.iter().all(|row| row.chars().count() == 10));
+
+        // The continuation takes the format of the character it belongs to
+        assert_eq!(cells[1][1].as_ref().map(|cell| cell.ch), Some('\0'));
+        assert!(cells[1][1].as_ref().is_some_and(|cell| cell.attrs.bold));
+    }
+
+    #[test]
+    fn test_clear_forwards_after_resize() {
+        // Shrinking leaves the cursor just past the last column of a full row
+        for (text, width) in [("prompt$ ", 4), ("ab", 1), ("abcdefgh", 4)] {
+            let mut emulator = emulator_without_shell(80, 24);
+            emulator.feed_str(text);
+            emulator.set_win_size(width, 24).unwrap();
+            emulator.feed_str("\x1b[J");
+            assert_eq!(emulator.data().visible, text.as_bytes(), "{text} at {width}");
+
+            emulator.feed_str("x");
+            assert!(emulator.screen_text().ends_with('x'), "{text} at {width}");
+        }
+    }
+
+    #[test]
+    fn test_set_cursor_pos_zero() {
+        let mut emulator = emulator_without_shell(10, 5);
+        emulator.feed_str("\x1b[3;4H\x1b[0;0H");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 0, y: 0 });
+        emulator.feed_str("\x1b[3;4H\x1b[0G");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 0, y: 2 });
+
+        // In origin mode a 0 is the top of the scroll region
+        emulator.feed_str("\x1b[2;4r\x1b[?6h\x1b[3;4H\x1b[0;0H");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 0, y: 1 });
+    }
+
+    #[test]
+    fn test_clear_backwards() {
+        let mut emulator = emulator_without_shell(80, 24);
+        emulator.feed_str("\x1b[1;31mabcdef\x1b[1;3H\x1b[0;44m\x1b[1K");
+        assert_eq!(emulator.data().visible, b"   def");
+
+        let tags = emulator.format_data().visible;
+        let erased = tags.iter().find(|tag| tag.start == 0).unwrap();
+        assert_eq!(erased.end, 3);
+        assert_eq!(erased.bg_color, TerminalColor::BackgroundBlue);
+        assert_eq!(erased.fg_color, TerminalColor::Default);
+        assert_eq!(erased.attributes, TextAttributes::default());
+        let kept = tags.iter().find(|tag| tag.start == 3).unwrap();
+        assert_eq!(kept.fg_color, TerminalColor::ForegroundRed);
+
+        emulator.feed_str("\x1b[2J\x1b[Habc\r\ndefgh\r\nijk\x1b[2;3H\x1b[1J");
+        assert_eq!(emulator.data().visible, b"   \n   gh\nijk");
+    }
+
+    #[test]
+    fn test_erase_chars() {
+        let mut emulator = emulator_without_shell(10, 5);
+        emulator.feed_str("\x1b[31mabcdefghijkl\x1b[1;3H\x1b[44m\x1b[3X");
+        assert_eq!(emulator.data().visible, b"ab   fghijkl");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 2, y: 0 });
+        assert_eq!(emulator.cell(2, 0).unwrap().bg, TerminalColor::BackgroundBlue);
+        assert_eq!(emulator.cell(5, 0).unwrap().fg, TerminalColor::ForegroundRed);
+
+        // Only up to the end of the row, the wrapped text on the next one stays
+        emulator.feed_str("\x1b[1;9H\x1b[99X");
+        assert_eq!(emulator.data().visible, b"ab   fgh  kl");
+
+        // Only up to the right margin, or the edge of the screen outside the margins
+        emulator.feed_str("\x1b[2J\x1b[Habcdefghij\x1b[?69h\x1b[2;6s\x1b[1;4H\x1b[99X");
+        assert_eq!(emulator.data().visible, b"abc   ghij");
+        emulator.feed_str("\x1b[1;9H\x1b[99X");
+        assert_eq!(emulator.data().visible, b"abc   gh  ");
+    }
+
+    #[test]
+    fn test_cursor_visible() {
+        let mut emulator = emulator_without_shell(10, 5);
+        assert!(emulator.cursor_visible());
+
+        emulator.feed_str("\x1b[?25l");
+        assert!(!emulator.cursor_visible());
+        emulator.feed_str("\x1b[?25h");
+        assert!(emulator.cursor_visible());
+
+        // Restoring a cursor saved before anything was shown keeps it visible
+        emulator.feed_str("\x1b8");
+        assert!(emulator.cursor_visible());
+    }
+
+    #[test]
+    fn test_soft_reset() {
+        let mut emulator = emulator_without_shell(80, 24);
+        emulator.feed_str("\x1b[?1h\x1b[?25l\x1b[1;3;4;7;31;44mhello\x1b7\x1b[!p");
+
+        let default = CursorState::default();
+        assert_eq!(emulator.cursor_state.attributes, default.attributes);
+        assert_eq!(emulator.cursor_state.fg_color, default.fg_color);
+        assert_eq!(emulator.cursor_state.bg_color, default.bg_color);
+        assert!(emulator.cursor_state.visible);
+        assert!(!emulator.decckm_mode);
+        assert!(emulator.saved_cursor.is_none());
+
+        // Screen and cursor position are left alone
+        assert_eq!(emulator.data().visible, b"hello");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 5, y: 0 });
+        let tag = &emulator.format_data().visible[0];
+        assert_eq!(tag.fg_color, TerminalColor::ForegroundRed);
+    }
+
+    #[test]
+    fn test_full_reset() {
+        let mut emulator = emulator_without_shell(80, 24);
+        emulator.feed_str("\x1b[?2004h\x1b[?5h\x1b[?7l\x1b[31mhello\r\nworld\x1b[?1049halt\x1bc");
 
-        // Exit alt screen
-        emulator.exit_alt_screen();
         assert!(!emulator.is_alt_screen_active());
-}
+        assert!(!emulator.bracketed_paste_mode);
+        assert!(!emulator.reverse_screen());
+        assert!(emulator.screen.auto_wrap());
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 0, y: 0 });
+        assert_eq!(emulator.data().scrollback, b"");
+        assert_eq!(emulator.data().visible, b"");
+
+        emulator.feed_str("a");
+        let tags = emulator.format_data().visible;
+        assert_eq!(tags.len(), 1);
+        assert_eq!(tags[0].fg_color, TerminalColor::Default);
+    }
+
+    #[test]
+    fn test_copy_text() {
+        let mut emulator = emulator_without_shell(5, 4);
+        emulator.feed_str("one  \r\nwrapping\r\na\r\nb ");
+
+        // "one" scrolled off, "wrapping" is two rows
+        assert_eq!(emulator.visible_text(false), "wrapp\ning\na\nb");
+        assert_eq!(emulator.visible_text(true), "wrapping\na\nb");
+        assert_eq!(emulator.all_text(false), "one\nwrapp\ning\na\nb");
+        assert_eq!(emulator.all_text(true), "one\nwrapping\na\nb");
+
+        // Half of the wrapped line is in scrollback now
+        emulator.feed_str("\r\nc");
+        assert_eq!(emulator.visible_text(true), "ing\na\nb\nc");
+        assert_eq!(emulator.all_text(true), "one\nwrapping\na\nb\nc");
+        assert_eq!(emulator.all_text(false), "one\nwrapp\ning\na\nb\nc");
+    }
 
+    #[test]
+    fn test_copy_trims_padding() {
+        let mut emulator = emulator_without_shell(10, 3);
+        // Moving right pads the line with spaces up to the space written in the last column
+        emulator.feed_str("a b\x1b[6C \r\n  x  y\x1b[3C");
+        assert_eq!(emulator.data().visible, b"a b       \n  x  y");
+
+        assert_eq!(emulator.screen_text(), "a b\n  x  y");
+        assert_eq!(emulator.visible_text(false), "a b\n  x  y");
+        assert_eq!(emulator.all_text(true), "a b\n  x  y");
+        assert_eq!(trim_line_ends(b"a b   \n \n  x"), b"a b\n\n  x");
+    }
+
+    #[test]
+    fn test_to_html() {
+        let mut emulator = emulator_without_shell(80, 24);
+        let defaults = HtmlColors {
+            foreground: (229, 229, 229),
+            background: (27, 27, 27),
+        };
+        emulator.feed_str("\x1b[31mred\x1b[1;44m<b>\x1b[0m plain");
+        assert_eq!(
+            emulator.to_html(defaults),
+            "<pre style=\"color:#e5e5e5;background:#1b1b1b\">\
+             <span style=\"color:#cd0000\">red</span>\
+             <span style=\"color:#cd0000;background:#0000ee;font-weight:bold\">&lt;b&gt;</span>\
+             \x20plain</pre>"
+        );
+
+        // Programs changing the default colors change the ones of the page
+        emulator.feed_str("\x1b]11;rgb:ff/ff/ff\x07\x1bc");
+        assert_eq!(
+            emulator.to_html(defaults),
+            "<pre style=\"color:#e5e5e5;background:#1b1b1b\"></pre>"
+        );
+        emulator.feed_str("\x1b]11;rgb:ff/ff/ff\x07\x1b[7mx");
+        assert_eq!(
+            emulator.to_html(defaults),
+            "<pre style=\"color:#e5e5e5;background:#ffffff\">\
+             <span style=\"color:#ffffff;background:#e5e5e5\">x</span></pre>"
+        );
+    }
+
+    #[test]
+    fn test_alignment_test() {
+        let mut emulator = emulator_without_shell(3, 3);
+        emulator.feed_str("ab\r\n\x1b[31mcd\x1b[2;3r\x1b#8");
+
+        // Each row is a line of its own rather than one long wrapped line
+        assert_eq!(emulator.data().visible, b"EEE\nEEE\nEEE");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 0, y: 0 });
+        assert_eq!(emulator.scroll_region, None);
+        let tags = emulator.format_data().visible;
+        assert!(tags.iter().all(|tag| tag.fg_color == TerminalColor::Default));
+
+        // Colors set before still apply to what is written next
+        emulator.feed_str("x");
+        assert_eq!(emulator.data().visible, b"xEE\nEEE\nEEE");
+        assert_eq!(emulator.format_data().visible[0].fg_color, TerminalColor::ForegroundRed);
+    }
+
+    #[test]
+    fn test_cursor_style() {
+        let mut emulator = emulator_without_shell(80, 24);
+        assert_eq!(emulator.cursor_style(), None);
+
+        emulator.feed_str("\x1b[4 q");
+        let underline = CursorStyle {
+            shape: CursorShape::Underline,
+            blink: false,
+        };
+        assert_eq!(emulator.cursor_style(), Some(underline));
+        // Soft reset keeps it, like xterm
+        emulator.feed_str("\x1b[!p");
+        assert_eq!(emulator.cursor_style(), Some(underline));
+        emulator.feed_str("\x1b[0 q");
+        assert_eq!(emulator.cursor_style(), None);
+
+        emulator.feed_str("\x1b[5 q\x1bc");
+        assert_eq!(emulator.cursor_style(), None);
+    }
+
+    #[test]
+    fn test_bell() {
+        let mut emulator = emulator_without_shell(80, 24);
+        emulator.feed_str("a\x07b");
+        assert_eq!(emulator.data().visible, b"ab");
+        assert!(emulator.take_bell());
+        assert!(!emulator.take_bell());
+    }
+
+    #[test]
+    fn test_newline_scrolls_at_bottom() {
+        let mut emulator = emulator_without_shell(80, 24);
+        let (_, height) = emulator.screen.dimensions();
+
+        let lines = (0..height).map(|i| format!("line{i}")).collect::<Vec<_>>();
+        emulator.feed_str(&lines.join("\r\n"));
+        assert_eq!(emulator.cursor_pos().y, height - 1);
+        assert_eq!(emulator.data().scrollback, b"");
+
+        emulator.feed_str("\r\n");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 0, y: height - 1 });
+        assert_eq!(emulator.data().scrollback, b"line0");
+
+        emulator.feed_str("next");
+        let visible = emulator.data().visible;
+        let visible_lines = visible.split(|b| *b == b'\n').collect::<Vec<_>>();
+        assert_eq!(visible_lines.len(), height);
+        assert_eq!(visible_lines[0], b"line1");
+        assert_eq!(visible_lines[height - 1], b"next");
+    }
+
+    #[test]
+    fn test_newline_scrolls_region() {
+        let mut emulator = emulator_without_shell(80, 24);
+        emulator.feed_str("a\r\nb\r\nc\r\nd\x1b[2;3r");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 0, y: 0 });
+
+        emulator.feed_str("\x1b[3;1H\nx");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 1, y: 2 });
+        assert_eq!(emulator.data().scrollback, b"");
+        assert_eq!(emulator.data().visible, b"a\nc\nx\nd");
+
+        // Below the region newlines stop at the bottom of the screen without scrolling
+        let (_, height) = emulator.screen.dimensions();
+        emulator.feed_str(&format!("\x1b[{height};1H\n\n"));
+        assert_eq!(emulator.cursor_pos().y, height - 1);
+        assert_eq!(emulator.data().scrollback, b"");
+    }
+
+    #[test]
+    fn test_clamp_cursor() {
+        let mut emulator = emulator_without_shell(80, 24);
+        let (width, height) = emulator.screen.dimensions();
+
+        emulator.feed_str("\x1b[999G");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: width - 1, y: 0 });
+
+        emulator.feed_str("\x1b[999;999H");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: width - 1, y: height - 1 });
+
+        emulator.feed_str("\x1b[999A\x1b[999D");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 0, y: 0 });
+        emulator.feed_str("\x1b[999B\x1b[999C");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: width - 1, y: height - 1 });
+
+        // Nothing was written, so moving around must not have padded the buffer
+        assert_eq!(emulator.data().visible, b"");
 
+        // Relative moves inside the scroll region stop at its margins
+        emulator.feed_str("\x1b[3;6r\x1b[4;1H\x1b[999A");
+        assert_eq!(emulator.cursor_pos().y, 2);
+        emulator.feed_str("\x1b[999B");
+        assert_eq!(emulator.cursor_pos().y, 5);
+    }
+
+    #[test]
+    fn test_backspace_crosses_soft_wrap() {
+        let mut emulator = emulator_without_shell(80, 24);
+        let (width, _) = emulator.screen.dimensions();
+
+        emulator.feed_str(&"a".repeat(width + 2));
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 2, y: 1 });
+
+        emulator.feed_str("\x08\x08\x08");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: width - 1, y: 0 });
+        emulator.feed_str("b");
+        assert_eq!(emulator.data().visible, ("a".repeat(width - 1) + "baa").as_bytes());
+
+        // A hard line break stops the backspace
+        emulator.feed_str("\x1b[2J\x1b[Habc\r\ndef\r\x08");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 0, y: 1 });
+    }
+
+    #[test]
+    fn test_pending_wrap() {
+        let mut emulator = emulator_without_shell(80, 24);
+        let (width, _) = emulator.screen.dimensions();
+        let line = "a".repeat(width);
+
+        // The cursor stays on the last column, where a newline leaves it on the next row
+        emulator.feed_str(&line);
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: width - 1, y: 0 });
+        emulator.feed_str("\nb");
+        let expected = format!("{line}\n{}b", " ".repeat(width - 1));
+        assert_eq!(emulator.data().visible, expected.as_bytes());
+
+        // Backspace moves off the last column rather than cancelling the wrap
+        emulator.feed_str("\x1b[2J\x1b[H");
+        emulator.feed_str(&line);
+        emulator.feed_str("\x08b");
+        let expected = format!("{}ba", "a".repeat(width - 2));
+        assert_eq!(emulator.data().visible, expected.as_bytes());
+
+        // Putting the cursor anywhere, even back on the last column, cancels the wrap
+        for (moves, expected) in [
+            ("\x1b[1;5H", "abcdX"),
+            ("\x1b[1;5f", "abcdX"),
+            ("\x1b[5G", "abcdX"),
+            ("\x1b[C", "abcdX"),
+            ("\x1b[A", "abcdX"),
+            ("\x1b[0D", "abcXe"),
+        ] {
+            let mut emulator = emulator_without_shell(5, 3);
+            emulator.feed_str(&format!("abcde{moves}X"));
+            assert_eq!(emulator.screen_text(), expected, "after {moves:?}");
+        }
+    }
+
+    /// Minimal xorshift generator so the fuzz tests are reproducible without extra dependencies,
+    /// also used by the parser's fuzz test
+    pub(super) struct XorShift(pub(super) u64);
+
+    impl XorShift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        pub(super) fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    fn random_fragment(rng: &mut XorShift) -> String {
+        const FRAGMENTS: &[&str] = &[
+            "\x1b", "\x1b[", "\x1b[?", "\x1b[?1049h", "\x1b[?1049l", "\x1b[?25l", "\x1b[?1h",
+            "\x1b[?7l", "\x1b[?7h",
+            "\x1b[H", "\x1b[0;0H", "\x1b[999;999H", "\x1b[2J", "\x1b[K", "\x1b[2K", "\x1b[P",
+            "\x1b[1J", "\x1b[1K", "\x1b[!p", "\x1bc", "\x1b[2;5r", "\x1b[r", "\x1b[3;999r",
+            "\x1b(0", "\x1b(B", "\x1b)0", "\x0e", "\x0f", "lqkx", "\x1b=", "\x1b>",
+            "\x1bPq#1;2;100;0;0!3~-~", "\x1b\\", "\x1bP", "\x1b]52;c;aGk=\x07", "\x1b]",
+            "\x1b]4;1;rgb:00/00/ff\x07", "\x1b]104\x07", "\x1b]11;#000000\x07",
+            "\x1b]7;file://host/tmp%2f\x07", "\x1b]133;A\x07", "\x1b]133;D;1\x07",
+            "\x1b[4h", "\x1b[4l", "\x1b[?6h", "\x1b[?6l", "\x1b[2;4r",
+            "\x1b[I", "\x1b[3Z", "\x1bH", "\x1b[g", "\x1b[3g", "\x1b[18t", "\x1b[3;1;1t",
+            "\x1b[99P", "\x1b[@", "\x1b[99@", "\x1b[A", "\x1b[99B", "\x1b[C", "\x1b[99D",
+            "\x1b[X", "\x1b[99X", "\x1b[J",
+            "\x1b[38;5;", "\x1b[38;2;1;2;3m", "\x1b[48;2m", "\x1b[0m", "\x1b[1;3;5m", "\x1b7",
+            "\x1b8", ";", "m", "\r", "\n", "\x08", "\x7f", "\t", "\x07",
+        ];
+
+        let mut ret = String::new();
+        for _ in 0..rng.below(8) {
+            match rng.below(4) {
+                0 => ret.push_str(FRAGMENTS[rng.below(FRAGMENTS.len())]),
+                1 => {
+                    let number = rng.below(100_000);
+                    ret.push_str(&number.to_string());
+                }
+                2 => {
+                    let c = char::from_u32(rng.below(0x11_0000) as u32).unwrap_or('\u{fffd}');
+                    ret.push(c);
+                }
+                _ => ret.push_str("hello world"),
+            }
+        }
+        ret
+    }
+
+    #[test]
+    fn test_feed_str_never_panics() {
+        let mut emulator = emulator_without_shell(80, 24);
+        let mut rng = XorShift(0x2545_f491_4f6c_dd1d);
+
+        for _ in 0..5000 {
+            // Resizing between sequences catches state that is only valid for the old size
+            if rng.below(20) == 0 {
+                let width = 1 + rng.below(120);
+                let height = 1 + rng.below(60);
+                emulator.set_win_size(width, height).unwrap();
+            }
+
+            emulator.feed_str(&random_fragment(&mut rng));
+            let (width, height) = emulator.screen.dimensions();
+            let cursor = emulator.cursor_pos();
+            assert!(cursor.y < height, "cursor y {} >= height {}", cursor.y, height);
+
+            // Finish any partial escape sequence and print something
+            emulator.feed_str("m.");
+
+            let cursor = emulator.cursor_pos();
+            assert!(cursor.x < width, "cursor x {} >= width {}", cursor.x, width);
+            assert!(cursor.y < height, "cursor y {} >= height {}", cursor.y, height);
+
+            let data = emulator.data();
+            let visible_lines = data.visible.split(|b| *b == b'\n').count();
+            assert!(visible_lines <= height + 1);
+        }
+    }
 }
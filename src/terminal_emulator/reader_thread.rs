@@ -0,0 +1,156 @@
+//! Reads the pty on its own thread, sleeping in a [`Poller`] until the shell writes something
+
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crossbeam::channel::{self, Receiver, Sender, TryRecvError};
+use polling::{Event, Events, PollMode, Poller};
+
+use super::ansi::{AnsiParser, TerminalOutput};
+use super::error::TerminalError;
+use super::recording::Recording;
+use super::trace::Tracer;
+use super::tty::PTY_READ_WRITE_TOKEN;
+
+/// Parsed batches the thread may be ahead by. Once full the thread stops reading, so output is
+/// left in the pty while the emulator is not taking it, e.g. when paused
+const CHANNEL_CAPACITY: usize = 64;
+
+type ReaderMessage = Result<Vec<TerminalOutput<'static>>, TerminalError>;
+
+pub(crate) struct ReaderThread {
+    receiver: Receiver<ReaderMessage>,
+    poller: Arc<Poller>,
+    stop: Arc<AtomicBool>,
+}
+
+impl ReaderThread {
+    /// Start reading `file`, which has to be non-blocking. What is read is also written to
+    /// `recording` and `tracer`, and `wake` is called from the thread whenever something was
+    /// queued
+    pub(crate) fn spawn<F>(
+        file: File,
+        recording: Recording,
+        tracer: Tracer,
+        wake: F,
+    ) -> Result<ReaderThread, TerminalError>
+    where
+        F: Fn() + Send + 'static,
+    {
+        let poller = Arc::new(Poller::new().map_err(TerminalError::Io)?);
+        // SAFETY: The file is moved into the thread, which deletes it from the poller before it
+        // is dropped
+        unsafe {
+            poller.add_with_mode(
+                &file,
+                Event::readable(PTY_READ_WRITE_TOKEN),
+                PollMode::Level,
+            )
+        }
+        .map_err(TerminalError::Io)?;
+
+        let (sender, receiver) = channel::bounded(CHANNEL_CAPACITY);
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_poller = Arc::clone(&poller);
+        let thread_stop = Arc::clone(&stop);
+        std::thread::Builder::new()
+            .name("pty reader".to_string())
+            .spawn(move || {
+                let mut file = file;
+                read_until_closed(
+                    &mut file,
+                    &thread_poller,
+                    &thread_stop,
+                    &sender,
+                    &recording,
+                    &tracer,
+                    &wake,
+                );
+                let _ = thread_poller.delete(&file);
+            })
+            .map_err(TerminalError::Io)?;
+
+        Ok(ReaderThread {
+            receiver,
+            poller,
+            stop,
+        })
+    }
+
+    /// The next batch of output if there is one. The shell going away is an error, and stays one
+    /// on every call after
+    pub(crate) fn try_recv(&self) -> Result<Option<Vec<TerminalOutput<'static>>>, TerminalError> {
+        match self.receiver.try_recv() {
+            Ok(message) => message.map(Some),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(TerminalError::ChildExited),
+        }
+    }
+}
+
+impl Drop for ReaderThread {
+    fn drop(&mut self) {
+        // A thread waiting on the poller is woken up to see this, one waiting to send sees the
+        // receiver go away
+        self.stop.store(true, Ordering::Relaxed);
+        if let Err(e) = self.poller.notify() {
+            warn!("Failed to stop pty reader thread: {e}");
+        }
+    }
+}
+
+fn read_until_closed(
+    file: &mut File,
+    poller: &Poller,
+    stop: &AtomicBool,
+    sender: &Sender<ReaderMessage>,
+    recording: &Recording,
+    tracer: &Tracer,
+    wake: &dyn Fn(),
+) {
+    let mut parser = AnsiParser::new();
+    let mut events = Events::new();
+    let mut buf = vec![0u8; 4096];
+    loop {
+        events.clear();
+        match poller.wait(&mut events, None) {
+            Ok(_) => (),
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => {
+                let _ = sender.send(Err(TerminalError::Io(e)));
+                wake();
+                return;
+            }
+        }
+        if stop.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let error = loop {
+            match file.read(&mut buf) {
+                Ok(0) => break Some(TerminalError::ChildExited),
+                Ok(read_size) => {
+                    let incoming = &buf[0..read_size];
+                    debug!("Incoming data: {:?}", std::str::from_utf8(incoming));
+                    recording.write(incoming);
+                    tracer.write_bytes(incoming);
+                    if sender.send(Ok(parser.push(incoming))).is_err() {
+                        return;
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break None,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => break Some(TerminalError::from_pty_io(e)),
+            }
+        };
+
+        if let Some(e) = error {
+            let _ = sender.send(Err(e));
+            wake();
+            return;
+        }
+        wake();
+    }
+}
@@ -0,0 +1,44 @@
+//! Raw copy of the shell's output, written from whichever thread reads the pty
+
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use super::error::TerminalError;
+
+/// Where output is recorded to, if anywhere. Clones share the file so that recording can start
+/// and stop after the reader thread got its clone
+#[derive(Clone, Default)]
+pub(crate) struct Recording(Arc<Mutex<Option<File>>>);
+
+impl Recording {
+    /// Record to `path`, replacing what is there
+    pub(crate) fn start(&self, path: &Path) -> Result<(), TerminalError> {
+        let file = File::create(path).map_err(TerminalError::Io)?;
+        *self.lock() = Some(file);
+        Ok(())
+    }
+
+    pub(crate) fn stop(&self) {
+        *self.lock() = None;
+    }
+
+    pub(crate) fn is_active(&self) -> bool {
+        self.lock().is_some()
+    }
+
+    /// Append `data` if recording. Failing to write stops the recording rather than the terminal
+    pub(crate) fn write(&self, data: &[u8]) {
+        let mut file = self.lock();
+        if let Some(Err(e)) = file.as_mut().map(|file| file.write_all(data)) {
+            warn!("Stopping recording, failed to write it: {e}");
+            *file = None;
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Option<File>> {
+        // Nothing panics while holding the lock, the file is still usable if something did
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
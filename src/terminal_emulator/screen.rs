@@ -0,0 +1,285 @@
+//! Text of the terminal together with its formatting, what the emulator writes into
+//!
+//! [`TerminalBuffer`] only knows about bytes, the colors and attributes of each byte are kept in
+//! a [`FormatTracker`] next to it and have to follow every edit. Both are kept in step here so
+//! that the emulator can say what to write and where without caring how it is stored. Built with
+//! the `grid` feature, `Grid` from grid.rs takes the place of this with the same methods
+
+use std::ops::Range;
+use std::sync::Arc;
+
+use super::ansi::PromptMarkKind;
+use super::buffer::{trim_line_ends, TerminalBuffer};
+use super::format_tracker::{FormatTracker, ImageTag};
+use super::{
+    split_format_data_for_scrollback, Cell, CursorPos, CursorState, FormatTag, ImagePlacement,
+    PromptMark, TerminalData, TerminalImage,
+};
+
+/// Where a write started, for attaching prompt marks and images to the text that follows. See
+/// [`Screen::insert_data`]
+pub(crate) type Anchor = usize;
+
+pub(crate) struct Screen {
+    buf: TerminalBuffer,
+    format_tracker: FormatTracker,
+}
+
+impl Screen {
+    pub(crate) fn new(width: usize, height: usize) -> Screen {
+        Screen {
+            buf: TerminalBuffer::new(width, height),
+            format_tracker: FormatTracker::new(),
+        }
+    }
+
+    pub(crate) fn dimensions(&self) -> (usize, usize) {
+        self.buf.dimensions()
+    }
+
+    pub(crate) fn auto_wrap(&self) -> bool {
+        self.buf.auto_wrap()
+    }
+
+    pub(crate) fn set_auto_wrap(&mut self, auto_wrap: bool) {
+        self.buf.set_auto_wrap(auto_wrap);
+    }
+
+    /// See [`TerminalBuffer::cancel_pending_wrap`]
+    pub(crate) fn cancel_pending_wrap(&mut self) {
+        self.buf.cancel_pending_wrap();
+    }
+
+    /// See [`TerminalBuffer::has_pending_wrap`]
+    pub(crate) fn has_pending_wrap(&self, cursor_pos: &CursorPos) -> bool {
+        self.buf.has_pending_wrap(cursor_pos)
+    }
+
+    pub(crate) fn set_scrollback_limit(&mut self, max_scrollback_lines: usize) {
+        self.buf.set_scrollback_limit(max_scrollback_lines);
+    }
+
+    /// Returns where the cursor ended up, None if the size did not change
+    pub(crate) fn set_win_size(
+        &mut self,
+        width: usize,
+        height: usize,
+        cursor_pos: &CursorPos,
+    ) -> Option<CursorPos> {
+        let response = self.buf.set_win_size(width, height, cursor_pos);
+        // Soft wraps are not stored so lines reflow by themselves, only the padding that keeps the
+        // cursor on a character can move text
+        self.format_tracker
+            .push_range_adjustment(response.insertion_range);
+        response.changed.then_some(response.new_cursor_pos)
+    }
+
+    /// Write `data` at `cursor_pos` formatted like `cursor`. Returns the cursor position after
+    /// the write and where the data ended up
+    pub(crate) fn insert_data(
+        &mut self,
+        cursor_pos: &CursorPos,
+        data: &[u8],
+        cursor: &CursorState,
+    ) -> (CursorPos, Anchor) {
+        let response = self.buf.insert_data(cursor_pos, data);
+        self.format_tracker.push_range_adjustment(response.insertion_range);
+        self.format_tracker
+            .push_range(cursor, response.written_range.clone());
+        let mut start = response.written_range.start;
+        if let Some(range) = response.trimmed_range {
+            start -= range.len();
+            self.format_tracker.delete_range(range);
+        }
+        (response.new_cursor_pos, start)
+    }
+
+    pub(crate) fn push_prompt_mark(&mut self, anchor: Anchor, kind: PromptMarkKind) {
+        self.format_tracker
+            .push_prompt_mark(PromptMark { start: anchor, kind });
+    }
+
+    /// Draw `image` over the cells below and right of where `anchor` is
+    pub(crate) fn push_image(
+        &mut self,
+        anchor: Anchor,
+        id: u64,
+        cells_wide: usize,
+        cells_high: usize,
+        image: Arc<TerminalImage>,
+    ) {
+        self.format_tracker.push_image(ImageTag {
+            start: anchor,
+            id,
+            cells_wide,
+            cells_high,
+            image,
+        });
+    }
+
+    /// Scroll the rows in `region` up by one, see [`TerminalBuffer::scroll_up`]
+    pub(crate) fn scroll_up(&mut self, region: Range<usize>) {
+        let response = self.buf.scroll_up(region);
+        self.format_tracker.push_range_adjustment(response.padding);
+        self.format_tracker
+            .push_range_adjustment(response.insertion_range);
+        if let Some(range) = response.deleted_range {
+            self.format_tracker.delete_range(range);
+        }
+        if let Some(range) = response.trimmed_range {
+            self.format_tracker.delete_range(range);
+        }
+    }
+
+    /// ICH, see [`TerminalBuffer::insert_blanks`]
+    pub(crate) fn insert_blanks(
+        &mut self,
+        cursor_pos: &CursorPos,
+        num_blanks: usize,
+        right_margin: usize,
+    ) {
+        let Some(response) = self.buf.insert_blanks(cursor_pos, num_blanks, right_margin) else {
+            return;
+        };
+        self.format_tracker
+            .push_range_adjustment(response.insertion_range);
+        if let Some(range) = response.deleted_range {
+            self.format_tracker.delete_range(range);
+        }
+    }
+
+    /// DCH, see [`TerminalBuffer::delete_chars`]
+    pub(crate) fn delete_chars(
+        &mut self,
+        cursor_pos: &CursorPos,
+        num_chars: usize,
+        right_margin: usize,
+    ) {
+        let Some(response) = self.buf.delete_chars(cursor_pos, num_chars, right_margin) else {
+            return;
+        };
+        self.format_tracker.delete_range(response.deleted_range);
+        if let Some(range) = response.insertion_range {
+            self.format_tracker.push_range_adjustment(range);
+        }
+    }
+
+    /// ECH, blanked cells are formatted like `erased`. See [`TerminalBuffer::erase_chars`]
+    pub(crate) fn erase_chars(
+        &mut self,
+        cursor_pos: &CursorPos,
+        num_chars: usize,
+        right_margin: usize,
+        erased: &CursorState,
+    ) {
+        if let Some(range) = self.buf.erase_chars(cursor_pos, num_chars, right_margin) {
+            self.format_tracker.push_range(erased, range);
+        }
+    }
+
+    /// Clear from the cursor to the end of the screen, what gets written there later is
+    /// formatted like `cursor` until something else is set
+    pub(crate) fn clear_forwards(&mut self, cursor_pos: &CursorPos, cursor: &CursorState) {
+        if let Some(buf_pos) = self.buf.clear_forwards(cursor_pos) {
+            self.format_tracker.push_range(cursor, buf_pos..usize::MAX);
+        }
+    }
+
+    pub(crate) fn clear_line_forwards(&mut self, cursor_pos: &CursorPos) {
+        if let Some(range) = self.buf.clear_line_forwards(cursor_pos) {
+            self.format_tracker.delete_range(range);
+        }
+    }
+
+    /// Blank the row up to and including the cursor, formatted like `erased`
+    pub(crate) fn clear_line_backwards(&mut self, cursor_pos: &CursorPos, erased: &CursorState) {
+        if let Some(range) = self.buf.clear_line_backwards(cursor_pos) {
+            self.format_tracker.push_range(erased, range);
+        }
+    }
+
+    /// Blank the screen up to and including the cursor, formatted like `erased`
+    pub(crate) fn clear_backwards(&mut self, cursor_pos: &CursorPos, erased: &CursorState) {
+        if let Some(range) = self.buf.clear_backwards(cursor_pos) {
+            self.format_tracker.push_range(erased, range);
+        }
+    }
+
+    /// Empty the buffer, what gets written next is formatted like `cursor` until something else
+    /// is set
+    pub(crate) fn clear_all(&mut self, cursor: &CursorState) {
+        self.format_tracker.push_range(cursor, 0..usize::MAX);
+        self.buf.clear_all();
+    }
+
+    /// Back to how a new screen starts out, keeping the size and scrollback limit
+    pub(crate) fn reset(&mut self) {
+        self.buf.clear_all();
+        self.buf.set_auto_wrap(true);
+        self.format_tracker.reset();
+    }
+
+    /// See [`TerminalBuffer::row_wraps`]
+    pub(crate) fn row_wraps(&self, row: usize) -> bool {
+        self.buf.row_wraps(row)
+    }
+
+    pub(crate) fn data(&self) -> TerminalData<&[u8]> {
+        self.buf.data()
+    }
+
+    pub(crate) fn format_data(&self) -> TerminalData<Vec<FormatTag>> {
+        split_format_data_for_scrollback(self.format_tracker.tags(), &self.buf.data_ranges())
+    }
+
+    pub(crate) fn prompt_marks(&self) -> &[PromptMark] {
+        self.format_tracker.prompt_marks()
+    }
+
+    pub(crate) fn cell(&self, x: usize, y: usize) -> Option<Cell> {
+        let (index, ch) = self.buf.visible_cell(x, y)?;
+        let tag = self.format_tracker.tag_at(index)?;
+        Some(Cell {
+            ch,
+            fg: tag.fg_color,
+            bg: tag.bg_color,
+            attrs: tag.attributes,
+        })
+    }
+
+    /// One line per visible row without trailing spaces
+    pub(crate) fn screen_text(&self) -> String {
+        let rows = self.buf.visible_rows().join(&b'\n');
+        String::from_utf8_lossy(&trim_line_ends(&rows)).into_owned()
+    }
+
+    /// See [`TerminalBuffer::text`]
+    pub(crate) fn visible_text(&self, logical_lines: bool) -> String {
+        self.buf.text(self.buf.data_ranges().visible, logical_lines)
+    }
+
+    /// [`Screen::visible_text`] of the scrollback and the screen
+    pub(crate) fn all_text(&self, logical_lines: bool) -> String {
+        let ranges = self.buf.data_ranges();
+        self.buf
+            .text(ranges.scrollback.start..ranges.visible.end, logical_lines)
+    }
+
+    /// Images whose top left corner is on screen
+    pub(crate) fn visible_images(&self) -> Vec<ImagePlacement> {
+        self.format_tracker
+            .images()
+            .iter()
+            .filter_map(|tag| {
+                let pos = self.buf.visible_pos(tag.start)?;
+                Some(ImagePlacement {
+                    id: tag.id,
+                    pos,
+                    cells_wide: tag.cells_wide,
+                    cells_high: tag.cells_high,
+                    image: Arc::clone(&tag.image),
+                })
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,273 @@
+//! Decoder for DEC sixel graphics, sent as `DCS P1;P2;P3 q <sixel data> ST`
+//!
+//! https://vt100.net/docs/vt3xx-gp/chapter14.html
+
+/// Images are clipped to this many pixels in either direction
+const MAX_DIMENSION: usize = 4096;
+
+/// Pixels drawn by a sixel, and how many
+const SIXEL_HEIGHT: usize = 6;
+
+/// VT340 default color map, in percent
+const DEFAULT_PALETTE: [[u8; 3]; 16] = [
+    [0, 0, 0],
+    [20, 20, 80],
+    [80, 13, 13],
+    [20, 80, 20],
+    [80, 20, 80],
+    [20, 80, 80],
+    [80, 80, 20],
+    [53, 53, 53],
+    [26, 26, 26],
+    [33, 33, 60],
+    [60, 26, 26],
+    [33, 60, 33],
+    [60, 33, 60],
+    [33, 60, 60],
+    [60, 60, 33],
+    [80, 80, 80],
+];
+
+const PALETTE_SIZE: usize = 256;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TerminalImage {
+    pub width: usize,
+    pub height: usize,
+    /// Row major, 4 bytes per pixel
+    pub rgba: Vec<u8>,
+}
+
+impl TerminalImage {
+    pub fn pixel(&self, x: usize, y: usize) -> [u8; 4] {
+        let start = (y * self.width + x) * 4;
+        self.rgba[start..start + 4].try_into().unwrap()
+    }
+}
+
+fn percent_to_u8(percent: usize) -> u8 {
+    ((percent.min(100) * 255 + 50) / 100) as u8
+}
+
+fn rgb_percent(r: usize, g: usize, b: usize) -> [u8; 3] {
+    [percent_to_u8(r), percent_to_u8(g), percent_to_u8(b)]
+}
+
+/// Sixel HLS has blue at 0 degrees and red at 120, rather than red at 0
+fn hls_to_rgb(hue: usize, lightness: usize, saturation: usize) -> [u8; 3] {
+    let hue = ((hue + 240) % 360) as f32;
+    let lightness = lightness.min(100) as f32 / 100.0;
+    let saturation = saturation.min(100) as f32 / 100.0;
+
+    let chroma = (1.0 - (2.0 * lightness - 1.0).abs()) * saturation;
+    let sector = hue / 60.0;
+    let x = chroma * (1.0 - (sector % 2.0 - 1.0).abs());
+    let (r, g, b) = match sector as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = lightness - chroma / 2.0;
+    let to_u8 = |v: f32| ((v + m) * 255.0).round().clamp(0.0, 255.0) as u8;
+    [to_u8(r), to_u8(g), to_u8(b)]
+}
+
+/// Read `;` separated numbers starting at `*pos`, leaving `*pos` on the first byte after them
+fn parse_numbers(data: &[u8], pos: &mut usize) -> Vec<usize> {
+    let mut numbers = vec![0usize];
+    while let Some(b) = data.get(*pos) {
+        match b {
+            b'0'..=b'9' => {
+                let last = numbers.last_mut().expect("numbers is never empty");
+                *last = last.saturating_mul(10).saturating_add((b - b'0') as usize);
+            }
+            b';' => numbers.push(0),
+            _ => break,
+        }
+        *pos += 1;
+    }
+    numbers
+}
+
+struct SixelDecoder {
+    palette: Vec<[u8; 3]>,
+    color: [u8; 3],
+    x: usize,
+    y: usize,
+    /// Drawn pixels, row major. Grows as sixels are drawn
+    pixels: Vec<Vec<Option<[u8; 3]>>>,
+    /// Size from the raster attributes, the image is at least this big
+    declared_size: (usize, usize),
+}
+
+impl SixelDecoder {
+    fn new() -> SixelDecoder {
+        let mut palette = vec![[0, 0, 0]; PALETTE_SIZE];
+        for (entry, percent) in palette.iter_mut().zip(DEFAULT_PALETTE) {
+            *entry = rgb_percent(percent[0] as usize, percent[1] as usize, percent[2] as usize);
+        }
+        let color = palette[0];
+
+        SixelDecoder {
+            palette,
+            color,
+            x: 0,
+            y: 0,
+            pixels: Vec::new(),
+            declared_size: (0, 0),
+        }
+    }
+
+    fn draw(&mut self, sixel: u8, count: usize) {
+        let bits = sixel - b'?';
+        let count = count.min(MAX_DIMENSION.saturating_sub(self.x));
+        for bit in 0..SIXEL_HEIGHT {
+            let y = self.y + bit;
+            if bits & (1 << bit) == 0 || y >= MAX_DIMENSION {
+                continue;
+            }
+
+            if self.pixels.len() <= y {
+                self.pixels.resize(y + 1, Vec::new());
+            }
+            let row = &mut self.pixels[y];
+            if row.len() < self.x + count {
+                row.resize(self.x + count, None);
+            }
+            row[self.x..self.x + count].fill(Some(self.color));
+        }
+        self.x += count;
+    }
+
+    fn select_or_define_color(&mut self, params: &[usize]) {
+        let index = params[0] % PALETTE_SIZE;
+        match params {
+            [_, 1, h, l, s] => self.palette[index] = hls_to_rgb(*h, *l, *s),
+            [_, 2, r, g, b] => self.palette[index] = rgb_percent(*r, *g, *b),
+            [_] => (),
+            _ => warn!("Invalid sixel color {params:?}"),
+        }
+        self.color = self.palette[index];
+    }
+
+    fn decode(mut self, data: &[u8], transparent_background: bool) -> TerminalImage {
+        let mut pos = 0;
+        while let Some(b) = data.get(pos) {
+            pos += 1;
+            match b {
+                b'?'..=b'~' => self.draw(*b, 1),
+                b'!' => {
+                    let count = parse_numbers(data, &mut pos)[0];
+                    if let Some(sixel @ b'?'..=b'~') = data.get(pos) {
+                        pos += 1;
+                        self.draw(*sixel, count);
+                    }
+                }
+                b'#' => {
+                    let params = parse_numbers(data, &mut pos);
+                    self.select_or_define_color(&params);
+                }
+                b'"' => {
+                    if let [_, _, width, height] = parse_numbers(data, &mut pos)[..] {
+                        self.declared_size =
+                            (width.min(MAX_DIMENSION), height.min(MAX_DIMENSION));
+                    }
+                }
+                b'$' => self.x = 0,
+                b'-' => {
+                    self.x = 0;
+                    self.y += SIXEL_HEIGHT;
+                }
+                _ => (),
+            }
+        }
+
+        self.into_image(transparent_background)
+    }
+
+    fn into_image(self, transparent_background: bool) -> TerminalImage {
+        let width = self
+            .pixels
+            .iter()
+            .map(Vec::len)
+            .max()
+            .unwrap_or(0)
+            .max(self.declared_size.0);
+        let height = self.pixels.len().max(self.declared_size.1);
+
+        let background = match transparent_background {
+            true => [0, 0, 0, 0],
+            false => {
+                let [r, g, b] = self.palette[0];
+                [r, g, b, 255]
+            }
+        };
+
+        let mut rgba = Vec::with_capacity(width * height * 4);
+        for y in 0..height {
+            for x in 0..width {
+                let pixel = self.pixels.get(y).and_then(|row| row.get(x)).copied().flatten();
+                match pixel {
+                    Some([r, g, b]) => rgba.extend([r, g, b, 255]),
+                    None => rgba.extend(background),
+                }
+            }
+        }
+
+        TerminalImage {
+            width,
+            height,
+            rgba,
+        }
+    }
+}
+
+/// Decode sixel `data`, the bytes after the `q` of the DCS. `params` are the DCS parameters
+pub(crate) fn decode(params: &[u8], data: &[u8]) -> TerminalImage {
+    let params = parse_numbers(params, &mut 0);
+    // P2 of 1 leaves pixels that are not drawn transparent
+    let transparent_background = params.get(1) == Some(&1);
+    SixelDecoder::new().decode(data, transparent_background)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const RED: [u8; 4] = [255, 0, 0, 255];
+    const BLUE: [u8; 4] = [0, 0, 255, 255];
+    const CLEAR: [u8; 4] = [0, 0, 0, 0];
+
+    #[test]
+    fn test_decode() {
+        // Two full red columns, the top left pixel drawn over in blue, then a band below with
+        // three pixels in its second row
+        let image = decode(b"0;1;0", b"#1;2;100;0;0~~$#2;2;0;0;100@-!3A");
+        assert_eq!((image.width, image.height), (3, 8));
+        assert_eq!(image.pixel(0, 0), BLUE);
+        assert_eq!(image.pixel(1, 0), RED);
+        assert_eq!(image.pixel(0, 5), RED);
+        assert_eq!(image.pixel(2, 0), CLEAR);
+        assert_eq!(image.pixel(0, 6), CLEAR);
+        assert_eq!(image.pixel(0, 7), BLUE);
+        assert_eq!(image.pixel(2, 7), BLUE);
+    }
+
+    #[test]
+    fn test_decode_hls_and_background() {
+        // Hue 120 is red in sixel HLS. Background is color 0, black by default
+        let image = decode(b"", b"\"1;1;2;1#3;1;120;50;100@");
+        assert_eq!((image.width, image.height), (2, 1));
+        assert_eq!(image.pixel(0, 0), RED);
+        assert_eq!(image.pixel(1, 0), [0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn test_decode_clips_huge_images() {
+        let image = decode(b"0;1", b"!99999~");
+        assert_eq!((image.width, image.height), (MAX_DIMENSION, SIXEL_HEIGHT));
+    }
+}
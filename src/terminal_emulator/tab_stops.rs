@@ -0,0 +1,102 @@
+//! Horizontal tab stops, set with HTS and cleared with TBC. Every 8th column starts out as a stop
+
+const DEFAULT_INTERVAL: usize = 8;
+
+pub(crate) struct TabStops {
+    /// One entry per column
+    stops: Vec<bool>,
+}
+
+impl TabStops {
+    pub(crate) fn new(width: usize) -> TabStops {
+        let mut tab_stops = TabStops { stops: Vec::new() };
+        tab_stops.resize(width);
+        tab_stops
+    }
+
+    /// Columns added on the right get the default stops, existing ones are kept
+    pub(crate) fn resize(&mut self, width: usize) {
+        let old_width = self.stops.len();
+        self.stops.truncate(width);
+        self.stops
+            .extend((old_width..width).map(|col| col % DEFAULT_INTERVAL == 0));
+    }
+
+    pub(crate) fn reset(&mut self) {
+        let width = self.stops.len();
+        self.stops.clear();
+        self.resize(width);
+    }
+
+    pub(crate) fn set(&mut self, col: usize) {
+        if let Some(stop) = self.stops.get_mut(col) {
+            *stop = true;
+        }
+    }
+
+    pub(crate) fn clear(&mut self, col: usize) {
+        if let Some(stop) = self.stops.get_mut(col) {
+            *stop = false;
+        }
+    }
+
+    pub(crate) fn clear_all(&mut self) {
+        self.stops.fill(false);
+    }
+
+    /// Column `n` stops right of `col`, stopping at the last column if there are not that many
+    pub(crate) fn forward(&self, mut col: usize, n: usize) -> usize {
+        let last_col = self.stops.len().saturating_sub(1);
+        col = col.min(last_col);
+        for _ in 0..n {
+            match (col + 1..last_col).find(|c| self.stops[*c]) {
+                Some(next) => col = next,
+                None => return last_col,
+            }
+        }
+        col
+    }
+
+    /// Column `n` stops left of `col`, stopping at the first column if there are not that many
+    pub(crate) fn backward(&self, mut col: usize, n: usize) -> usize {
+        col = col.min(self.stops.len().saturating_sub(1));
+        for _ in 0..n {
+            match (1..col).rev().find(|c| self.stops[*c]) {
+                Some(prev) => col = prev,
+                None => return 0,
+            }
+        }
+        col
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_default_stops() {
+        let tab_stops = TabStops::new(20);
+        assert_eq!(tab_stops.forward(0, 1), 8);
+        assert_eq!(tab_stops.forward(8, 1), 16);
+        assert_eq!(tab_stops.forward(3, 2), 16);
+        assert_eq!(tab_stops.forward(16, 1), 19);
+        assert_eq!(tab_stops.backward(19, 1), 16);
+        assert_eq!(tab_stops.backward(16, 1), 8);
+        assert_eq!(tab_stops.backward(9, 5), 0);
+        assert_eq!(tab_stops.backward(0, 1), 0);
+    }
+
+    #[test]
+    fn test_resize_keeps_stops() {
+        let mut tab_stops = TabStops::new(10);
+        tab_stops.clear_all();
+        tab_stops.set(3);
+        tab_stops.resize(20);
+        assert_eq!(tab_stops.forward(0, 1), 3);
+        assert_eq!(tab_stops.forward(3, 1), 16);
+
+        tab_stops.reset();
+        assert_eq!(tab_stops.forward(0, 1), 8);
+    }
+}
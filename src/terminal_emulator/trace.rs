@@ -0,0 +1,111 @@
+//! The last raw bytes read from the shell and what they were parsed into, for debugging the parser
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use super::ansi::TerminalOutput;
+
+/// Older bytes and outputs are dropped once there are more than these
+const MAX_TRACE_BYTES: usize = 4096;
+const MAX_TRACE_OUTPUTS: usize = 256;
+
+/// What [`TerminalEmulator::trace`](super::TerminalEmulator::trace) returns, oldest first
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Trace {
+    pub bytes: Vec<u8>,
+    pub outputs: Vec<String>,
+}
+
+#[derive(Default)]
+struct TraceBuffers {
+    bytes: VecDeque<u8>,
+    outputs: VecDeque<String>,
+}
+
+/// Where a trace is kept while tracing. Clones share it, like [`super::recording::Recording`],
+/// so that the reader thread can add the bytes it reads
+#[derive(Clone, Default)]
+pub(crate) struct Tracer(Arc<Mutex<Option<TraceBuffers>>>);
+
+impl Tracer {
+    /// Start or stop tracing, stopping drops what was traced
+    pub(crate) fn set_active(&self, active: bool) {
+        let mut buffers = self.lock();
+        match (active, buffers.is_some()) {
+            (true, false) => *buffers = Some(TraceBuffers::default()),
+            (false, true) => *buffers = None,
+            _ => (),
+        }
+    }
+
+    pub(crate) fn write_bytes(&self, data: &[u8]) {
+        if let Some(buffers) = self.lock().as_mut() {
+            buffers.bytes.extend(data);
+            let excess = buffers.bytes.len().saturating_sub(MAX_TRACE_BYTES);
+            buffers.bytes.drain(..excess);
+        }
+    }
+
+    pub(crate) fn push_output(&self, output: &TerminalOutput) {
+        if let Some(buffers) = self.lock().as_mut() {
+            if buffers.outputs.len() == MAX_TRACE_OUTPUTS {
+                buffers.outputs.pop_front();
+            }
+            buffers.outputs.push_back(describe_output(output));
+        }
+    }
+
+    pub(crate) fn snapshot(&self) -> Option<Trace> {
+        self.lock().as_ref().map(|buffers| Trace {
+            bytes: buffers.bytes.iter().copied().collect(),
+            outputs: buffers.outputs.iter().cloned().collect(),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Option<TraceBuffers>> {
+        // Nothing panics while holding the lock, the trace is still usable if something did
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// `output`'s Debug form, with text shown as text rather than a list of bytes
+fn describe_output(output: &TerminalOutput) -> String {
+    match output {
+        TerminalOutput::Data(data) => format!("Data({:?})", String::from_utf8_lossy(data)),
+        TerminalOutput::DataBorrowed(data) => format!("Data({:?})", String::from_utf8_lossy(data)),
+        output => format!("{output:?}"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_trace_is_bounded() {
+        let tracer = Tracer::default();
+        tracer.write_bytes(b"before");
+        tracer.push_output(&TerminalOutput::Bell);
+        assert_eq!(tracer.snapshot(), None);
+
+        tracer.set_active(true);
+        tracer.write_bytes(&vec![b'a'; MAX_TRACE_BYTES]);
+        tracer.write_bytes(b"hi");
+        for _ in 0..MAX_TRACE_OUTPUTS {
+            tracer.push_output(&TerminalOutput::Bell);
+        }
+        tracer.push_output(&TerminalOutput::DataBorrowed(b"hi"));
+
+        let trace = tracer.snapshot().unwrap();
+        assert_eq!(trace.bytes.len(), MAX_TRACE_BYTES);
+        assert!(trace.bytes.ends_with(b"aahi"));
+        assert_eq!(trace.outputs.len(), MAX_TRACE_OUTPUTS);
+        assert_eq!(trace.outputs.last().unwrap(), "Data(\"hi\")");
+
+        // Turning it on again keeps it, turning it off drops it
+        tracer.set_active(true);
+        assert_eq!(tracer.snapshot().unwrap(), trace);
+        tracer.set_active(false);
+        assert_eq!(tracer.snapshot(), None);
+    }
+}
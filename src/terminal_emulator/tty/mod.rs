@@ -1,27 +1,49 @@
-pub(crate) mod unix;
-// tty/mod.rs
+//! TTY related functionality.
 use std::collections::HashMap;
 use std::path::PathBuf;
+use std::io;
 use std::sync::Arc;
-use std::{env, io};
 
 use polling::{Event, PollMode, Poller};
 
-pub use unix::*;
+mod unix;
 
 pub use self::unix::*;
 
+/// `TERM` unless [`Options::term`] says otherwise, the terminfo entry whose capabilities the
+/// emulator implements
+pub const DEFAULT_TERM: &str = "xterm-256color";
+
+/// `COLORTERM` unless [`Options::colorterm`] says otherwise, advertising 24-bit color
+pub const DEFAULT_COLORTERM: &str = "truecolor";
+
 /// Configuration for the `Pty` interface
-#[derive(Clone, Debug, PartialEq, Eq, Default)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Options {
-    /// Shell configuration
+    /// Shell configuration, the user's login shell is used when unset
     pub shell: Option<Shell>,
     /// Working directory
     pub working_directory: Option<PathBuf>,
-    /// Environment variables
+    /// `TERM` for the shell, whatever the emulator was started with
+    pub term: String,
+    /// `COLORTERM` for the shell, which does not get one when unset
+    pub colorterm: Option<String>,
+    /// Environment variables, set after and so overriding the ones above
     pub env: HashMap<String, String>,
 }
 
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            shell: None,
+            working_directory: None,
+            term: DEFAULT_TERM.to_string(),
+            colorterm: Some(DEFAULT_COLORTERM.to_string()),
+            env: HashMap::new(),
+        }
+    }
+}
+
 /// Shell configuration
 #[derive(Clone, Debug, PartialEq, Eq, Default)]
 pub struct Shell {
@@ -40,6 +62,9 @@ pub trait EventedReadWrite {
     type Reader: io::Read;
     type Writer: io::Write;
 
+    /// # Safety
+    ///
+    /// The underlying sources must be deregistered before they are dropped
     unsafe fn register(
         &mut self,
         poll: &Arc<Poller>,
@@ -70,14 +95,3 @@ pub enum ChildEvent {
 pub trait EventedPty: EventedReadWrite {
     fn next_child_event(&mut self) -> Option<ChildEvent>;
 }
-
-/// Terminal environment setup
-pub fn setup_env() {
-    // Set default TERM if not already configured
-    if env::var("TERM").is_err() {
-        env::set_var("TERM", "xterm-256color");
-    }
-
-    // Advertise truecolor support
-    env::set_var("COLORTERM", "truecolor");
-}
\ No newline at end of file
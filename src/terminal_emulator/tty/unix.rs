@@ -1,67 +1,99 @@
-use std::ffi::CStr;
 use std::fs::File;
 use std::io::{Error, ErrorKind, Read, Result};
-use std::mem::MaybeUninit;
-use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
 use std::os::unix::net::UnixStream;
 use std::os::unix::process::CommandExt;
 #[cfg(target_os = "macos")]
 use std::path::Path;
 use std::process::{Child, Command};
 use std::sync::Arc;
-use std::{env, ptr};
+use std::time::{Duration, Instant};
+use std::{env, thread};
+
 use libc::c_int;
-use nix::unistd::Pid;
 use nix::fcntl::{self, FcntlArg, OFlag};
 use nix::pty::{openpty, Winsize};
-use nix::sys::ioctl;
 use nix::sys::signal::{self, SigHandler, Signal};
-use nix::sys::termios::{self, InputFlags, SetArg,Termios};
-use nix::unistd::{self, Uid, User};
+use nix::sys::termios::{self, InputFlags, SetArg};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{self, Pid, Uid, User};
 use polling::{Event, PollMode, Poller};
+use signal_hook::consts as sigconsts;
 use signal_hook::low_level::{pipe as signal_pipe, unregister as unregister_signal};
-use signal_hook::{consts as sigconsts, SigId};
+use signal_hook::SigId;
 
-// Add this at the top of unix.rs
-use log::error;
+use crate::terminal_emulator::error::TerminalError;
 use crate::terminal_emulator::event::{OnResize, WindowSize};
 use crate::terminal_emulator::tty::{ChildEvent, EventedPty, EventedReadWrite, Options};
-nix::ioctl_write_ptr!(tiocswinsz, 'T', 103, nix::pty::Winsize);
-nix::ioctl_none!(tiocsctty, 'T', 98);
+
+nix::ioctl_write_ptr_bad!(tiocswinsz, libc::TIOCSWINSZ, Winsize);
+nix::ioctl_none_bad!(tiocsctty, libc::TIOCSCTTY);
 
 pub(crate) const PTY_READ_WRITE_TOKEN: usize = 0;
 
 pub(crate) const PTY_CHILD_EVENT_TOKEN: usize = 1;
 
-macro_rules! die {
-    ($($arg:tt)*) => {{
-        error!($($arg)*);
-        std::process::exit(1);
-    }}
+/// How long a child gets to exit after SIGHUP before it is killed
+pub const HANGUP_GRACE_PERIOD: Duration = Duration::from_millis(500);
+
+fn set_controlling_terminal(fd: RawFd) -> Result<c_int> {
+    // SAFETY: fd is the slave end of a freshly opened pty, owned by the child at this point
+    unsafe { tiocsctty(fd) }.map_err(Error::from)
 }
 
-fn set_controlling_terminal(fd: RawFd) -> std::result::Result<c_int, Error> {
-    unsafe { tiocsctty(fd) }
-        .map_err(|e| Error::new(ErrorKind::Other, e))
+/// User information that is needed to launch the shell
+struct ShellUser {
+    user: String,
+    home: String,
+    shell: String,
 }
 
-/// User information structure
-struct Passwd<'a> {
-    name: &'a str,
-    dir: &'a str,
-    shell: &'a str,
+impl ShellUser {
+    /// Look up the user's shell and home, preferring the environment over the passwd entry
+    fn from_env() -> Result<Self> {
+        let pw = User::from_uid(Uid::current())
+            .map_err(Error::from)
+            .and_then(|user| {
+                user.ok_or_else(|| Error::new(ErrorKind::NotFound, "User not found"))
+            });
+
+        let user = match env::var("USER") {
+            Ok(user) => user,
+            Err(_) => pw.as_ref().map_err(clone_error)?.name.clone(),
+        };
+
+        let home = match env::var("HOME") {
+            Ok(home) => home,
+            Err(_) => pw.as_ref().map_err(clone_error)?.dir.to_string_lossy().into_owned(),
+        };
+
+        let shell = match env::var("SHELL") {
+            Ok(shell) => shell,
+            Err(_) => pw.as_ref().map_err(clone_error)?.shell.to_string_lossy().into_owned(),
+        };
+
+        Ok(Self { user, home, shell })
+    }
 }
 
-/// Get user information
-fn get_pw_entry() -> Result<Passwd<'static>> {
-    let user = User::from_uid(Uid::current())?
-        .ok_or_else(|| Error::new(ErrorKind::NotFound, "User not found"))?;
+fn clone_error(e: &Error) -> Error {
+    Error::new(e.kind(), e.to_string())
+}
 
-    Ok(Passwd {
-        name: user.name.as_str(),
-        dir: user.dir.as_str()?,
-        shell: user.shell.as_str()?,
-    })
+#[cfg(not(target_os = "macos"))]
+fn default_shell_command(shell: &str, _user: &str, _home: &str) -> Command {
+    Command::new(shell)
+}
+
+#[cfg(target_os = "macos")]
+fn default_shell_command(shell: &str, user: &str, home: &str) -> Command {
+    let shell_name = shell.rsplit('/').next().unwrap_or(shell);
+    let mut login_command = Command::new("/usr/bin/login");
+    let exec = format!("exec -a -{} {}", shell_name, shell);
+    let has_home_hushlogin = Path::new(home).join(".hushlogin").exists();
+    let flags = if has_home_hushlogin { "-qflp" } else { "-flp" };
+    login_command.args([flags, user, "/bin/zsh", "-fc", &exec]);
+    login_command
 }
 
 pub struct Pty {
@@ -69,28 +101,13 @@ pub struct Pty {
     file: File,
     signals: UnixStream,
     sig_id: SigId,
+    /// Set once SIGHUP was sent, see [`Pty::hang_up`]
+    hung_up: bool,
+    /// Set once [`Pty::reap`] waited for the child, which `child` does not know about
+    reaped: bool,
 }
 
-
-
 impl Pty {
-    pub fn new(config: &Options, window_size: WindowSize) -> Result<Self> {
-        let winsize = window_size.to_winsize();
-        let pty = openpty(Some(&winsize), None)?;
-        let master = unsafe { OwnedFd::from_raw_fd(pty.master) };
-        let slave = unsafe { OwnedFd::from_raw_fd(pty.slave) };
-
-        // Remove the old placeholder implementation
-        // and use the actual initialization code
-        let (child, file, signals, sig_id) = setup_pty(config, master, slave)?;
-
-        Ok(Pty {
-            child,
-            file,
-            signals,
-            sig_id
-        })
-    }
     pub fn child(&self) -> &Child {
         &self.child
     }
@@ -98,179 +115,89 @@ impl Pty {
     pub fn file(&self) -> &File {
         &self.file
     }
-}
-// Remove the nested setup_pty definition and fix parameters
-fn setup_pty(
-    config: &Options,
-    master: OwnedFd,
-    slave: OwnedFd
-) -> Result<(Child, File, UnixStream, SigId)> {
-    let user = ShellUser::from_env()?;
-    let mut builder = if let Some(shell) = &config.shell {
-        let mut cmd = Command::new(&shell.program);
-        cmd.args(&shell.args);
-        cmd
-    } else {
-        default_shell_command(&user.shell, &user.user, &user.home)
-    };
 
-    builder
-        .stdin(slave.try_clone()?)
-        .stdout(slave.try_clone()?)
-        .stderr(slave)
-        .env("TERM", "xterm-256color")
-        .env("COLORTERM", "truecolor")
-        .env("USER", &user.user)
-        .env("HOME", &user.home)
-        .env_remove("XDG_ACTIVATION_TOKEN")
-        .env_remove("DESKTOP_STARTUP_ID");
-
-    if let Some(wd) = &config.working_directory {
-        builder.current_dir(wd);
+    /// Tell the kernel (and in turn the shell) about the new window size
+    pub fn set_window_size(&mut self, window_size: WindowSize) -> std::result::Result<(), TerminalError> {
+        let win = window_size.to_winsize();
+        // SAFETY: file is a valid pty master for the lifetime of self
+        unsafe { tiocswinsz(self.file.as_raw_fd(), &win) }
+            .map_err(|e| TerminalError::Ioctl(e.into()))?;
+        Ok(())
     }
 
-    unsafe {
-        builder.pre_exec(move || {
-            unistd::setsid()?;
-            ioctl::ioctl(slave.as_raw_fd(), tiocsctty)?;
-
-            unsafe {
-                libc::close(slave.as_raw_fd());
-                libc::close(master.as_raw_fd());
-            }
-
-            for sig in &[
-                Signal::SIGCHLD,
-                Signal::SIGHUP,
-                Signal::SIGINT,
-                Signal::SIGQUIT,
-                Signal::SIGTERM,
-            ] {
-                signal::signal(*sig, SigHandler::SigDfl)?;
-            }
-
-            Ok(())
-        });
+    /// Whether XON/XOFF flow control (IXON) is on. Programs in raw mode turn it off to receive
+    /// Ctrl+S and Ctrl+Q themselves
+    pub fn flow_control_enabled(&self) -> bool {
+        termios::tcgetattr(&self.file)
+            .is_ok_and(|termios| termios.input_flags.contains(InputFlags::IXON))
     }
 
-    let (sender, recv) = UnixStream::pair()?;
-    let sig_id = signal_pipe::register(sigconsts::SIGCHLD, sender)?;
-    recv.set_nonblocking(true)?;
-
-    let child = builder.spawn().map_err(|e| {
-        Error::new(
-            ErrorKind::Other,
-            format!("Failed to spawn shell: {}", e),
-        )
-    })?;
-
-    set_nonblocking(master.as_raw_fd())?;
-
-    Ok((
-        child,
-        File::from(master),
-        recv,
-        sig_id
-    ))
-}
-
-// Fix PathBuf to string conversion
-fn get_pw_entry() -> Result<Passwd<'static>> {
-    let user = User::from_uid(Uid::current())?
-        .ok_or_else(|| Error::new(ErrorKind::NotFound, "User not found"))?;
-
-    Ok(Passwd {
-        name: user.name.as_str(),
-        dir: user.dir.to_str()
-            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Invalid home directory"))?,
-        shell: user.shell.to_str()
-            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Invalid shell path"))?,
-    })
-}
-
-// Remove duplicate implementations
-struct ShellUser {
-    user: String,
-    home: String,
-    shell: String,
-}
-
-impl ShellUser {
-    fn from_env() -> Result<Self> {
-        let pw = get_pw_entry()?;
-
-        Ok(Self {
-            user: env::var("USER").unwrap_or_else(|_| pw.name.to_owned()),
-            home: env::var("HOME").unwrap_or_else(|_| pw.dir.to_owned()),
-            shell: env::var("SHELL").unwrap_or_else(|_| pw.shell.to_owned()),
-        })
+    /// Send the child SIGHUP, which shells exit on. Only the first call sends it
+    pub fn hang_up(&mut self) {
+        if !self.hung_up {
+            let _ = signal::kill(self.pid(), Signal::SIGHUP);
+            self.hung_up = true;
+        }
     }
-}
 
-
-/// User information
-struct ShellUser {
-    user: String,
-    home: String,
-    shell: String,
-}
-
-impl ShellUser {
-    fn from_env() -> Result<Self> {
-        let pw = get_pw_entry();
-
-        let user = env::var("USER")
-            .or_else(|_| pw.as_ref().map(|p| p.name.to_string()))
-            .map_err(|_| Error::new(ErrorKind::Other, "Failed to get username"))?;
-
-        let home = env::var("HOME")
-            .or_else(|_| pw.as_ref().map(|p| p.dir.to_string()))
-            .map_err(|_| Error::new(ErrorKind::Other, "Failed to get home directory"))?;
-
-        let shell = env::var("SHELL")
-            .or_else(|_| pw.as_ref().map(|p| p.shell.to_string()))
-            .map_err(|_| Error::new(ErrorKind::Other, "Failed to get shell"))?;
-
-        Ok(Self { user, home, shell })
+    /// Hang up on the child and wait for it to exit until `deadline`, killing it if it is still
+    /// running then. Hanging up on several children first and then reaping them against one
+    /// deadline waits for the slowest of them only
+    pub fn reap(&mut self, deadline: Instant) {
+        self.hang_up();
+        if matches!(self.child.try_wait(), Ok(None)) {
+            reap(self.pid(), deadline);
+            self.reaped = true;
+        }
     }
-}
 
-#[cfg(not(target_os = "macos"))]
-fn default_shell_command(shell: &str, _user: &str, _home: &str) -> Command {
-    Command::new(shell)
+    fn pid(&self) -> Pid {
+        Pid::from_raw(self.child.id() as i32)
+    }
 }
 
-#[cfg(target_os = "macos")]
-fn default_shell_command(shell: &str, user: &str, home: &str) -> Command {
-    let shell_name = shell.rsplit('/').next().unwrap();
-    let mut login_command = Command::new("/usr/bin/login");
-    let exec = format!("exec -a -{} {}", shell_name, shell);
-    let has_home_hushlogin = Path::new(home).join(".hushlogin").exists();
-    let flags = if has_home_hushlogin { "-qflp" } else { "-flp" };
-    login_command.args([flags, user, "/bin/zsh", "-fc", &exec]);
-    login_command
+/// Wait for the child `pid` to exit until `deadline`, killing it if it is still running then
+fn reap(pid: Pid, deadline: Instant) {
+    while Instant::now() < deadline {
+        match waitpid(pid, Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::StillAlive) => thread::sleep(Duration::from_millis(10)),
+            // Exited, or can't be waited for at all
+            _ => return,
+        }
+    }
+    warn!("Child {pid} is still running after SIGHUP, killing it");
+    let _ = signal::kill(pid, Signal::SIGKILL);
+    let _ = waitpid(pid, None);
 }
 
-/// Create a new PTY
-pub fn new(config: &Options, window_size: WindowSize, window_id: u64) -> Result<Pty> {
+/// Create a new PTY and spawn the configured shell on it
+pub fn new(
+    config: &Options,
+    window_size: WindowSize,
+    window_id: u64,
+) -> std::result::Result<Pty, TerminalError> {
     let winsize = window_size.to_winsize();
-    let pty = openpty(Some(&winsize), None)?;
-    let master = unsafe { OwnedFd::from_raw_fd(pty.master) };
-    let slave = unsafe { OwnedFd::from_raw_fd(pty.slave) };
-    from_fd(config, window_id, master, slave)
+    let pty = openpty(Some(&winsize), None).map_err(|e| TerminalError::OpenPty(e.into()))?;
+    from_fd(config, window_id, pty.master, pty.slave)
 }
 
-pub fn from_fd(config: &Options, window_id: u64, master: OwnedFd, slave: OwnedFd) -> Result<Pty> {
+/// Spawn the configured shell on an already opened master/slave pair
+pub fn from_fd(
+    config: &Options,
+    window_id: u64,
+    master: OwnedFd,
+    slave: OwnedFd,
+) -> std::result::Result<Pty, TerminalError> {
     let master_fd = master.as_raw_fd();
     let slave_fd = slave.as_raw_fd();
 
     #[cfg(any(target_os = "linux", target_os = "macos"))]
-    if let Ok(mut termios) = termios::tcgetattr(master_fd) {
+    if let Ok(mut termios) = termios::tcgetattr(&master) {
         termios.input_flags.set(InputFlags::IUTF8, true);
-        let _ = termios::tcsetattr(master_fd, SetArg::TCSANOW, &termios);
+        let _ = termios::tcsetattr(&master, SetArg::TCSANOW, &termios);
     }
 
-    let user = ShellUser::from_env()?;
+    let user = ShellUser::from_env().map_err(TerminalError::Fork)?;
 
     let mut builder = if let Some(shell) = config.shell.as_ref() {
         let mut cmd = Command::new(&shell.program);
@@ -280,8 +207,8 @@ pub fn from_fd(config: &Options, window_id: u64, master: OwnedFd, slave: OwnedFd
         default_shell_command(&user.shell, &user.user, &user.home)
     };
 
-    builder.stdin(slave.try_clone()?);
-    builder.stderr(slave.try_clone()?);
+    builder.stdin(slave.try_clone().map_err(TerminalError::Fork)?);
+    builder.stderr(slave.try_clone().map_err(TerminalError::Fork)?);
     builder.stdout(slave);
 
     let window_id = window_id.to_string();
@@ -289,6 +216,11 @@ pub fn from_fd(config: &Options, window_id: u64, master: OwnedFd, slave: OwnedFd
     builder.env("USER", user.user);
     builder.env("HOME", user.home);
     builder.env("WINDOWID", window_id);
+    builder.env("TERM", &config.term);
+    match &config.colorterm {
+        Some(colorterm) => builder.env("COLORTERM", colorterm),
+        None => builder.env_remove("COLORTERM"),
+    };
     for (key, value) in &config.env {
         builder.env(key, value);
     }
@@ -297,9 +229,10 @@ pub fn from_fd(config: &Options, window_id: u64, master: OwnedFd, slave: OwnedFd
     builder.env_remove("DESKTOP_STARTUP_ID");
 
     let working_directory = config.working_directory.clone();
+    // SAFETY: Only async-signal-safe calls are made between fork and exec
     unsafe {
         builder.pre_exec(move || {
-            unistd::setsid().map_err(|e| Error::new(ErrorKind::Other, e))?;
+            unistd::setsid().map_err(Error::from)?;
 
             if let Some(working_directory) = working_directory.as_ref() {
                 let _ = env::set_current_dir(working_directory);
@@ -307,14 +240,18 @@ pub fn from_fd(config: &Options, window_id: u64, master: OwnedFd, slave: OwnedFd
 
             set_controlling_terminal(slave_fd)?;
 
-            unsafe {
-                nix::libc::close(slave_fd);
-                nix::libc::close(master_fd);
-            }
+            libc::close(slave_fd);
+            libc::close(master_fd);
 
-            for sig in &[Signal::SIGCHLD, Signal::SIGHUP, Signal::SIGINT,
-                Signal::SIGQUIT, Signal::SIGTERM, Signal::SIGALRM] {
-                signal::signal(*sig, SigHandler::SigDfl)?;
+            for sig in &[
+                Signal::SIGCHLD,
+                Signal::SIGHUP,
+                Signal::SIGINT,
+                Signal::SIGQUIT,
+                Signal::SIGTERM,
+                Signal::SIGALRM,
+            ] {
+                signal::signal(*sig, SigHandler::SigDfl).map_err(Error::from)?;
             }
 
             Ok(())
@@ -322,52 +259,145 @@ pub fn from_fd(config: &Options, window_id: u64, master: OwnedFd, slave: OwnedFd
     }
 
     let (signals, sig_id) = {
-        let (sender, recv) = UnixStream::pair()?;
-        let sig_id = signal_pipe::register(sigconsts::SIGCHLD, sender)?;
-        recv.set_nonblocking(true)?;
+        let (sender, recv) = UnixStream::pair().map_err(TerminalError::Fork)?;
+        let sig_id =
+            signal_pipe::register(sigconsts::SIGCHLD, sender).map_err(TerminalError::Fork)?;
+        if let Err(e) = recv.set_nonblocking(true) {
+            unregister_signal(sig_id);
+            return Err(TerminalError::NonBlocking(e));
+        }
         (recv, sig_id)
     };
 
     match builder.spawn() {
         Ok(child) => {
-            set_nonblocking(master_fd)?;
-            Ok(Pty { child, file: File::from(master), signals, sig_id })
-        },
-        Err(err) => Err(Error::new(
-            err.kind(),
-            format!("Failed to spawn command '{}': {}", builder.get_program().to_string_lossy(), err),
-        )),
+            // Dropping the Pty takes care of reaping the child if this fails
+            let pty = Pty {
+                child,
+                file: File::from(master),
+                signals,
+                sig_id,
+                hung_up: false,
+                reaped: false,
+            };
+            set_nonblocking(master_fd).map_err(TerminalError::NonBlocking)?;
+            Ok(pty)
+        }
+        Err(err) => {
+            unregister_signal(sig_id);
+            Err(TerminalError::Fork(Error::new(
+                err.kind(),
+                format!(
+                    "Failed to spawn command '{}': {}",
+                    builder.get_program().to_string_lossy(),
+                    err
+                ),
+            )))
+        }
     }
 }
 
-
-
 impl Drop for Pty {
     fn drop(&mut self) {
-        // Convert child PID to nix's Pid type
-        let pid = Pid::from_raw(self.child.id() as i32);
+        // Make sure the child is not left running once the terminal goes away. Shells exit on
+        // SIGHUP, one that ignores it is killed after a grace period. That is waited out on a
+        // thread of its own, so that closing a terminal does not hold up the thread closing it
+        self.hang_up();
+        unregister_signal(self.sig_id);
+        if self.reaped || !matches!(self.child.try_wait(), Ok(None)) {
+            return;
+        }
 
-        // Send SIGHUP to child process
-        let _ = signal::kill(pid, Signal::SIGHUP);
+        let pid = self.pid();
+        let deadline = Instant::now() + HANGUP_GRACE_PERIOD;
+        let spawned = thread::Builder::new()
+            .name("pty reaper".to_string())
+            .spawn(move || reap(pid, deadline));
+        if let Err(e) = spawned {
+            warn!("Failed to start a thread to reap child {pid}: {e}");
+            reap(pid, deadline);
+        }
+    }
+}
 
-        // Clean up signal handler
-        unregister_signal(self.sig_id);
+impl EventedReadWrite for Pty {
+    type Reader = File;
+    type Writer = File;
+
+    unsafe fn register(
+        &mut self,
+        poll: &Arc<Poller>,
+        mut interest: Event,
+        poll_opts: PollMode,
+    ) -> Result<()> {
+        interest.key = PTY_READ_WRITE_TOKEN;
+        poll.add_with_mode(&self.file, interest, poll_opts)?;
+        poll.add_with_mode(
+            &self.signals,
+            Event::readable(PTY_CHILD_EVENT_TOKEN),
+            PollMode::Level,
+        )
+    }
 
-        // Wait for child to exit
-        let _ = self.child.wait();
+    fn reregister(
+        &mut self,
+        poll: &Arc<Poller>,
+        mut interest: Event,
+        poll_opts: PollMode,
+    ) -> Result<()> {
+        interest.key = PTY_READ_WRITE_TOKEN;
+        poll.modify_with_mode(&self.file, interest, poll_opts)?;
+        poll.modify_with_mode(
+            &self.signals,
+            Event::readable(PTY_CHILD_EVENT_TOKEN),
+            PollMode::Level,
+        )
+    }
+
+    fn deregister(&mut self, poll: &Arc<Poller>) -> Result<()> {
+        poll.delete(&self.file)?;
+        poll.delete(&self.signals)
+    }
+
+    fn reader(&mut self) -> &mut File {
+        &mut self.file
+    }
+
+    fn writer(&mut self) -> &mut File {
+        &mut self.file
     }
 }
 
-// Rest of EventedReadWrite, EventedPty, and OnResize implementations remain similar
-// but use nix where appropriate for Winsize and ioctl calls...
+impl EventedPty for Pty {
+    fn next_child_event(&mut self) -> Option<ChildEvent> {
+        // See if there has been a SIGCHLD
+        let mut buf = [0u8; 1];
+        if let Err(err) = self.signals.read(&mut buf) {
+            if err.kind() != ErrorKind::WouldBlock {
+                error!("Error reading from signal pipe: {}", err);
+            }
+            return None;
+        }
+
+        match self.child.try_wait() {
+            Err(err) => {
+                error!("Error checking child process termination: {}", err);
+                None
+            }
+            Ok(None) => None,
+            Ok(exit_status) => Some(ChildEvent::Exited(exit_status.and_then(|s| s.code()))),
+        }
+    }
+}
 
 impl OnResize for Pty {
     fn on_resize(&mut self, window_size: WindowSize) {
-        let win = window_size.to_winsize();
-        unsafe { tiocswinsz(self.file.as_raw_fd(), &win) }
-            .unwrap_or_else(|e| die!("ioctl TIOCSWINSZ failed: {}", e));
+        if let Err(e) = self.set_window_size(window_size) {
+            error!("{e}");
+        }
     }
 }
+
 /// Winsize conversion
 pub trait ToWinsize {
     fn to_winsize(self) -> Winsize;
@@ -376,10 +406,10 @@ pub trait ToWinsize {
 impl ToWinsize for WindowSize {
     fn to_winsize(self) -> Winsize {
         Winsize {
-            ws_row: self.num_lines as u16,
-            ws_col: self.num_cols as u16,
-            ws_xpixel: (self.num_cols * self.cell_width) as u16,
-            ws_ypixel: (self.num_lines * self.cell_height) as u16,
+            ws_row: self.num_lines,
+            ws_col: self.num_cols,
+            ws_xpixel: self.num_cols.saturating_mul(self.cell_width),
+            ws_ypixel: self.num_lines.saturating_mul(self.cell_height),
         }
     }
 }
@@ -391,4 +421,3 @@ fn set_nonblocking(fd: RawFd) -> Result<()> {
     fcntl::fcntl(fd, FcntlArg::F_SETFL(new_flags))?;
     Ok(())
 }
-